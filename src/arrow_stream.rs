@@ -0,0 +1,118 @@
+use crate::error::ConverterError;
+use polars::export::arrow::io::ipc::write::{StreamWriter, WriteOptions};
+use polars::prelude::*;
+use std::fs::File;
+use std::io::Write;
+use std::net::TcpListener;
+use std::os::fd::FromRawFd;
+use std::os::unix::net::UnixListener;
+
+/*
+The hit table as an Arrow IPC stream (--arrow-stream), for online monitoring
+without temporary files. Every time the sorter flushes its hits (every chunk,
+and every --flush-interval seconds for a followed run or a stream) they go out
+as a record batch, with the same columns as the converted parquet file.
+
+  -                  stdout, everything that is normally printed goes to stderr
+  unix:/path/sock    a unix socket, the converter waits for a client to connect
+  tcp://host:port    a tcp port, the converter waits for a client to connect
+
+e.g. pyarrow.ipc.open_stream(socket.create_connection(("daq01", 9091)).makefile("rb"))
+reads the batches as they come. If the client goes away the conversion carries
+on without the stream.
+*/
+
+pub struct ArrowStream {
+    target: String,
+    // until the columns are known
+    output: Option<Box<dyn Write + Send>>,
+    // unbuffered, the client gets every batch as soon as it is written
+    writer: Option<StreamWriter<Box<dyn Write + Send>>>,
+}
+
+fn stream_error(target: &str) -> impl Fn(std::io::Error) -> ConverterError + '_ {
+    move |e| ConverterError::Io(target.to_string(), e)
+}
+
+impl ArrowStream {
+    // Waits for the client. This happens before anything is read so that with
+    // stdout nothing else gets printed into the stream.
+    pub fn open(target: &str) -> Result<Self, ConverterError> {
+        let output: Box<dyn Write + Send> = if target == "-" {
+            // the stream gets the real stdout and the messages go to stderr
+            unsafe {
+                let fd = libc::dup(1);
+                if fd < 0 || libc::dup2(2, 1) < 0 {
+                    return Err(ConverterError::Io(
+                        "stdout".to_string(),
+                        std::io::Error::last_os_error(),
+                    ));
+                }
+                Box::new(File::from_raw_fd(fd))
+            }
+        } else if let Some(path) = target.strip_prefix("unix:") {
+            // a socket left over from an earlier run
+            let _ = std::fs::remove_file(path);
+            let listener = UnixListener::bind(path).map_err(stream_error(target))?;
+            println!("Waiting for the arrow stream client on {}", target);
+            Box::new(listener.accept().map_err(stream_error(target))?.0)
+        } else if let Some(address) = target.strip_prefix("tcp://") {
+            let listener = TcpListener::bind(address).map_err(stream_error(target))?;
+            println!("Waiting for the arrow stream client on {}", target);
+            Box::new(listener.accept().map_err(stream_error(target))?.0)
+        } else {
+            return Err(ConverterError::Config(format!(
+                "bad arrow stream {}, use -, unix:/path or tcp://host:port",
+                target
+            )));
+        };
+        Ok(ArrowStream {
+            target: target.to_string(),
+            output: Some(output),
+            writer: None,
+        })
+    }
+
+    // the client is gone, the run is converted anyway
+    fn stop(&mut self, e: PolarsError) {
+        println!("Stopped the arrow stream to {}: {}", self.target, e);
+        self.writer = None;
+    }
+
+    // the schema message, at the first flush
+    pub fn start(&mut self, schema: &Schema, compression: Option<IpcCompression>) {
+        let Some(output) = self.output.take() else {
+            return;
+        };
+        let mut writer = StreamWriter::new(
+            output,
+            WriteOptions {
+                compression: compression.map(|c| c.into()),
+            },
+        );
+        match writer.start(&schema.to_arrow(), None) {
+            Ok(()) => self.writer = Some(writer),
+            Err(e) => self.stop(e),
+        }
+    }
+
+    pub fn write_batch(&mut self, df: &DataFrame) {
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+        let result = df
+            .iter_chunks()
+            .try_for_each(|batch| writer.write(&batch, None));
+        if let Err(e) = result {
+            self.stop(e);
+        }
+    }
+
+    pub fn finish(mut self) {
+        if let Some(writer) = &mut self.writer {
+            if let Err(e) = writer.finish() {
+                self.stop(e);
+            }
+        }
+    }
+}
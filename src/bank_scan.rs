@@ -0,0 +1,231 @@
+use crate::bitmasks;
+use crate::module_config::Module;
+use midasio::read::event::EventView;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/*
+Used by the init subcommand to write a starter config file.
+
+We walk through the whole file once, keep some statistics for every bank name
+and then take a guess at what kind of module produced it. The guesses are
+only as good as the frontend is predictable, so the config file that comes out
+is meant to be looked over before it is used.
+*/
+
+pub struct BankStats {
+    pub event_ids: Vec<u16>,
+    pub count: u64,
+    pub min_size: usize,
+    pub max_size: usize,
+    pub total_size: usize,
+    // number of 32 bit words with each of the Mesytec data signatures (bits 30-31)
+    pub signatures: [u64; 4],
+    // largest channel field seen in Mesytec data words (bits 16-22)
+    pub max_mesytec_channel: u32,
+    // the banks are multiples of 3 words, and the first word of each triplet looks like v1730 data
+    pub v1730_like: bool,
+}
+
+impl Default for BankStats {
+    fn default() -> Self {
+        BankStats {
+            event_ids: Vec::new(),
+            count: 0,
+            min_size: usize::MAX,
+            max_size: 0,
+            total_size: 0,
+            signatures: [0; 4],
+            max_mesytec_channel: 0,
+            v1730_like: true,
+        }
+    }
+}
+
+impl BankStats {
+    fn add_bank(&mut self, event_id: u16, data: &[u8]) {
+        if !self.event_ids.contains(&event_id) {
+            self.event_ids.push(event_id);
+        }
+        self.count += 1;
+        self.min_size = self.min_size.min(data.len());
+        self.max_size = self.max_size.max(data.len());
+        self.total_size += data.len();
+
+        let words: Vec<u32> = data.chunks_exact(4).map(bitmasks::to_u32_le).collect();
+        for &word in words.iter() {
+            let data_sig = word >> 30 & bitmasks::TWO_BIT;
+            self.signatures[data_sig as usize] += 1;
+            // data word with the "actual data" subheader
+            if data_sig == 0 && (word >> 28 & bitmasks::TWO_BIT) == 1 {
+                self.max_mesytec_channel = self
+                    .max_mesytec_channel
+                    .max(word >> 16 & bitmasks::SEVEN_BIT);
+            }
+        }
+        // v1730 hits are (channel, long), time, (extended time, fine time)
+        if words.is_empty()
+            || !words.len().is_multiple_of(3)
+            || words.chunks(3).any(|w| w[0] >> 20 != 0)
+        {
+            self.v1730_like = false;
+        }
+    }
+
+    pub fn mean_size(&self) -> f64 {
+        self.total_size as f64 / self.count as f64
+    }
+
+    // returns the module type, number of channels and a comment on how we got there
+    pub fn guess_module(&self) -> (Option<&str>, u32, &str) {
+        let total_words: u64 = self.signatures.iter().sum();
+        let headers = self.signatures[1];
+        let event_ends = self.signatures[3];
+        if self.event_ids.iter().all(|&id| id == 2) {
+            (
+                None,
+                32,
+                "scaler bank, scalers are read from event id 2 without a module entry",
+            )
+        } else if self.min_size == 34 * 4 && self.max_size == 34 * 4 {
+            (Some("adc"), 32, "fixed 34 word banks look like a v785 ADC")
+        } else if headers > 0
+            && event_ends > 0
+            // a few corrupted words should not stop us from recognising the module
+            && self.signatures[2] * 100 <= total_words
+            && headers.abs_diff(event_ends) * 10 <= headers
+        {
+            // Mesytec channel fields are 0-31 for the 16 channel firmwares, up to 127 for 32
+            let nchannels = if self.max_mesytec_channel > 31 {
+                32
+            } else {
+                16
+            };
+            (
+                Some("scp"),
+                nchannels,
+                "Mesytec header/end-of-event pattern, could also be qdc",
+            )
+        } else if self.v1730_like && total_words > 0 {
+            (
+                Some("v1730"),
+                16,
+                "banks are made of v1730 style 3 word hits",
+            )
+        } else if self.max_size == 32 * 4 {
+            // only after the data signatures, a Mesytec bank can top out at 32 words too
+            (
+                None,
+                32,
+                "scaler sized bank, scalers are read from event id 2 without a module entry",
+            )
+        } else {
+            (None, 0, "could not guess the module type")
+        }
+    }
+}
+
+pub fn scan_file<'a>(
+    events: impl IntoIterator<Item = EventView<'a>>,
+) -> BTreeMap<String, BankStats> {
+    let mut banks: BTreeMap<String, BankStats> = BTreeMap::new();
+    for event in events {
+        let event_id = event.id();
+        for bank in event {
+            banks
+                .entry(bank.name().to_string())
+                .or_default()
+                .add_bank(event_id, bank.data_slice());
+        }
+    }
+    banks
+}
+
+// the guesses as modules, for when there is no config to go by
+pub fn guessed_modules(banks: &BTreeMap<String, BankStats>) -> Vec<Module> {
+    banks
+        .iter()
+        .filter_map(|(name, stats)| {
+            let (mod_type, nchannels, _) = stats.guess_module();
+            mod_type.map(|t| Module::new(name, t, nchannels))
+        })
+        .collect()
+}
+
+pub fn print_summary(banks: &BTreeMap<String, BankStats>) {
+    println!(
+        "{:<6} {:<10} {:>10} {:>8} {:>8} {:>10} {:>10} {:>10} {:>10} {:>10}  guess",
+        "bank", "event ids", "count", "min B", "max B", "mean B", "data", "header", "sig 2", "eoe"
+    );
+    for (name, stats) in banks.iter() {
+        let (mod_type, nchannels, _) = stats.guess_module();
+        let ids = stats
+            .event_ids
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<String>>()
+            .join(",");
+        println!(
+            "{:<6} {:<10} {:>10} {:>8} {:>8} {:>10.1} {:>10} {:>10} {:>10} {:>10}  {}",
+            name,
+            ids,
+            stats.count,
+            stats.min_size,
+            stats.max_size,
+            stats.mean_size(),
+            stats.signatures[0],
+            stats.signatures[1],
+            stats.signatures[2],
+            stats.signatures[3],
+            match mod_type {
+                Some(t) => format!("{} ({} channels)", t, nchannels),
+                None => "-".to_string(),
+            }
+        );
+    }
+}
+
+// Write a config file with one [[modules]] entry per bank. Banks we could not
+// place are written commented out, so they show up when the file is reviewed.
+pub fn write_starter_config(
+    banks: &BTreeMap<String, BankStats>,
+    filename: &str,
+) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(filename)?);
+    writeln!(file, "# Starter config generated by midas-converter init.")?;
+    writeln!(file, "# Check every module before using it!")?;
+    for (name, stats) in banks.iter() {
+        let (mod_type, nchannels, reason) = stats.guess_module();
+        let ids = stats
+            .event_ids
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+        writeln!(file)?;
+        writeln!(
+            file,
+            "# {}: {} banks in event id(s) {}, {} to {} bytes",
+            name, stats.count, ids, stats.min_size, stats.max_size
+        )?;
+        writeln!(file, "# {}", reason)?;
+        let prefix = if mod_type.is_some() { "" } else { "# " };
+        writeln!(file, "{}[[modules]]", prefix)?;
+        writeln!(file, "{}name = \"{}\"", prefix, name)?;
+        writeln!(
+            file,
+            "{}address = \"0x0\" # fill in the vme address",
+            prefix
+        )?;
+        writeln!(
+            file,
+            "{}mod_type = \"{}\"",
+            prefix,
+            mod_type.unwrap_or("unknown")
+        )?;
+        writeln!(file, "{}nchannels = {}", prefix, nchannels)?;
+        writeln!(file, "{}setup_file = \"\"", prefix)?;
+    }
+    Ok(())
+}
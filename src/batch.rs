@@ -0,0 +1,97 @@
+use crate::error::ConverterError;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/*
+Converting a whole experiment at once. Every worker thread takes the next file
+off the list until there are none left, and each run gets its own spinner above
+a bar counting the finished runs. A run that fails (or panics) is reported at
+the end and doesn't stop the others.
+
+Each run already keeps polars busy on several threads while it converts, so a
+handful of workers is usually enough to keep the machine full.
+*/
+
+enum Outcome {
+    Converted,
+    SkippedData,
+    Failed(String),
+}
+
+// files are the names of the runs, convert gets the index of the run
+pub fn run_batch<F>(files: &[String], jobs: usize, convert: F) -> bool
+where
+    F: Fn(usize, ProgressBar) -> Result<bool, ConverterError> + Sync,
+{
+    let multi = MultiProgress::new();
+    let overall = multi.add(ProgressBar::new(files.len() as u64));
+    overall.set_style(
+        ProgressStyle::with_template("{pos}/{len} runs [{bar:40}] {elapsed_precise}")
+            .unwrap()
+            .progress_chars("=> "),
+    );
+    let spinner_style = ProgressStyle::with_template("{spinner} {prefix}: {msg}").unwrap();
+
+    let next_file = AtomicUsize::new(0);
+    let outcomes: Mutex<Vec<Option<Outcome>>> = Mutex::new(files.iter().map(|_| None).collect());
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, files.len()) {
+            scope.spawn(|| loop {
+                let i = next_file.fetch_add(1, Ordering::SeqCst);
+                if i >= files.len() {
+                    break;
+                }
+                let pb = multi.insert_before(&overall, ProgressBar::new_spinner());
+                pb.set_style(spinner_style.clone());
+                pb.set_prefix(files[i].clone());
+
+                let result = panic::catch_unwind(AssertUnwindSafe(|| convert(i, pb.clone())));
+                let outcome = match result {
+                    Ok(Ok(true)) => Outcome::Converted,
+                    Ok(Ok(false)) => Outcome::SkippedData,
+                    Ok(Err(e)) => Outcome::Failed(e.to_string()),
+                    Err(_) => Outcome::Failed("the conversion panicked".to_string()),
+                };
+                pb.finish_and_clear();
+                overall.inc(1);
+                outcomes.lock().unwrap()[i] = Some(outcome);
+            });
+        }
+    });
+    overall.finish();
+
+    // one line per run, in the order they were given
+    let outcomes = outcomes.into_inner().unwrap();
+    let width = files.iter().map(|f| f.len()).max().unwrap_or(0);
+    let mut failed = 0;
+    let mut skipped = 0;
+    println!();
+    for (file, outcome) in files.iter().zip(outcomes.iter()) {
+        let status = match outcome {
+            Some(Outcome::Converted) => "ok".to_string(),
+            Some(Outcome::SkippedData) => {
+                skipped += 1;
+                "ok, skipped data".to_string()
+            }
+            Some(Outcome::Failed(msg)) => {
+                failed += 1;
+                format!("FAILED: {}", msg)
+            }
+            None => {
+                failed += 1;
+                "FAILED: not converted".to_string()
+            }
+        };
+        println!("{:<width$}  {}", file, status, width = width);
+    }
+    println!(
+        "{} runs: {} converted ({} skipped data), {} failed",
+        files.len(),
+        files.len() - failed,
+        skipped,
+        failed
+    );
+    failed == 0
+}
@@ -3,6 +3,7 @@
 //associated constants
 pub const ONE_BIT: u32 = 0x1;
 pub const TWO_BIT: u32 = 0x3;
+#[allow(dead_code)]
 pub const THREE_BIT: u32 = 0x7;
 pub const FOUR_BIT: u32 = 0xF;
 pub const FIVE_BIT: u32 = 0x1F;
@@ -0,0 +1,277 @@
+use crate::error::ConverterError;
+use crate::mdpp_bank::MDPPEvent;
+use crate::module_config::Module;
+use crate::output_options::OutputConfig;
+use crate::run_metadata;
+use crate::v1730_bank::v1730Hit;
+use crate::v785_bank::v785Hit;
+use polars::prelude::*;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+/*
+Hive partitioned parquet dataset for looking at many runs at once.
+
+<dataset>/run=123/module=SCP1/part-0.parquet
+<dataset>/run=123/module=SCP1/part-1.parquet
+<dataset>/run=124/module=SCP1/part-0.parquet
+
+Like the HDF5 output it is filled by the sorters every chunk_size events, and
+every chunk of a module becomes a new part file, so nothing already in the
+dataset gets rewritten. Converting a run again replaces only the run=N
+directory of that run.
+
+Every part has a run column in front of the columns of the parquet output,
+with the same names and narrow types, so the files can be read on their own
+without knowing the directory layout. module stays the hardware module id of
+the mdpp data, the module name is only in the path.
+*/
+
+pub struct DatasetOutput {
+    run_dir: PathBuf,
+    run_number: u32,
+    flag_events: bool,
+    subruns: bool,
+    output: OutputConfig,
+    metadata: Vec<(String, String)>,
+    // next part number of every module
+    parts: HashMap<String, usize>,
+}
+
+fn dataset_error(path: &Path) -> impl Fn(PolarsError) -> ConverterError + '_ {
+    move |e| ConverterError::Output(format!("{}: {}", path.display(), e))
+}
+
+impl DatasetOutput {
+    pub fn create(
+        dataset: &str,
+        run_number: u32,
+        modules: &[Module],
+        flag_events: bool,
+        subruns: bool,
+        output: &OutputConfig,
+        metadata: &[(String, String)],
+    ) -> Result<Self, ConverterError> {
+        let run_dir = Path::new(dataset).join(format!("run={}", run_number));
+        if run_dir.exists() {
+            println!("Replacing run {} in the dataset {}", run_number, dataset);
+            fs::remove_dir_all(&run_dir)
+                .map_err(|e| ConverterError::Io(run_dir.display().to_string(), e))?;
+        }
+        // a bad setting fails before the sort, not at the first part
+        output.parquet_options()?;
+        Ok(DatasetOutput {
+            run_dir,
+            run_number,
+            flag_events,
+            subruns,
+            output: output.clone(),
+            metadata: metadata.to_vec(),
+            parts: modules.iter().map(|m| (m.name.clone(), 0)).collect(),
+        })
+    }
+
+    fn write_part(&mut self, module: &str, mut columns: Vec<Series>) -> Result<(), ConverterError> {
+        let rows = columns.first().map_or(0, |c| c.len());
+        // nothing from this module in the chunk
+        if rows == 0 {
+            return Ok(());
+        }
+        columns.insert(0, Series::new("run", vec![self.run_number; rows]));
+        let part = match self.parts.get_mut(module) {
+            Some(part) => part,
+            None => return Ok(()),
+        };
+
+        let module_dir = self.run_dir.join(format!("module={}", module));
+        fs::create_dir_all(&module_dir)
+            .map_err(|e| ConverterError::Io(module_dir.display().to_string(), e))?;
+        let path = module_dir.join(format!("part-{}.parquet", part));
+        *part += 1;
+
+        let options = self.output.parquet_options()?;
+        let df = DataFrame::new(columns).map_err(dataset_error(&path))?;
+        let mut df = self.output.parquet_columns(df)?;
+        let file =
+            File::create(&path).map_err(|e| ConverterError::Io(path.display().to_string(), e))?;
+        ParquetWriter::new(file)
+            .with_compression(options.compression)
+            .with_statistics(options.statistics)
+            .with_row_group_size(options.row_group_size)
+            .finish(&mut df)
+            .map_err(dataset_error(&path))?;
+        run_metadata::add_parquet_metadata(&path, &self.metadata)
+            .map_err(|e| ConverterError::Io(path.display().to_string(), e))
+    }
+
+    pub fn write_mdpp(&mut self, module: &str, events: &[MDPPEvent]) -> Result<(), ConverterError> {
+        let columns = mdpp_columns(events, self.flag_events, self.subruns);
+        self.write_part(module, columns)
+    }
+
+    pub fn write_v785(&mut self, module: &str, hits: &[v785Hit]) -> Result<(), ConverterError> {
+        let columns = v785_columns(hits, self.flag_events, self.subruns);
+        self.write_part(module, columns)
+    }
+
+    pub fn write_v1730(&mut self, module: &str, hits: &[v1730Hit]) -> Result<(), ConverterError> {
+        let columns = v1730_columns(hits, self.flag_events, self.subruns);
+        self.write_part(module, columns)
+    }
+}
+
+/*
+The tables of a chunk of hits, also used by the live output of --follow. The
+columns have the narrow types of the parquet output.
+*/
+
+// the flags and subrun columns go on the end of every table
+fn flag_columns(
+    flag_events: bool,
+    subruns: bool,
+    flags: impl Iterator<Item = u8>,
+    subrun: impl Iterator<Item = u32>,
+) -> Vec<Series> {
+    let mut columns = Vec::new();
+    if flag_events {
+        columns.push(Series::new("flags", flags.collect::<Vec<u8>>()));
+    }
+    if subruns {
+        columns.push(Series::new(
+            "subrun",
+            subrun.map(|s| s as u16).collect::<Vec<u16>>(),
+        ));
+    }
+    columns
+}
+
+pub fn mdpp_columns(events: &[MDPPEvent], flag_events: bool, subruns: bool) -> Vec<Series> {
+    let hits = || {
+        events.iter().flat_map(|event| {
+            event
+                .channels
+                .iter()
+                .zip(event.channel_hits.iter())
+                .map(move |(&channel, hit)| (event, channel, hit))
+        })
+    };
+    let mut columns = vec![
+        Series::new(
+            "module",
+            hits()
+                .map(|(e, _, _)| e.module_id as u8)
+                .collect::<Vec<_>>(),
+        ),
+        Series::new(
+            "channel",
+            hits().map(|(_, c, _)| c as u8).collect::<Vec<_>>(),
+        ),
+        Series::new(
+            "adc",
+            hits()
+                .map(|(_, _, h)| h.adc_value as u16)
+                .collect::<Vec<_>>(),
+        ),
+        Series::new(
+            "long",
+            hits()
+                .map(|(_, _, h)| h.long_value as u16)
+                .collect::<Vec<_>>(),
+        ),
+        Series::new(
+            "short",
+            hits()
+                .map(|(_, _, h)| h.short_value as u16)
+                .collect::<Vec<_>>(),
+        ),
+        Series::new(
+            "tdc",
+            hits()
+                .map(|(_, _, h)| h.tdc_value as u16)
+                .collect::<Vec<_>>(),
+        ),
+        Series::new(
+            "trigger_dt",
+            hits()
+                .map(|(_, _, h)| h.trigger_dt_value)
+                .collect::<Vec<_>>(),
+        ),
+        Series::new(
+            "pileup",
+            hits().map(|(_, _, h)| h.pile_up).collect::<Vec<_>>(),
+        ),
+        Series::new(
+            "evt_ts",
+            hits()
+                .map(|(e, _, _)| e.evt_timestamp as i64)
+                .collect::<Vec<_>>(),
+        ),
+    ];
+    columns.extend(flag_columns(
+        flag_events,
+        subruns,
+        hits().map(|(e, _, _)| e.flags),
+        hits().map(|(e, _, _)| e.subrun),
+    ));
+    columns
+}
+
+// one row per channel of every hit, like the csv, with its module column that is always 0
+pub fn v785_columns(hits: &[v785Hit], flag_events: bool, subruns: bool) -> Vec<Series> {
+    let rows = || {
+        hits.iter().flat_map(|hit| {
+            hit.values
+                .iter()
+                .enumerate()
+                .map(move |(c, &v)| (hit, c, v))
+        })
+    };
+    let mut columns = vec![
+        Series::new("module", rows().map(|_| 0u8).collect::<Vec<_>>()),
+        Series::new(
+            "channel",
+            rows().map(|(_, c, _)| c as u8).collect::<Vec<_>>(),
+        ),
+        // the whole data word, not just the 12 bit conversion
+        Series::new("adc", rows().map(|(_, _, v)| v).collect::<Vec<u32>>()),
+        Series::new(
+            "evt_ts",
+            rows().map(|(h, _, _)| h.evt as i64).collect::<Vec<_>>(),
+        ),
+    ];
+    columns.extend(flag_columns(
+        flag_events,
+        subruns,
+        rows().map(|(h, _, _)| h.flags),
+        rows().map(|(h, _, _)| h.subrun),
+    ));
+    columns
+}
+
+pub fn v1730_columns(hits: &[v1730Hit], flag_events: bool, subruns: bool) -> Vec<Series> {
+    let mut columns = vec![
+        Series::new(
+            "channel",
+            hits.iter().map(|h| h.channel as u8).collect::<Vec<_>>(),
+        ),
+        Series::new(
+            "long",
+            hits.iter().map(|h| h.long as u16).collect::<Vec<_>>(),
+        ),
+        Series::new(
+            "coarse_time",
+            hits.iter()
+                .map(|h| h.coarse_time as i64)
+                .collect::<Vec<_>>(),
+        ),
+        Series::new("time", hits.iter().map(|h| h.time).collect::<Vec<_>>()),
+    ];
+    columns.extend(flag_columns(
+        flag_events,
+        subruns,
+        hits.iter().map(|h| h.flags),
+        hits.iter().map(|h| h.subrun),
+    ));
+    columns
+}
@@ -1,34 +1,280 @@
 use crate::bitmasks;
-use midasio::read::file::FileView;
+use crate::event_sequence::Sequence;
+use crate::mdpp_bank::MDPPBank;
+use crate::module_config::Module;
+use crate::v1730_bank::v1730Bank;
+use crate::v785_bank::v785Bank;
+use midasio::read::event::EventView;
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufWriter;
 
 /*
 This module can be used to debug issues with the Midas frontend.
-Right now it just provides functions to tally the number of event
-headers and events ends.
+
+Every bank gets a report with the raw word counts (headers, end of events,
+dummy words, extended timestamps, words with an invalid signature) and then
+the bank is run through the same decoder the converter would use, in resilient
+mode, to get junk events, channel hits, pile up and the event counter
+sequence. Banks that are not in the config only get the raw counts.
+
+The report is printed as a table and written as JSON for the run database.
 */
 
-pub fn event_diagnostics<'a>(file_view: &'a FileView) -> (u32, u32, u32) {
-    let mut total_banks: u32 = 0;
-    let mut total_headers: u32 = 0;
-    let mut total_event_ends: u32 = 0;
-    for (_event_num, event) in (*file_view).into_iter().enumerate() {
-        if event.id() == 1 {
-            for bank in event {
-                total_banks += 1;
-                for chunk in bank.data_slice().chunks(4) {
-                    let temp = bitmasks::to_u32_le(chunk);
-                    // get the identifier
-                    let data_sig = temp >> 30 & bitmasks::TWO_BIT;
-                    match data_sig {
-                        0 => {}
-                        1 => total_headers += 1,
-                        3 => total_event_ends += 1,
-                        _ => panic!("Invalid Data signature in bank!"),
+#[derive(Serialize, Default)]
+pub struct BankReport {
+    pub mod_type: Option<String>,
+    pub nchannels: u32,
+    pub event_ids: Vec<u16>,
+    pub banks: u64,
+    pub words: u64,
+    pub headers: u64,
+    pub end_of_events: u64,
+    pub junk_events: i64,
+    pub dummy_words: u64,
+    pub extended_ts_words: u64,
+    pub invalid_signature_words: u64,
+    // words or banks that the decoder had to skip
+    pub bad_words: u64,
+    pub bad_banks: u64,
+    pub channel_hits: Vec<u64>,
+    pub pile_up: Vec<u64>,
+    // end of event counter (or timestamp) of the Mesytec modules
+    pub counter: Sequence,
+}
+
+impl BankReport {
+    fn new(module: Option<&Module>) -> Self {
+        let nchannels = module.map_or(0, |m| m.nchannels);
+        BankReport {
+            mod_type: module.map(|m| m.mod_type.to_string()),
+            nchannels,
+            channel_hits: vec![0; nchannels as usize],
+            pile_up: vec![0; nchannels as usize],
+            counter: Sequence::new(module.is_some_and(|m| m.timestamp)),
+            ..Default::default()
+        }
+    }
+
+    fn count_words(&mut self, data: &[u8]) {
+        for chunk in data.chunks_exact(4) {
+            let word = bitmasks::to_u32_le(chunk);
+            self.words += 1;
+            match word >> 30 & bitmasks::TWO_BIT {
+                0 => match word >> 28 & bitmasks::TWO_BIT {
+                    0 => self.dummy_words += 1,
+                    2 => self.extended_ts_words += 1,
+                    _ => {}
+                },
+                1 => self.headers += 1,
+                2 => self.invalid_signature_words += 1,
+                _ => self.end_of_events += 1,
+            }
+        }
+    }
+
+    fn add_hit(&mut self, channel: u32, pile_up: bool) {
+        // channels outside of the module would be a decoder bug, but don't panic over it
+        if let Some(hits) = self.channel_hits.get_mut(channel as usize) {
+            *hits += 1;
+            if pile_up {
+                self.pile_up[channel as usize] += 1;
+            }
+        }
+    }
+
+    pub fn pile_up_fraction(&self) -> f64 {
+        let hits: u64 = self.channel_hits.iter().sum();
+        if hits == 0 {
+            return 0.0;
+        }
+        self.pile_up.iter().sum::<u64>() as f64 / hits as f64
+    }
+}
+
+// the decoder that goes with each module type, kept between midas events
+enum Decoder {
+    Mdpp(MDPPBank),
+    Adc(v785Bank),
+    V1730(v1730Bank),
+    None,
+}
+
+impl Decoder {
+    fn new(module: Option<&Module>) -> Self {
+        match module.map(|m| m.mod_type.as_str()) {
+            Some("scp") | Some("qdc") => Decoder::Mdpp(MDPPBank::new()),
+            Some("adc") => Decoder::Adc(v785Bank::new()),
+            Some("v1730") => Decoder::V1730(v1730Bank::new()),
+            _ => Decoder::None,
+        }
+    }
+
+    fn decode(&mut self, module: Option<&Module>, data: &[u8], report: &mut BankReport) {
+        let result = match self {
+            Decoder::Mdpp(bank) => {
+                let m = module.unwrap();
+                let result = bank.parse(&m.mod_type, m.nchannels, data, true);
+                for event in bank.completed_events() {
+                    for (&channel, hit) in event.channels.iter().zip(event.channel_hits.iter()) {
+                        report.add_hit(channel, hit.pile_up);
                     }
+                    report.counter.add(event.evt_timestamp);
+                }
+                report.junk_events = bank.junk_events;
+                bank.clear_data();
+                result
+            }
+            Decoder::Adc(bank) => {
+                let result = bank.parse(data);
+                for hit in bank.hits.drain(..) {
+                    for (channel, &value) in hit.values.iter().enumerate() {
+                        if value != 0 {
+                            report.add_hit(channel as u32, false);
+                        }
+                    }
+                }
+                result
+            }
+            Decoder::V1730(bank) => {
+                let result = bank.parse(data, true);
+                for hit in bank.hits.drain(..) {
+                    report.add_hit(hit.channel, false);
+                }
+                result
+            }
+            Decoder::None => Ok(0),
+        };
+        match result {
+            Ok(skipped) => report.bad_words += skipped,
+            Err(_) => report.bad_banks += 1,
+        }
+    }
+}
+
+#[derive(Serialize, Default)]
+pub struct RunReport {
+    pub events: u64,
+    pub events_by_id: BTreeMap<u16, u64>,
+    // per event id, every midas equipment counts on its own
+    pub serial_numbers: BTreeMap<u16, Sequence>,
+    // midas timestamps are in seconds, so only going backwards is interesting
+    pub timestamps_backwards: u64,
+    pub banks: BTreeMap<String, BankReport>,
+}
+
+pub fn run_diagnostics<'a>(
+    events: impl IntoIterator<Item = EventView<'a>>,
+    modules: &[Module],
+) -> RunReport {
+    let mut report = RunReport::default();
+    let mut decoders: BTreeMap<String, Decoder> = BTreeMap::new();
+    let mut last_timestamp: Option<u32> = None;
+    for event in events {
+        report.events += 1;
+        *report.events_by_id.entry(event.id()).or_insert(0) += 1;
+        report
+            .serial_numbers
+            .entry(event.id())
+            .or_default()
+            .add(event.serial_number() as u64);
+        if last_timestamp.is_some_and(|ts| event.timestamp() < ts) {
+            report.timestamps_backwards += 1;
+        }
+        last_timestamp = Some(event.timestamp());
+
+        for bank in event {
+            let module = modules.iter().find(|m| m.name == bank.name());
+            let bank_report = report
+                .banks
+                .entry(bank.name().to_string())
+                .or_insert_with(|| BankReport::new(module));
+            if !bank_report.event_ids.contains(&event.id()) {
+                bank_report.event_ids.push(event.id());
+            }
+            bank_report.banks += 1;
+            bank_report.count_words(bank.data_slice());
+            // only trigger events go through the decoders, same as the sorters
+            if event.id() == 1 && bank.data_slice().len() != 1 {
+                decoders
+                    .entry(bank.name().to_string())
+                    .or_insert_with(|| Decoder::new(module))
+                    .decode(module, bank.data_slice(), bank_report);
+            }
+        }
+    }
+    report
+}
+
+impl RunReport {
+    pub fn print_table(&self) {
+        println!(
+            "Events: {} ({})",
+            self.events,
+            self.events_by_id
+                .iter()
+                .map(|(id, n)| format!("id {}: {}", id, n))
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+        for (id, serials) in self.serial_numbers.iter() {
+            println!(
+                "Serial numbers of event id {}: {} gaps ({} missing), {} repeated, {} out of order",
+                id, serials.gaps, serials.missing, serials.repeats, serials.backwards
+            );
+        }
+        println!("Timestamps going backwards: {}", self.timestamps_backwards);
+        println!();
+        println!(
+            "{:<6} {:<8} {:>9} {:>10} {:>9} {:>9} {:>6} {:>8} {:>8} {:>8} {:>8} {:>8} {:>7} {:>6} {:>6} {:>6}",
+            "bank", "type", "banks", "words", "headers", "eoe", "junk", "dummy", "ext ts",
+            "bad sig", "bad wrd", "bad bnk", "pileup", "gaps", "repeat", "back"
+        );
+        for (name, bank) in self.banks.iter() {
+            println!(
+                "{:<6} {:<8} {:>9} {:>10} {:>9} {:>9} {:>6} {:>8} {:>8} {:>8} {:>8} {:>8} {:>6.2}% {:>6} {:>6} {:>6}",
+                name,
+                bank.mod_type.as_deref().unwrap_or("-"),
+                bank.banks,
+                bank.words,
+                bank.headers,
+                bank.end_of_events,
+                bank.junk_events,
+                bank.dummy_words,
+                bank.extended_ts_words,
+                bank.invalid_signature_words,
+                bank.bad_words,
+                bank.bad_banks,
+                100.0 * bank.pile_up_fraction(),
+                bank.counter.gaps,
+                bank.counter.repeats,
+                bank.counter.backwards
+            );
+        }
+        // per channel hits for everything we could decode
+        for (name, bank) in self.banks.iter() {
+            if bank.channel_hits.is_empty() {
+                continue;
+            }
+            println!();
+            println!("{} channel hits (pile up):", name);
+            for (channel, (hits, pile_up)) in bank
+                .channel_hits
+                .iter()
+                .zip(bank.pile_up.iter())
+                .enumerate()
+            {
+                if *hits > 0 {
+                    println!("  {:>3}: {:>10} ({})", channel, hits, pile_up);
                 }
             }
         }
     }
 
-    (total_banks, total_headers, total_event_ends)
+    pub fn write_json(&self, filename: &str) -> std::io::Result<()> {
+        let file = BufWriter::new(File::create(filename)?);
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
 }
@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+/*
+Everything that can go wrong during a conversion ends up as a ConverterError,
+and every kind of error has its own exit code so that batch scripts can tell
+what happened.
+
+The decoders only know about the bank they are working on, so they return a
+DecodeError and the sorter adds the bank name and event serial.
+*/
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    BankLength(usize),
+    InvalidDataSignature(u32),
+    InvalidSubheader(u32),
+    UnknownEventType(u32),
+    DataOutsideEvent(u32),
+    UnknownModuleType(String),
+    UnsupportedChannels(u32),
+    BankSize { expected: usize, found: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::BankLength(len) => {
+                write!(f, "bank length {} is not a multiple of 4 bytes", len)
+            }
+            DecodeError::InvalidDataSignature(word) => {
+                write!(f, "invalid data signature in word {:#010x}", word)
+            }
+            DecodeError::InvalidSubheader(word) => {
+                write!(f, "invalid subheader in word {:#010x}", word)
+            }
+            DecodeError::UnknownEventType(word) => {
+                write!(f, "unknown event type in word {:#010x}", word)
+            }
+            DecodeError::DataOutsideEvent(word) => {
+                write!(f, "data word {:#010x} outside of an event", word)
+            }
+            DecodeError::UnknownModuleType(mod_type) => {
+                write!(f, "unknown module type {}", mod_type)
+            }
+            DecodeError::UnsupportedChannels(nchannels) => {
+                write!(f, "unsupported number of channels {}", nchannels)
+            }
+            DecodeError::BankSize { expected, found } => {
+                write!(f, "bank has {} words, expected {}", found, expected)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConverterError {
+    Config(String),
+    Io(String, std::io::Error),
+    Midas(String),
+    Decode {
+        serial: u32,
+        bank: String,
+        error: DecodeError,
+    },
+    UnknownBank {
+        serial: u32,
+        bank: String,
+    },
+    Output(String),
+}
+
+impl ConverterError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ConverterError::Config(_) => 2,
+            ConverterError::Io(_, _) => 3,
+            ConverterError::Midas(_) => 4,
+            ConverterError::Decode { .. } => 5,
+            ConverterError::UnknownBank { .. } => 6,
+            ConverterError::Output(_) => 7,
+        }
+    }
+}
+
+// exit code for a resilient run that finished but had to skip data
+pub const EXIT_SKIPPED_DATA: i32 = 8;
+// exit code for a batch where at least one of the runs failed
+pub const EXIT_BATCH_FAILED: i32 = 9;
+// exit code for diff when the two outputs are not the same
+pub const EXIT_OUTPUTS_DIFFER: i32 = 10;
+
+impl fmt::Display for ConverterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConverterError::Config(msg) => write!(f, "config error: {}", msg),
+            ConverterError::Io(path, e) => write!(f, "I/O error on {}: {}", path, e),
+            ConverterError::Midas(msg) => write!(f, "midas file error: {}", msg),
+            ConverterError::Decode {
+                serial,
+                bank,
+                error,
+            } => write!(
+                f,
+                "decode error in bank {} of event serial {}: {}",
+                bank, serial, error
+            ),
+            ConverterError::UnknownBank { serial, bank } => write!(
+                f,
+                "no bank matching name {} in event serial {}",
+                bank, serial
+            ),
+            ConverterError::Output(msg) => write!(f, "output error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConverterError {}
+
+/*
+In resilient mode the sorters keep going after a decode error, and this is
+where the damage is tallied up. Without resilient mode the first error is
+handed straight back.
+*/
+
+pub struct ErrorTally {
+    resilient: bool,
+    pub bad_words: BTreeMap<String, u64>,
+    pub bad_word_events: Vec<(u32, String, u64)>,
+    pub bad_banks: Vec<(u32, String, DecodeError)>,
+}
+
+impl ErrorTally {
+    pub fn new(resilient: bool) -> Self {
+        ErrorTally {
+            resilient,
+            bad_words: BTreeMap::new(),
+            bad_word_events: Vec::new(),
+            bad_banks: Vec::new(),
+        }
+    }
+
+    // takes the result of a decoder, which is the number of skipped words
+    pub fn check(
+        &mut self,
+        serial: u32,
+        bank: &str,
+        result: Result<u64, DecodeError>,
+    ) -> Result<(), ConverterError> {
+        match result {
+            Ok(0) => Ok(()),
+            Ok(skipped) => {
+                *self.bad_words.entry(bank.to_string()).or_insert(0) += skipped;
+                self.bad_word_events
+                    .push((serial, bank.to_string(), skipped));
+                Ok(())
+            }
+            Err(error) if self.resilient => {
+                self.bad_banks.push((serial, bank.to_string(), error));
+                Ok(())
+            }
+            Err(error) => Err(ConverterError::Decode {
+                serial,
+                bank: bank.to_string(),
+                error,
+            }),
+        }
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.bad_words.is_empty() && self.bad_banks.is_empty()
+    }
+
+    pub fn print_summary(&self) {
+        if self.is_clean() {
+            return;
+        }
+        println!("Skipped data:");
+        for (bank, count) in self.bad_words.iter() {
+            println!("  {}: {} bad words", bank, count);
+        }
+        // the first few are plenty to go looking for the problem
+        for (serial, bank, skipped) in self.bad_word_events.iter().take(20) {
+            println!(
+                "  bank {} in event serial {}: {} bad words",
+                bank, serial, skipped
+            );
+        }
+        for (serial, bank, error) in self.bad_banks.iter().take(20) {
+            println!("  bank {} in event serial {}: {}", bank, serial, error);
+        }
+        if self.bad_banks.len() > 20 {
+            println!("  ... {} bad banks in total", self.bad_banks.len());
+        }
+    }
+}
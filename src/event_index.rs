@@ -0,0 +1,278 @@
+use crate::error::ConverterError;
+use crate::mdpp_bank::MDPPBank;
+use crate::module_config::Module;
+use crate::salvage::{MidasInput, EVENT_HEADER_SIZE};
+use crate::v1730_bank::v1730Bank;
+use crate::v785_bank::v785Bank;
+use midasio::read::event::EventView;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+
+/*
+Where every event is in the midas file (--index), so that extract can get at a
+single event without reading the whole run.
+
+<input>_index.csv goes next to the midas file, not the output, so extract finds
+it from the midas file alone whatever -o was. It has a row per midas event:
+
+  offset,size,event_id,serial,timestamp,SCP1_first,SCP1_last,...
+
+offset and size are the bytes of the whole event (header included) in the
+uncompressed midas file. Every module gets the first and last value of its
+timestamp column in the converted output (evt_ts, or coarse_time for the
+v1730) that came out of the event, empty if it had none, so a hit in the
+converted output can be traced back to the midas event it was decoded from.
+Like in the sorter an MDPP event that was split over two midas events belongs
+to the one with its end of event word.
+*/
+
+pub struct IndexEntry {
+    pub offset: u64,
+    pub size: u64,
+    pub event_id: u16,
+    pub serial: u32,
+    // per module, the timestamps of its hits in this event
+    pub ranges: Vec<Option<(i64, i64)>>,
+}
+
+// run00123.mid.lz4 -> run00123_index.csv
+pub fn index_filename(input_file: &str) -> String {
+    format!("{}_index.csv", input_file.split('.').next().unwrap())
+}
+
+// the events are views into the file contents, for whole and salvaged files alike
+fn event_offset(contents: &[u8], event: &EventView) -> usize {
+    event.all_banks_slice().as_ptr() as usize - contents.as_ptr() as usize - EVENT_HEADER_SIZE - 8
+}
+
+// the decoders keep going across events like in the sorter
+enum Decoder {
+    Mdpp(MDPPBank),
+    Adc(v785Bank),
+    V1730(v1730Bank),
+}
+
+impl Decoder {
+    fn new(module: &Module) -> Self {
+        match module.mod_type.as_str() {
+            "adc" => Decoder::Adc(v785Bank::new()),
+            "v1730" => Decoder::V1730(v1730Bank::new()),
+            _ => Decoder::Mdpp(MDPPBank::with_capacity(64)),
+        }
+    }
+
+    // bad data just has no timestamps, the sort reports it
+    fn timestamps(&mut self, module: &Module, bank: &[u8]) -> Vec<i64> {
+        match self {
+            Decoder::Mdpp(mdpp) => {
+                let _ = mdpp.parse(&module.mod_type, module.nchannels, bank, true);
+                let timestamps = mdpp
+                    .completed_events()
+                    .iter()
+                    .map(|e| e.evt_timestamp as i64)
+                    .collect();
+                mdpp.clear_data();
+                timestamps
+            }
+            Decoder::Adc(v785) => {
+                let _ = v785.parse(bank);
+                v785.hits.drain(..).map(|h| h.evt as i64).collect()
+            }
+            Decoder::V1730(v1730) => {
+                let _ = v1730.parse(bank, true);
+                v1730.hits.drain(..).map(|h| h.coarse_time as i64).collect()
+            }
+        }
+    }
+}
+
+pub fn write_index(
+    input: &MidasInput,
+    contents: &[u8],
+    modules: &[Module],
+    index_file: &str,
+) -> Result<u64, ConverterError> {
+    let io_error = |e| ConverterError::Io(index_file.to_string(), e);
+    let mut writer = BufWriter::new(File::create(index_file).map_err(io_error)?);
+    write!(writer, "offset,size,event_id,serial,timestamp").map_err(io_error)?;
+    for m in modules {
+        write!(writer, ",{}_first,{}_last", m.name, m.name).map_err(io_error)?;
+    }
+    writeln!(writer).map_err(io_error)?;
+
+    let mut decoders: Vec<Decoder> = modules.iter().map(Decoder::new).collect();
+    let mut events = 0;
+    for event in input.events() {
+        let offset = event_offset(contents, &event);
+        let size = EVENT_HEADER_SIZE + 8 + event.all_banks_slice().len();
+        write!(
+            writer,
+            "{},{},{},{},{}",
+            offset,
+            size,
+            event.id(),
+            event.serial_number(),
+            event.timestamp()
+        )
+        .map_err(io_error)?;
+        let mut ranges: Vec<Option<(i64, i64)>> = vec![None; modules.len()];
+        // same banks as the sorter looks at
+        if event.id() == 1 {
+            for bank in event {
+                if bank.data_slice().len() == 1 {
+                    continue;
+                }
+                let Some(i) = modules.iter().position(|m| m.name == bank.name()) else {
+                    continue;
+                };
+                for ts in decoders[i].timestamps(&modules[i], bank.data_slice()) {
+                    ranges[i] = Some(match ranges[i] {
+                        Some((first, last)) => (first.min(ts), last.max(ts)),
+                        None => (ts, ts),
+                    });
+                }
+            }
+        }
+        for range in ranges {
+            match range {
+                Some((first, last)) => write!(writer, ",{},{}", first, last),
+                None => write!(writer, ",,"),
+            }
+            .map_err(io_error)?;
+        }
+        writeln!(writer).map_err(io_error)?;
+        events += 1;
+    }
+    writer.flush().map_err(io_error)?;
+    Ok(events)
+}
+
+// the module names and the rows of an index file
+pub fn read_index(index_file: &str) -> Result<(Vec<String>, Vec<IndexEntry>), ConverterError> {
+    let contents = fs::read_to_string(index_file)
+        .map_err(|e| ConverterError::Io(index_file.to_string(), e))?;
+    let bad = |line: usize| {
+        ConverterError::Config(format!(
+            "{} line {} is not an index row, convert the run again with --index",
+            index_file, line
+        ))
+    };
+    // the midas timestamp is only there for people reading the index
+    let mut lines = contents.lines();
+    let header: Vec<&str> = lines.next().ok_or_else(|| bad(1))?.split(',').collect();
+    if header.len() < 5 || header[0] != "offset" {
+        return Err(bad(1));
+    }
+    let modules: Vec<String> = header[5..]
+        .iter()
+        .filter_map(|c| c.strip_suffix("_first"))
+        .map(|c| c.to_string())
+        .collect();
+
+    let mut entries = Vec::new();
+    for (i, line) in lines.enumerate() {
+        let fields: Vec<&str> = line.split(',').collect();
+        if fields.len() != 5 + 2 * modules.len() {
+            return Err(bad(i + 2));
+        }
+        let number = |f: &str| f.parse::<i64>().map_err(|_| bad(i + 2));
+        let ranges = fields[5..]
+            .chunks(2)
+            .map(|pair| match pair {
+                ["", ""] => Ok(None),
+                [first, last] => Ok(Some((number(first)?, number(last)?))),
+                _ => Err(bad(i + 2)),
+            })
+            .collect::<Result<_, _>>()?;
+        entries.push(IndexEntry {
+            offset: number(fields[0])? as u64,
+            size: number(fields[1])? as u64,
+            event_id: number(fields[2])? as u16,
+            serial: number(fields[3])? as u32,
+            ranges,
+        });
+    }
+    Ok((modules, entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extract::read_event;
+    use crate::generate::{generate, GeneratorSettings};
+    use crate::module_config::OdbMapping;
+
+    #[test]
+    fn index_round_trip() {
+        let dir = std::env::temp_dir().join(format!("event_index_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let run_file = dir.join("run00003.mid").to_str().unwrap().to_string();
+        let index_file = index_filename(&run_file);
+        assert_eq!(index_file, dir.join("run00003_index.csv").to_str().unwrap());
+        let mut scp = Module::new("SCP1", "scp", 16);
+        scp.timestamp = true;
+        let modules = [scp, Module::new("QDC1", "qdc", 32)];
+        let settings = GeneratorSettings {
+            events: 500,
+            rate: 1000.0,
+            occupancy: 0.25,
+            noise: 0.05,
+            broken: 0.0,
+            scaler_interval: 0.1,
+            seed: 3,
+            run_number: 3,
+        };
+        generate(&modules, &OdbMapping::default(), &settings, &run_file).unwrap();
+
+        let contents = fs::read(&run_file).unwrap();
+        let input = MidasInput::open(&contents).unwrap();
+        let written = write_index(&input, &contents, &modules, &index_file).unwrap();
+        let header = fs::read_to_string(&index_file)
+            .unwrap()
+            .lines()
+            .next()
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            header,
+            "offset,size,event_id,serial,timestamp,SCP1_first,SCP1_last,QDC1_first,QDC1_last"
+        );
+        let (index_modules, entries) = read_index(&index_file).unwrap();
+        assert_eq!(index_modules, ["SCP1", "QDC1"]);
+        assert_eq!(entries.len() as u64, written);
+
+        // every event comes back byte for byte
+        let mut file = File::open(&run_file).unwrap();
+        for (entry, event) in entries.iter().zip(input.events()) {
+            assert_eq!(
+                (entry.event_id, entry.serial),
+                (event.id(), event.serial_number())
+            );
+            let start = event_offset(&contents, &event);
+            assert_eq!(
+                read_event(&mut file, &run_file, entry).unwrap(),
+                &contents[start..start + entry.size as usize]
+            );
+            // a trigger has one MDPP event per module, the scalers have none
+            let expected = if event.id() == 1 { 2 } else { 0 };
+            assert_eq!(entry.ranges.iter().flatten().count(), expected);
+        }
+
+        // every hit of the truth table can be found by its timestamp
+        let truth = fs::read_to_string(dir.join("run00003_truth.csv")).unwrap();
+        for row in truth.lines().skip(1) {
+            let fields: Vec<&str> = row.split(',').collect();
+            let module: usize = fields[0].parse().unwrap();
+            let evt_ts: i64 = fields[8].parse().unwrap();
+            assert!(entries
+                .iter()
+                .any(|e| e.ranges[module]
+                    .is_some_and(|(first, last)| first <= evt_ts && evt_ts <= last)));
+        }
+
+        // rows that don't match the header
+        fs::write(&index_file, format!("{}\n1,2,3\n", header)).unwrap();
+        assert!(read_index(&index_file).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
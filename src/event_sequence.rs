@@ -0,0 +1,160 @@
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+
+/*
+Checks for lost events.
+
+The Mesytec end of event words carry an event counter (or a timestamp, depending
+on how the module is set up) and every midas event has a serial number, so if
+either of them skips ahead, repeats or goes backwards something got lost or
+mixed up on the way to disk. Modules that sit on the same trigger should also
+end up with the same number of events.
+
+The flags that come out of Sequence::add are written to the flags column when
+--flag-events is used.
+*/
+
+// bits of the flags column
+pub const FLAG_GAP: u8 = 1;
+pub const FLAG_REPEAT: u8 = 2;
+pub const FLAG_BACKWARDS: u8 = 4;
+
+// A sequence that should go up by one every time. For timestamps gaps are
+// expected, so only repeats and backwards steps count.
+#[derive(Serialize, Default, Debug)]
+pub struct Sequence {
+    #[serde(skip)]
+    pub timestamps: bool,
+    pub first: Option<u64>,
+    pub last: Option<u64>,
+    // jumps forward by more than one, and how many values were jumped over
+    pub gaps: u64,
+    pub missing: u64,
+    // same value twice in a row
+    pub repeats: u64,
+    // value went down
+    pub backwards: u64,
+    // (previous, current) for the first few problems
+    #[serde(skip)]
+    pub examples: Vec<(u64, u64)>,
+}
+
+impl Sequence {
+    pub fn new(timestamps: bool) -> Self {
+        Sequence {
+            timestamps,
+            ..Default::default()
+        }
+    }
+
+    pub fn add(&mut self, value: u64) -> u8 {
+        let flag = match self.last {
+            None => {
+                self.first = Some(value);
+                0
+            }
+            Some(last) if value == last => {
+                self.repeats += 1;
+                FLAG_REPEAT
+            }
+            Some(last) if value < last => {
+                self.backwards += 1;
+                FLAG_BACKWARDS
+            }
+            Some(last) if value > last + 1 && !self.timestamps => {
+                self.gaps += 1;
+                self.missing += value - last - 1;
+                FLAG_GAP
+            }
+            Some(_) => 0,
+        };
+        if flag != 0 && self.examples.len() < 5 {
+            self.examples.push((self.last.unwrap(), value));
+        }
+        self.last = Some(value);
+        flag
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.gaps == 0 && self.repeats == 0 && self.backwards == 0
+    }
+
+    fn print_summary(&self, name: &str) {
+        println!(
+            "  {}: {} gaps ({} missing), {} repeated, {} out of order",
+            name, self.gaps, self.missing, self.repeats, self.backwards
+        );
+        for (previous, value) in self.examples.iter() {
+            println!("    {} followed by {}", previous, value);
+        }
+    }
+}
+
+// Everything the sorters keep track of over a run.
+#[derive(Default)]
+pub struct SequenceChecks {
+    // serial numbers are counted per event id, midas gives every equipment its own
+    pub serials: BTreeMap<u16, Sequence>,
+    // end of event counters of the Mesytec modules
+    pub counters: BTreeMap<String, Sequence>,
+    // number of events each module produced
+    pub event_counts: BTreeMap<String, u64>,
+}
+
+impl SequenceChecks {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn add_serial(&mut self, event_id: u16, serial: u32) -> u8 {
+        self.serials.entry(event_id).or_default().add(serial as u64)
+    }
+
+    pub fn counter(&mut self, module: &str, timestamps: bool) -> &mut Sequence {
+        self.counters
+            .entry(module.to_string())
+            .or_insert_with(|| Sequence::new(timestamps))
+    }
+
+    pub fn add_events(&mut self, module: &str, count: u64) {
+        *self.event_counts.entry(module.to_string()).or_insert(0) += count;
+    }
+
+    // all of the modules see the same trigger, so they should agree
+    pub fn counts_agree(&self) -> bool {
+        let mut counts = self.event_counts.values();
+        match counts.next() {
+            Some(first) => counts.all(|c| c == first),
+            None => true,
+        }
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.serials.values().all(|s| s.is_clean())
+            && self.counters.values().all(|s| s.is_clean())
+            && self.counts_agree()
+    }
+
+    pub fn print_summary(&self) {
+        if self.is_clean() {
+            return;
+        }
+        println!("Event sequence problems:");
+        for (event_id, sequence) in self.serials.iter() {
+            if !sequence.is_clean() {
+                sequence.print_summary(&format!("serial numbers of event id {}", event_id));
+            }
+        }
+        for (module, sequence) in self.counters.iter() {
+            if !sequence.is_clean() {
+                sequence.print_summary(&format!("event counter of {}", module));
+            }
+        }
+        if !self.counts_agree() {
+            println!("  modules saw different numbers of events:");
+            for (module, count) in self.event_counts.iter() {
+                println!("    {}: {}", module, count);
+            }
+        }
+    }
+}
@@ -0,0 +1,362 @@
+use crate::bitmasks;
+use crate::error::ConverterError;
+use crate::event_index::IndexEntry;
+use crate::mdpp_bank::{MDPPBank, MDPPEvent};
+use crate::module_config::Module;
+use crate::salvage::{self, BOR_ID, EVENT_HEADER_SIZE, MAGIC};
+use crate::sis3820::ScalerBank;
+use crate::v1730_bank::v1730Bank;
+use crate::v785_bank::v785Bank;
+use midasio::read::event::EventView;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+/*
+Printing single events of a big midas file (extract), using the index that a
+conversion with --index left next to the output (event_index.rs). Only the
+BOR and the events that are asked for are read from the file, so this is
+instant even for runs of many GB. Compressed files have to be decompressed
+first, which is not.
+
+The events can be picked by serial number (--serial 1234 or 100-200), by
+midas event id, or by the timestamp of a hit in the converted output
+(--module SCP1 --timestamp 1234567), which finds the midas event(s) the hit
+was decoded from.
+
+Every bank is decoded on its own with the modules of the config. The MDPP
+decoders don't know what came before, so the words in front of the first
+header (the rest of an event split over two midas events) and an event
+without its end of event word are pointed out instead of decoded. The v785
+event counters come from the index. --hex prints the raw words instead, and
+so does every bank that is not in the config.
+*/
+
+pub struct ExtractSelection {
+    pub serials: Option<(u32, u32)>,
+    pub event_ids: Vec<u16>,
+    // a module of the index and a value of its timestamp column
+    pub timestamp: Option<(String, i64)>,
+}
+
+pub fn select<'a>(
+    index_modules: &[String],
+    entries: &'a [IndexEntry],
+    selection: &ExtractSelection,
+) -> Result<Vec<&'a IndexEntry>, ConverterError> {
+    let module = match &selection.timestamp {
+        Some((name, ts)) => match index_modules.iter().position(|m| m == name) {
+            Some(i) => Some((i, *ts)),
+            None => {
+                return Err(ConverterError::Config(format!(
+                    "module {} is not in the index, it has {}",
+                    name,
+                    index_modules.join(", ")
+                )))
+            }
+        },
+        None => None,
+    };
+    Ok(entries
+        .iter()
+        .filter(|e| {
+            selection
+                .serials
+                .is_none_or(|(first, last)| (first..=last).contains(&e.serial))
+        })
+        .filter(|e| selection.event_ids.is_empty() || selection.event_ids.contains(&e.event_id))
+        .filter(|e| {
+            module.is_none_or(|(i, ts)| {
+                e.ranges[i].is_some_and(|(first, last)| (first..=last).contains(&ts))
+            })
+        })
+        .collect())
+}
+
+// time and odb dump of the BOR, without reading the rest of the file
+pub fn read_bor(input_file: &str) -> Result<(u32, Vec<u8>), ConverterError> {
+    let io_error = |e| ConverterError::Io(input_file.to_string(), e);
+    let mut file = File::open(input_file).map_err(io_error)?;
+    let mut header = [0u8; EVENT_HEADER_SIZE];
+    file.read_exact(&mut header).map_err(io_error)?;
+    if salvage::u16_at(&header, 0) != BOR_ID || salvage::u16_at(&header, 2) != MAGIC {
+        return Err(ConverterError::Midas(
+            "no begin of run record, this does not look like a midas file".to_string(),
+        ));
+    }
+    let mut odb = vec![0u8; salvage::u32_at(&header, 12) as usize];
+    file.read_exact(&mut odb).map_err(io_error)?;
+    Ok((salvage::u32_at(&header, 8), odb))
+}
+
+// the bytes of the event an index entry points at
+pub fn read_event(
+    file: &mut File,
+    input_file: &str,
+    entry: &IndexEntry,
+) -> Result<Vec<u8>, ConverterError> {
+    let io_error = |e| ConverterError::Io(input_file.to_string(), e);
+    let mut buffer = vec![0u8; entry.size as usize];
+    file.seek(SeekFrom::Start(entry.offset)).map_err(io_error)?;
+    file.read_exact(&mut buffer).map_err(io_error)?;
+    // a stale index points into the middle of something else
+    match salvage::event_at(&buffer, 0) {
+        Some((event, _)) if event.serial_number() == entry.serial => Ok(buffer),
+        _ => Err(ConverterError::Midas(format!(
+            "there is no event {} at byte {} of {}, the index is not from this file",
+            entry.serial, entry.offset, input_file
+        ))),
+    }
+}
+
+pub fn extract(
+    input_file: &str,
+    start_time: u32,
+    index_modules: &[String],
+    entries: &[&IndexEntry],
+    modules: &[Module],
+    hex: bool,
+) -> Result<(), ConverterError> {
+    let mut file =
+        File::open(input_file).map_err(|e| ConverterError::Io(input_file.to_string(), e))?;
+    for entry in entries {
+        let buffer = read_event(&mut file, input_file, entry)?;
+        let (event, _) = salvage::event_at(&buffer, 0).unwrap();
+        print_event(&event, entry, start_time, index_modules, modules, hex);
+    }
+    Ok(())
+}
+
+fn print_event(
+    event: &EventView,
+    entry: &IndexEntry,
+    start_time: u32,
+    index_modules: &[String],
+    modules: &[Module],
+    hex: bool,
+) {
+    println!(
+        "Event {} id {} trigger mask {:#x} at {} s, byte {} ({} bytes)",
+        event.serial_number(),
+        event.id(),
+        event.trigger_mask(),
+        event.timestamp() as i64 - start_time as i64,
+        entry.offset,
+        entry.size
+    );
+    for bank in *event {
+        let data = bank.data_slice();
+        let module = modules.iter().find(|m| m.name == bank.name());
+        match module {
+            Some(m) => println!(
+                "  {} {} with {} channels, {} words",
+                bank.name(),
+                m.mod_type,
+                m.nchannels,
+                data.len() / 4
+            ),
+            None => println!("  {} {} bytes", bank.name(), data.len()),
+        }
+        if hex {
+            print_hex(data);
+            continue;
+        }
+        match (event.id(), module) {
+            (1, Some(m)) => match m.mod_type.as_str() {
+                "adc" => {
+                    // the counter runs over the whole file, only the index knows it
+                    let first = index_modules
+                        .iter()
+                        .position(|name| *name == m.name)
+                        .and_then(|i| entry.ranges[i])
+                        .map(|(first, _)| first);
+                    print_v785(data, first)
+                }
+                "v1730" => print_v1730(data),
+                _ => print_mdpp(m, data),
+            },
+            (2, _) => print_scaler(data),
+            _ => print_hex(data),
+        }
+    }
+}
+
+fn print_hex(data: &[u8]) {
+    for (i, line) in data.chunks(32).enumerate() {
+        let words: Vec<String> = line
+            .chunks(4)
+            .map(|w| match w.len() {
+                4 => format!("{:08x}", bitmasks::to_u32_le(w)),
+                _ => w.iter().map(|b| format!("{:02x}", b)).collect(),
+            })
+            .collect();
+        println!("    {:6}: {}", i * 32, words.join(" "));
+    }
+}
+
+fn print_mdpp(module: &Module, data: &[u8]) {
+    let start = MDPPBank::first_header(data).unwrap_or(data.len());
+    if start > 0 {
+        println!(
+            "    {} words before the first header, part of an event started in an earlier midas event:",
+            start / 4
+        );
+        print_hex(&data[..start]);
+    }
+    if start == data.len() {
+        return;
+    }
+    let mut mdpp = MDPPBank::with_capacity(8);
+    match mdpp.parse(&module.mod_type, module.nchannels, &data[start..], true) {
+        Ok(0) => {}
+        Ok(bad_words) => println!("    {} bad words", bad_words),
+        Err(e) => {
+            println!("    {}", e);
+            print_hex(data);
+            return;
+        }
+    }
+    for event in mdpp.completed_events() {
+        println!(
+            "    module {} evt_ts {}",
+            event.module_id, event.evt_timestamp
+        );
+        print_mdpp_hits(event);
+    }
+    if let Some(event) = mdpp.take_open_event() {
+        println!(
+            "    module {} without an end of event word, it ends in a later midas event",
+            event.module_id
+        );
+        print_mdpp_hits(&event);
+    }
+    if mdpp.junk_events > 0 {
+        println!("    {} events cut off by the next header", mdpp.junk_events);
+    }
+}
+
+fn print_mdpp_hits(event: &MDPPEvent) {
+    for (channel, hit) in event.channels.iter().zip(event.channel_hits.iter()) {
+        let mut line = format!("      channel {:2}", channel);
+        if hit.adc_filled {
+            line += &format!(" adc {}", hit.adc_value);
+        }
+        if hit.long_filled {
+            line += &format!(" long {}", hit.long_value);
+        }
+        if hit.short_filled {
+            line += &format!(" short {}", hit.short_value);
+        }
+        if hit.tdc_filled {
+            line += &format!(" tdc {}", hit.tdc_value);
+        }
+        if hit.trigger_dt_filled {
+            line += &format!(" trigger_dt {}", hit.trigger_dt_value);
+        }
+        if hit.pile_up {
+            line += " pileup";
+        }
+        println!("{}", line);
+    }
+}
+
+fn print_v785(data: &[u8], first_evt: Option<i64>) {
+    let mut v785 = v785Bank::new();
+    if let Err(e) = v785.parse(data) {
+        println!("    {}", e);
+        print_hex(data);
+        return;
+    }
+    for hit in v785.hits.iter() {
+        match first_evt {
+            Some(first) => println!("    evt {}", first + hit.evt as i64),
+            None => println!("    evt not in the index"),
+        }
+        // the converted output has a row for every channel, zeros included
+        for (channel, value) in hit.values.iter().enumerate().filter(|(_, v)| **v != 0) {
+            println!("      channel {:2} adc {}", channel, value);
+        }
+    }
+}
+
+fn print_v1730(data: &[u8]) {
+    let mut v1730 = v1730Bank::new();
+    match v1730.parse(data, true) {
+        Ok(0) => {}
+        Ok(leftover) => println!("    {} words after the last hit", leftover),
+        Err(e) => {
+            println!("    {}", e);
+            print_hex(data);
+            return;
+        }
+    }
+    for hit in v1730.hits.iter() {
+        println!(
+            "    channel {:2} long {} coarse_time {} time {}",
+            hit.channel, hit.long, hit.coarse_time, hit.time
+        );
+    }
+}
+
+fn print_scaler(data: &[u8]) {
+    let mut scaler = ScalerBank::new();
+    if let Err(e) = scaler.parse(data) {
+        println!("    {}", e);
+        print_hex(data);
+        return;
+    }
+    for (i, counts) in scaler.data.iter().take(data.len() / 4).enumerate() {
+        println!("    scaler {:2} {}", i, counts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_index::{read_index, write_index};
+    use crate::salvage::tests::b32a_run;
+    use crate::salvage::MidasInput;
+    use std::fs;
+
+    #[test]
+    fn aligned_banks_round_trip() {
+        let dir = std::env::temp_dir().join(format!("extract_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let run_file = dir.join("run.mid").to_str().unwrap().to_string();
+        let index_file = dir.join("run_index.csv").to_str().unwrap().to_string();
+        let (run, offsets) = b32a_run();
+        fs::write(&run_file, &run).unwrap();
+
+        let input = MidasInput::open(&run).unwrap();
+        assert_eq!(write_index(&input, &run, &[], &index_file).unwrap(), 3);
+        let (modules, entries) = read_index(&index_file).unwrap();
+        let selection = ExtractSelection {
+            serials: Some((1, 2)),
+            event_ids: vec![1],
+            timestamp: None,
+        };
+        let selected = select(&modules, &entries, &selection).unwrap();
+        assert_eq!(
+            selected.iter().map(|e| e.serial).collect::<Vec<_>>(),
+            [1, 2]
+        );
+
+        let mut file = File::open(&run_file).unwrap();
+        for (entry, &(offset, length)) in entries.iter().zip(&offsets) {
+            assert_eq!((entry.offset, entry.size), (offset as u64, length as u64));
+            assert_eq!(
+                read_event(&mut file, &run_file, entry).unwrap(),
+                &run[offset..offset + length]
+            );
+        }
+        assert_eq!(read_bor(&run_file).unwrap(), (1000, b"<odb/>".to_vec()));
+        extract(&run_file, 1000, &modules, &selected, &[], true).unwrap();
+
+        // an index of another file
+        let stale = IndexEntry {
+            offset: entries[1].offset + 8,
+            ..entries.into_iter().nth(1).unwrap()
+        };
+        assert!(read_event(&mut file, &run_file, &stale).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
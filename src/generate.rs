@@ -0,0 +1,855 @@
+use crate::error::ConverterError;
+use crate::midas_structs::{self, BankHeader, EventHeader};
+use crate::module_config::{self, Module, OdbMapping};
+use crate::salvage::BANK32_FLAG;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Distribution, Exp, Normal};
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+/*
+Synthetic runs with a known answer (generate).
+
+Writes a midas file with the banks every module in the config would send, and
+next to it a truth table with the hits that went into them, in the same columns
+as the csv the converter writes for that kind of module. Converting the file
+with the same config and comparing the two checks every decoder end to end,
+and a synthetic file can be handed around when real data can't.
+
+  scp/qdc   header, extended timestamp (if timestamp = true), data words, end of
+            event. Every --noise-th word is a dummy word, every --broken-th event
+            is cut off before its end of event word and is followed by a good one
+            in the same bank, like when a readout gets interrupted.
+  adc       the 32 channel values of the v785 (zero if the channel didn't fire)
+  v1730     a triplet per hit: channel and long, coarse time, extended time and
+            fine time
+  scalers   an event with id 2 every --scaler-interval seconds, with the hits
+            per channel so far (channel modulo 32 for every module)
+
+The triggers come at --rate per second on average, and every channel fires with
+probability --occupancy, at least one per module. Each channel has a peak at its
+own place in the range of the module, and --noise of the hits are flat over the
+whole range instead, with pile up set for scp modules.
+
+The MDPP timestamps count a 16 MHz clock that starts two seconds before the 30
+bits of the end of event word run out, so anything but a short run needs the
+extended timestamp to come out right. The counter mode counts the broken events
+too, so they show up as gaps like lost events would. The short integral of a
+16 channel qdc does not fit in the channel field of the data word, so those only
+get long and tdc.
+*/
+
+// the BOR time of every synthetic run, so the same seed gives the same file
+const START_TIME: u32 = 1_700_000_000;
+const MDPP_CLOCK: f64 = 16e6;
+const MDPP_TS_START: u64 = (1 << 30) - 2 * 16_000_000;
+// the v1730 coarse time counts 2 ns
+const V1730_CLOCK: f64 = 5e8;
+
+pub struct GeneratorSettings {
+    pub events: u64,
+    // triggers per second
+    pub rate: f64,
+    // probability for every channel to fire in a trigger
+    pub occupancy: f64,
+    // probability for a hit to be noise and for an MDPP event to get a dummy word
+    pub noise: f64,
+    // probability for an MDPP event to be preceded by a cut off one
+    pub broken: f64,
+    // seconds between scaler events, 0 for none
+    pub scaler_interval: f64,
+    pub seed: u64,
+    pub run_number: u32,
+}
+
+#[derive(Default)]
+pub struct GenerateSummary {
+    pub trigger_events: u64,
+    pub scaler_events: u64,
+    pub hits: u64,
+    pub broken_events: u64,
+    // seconds between the first and last trigger
+    pub duration: u32,
+}
+
+struct Generator<'a> {
+    settings: &'a GeneratorSettings,
+    rng: StdRng,
+    truth: BufWriter<File>,
+    // hits per channel so far
+    scalers: [u32; 32],
+    summary: GenerateSummary,
+}
+
+impl Generator<'_> {
+    // the channels that fired, at least one
+    fn fired_channels(&mut self, nchannels: u32) -> Vec<u32> {
+        let mut channels: Vec<u32> = (0..nchannels)
+            .filter(|_| self.rng.gen_bool(self.settings.occupancy))
+            .collect();
+        if channels.is_empty() {
+            channels.push(self.rng.gen_range(0..nchannels));
+        }
+        for &channel in channels.iter() {
+            self.scalers[channel as usize % 32] += 1;
+        }
+        self.summary.hits += channels.len() as u64;
+        channels
+    }
+
+    // a value between 1 and max, around the peak of the channel unless it is noise
+    fn value(&mut self, channel: u32, nchannels: u32, max: u32) -> (u32, bool) {
+        if self.rng.gen_bool(self.settings.noise) {
+            return (self.rng.gen_range(1..=max), true);
+        }
+        let peak = max as f64 * (channel + 1) as f64 / (nchannels + 1) as f64;
+        let value = Normal::new(peak, max as f64 / 100.0)
+            .unwrap()
+            .sample(&mut self.rng);
+        (value.round().clamp(1.0, max as f64) as u32, false)
+    }
+
+    fn mdpp_words(
+        &mut self,
+        module: &Module,
+        id: u32,
+        counter: &mut u64,
+        time: f64,
+    ) -> io::Result<Vec<u32>> {
+        let nchannels = module.nchannels;
+        let qdc = module.mod_type == "qdc";
+        let pileup_bit = if nchannels == 32 { 1 << 24 } else { 1 << 23 };
+        let data = |channel: u32, evt_type: u32, value: u32| {
+            0x1000_0000 | (channel + evt_type * nchannels) << 16 | value
+        };
+        let header = 0x4000_0000 | id << 16;
+        let ticks = MDPP_TS_START + (time * MDPP_CLOCK) as u64;
+        let extended = 0x2000_0000 | (ticks >> 30) as u32 & 0xFFFF;
+        let mut words = Vec::new();
+
+        if self.rng.gen_bool(self.settings.broken) {
+            // nwords says more is coming than what made it
+            let nwords = 2 * nchannels + 2;
+            words.push(header | nwords);
+            for _ in 0..self.rng.gen_range(1..=nchannels) {
+                let channel = self.rng.gen_range(0..nchannels);
+                words.push(data(channel, 1, self.rng.gen_range(0..=0xFFFF)));
+            }
+            *counter += 1;
+            self.summary.broken_events += 1;
+        }
+
+        let start = words.len();
+        words.push(header);
+        if module.timestamp {
+            words.push(extended);
+        }
+        let mut rows = Vec::new();
+        for channel in self.fired_channels(nchannels) {
+            let (mut adc, mut long, mut short, mut pileup) = (0, 0, 0, false);
+            let (value, noise) = self.value(channel, nchannels, 0xFFFF);
+            if qdc {
+                long = value;
+                words.push(data(channel, 0, long));
+                if nchannels == 32 {
+                    short = long / 4;
+                    words.push(data(channel, 3, short));
+                }
+            } else {
+                adc = value;
+                pileup = noise;
+                words.push(data(channel, 0, adc) | if pileup { pileup_bit } else { 0 });
+            }
+            let (tdc, _) = self.value(channel, nchannels, 0xFFFF);
+            words.push(data(channel, 1, tdc));
+            rows.push((channel, adc, long, short, tdc, pileup));
+        }
+        // the fill word the module pads its events with
+        if self.rng.gen_bool(self.settings.noise) {
+            words.push(0);
+        }
+        let evt_ts = if module.timestamp {
+            ticks
+        } else {
+            *counter & 0x3FFF_FFFF
+        };
+        words.push(0xC000_0000 | (evt_ts & 0x3FFF_FFFF) as u32);
+        words[start] |= (words.len() - start - 1) as u32 & 0x3FF;
+        *counter += 1;
+
+        for (channel, adc, long, short, tdc, pileup) in rows {
+            writeln!(
+                self.truth,
+                "{},{},{},{},{},{},0,{},{}",
+                id, channel, adc, long, short, tdc, pileup, evt_ts
+            )?;
+        }
+        Ok(words)
+    }
+
+    fn v785_words(&mut self, module: &Module, counter: &mut u64) -> io::Result<Vec<u32>> {
+        let nchannels = module.nchannels.min(32);
+        let mut values = [0; 32];
+        for channel in self.fired_channels(nchannels) {
+            values[channel as usize] = self.value(channel, nchannels, 0xFFF).0;
+        }
+        // the converter writes every channel of a v785 hit, and always module 0
+        for (channel, value) in values.iter().enumerate() {
+            writeln!(self.truth, "0,{},{},{}", channel, value, counter)?;
+        }
+        *counter += 1;
+        // 34 words like the frontend sends, only the first 32 are values
+        let mut words = values.to_vec();
+        words.extend([0, 0]);
+        Ok(words)
+    }
+
+    fn v1730_words(&mut self, module: &Module, time: f64) -> io::Result<Vec<u32>> {
+        let nchannels = module.nchannels.min(16);
+        let mut words = Vec::new();
+        for channel in self.fired_channels(nchannels) {
+            let (long, _) = self.value(channel, nchannels, 0x7FFF);
+            // a few ns between the channels
+            let coarse_time = (time * V1730_CLOCK) as u64 + self.rng.gen_range(0..8);
+            let fine_time = self.rng.gen_range(0..1024);
+            words.push(channel << 16 | long);
+            words.push((coarse_time & 0x7FFF_FFFF) as u32);
+            words.push(((coarse_time >> 31) as u32 & 0xFFFF) << 16 | fine_time);
+            let time = (coarse_time as f64) + ((fine_time as f64) / 1024.0);
+            writeln!(self.truth, "{},{},{},{}", channel, long, coarse_time, time)?;
+        }
+        Ok(words)
+    }
+}
+
+fn write_event(
+    w: &mut impl Write,
+    event_id: u16,
+    serial_number: u32,
+    time_stamp: u32,
+    banks: &[u8],
+) -> io::Result<()> {
+    EventHeader {
+        event_id,
+        trigger_mask: 1 << (event_id - 1),
+        serial_number,
+        time_stamp,
+        event_size: banks.len() as u32 + 8,
+    }
+    .write(w)?;
+    BankHeader {
+        all_banks_size: banks.len() as u32,
+        flags: BANK32_FLAG,
+    }
+    .write(w)?;
+    w.write_all(banks)
+}
+
+// the time format of the Runinfo keys
+fn odb_time(time: u32) -> String {
+    chrono::DateTime::from_timestamp(time as i64, 0)
+        .unwrap()
+        .format("%a %b %d %H:%M:%S %Y")
+        .to_string()
+}
+
+fn odb_dump(odb: &Value) -> Vec<u8> {
+    let mut dump = serde_json::to_vec_pretty(odb).unwrap();
+    dump.push(0);
+    dump
+}
+
+// the kind of csv the module ends up in
+fn sorter(module: &Module) -> &str {
+    match module.mod_type.as_str() {
+        "qdc" => "scp",
+        mod_type => mod_type,
+    }
+}
+
+fn check_settings(modules: &[Module], settings: &GeneratorSettings) -> Result<(), ConverterError> {
+    for (name, p) in [
+        ("occupancy", settings.occupancy),
+        ("noise", settings.noise),
+        ("broken", settings.broken),
+    ] {
+        if !(0.0..=1.0).contains(&p) {
+            return Err(ConverterError::Config(format!(
+                "--{} is a probability, it has to be between 0 and 1",
+                name
+            )));
+        }
+    }
+    if settings.rate.is_nan() || settings.rate <= 0.0 {
+        return Err(ConverterError::Config(
+            "--rate has to be above 0".to_string(),
+        ));
+    }
+    if modules.is_empty() {
+        return Err(ConverterError::Config(
+            "the config has no modules to generate".to_string(),
+        ));
+    }
+    if let Some(m) = modules.iter().find(|m| m.name.len() != 4) {
+        return Err(ConverterError::Config(format!(
+            "module {} can't be a midas bank, the names have 4 characters",
+            m.name
+        )));
+    }
+    // one truth table for the one sorter that will read the file
+    if modules.iter().any(|m| sorter(m) != sorter(&modules[0])) {
+        return Err(ConverterError::Config(
+            "generate one kind of module at a time, a run is sorted as MDPP, v785 or v1730"
+                .to_string(),
+        ));
+    }
+    Ok(())
+}
+
+pub fn generate(
+    modules: &[Module],
+    mapping: &OdbMapping,
+    settings: &GeneratorSettings,
+    output_file: &str,
+) -> Result<GenerateSummary, ConverterError> {
+    check_settings(modules, settings)?;
+    let stem = output_file.split('.').next().unwrap();
+    let truth_file = format!("{}_truth.csv", stem);
+    let scaler_file = format!("{}_truth_scaler.csv", stem);
+    let io_error = |file: &str| {
+        let file = file.to_string();
+        move |e| ConverterError::Io(file.clone(), e)
+    };
+    let mut writer = BufWriter::new(File::create(output_file).map_err(io_error(output_file))?);
+    let mut scaler_truth =
+        BufWriter::new(File::create(&scaler_file).map_err(io_error(&scaler_file))?);
+    let mut generator = Generator {
+        settings,
+        rng: StdRng::seed_from_u64(settings.seed),
+        truth: BufWriter::new(File::create(&truth_file).map_err(io_error(&truth_file))?),
+        scalers: [0; 32],
+        summary: GenerateSummary::default(),
+    };
+
+    // same columns as the csv of the sorter that reads the file
+    let truth_header = match modules[0].mod_type.as_str() {
+        "adc" => "module,channel,adc,evt_ts",
+        "v1730" => "channel,long,coarse_time,time",
+        _ => "module,channel,adc,long,short,tdc,trigger_dt,pileup,evt_ts",
+    };
+    writeln!(generator.truth, "{}", truth_header).map_err(io_error(&truth_file))?;
+    let scaler_header: Vec<String> = (0..32).map(|i| format!("chan_{}", i)).collect();
+    writeln!(scaler_truth, "{}", scaler_header.join(",")).map_err(io_error(&scaler_file))?;
+
+    let mut odb = module_config::config_to_odb(modules, mapping);
+    odb["Runinfo"] = json!({
+        "Run number": settings.run_number,
+        "Start time": odb_time(START_TIME),
+        "Start time binary": START_TIME,
+    });
+    odb["Experiment"] = json!({
+        "Run parameters": {
+            "Comment": format!("synthetic run, seed {}", settings.seed),
+        }
+    });
+    midas_structs::write_bor(
+        &mut writer,
+        settings.run_number,
+        START_TIME,
+        &odb_dump(&odb),
+    )
+    .map_err(io_error(output_file))?;
+
+    let arrivals = Exp::new(settings.rate).unwrap();
+    let mut counters = vec![0; modules.len()];
+    let (mut trigger_serial, mut scaler_serial) = (0, 0);
+    let mut next_scaler = settings.scaler_interval;
+    let mut time = 0.0;
+    for _ in 0..settings.events {
+        time += arrivals.sample(&mut generator.rng);
+        while settings.scaler_interval > 0.0 && time >= next_scaler {
+            let mut banks = Vec::new();
+            midas_structs::write_bank32(&mut banks, "SCLR", &generator.scalers)
+                .map_err(io_error(output_file))?;
+            write_event(
+                &mut writer,
+                2,
+                scaler_serial,
+                START_TIME + next_scaler as u32,
+                &banks,
+            )
+            .map_err(io_error(output_file))?;
+            let row: Vec<String> = generator.scalers.iter().map(|s| s.to_string()).collect();
+            writeln!(scaler_truth, "{}", row.join(",")).map_err(io_error(&scaler_file))?;
+            scaler_serial += 1;
+            generator.summary.scaler_events += 1;
+            next_scaler += settings.scaler_interval;
+        }
+
+        let mut banks = Vec::new();
+        for (i, module) in modules.iter().enumerate() {
+            let words = match module.mod_type.as_str() {
+                "adc" => generator.v785_words(module, &mut counters[i]),
+                "v1730" => generator.v1730_words(module, time),
+                _ => generator.mdpp_words(module, i as u32, &mut counters[i], time),
+            }
+            .map_err(io_error(&truth_file))?;
+            midas_structs::write_bank32(&mut banks, &module.name, &words)
+                .map_err(io_error(output_file))?;
+        }
+        write_event(
+            &mut writer,
+            1,
+            trigger_serial,
+            START_TIME + time as u32,
+            &banks,
+        )
+        .map_err(io_error(output_file))?;
+        trigger_serial += 1;
+        generator.summary.trigger_events += 1;
+    }
+
+    let stop_time = START_TIME + time as u32;
+    odb["Runinfo"]["Stop time"] = Value::from(odb_time(stop_time));
+    odb["Runinfo"]["Stop time binary"] = Value::from(stop_time);
+    midas_structs::write_eor(&mut writer, settings.run_number, stop_time, &odb_dump(&odb))
+        .map_err(io_error(output_file))?;
+    writer.flush().map_err(io_error(output_file))?;
+    generator.truth.flush().map_err(io_error(&truth_file))?;
+    scaler_truth.flush().map_err(io_error(&scaler_file))?;
+    generator.summary.duration = time as u32;
+    Ok(generator.summary)
+}
+
+// Synthetic runs converted the way the command line does it and compared with
+// their truth tables, for every kind of module the sorters know.
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::hdf5_file::tests::read_h5;
+    use crate::salvage::MidasInput;
+    use crate::{convert, input_runs, Args};
+    use clap::Parser;
+    use indicatif::ProgressBar;
+    use polars::prelude::{DataType, ParquetReader, SerReader};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    pub const MDPP_CONFIG: &str = r#"
+[[modules]]
+name = "SCP1"
+address = "0xE1FA0000"
+mod_type = "scp"
+nchannels = 16
+setup_file = "x"
+timestamp = true
+
+[[modules]]
+name = "QDC1"
+address = "0xE1FB0000"
+mod_type = "qdc"
+nchannels = 32
+setup_file = "x"
+"#;
+
+    const V785_CONFIG: &str = r#"
+[[modules]]
+name = "ADC1"
+address = "0x1F11"
+mod_type = "adc"
+nchannels = 32
+setup_file = "x"
+"#;
+
+    pub const V1730_CONFIG: &str = r#"
+[[modules]]
+name = "V173"
+address = "0x3210"
+mod_type = "v1730"
+nchannels = 16
+setup_file = "x"
+"#;
+
+    pub fn settings(broken: f64) -> GeneratorSettings {
+        GeneratorSettings {
+            events: 3000,
+            rate: 1000.0,
+            occupancy: 0.25,
+            noise: 0.05,
+            broken,
+            scaler_interval: 0.5,
+            seed: 7,
+            run_number: 12,
+        }
+    }
+
+    // a directory of its own for every test, the outputs are named after the input
+    // so it can't have a dot in it
+    pub fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("midas_converter_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // writes the config and the synthetic run, returns the config file and the run
+    pub fn generate_run(
+        dir: &Path,
+        config: &str,
+        settings: &GeneratorSettings,
+    ) -> (String, String, GenerateSummary) {
+        let config_file = dir.join("config.toml").to_str().unwrap().to_string();
+        fs::write(&config_file, config).unwrap();
+        let toml_config = module_config::create_config(&config_file).unwrap();
+        let config = module_config::resolve_config(Some(toml_config), &Value::Null, false).unwrap();
+        let run = dir.join("run.mid").to_str().unwrap().to_string();
+        let summary = generate(&config.modules, &config.odb_config, settings, &run).unwrap();
+        (config_file, run, summary)
+    }
+
+    // converts to <dir>/<output>.csv, true if the run was complete
+    pub fn convert_run(run: &str, config_file: &str, output: &str, extra: &[&str]) -> bool {
+        try_convert_run(run, config_file, output, extra).unwrap()
+    }
+
+    // the same, for the tests that expect the conversion to fail
+    pub fn try_convert_run(
+        run: &str,
+        config_file: &str,
+        output: &str,
+        extra: &[&str],
+    ) -> Result<bool, ConverterError> {
+        let mut argv = vec![
+            "midas-converter",
+            run,
+            "-C",
+            config_file,
+            "-c",
+            "-o",
+            output,
+        ];
+        argv.extend_from_slice(extra);
+        let args = Args::parse_from(argv);
+        let runs = input_runs(&args).unwrap();
+        convert(&args, &runs[0], ProgressBar::hidden())
+    }
+
+    // the modules are written one after the other every chunk, the truth table
+    // has them in trigger order
+    pub fn sorted_rows(file: &str) -> (String, Vec<String>) {
+        let contents = fs::read_to_string(file).unwrap();
+        let mut lines = contents.lines().map(|l| l.to_string());
+        let header = lines.next().unwrap();
+        let mut rows: Vec<String> = lines.collect();
+        rows.sort();
+        (header, rows)
+    }
+
+    pub fn stem(path: &str) -> &str {
+        path.split('.').next().unwrap()
+    }
+
+    // The words of every module cut into banks of a few words, so that most MDPP
+    // events are split over two or more midas events like a FIFO readout does.
+    // The scaler events are left out.
+    fn rebank(run: &str) {
+        let contents = fs::read(run).unwrap();
+        let input = MidasInput::open(&contents).unwrap();
+        let (run_number, (start_time, bor_odb), eor) = input.odb_records();
+        let mut words: Vec<(String, Vec<u32>)> = Vec::new();
+        for event in input.events().filter(|e| e.id() == 1) {
+            for bank in event {
+                let data = bank
+                    .data_slice()
+                    .chunks_exact(4)
+                    .map(|b| u32::from_le_bytes(b.try_into().unwrap()));
+                match words.iter_mut().find(|(name, _)| name == bank.name()) {
+                    Some((_, module_words)) => module_words.extend(data),
+                    None => words.push((bank.name().to_string(), data.collect())),
+                }
+            }
+        }
+
+        let mut writer = BufWriter::new(File::create(run).unwrap());
+        midas_structs::write_bor(&mut writer, run_number, start_time, bor_odb).unwrap();
+        let mut offsets = vec![0; words.len()];
+        let mut serial = 0;
+        while offsets.iter().zip(&words).any(|(&o, (_, w))| o < w.len()) {
+            let mut banks = Vec::new();
+            for (i, (name, module_words)) in words.iter().enumerate() {
+                let length = [1, 2, 3, 5, 7, 11, 4][(serial as usize + i) % 7];
+                let end = (offsets[i] + length).min(module_words.len());
+                if end > offsets[i] {
+                    midas_structs::write_bank32(&mut banks, name, &module_words[offsets[i]..end])
+                        .unwrap();
+                }
+                offsets[i] = end;
+            }
+            write_event(&mut writer, 1, serial, start_time, &banks).unwrap();
+            serial += 1;
+        }
+        let (stop_time, eor_odb) = eor.unwrap();
+        midas_structs::write_eor(&mut writer, run_number, stop_time, eor_odb).unwrap();
+        writer.flush().unwrap();
+    }
+
+    fn check_round_trip(name: &str, config: &str, settings: &GeneratorSettings) -> GenerateSummary {
+        let dir = test_dir(name);
+        let (config_file, run, summary) = generate_run(&dir, config, settings);
+        let output = dir.join("out").to_str().unwrap().to_string();
+        assert!(convert_run(&run, &config_file, &output, &[]));
+
+        let (header, rows) = sorted_rows(&format!("{}.csv", output));
+        let (truth_header, truth_rows) = sorted_rows(&format!("{}_truth.csv", stem(&run)));
+        assert_eq!(header, truth_header);
+        assert!(
+            rows == truth_rows,
+            "{} hits differ from the truth table",
+            name
+        );
+
+        // the converted scalers start from the zeros before the first scaler event
+        let scalers = fs::read_to_string(format!("{}_scaler.csv", output)).unwrap();
+        let truth_scalers = fs::read_to_string(format!("{}_truth_scaler.csv", stem(&run))).unwrap();
+        let mut scaler_rows: Vec<&str> = scalers.lines().collect();
+        assert!(scaler_rows.remove(1).split(',').all(|v| v == "0"));
+        assert_eq!(scaler_rows, truth_scalers.lines().collect::<Vec<_>>());
+        assert_eq!(scaler_rows.len() as u64, summary.scaler_events + 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+        summary
+    }
+
+    #[test]
+    fn mdpp_round_trip() {
+        let summary = check_round_trip("mdpp", MDPP_CONFIG, &settings(0.0));
+        assert!(summary.hits > 0 && summary.scaler_events > 0);
+    }
+
+    #[test]
+    fn mdpp_broken_events_round_trip() {
+        let summary = check_round_trip("broken", MDPP_CONFIG, &settings(0.05));
+        assert!(summary.broken_events > 0);
+    }
+
+    #[test]
+    fn mdpp_events_split_over_midas_events() {
+        let dir = test_dir("split");
+        let (config_file, run, _) = generate_run(&dir, MDPP_CONFIG, &settings(0.05));
+        rebank(&run);
+        let (_, truth_rows) = sorted_rows(&format!("{}_truth.csv", stem(&run)));
+        // the workers only see their own batch, the rest is put together by the writer
+        for threads in ["1", "4"] {
+            let output = dir.join(format!("out{}", threads));
+            let output = output.to_str().unwrap();
+            assert!(convert_run(&run, &config_file, output, &["-t", threads]));
+            let (_, rows) = sorted_rows(&format!("{}.csv", output));
+            assert!(
+                rows == truth_rows,
+                "split events differ with -t {}",
+                threads
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn v785_round_trip() {
+        check_round_trip("v785", V785_CONFIG, &settings(0.0));
+    }
+
+    // the v785 adc is the whole data word, the bits above the conversion
+    // have to make it into the parquet file and the dataset
+    #[test]
+    fn v785_words_are_not_cut() {
+        let dir = test_dir("v785_words");
+        let (config_file, run, _) = generate_run(&dir, V785_CONFIG, &settings(0.0));
+        let mut contents = fs::read(&run).unwrap();
+        let first = {
+            let input = MidasInput::open(&contents).unwrap();
+            let event = input.events().find(|e| e.id() == 1).unwrap();
+            let bank = event.into_iter().next().unwrap();
+            bank.data_slice().as_ptr() as usize - contents.as_ptr() as usize
+        };
+        contents[first..first + 4].copy_from_slice(&0x0004_1234u32.to_le_bytes());
+        fs::write(&run, &contents).unwrap();
+
+        let output = dir.join("out").to_str().unwrap().to_string();
+        let dataset = dir.join("dataset").to_str().unwrap().to_string();
+        assert!(convert_run(
+            &run,
+            &config_file,
+            &output,
+            &["-p", "--dataset", &dataset]
+        ));
+        let part = format!("{}/run=12/module=ADC1/part-0.parquet", dataset);
+        for file in [format!("{}.parquet", output), part] {
+            let df = ParquetReader::new(File::open(&file).unwrap())
+                .finish()
+                .unwrap();
+            let adc = df.column("adc").unwrap();
+            assert_eq!(adc.dtype(), &DataType::UInt32, "{}", file);
+            assert_eq!(adc.u32().unwrap().get(0), Some(0x0004_1234), "{}", file);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn v1730_round_trip() {
+        check_round_trip("v1730", V1730_CONFIG, &settings(0.0));
+    }
+
+    // The hdf5 output has a dataset per field in a group per module, put back
+    // together the groups have to give the truth table.
+    fn check_hdf5_round_trip(name: &str, config: &str, groups: &[&str]) {
+        let dir = test_dir(name);
+        let settings = settings(0.05);
+        let (config_file, run, _) = generate_run(&dir, config, &settings);
+        let output = dir.join("out").to_str().unwrap().to_string();
+        assert!(convert_run(&run, &config_file, &output, &["--hdf5"]));
+        let contents = read_h5(&format!("{}.h5", output));
+
+        let (header, truth_rows) = sorted_rows(&format!("{}_truth.csv", stem(&run)));
+        let mut rows = Vec::new();
+        for group in groups {
+            let columns: Vec<Vec<String>> = header
+                .split(',')
+                .map(|field| {
+                    let values = contents.values(&format!("{}/{}", group, field));
+                    // hdf5 has no bool, the pileup flag is a u8
+                    match field {
+                        "pileup" => values.iter().map(|v| (v == "1").to_string()).collect(),
+                        _ => values,
+                    }
+                })
+                .collect();
+            for i in 0..columns[0].len() {
+                let row: Vec<&str> = columns.iter().map(|c| c[i].as_str()).collect();
+                rows.push(row.join(","));
+            }
+        }
+        rows.sort();
+        assert!(
+            rows == truth_rows,
+            "{} hdf5 differs from the truth table",
+            name
+        );
+
+        let truth_scalers = fs::read_to_string(format!("{}_truth_scaler.csv", stem(&run))).unwrap();
+        let columns: Vec<Vec<String>> = (0..32)
+            .map(|i| contents.values(&format!("/scalers/chan_{}", i)))
+            .collect();
+        let scaler_rows: Vec<String> = (0..columns[0].len())
+            .map(|i| {
+                columns
+                    .iter()
+                    .map(|c| c[i].as_str())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+            .collect();
+        // same as the csv, from the zeros before the first scaler event
+        assert!(scaler_rows[0].split(',').all(|v| v == "0"));
+        assert_eq!(
+            scaler_rows[1..],
+            truth_scalers.lines().skip(1).collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            contents.attribute("/", "run_number"),
+            settings.run_number.to_string()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mdpp_hdf5_round_trip() {
+        check_hdf5_round_trip("mdpp_hdf5", MDPP_CONFIG, &["/mdpp/SCP1", "/mdpp/QDC1"]);
+    }
+
+    #[test]
+    fn v1730_hdf5_round_trip() {
+        check_hdf5_round_trip("v1730_hdf5", V1730_CONFIG, &["/v1730/V173"]);
+    }
+
+    // every output is named after the csv, whatever dots are in the path
+    #[test]
+    fn outputs_go_next_to_the_csv() {
+        let dir = test_dir("output_names");
+        let config = format!(
+            "{}\n[[histograms]]\nmodule = \"SCP1\"\nx = \"adc\"\n",
+            MDPP_CONFIG
+        );
+        let (config_file, run, _) = generate_run(&dir, &config, &settings(0.0));
+        let out_dir = dir.join("out.d");
+        fs::create_dir_all(&out_dir).unwrap();
+        let output = out_dir.join("x.csv").to_str().unwrap().to_string();
+        assert!(convert_run(&run, &config_file, &output, &["-p"]));
+        let mut files: Vec<String> = fs::read_dir(&out_dir)
+            .unwrap()
+            .map(|f| f.unwrap().file_name().to_str().unwrap().to_string())
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                "x.csv",
+                "x.parquet",
+                "x_bor_odb.json",
+                "x_eor_odb.json",
+                "x_hists.npz",
+                "x_scaler.csv"
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn threads_give_the_same_output() {
+        let dir = test_dir("threads");
+        let (config_file, run, _) = generate_run(&dir, MDPP_CONFIG, &settings(0.05));
+        let one = dir.join("one").to_str().unwrap().to_string();
+        let many = dir.join("many").to_str().unwrap().to_string();
+        // small chunks so the batches of the workers get flushed in between
+        assert!(convert_run(
+            &run,
+            &config_file,
+            &one,
+            &["-t", "1", "--chunk-size", "500"]
+        ));
+        assert!(convert_run(
+            &run,
+            &config_file,
+            &many,
+            &["-t", "4", "--chunk-size", "500"]
+        ));
+        for suffix in [".csv", "_scaler.csv"] {
+            let a = fs::read(format!("{}{}", one, suffix)).unwrap();
+            let b = fs::read(format!("{}{}", many, suffix)).unwrap();
+            assert!(a == b, "{} differs between -t 1 and -t 4", suffix);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_run_is_salvaged() {
+        let dir = test_dir("truncated");
+        let (config_file, run, summary) = generate_run(&dir, MDPP_CONFIG, &settings(0.0));
+        // cut off in the middle of an event, without the EOR
+        let contents = fs::read(&run).unwrap();
+        fs::write(&run, &contents[..contents.len() * 2 / 3 + 5]).unwrap();
+        let output = dir.join("out").to_str().unwrap().to_string();
+        assert!(!convert_run(&run, &config_file, &output, &[]));
+
+        // what is left are the hits of the events before the cut
+        let (_, rows) = sorted_rows(&format!("{}.csv", output));
+        let (_, truth_rows) = sorted_rows(&format!("{}_truth.csv", stem(&run)));
+        assert!(rows.len() as u64 > summary.hits / 2);
+        assert!(rows.len() < truth_rows.len());
+        assert!(rows.iter().all(|r| truth_rows.binary_search(r).is_ok()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
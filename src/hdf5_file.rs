@@ -0,0 +1,996 @@
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+/*
+A small HDF5 file writer, so that we don't need libhdf5.
+
+This writes the oldest version of the format (superblock 0, version 1 object
+headers and symbol table groups), which every HDF5 library can read. Only what
+we need is here: groups with string attributes and one dimensional datasets
+that can be appended to. Everything is little endian with 8 byte addresses.
+
+The datasets are chunked, CHUNK_ROWS values to a chunk, and every chunk goes
+through the shuffle and deflate filters, same as h5py with
+compression="gzip", shuffle=True. Chunks are written as soon as they are
+full, and everything that points at them (chunk indexes, object headers,
+groups) is written by close, after which the superblock at the start of the
+file is filled in. So the layout is
+
+    superblock (96 bytes)
+    data chunks, in the order they filled up
+    chunk B-tree + object header, for every dataset
+    local heap + symbol table nodes + B-tree + object header, for every group
+
+The group and chunk indexes are version 1 B-trees, built bottom up once we
+know all of the children.
+*/
+
+const CHUNK_ROWS: usize = 65536;
+const DEFLATE_LEVEL: u32 = 4;
+const SIGNATURE: [u8; 8] = [0x89, b'H', b'D', b'F', b'\r', b'\n', 0x1a, b'\n'];
+const SUPERBLOCK_LEN: usize = 96;
+const UNDEFINED: u64 = u64::MAX;
+// group symbol table nodes hold 2 * GROUP_LEAF_K entries, group B-tree nodes
+// 2 * GROUP_INTERNAL_K children. The chunk B-tree K is fixed for superblock 0.
+const GROUP_LEAF_K: usize = 4;
+const GROUP_INTERNAL_K: usize = 16;
+const CHUNK_K: usize = 32;
+// the local heap marks an empty free list with 1, not the undefined address
+const HEAP_FREE_NULL: u64 = 1;
+// symbol table entries with the group B-tree and heap in the scratch pad
+const CACHE_STAB: u32 = 1;
+
+// object header message types
+const DATASPACE: u16 = 0x0001;
+const DATATYPE: u16 = 0x0003;
+const FILL_VALUE: u16 = 0x0005;
+const LAYOUT: u16 = 0x0008;
+const FILTER_PIPELINE: u16 = 0x000b;
+const ATTRIBUTE: u16 = 0x000c;
+const SYMBOL_TABLE: u16 = 0x0011;
+// the message can't change, what libhdf5 sets for datatypes and fill values
+const CONSTANT: u8 = 1;
+
+const FILTER_DEFLATE: u16 = 1;
+const FILTER_SHUFFLE: u16 = 2;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum H5Type {
+    U8,
+    U32,
+    U64,
+    I64,
+    F64,
+}
+
+impl H5Type {
+    fn size(&self) -> usize {
+        match self {
+            H5Type::U8 => 1,
+            H5Type::U32 => 4,
+            H5Type::U64 | H5Type::I64 | H5Type::F64 => 8,
+        }
+    }
+
+    // datatype message, little endian integers and IEEE doubles
+    fn message(&self) -> Vec<u8> {
+        let mut msg = Vec::new();
+        let bits = (8 * self.size()) as u16;
+        match self {
+            H5Type::F64 => {
+                // version 1, floating point class. Implied mantissa msb, sign bit 63
+                msg.extend_from_slice(&[0x11, 0x20, 63, 0]);
+                msg.extend_from_slice(&(self.size() as u32).to_le_bytes());
+                msg.extend_from_slice(&0u16.to_le_bytes());
+                msg.extend_from_slice(&bits.to_le_bytes());
+                // exponent location and size, mantissa location and size
+                msg.extend_from_slice(&[52, 11, 0, 52]);
+                msg.extend_from_slice(&1023u32.to_le_bytes());
+            }
+            _ => {
+                // version 1, fixed point class, bit 3 is signed
+                let signed = if *self == H5Type::I64 { 0x08 } else { 0 };
+                msg.extend_from_slice(&[0x10, signed, 0, 0]);
+                msg.extend_from_slice(&(self.size() as u32).to_le_bytes());
+                msg.extend_from_slice(&0u16.to_le_bytes());
+                msg.extend_from_slice(&bits.to_le_bytes());
+            }
+        }
+        msg
+    }
+}
+
+// Rust types that can be appended to a dataset
+pub trait H5Value {
+    const TYPE: H5Type;
+    fn push(self, buf: &mut Vec<u8>);
+}
+
+impl H5Value for u8 {
+    const TYPE: H5Type = H5Type::U8;
+    fn push(self, buf: &mut Vec<u8>) {
+        buf.push(self);
+    }
+}
+
+impl H5Value for u32 {
+    const TYPE: H5Type = H5Type::U32;
+    fn push(self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl H5Value for u64 {
+    const TYPE: H5Type = H5Type::U64;
+    fn push(self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl H5Value for i64 {
+    const TYPE: H5Type = H5Type::I64;
+    fn push(self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl H5Value for f64 {
+    const TYPE: H5Type = H5Type::F64;
+    fn push(self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+struct Group {
+    path: String,
+    attributes: Vec<(String, String)>,
+    // filled in by close
+    btree: u64,
+    heap: u64,
+    header: u64,
+}
+
+struct Dataset {
+    path: String,
+    dtype: H5Type,
+    rows: u64,
+    // the chunk that is filling up
+    buffer: Vec<u8>,
+    // address and size on disk of every full chunk
+    chunks: Vec<(u64, u32)>,
+}
+
+// an entry in the symbol table of a group
+struct Link {
+    name: String,
+    header: u64,
+    // B-tree and local heap, for groups
+    stab: Option<(u64, u64)>,
+}
+
+// "/mdpp/scp1" -> ("/mdpp", "scp1")
+fn split_path(path: &str) -> (&str, &str) {
+    let i = path.rfind('/').unwrap_or(0);
+    let parent = if i == 0 { "/" } else { &path[..i] };
+    (parent, &path[i + 1..])
+}
+
+fn pad8(buf: &mut Vec<u8>) {
+    buf.resize(buf.len().next_multiple_of(8), 0);
+}
+
+// version 1 object header, every message is padded to 8 bytes
+fn object_header(messages: &[(u16, u8, Vec<u8>)]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (msg_type, flags, data) in messages.iter() {
+        let len = data.len().next_multiple_of(8);
+        body.extend_from_slice(&msg_type.to_le_bytes());
+        body.extend_from_slice(&(len as u16).to_le_bytes());
+        body.extend_from_slice(&[*flags, 0, 0, 0]);
+        body.extend_from_slice(data);
+        pad8(&mut body);
+    }
+    let mut header = vec![1, 0];
+    header.extend_from_slice(&(messages.len() as u16).to_le_bytes());
+    header.extend_from_slice(&1u32.to_le_bytes()); // reference count
+    header.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    header.extend_from_slice(&[0; 4]); // the messages start 8 byte aligned
+    header.extend(body);
+    header
+}
+
+// version 1 dataspace, a scalar without dims, else extendable without limit
+fn dataspace(dims: &[u64]) -> Vec<u8> {
+    let max_dims = if dims.is_empty() { 0 } else { 1 };
+    let mut msg = vec![1, dims.len() as u8, max_dims, 0, 0, 0, 0, 0];
+    for dim in dims.iter() {
+        msg.extend_from_slice(&dim.to_le_bytes());
+    }
+    for _ in dims.iter() {
+        msg.extend_from_slice(&UNDEFINED.to_le_bytes());
+    }
+    msg
+}
+
+// scalar UTF-8 string attribute, padded with nulls
+fn string_attribute(name: &str, value: &str) -> Vec<u8> {
+    let size = value.len().max(1);
+    let mut dtype = vec![0x13, 0x11, 0, 0];
+    dtype.extend_from_slice(&(size as u32).to_le_bytes());
+    let space = dataspace(&[]);
+
+    let mut msg = vec![1, 0];
+    msg.extend_from_slice(&(name.len() as u16 + 1).to_le_bytes());
+    msg.extend_from_slice(&(dtype.len() as u16).to_le_bytes());
+    msg.extend_from_slice(&(space.len() as u16).to_le_bytes());
+    msg.extend_from_slice(name.as_bytes());
+    msg.push(0);
+    pad8(&mut msg);
+    msg.extend(dtype);
+    pad8(&mut msg);
+    msg.extend(space);
+    pad8(&mut msg);
+    msg.extend_from_slice(value.as_bytes());
+    msg.resize(msg.len() + size - value.len(), 0);
+    msg
+}
+
+// the key of a data chunk: its size on disk, the filters that were skipped
+// (none) and where it starts, plus a 0 for the element size dimension
+fn chunk_key(nbytes: u32, row: u64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(24);
+    key.extend_from_slice(&nbytes.to_le_bytes());
+    key.extend_from_slice(&0u32.to_le_bytes());
+    key.extend_from_slice(&row.to_le_bytes());
+    key.extend_from_slice(&0u64.to_le_bytes());
+    key
+}
+
+pub struct H5File {
+    file: BufWriter<File>,
+    position: u64,
+    groups: Vec<Group>,
+    datasets: Vec<Dataset>,
+}
+
+impl H5File {
+    pub fn create(filename: &str) -> std::io::Result<Self> {
+        let mut h5 = H5File {
+            file: BufWriter::new(File::create(filename)?),
+            position: 0,
+            groups: Vec::new(),
+            datasets: Vec::new(),
+        };
+        // filled in by close
+        h5.write_record(&[0; SUPERBLOCK_LEN])?;
+        h5.group("/");
+        Ok(h5)
+    }
+
+    fn write_record(&mut self, record: &[u8]) -> std::io::Result<u64> {
+        let address = self.position;
+        self.file.write_all(record)?;
+        self.position += record.len() as u64;
+        Ok(address)
+    }
+
+    // index of the group, which is made along with its parents if it isn't there yet
+    fn group(&mut self, path: &str) -> usize {
+        if let Some(i) = self.groups.iter().position(|g| g.path == path) {
+            return i;
+        }
+        if path != "/" {
+            self.group(split_path(path).0);
+        }
+        self.groups.push(Group {
+            path: path.to_string(),
+            attributes: Vec::new(),
+            btree: UNDEFINED,
+            heap: UNDEFINED,
+            header: UNDEFINED,
+        });
+        self.groups.len() - 1
+    }
+
+    // "/" is the root group
+    pub fn set_attribute(&mut self, path: &str, name: &str, value: &str) {
+        let i = self.group(path);
+        self.groups[i]
+            .attributes
+            .push((name.to_string(), value.to_string()));
+    }
+
+    // returns the id used by append
+    pub fn create_dataset(&mut self, path: &str, dtype: H5Type) -> usize {
+        self.group(split_path(path).0);
+        self.datasets.push(Dataset {
+            path: path.to_string(),
+            dtype,
+            rows: 0,
+            buffer: Vec::with_capacity(CHUNK_ROWS * dtype.size()),
+            chunks: Vec::new(),
+        });
+        self.datasets.len() - 1
+    }
+
+    pub fn append<T: H5Value>(
+        &mut self,
+        id: usize,
+        values: impl IntoIterator<Item = T>,
+    ) -> std::io::Result<()> {
+        debug_assert_eq!(self.datasets[id].dtype, T::TYPE);
+        let chunk_len = CHUNK_ROWS * T::TYPE.size();
+        for value in values {
+            let dataset = &mut self.datasets[id];
+            value.push(&mut dataset.buffer);
+            dataset.rows += 1;
+            if dataset.buffer.len() == chunk_len {
+                self.write_chunk(id)?;
+            }
+        }
+        Ok(())
+    }
+
+    // shuffle the bytes of the values so all of the first bytes are next to
+    // each other, then all of the second bytes etc., and deflate
+    fn write_chunk(&mut self, id: usize) -> std::io::Result<()> {
+        let dataset = &mut self.datasets[id];
+        let size = dataset.dtype.size();
+        // the last chunk still has to be full size
+        dataset.buffer.resize(CHUNK_ROWS * size, 0);
+        let mut shuffled = vec![0; dataset.buffer.len()];
+        for (i, value) in dataset.buffer.chunks(size).enumerate() {
+            for (j, &byte) in value.iter().enumerate() {
+                shuffled[j * CHUNK_ROWS + i] = byte;
+            }
+        }
+        dataset.buffer.clear();
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(DEFLATE_LEVEL));
+        encoder.write_all(&shuffled)?;
+        let compressed = encoder.finish()?;
+        let address = self.write_record(&compressed)?;
+        self.datasets[id]
+            .chunks
+            .push((address, compressed.len() as u32));
+        Ok(())
+    }
+
+    // Version 1 B-tree, built one level at a time from the bottom. entries
+    // are the left key and address of every child, right_key closes off the
+    // last one. Returns the address of the root node.
+    fn write_btree(
+        &mut self,
+        node_type: u8,
+        k: usize,
+        mut entries: Vec<(Vec<u8>, u64)>,
+        right_key: Vec<u8>,
+    ) -> std::io::Result<u64> {
+        let node_len = 24 + 2 * k * 8 + (2 * k + 1) * right_key.len();
+        let mut level = 0;
+        loop {
+            let first = self.position;
+            let mut nodes: Vec<&[(Vec<u8>, u64)]> = entries.chunks(2 * k).collect();
+            // a tree with nothing in it is still one (empty) node
+            if nodes.is_empty() {
+                nodes.push(&[]);
+            }
+            let mut parents = Vec::new();
+            for (i, node) in nodes.iter().enumerate() {
+                let address = first + (i * node_len) as u64;
+                let left = if i == 0 {
+                    UNDEFINED
+                } else {
+                    address - node_len as u64
+                };
+                let right = if i + 1 == nodes.len() {
+                    UNDEFINED
+                } else {
+                    address + node_len as u64
+                };
+                let mut buf = Vec::with_capacity(node_len);
+                buf.extend_from_slice(b"TREE");
+                buf.push(node_type);
+                buf.push(level);
+                buf.extend_from_slice(&(node.len() as u16).to_le_bytes());
+                buf.extend_from_slice(&left.to_le_bytes());
+                buf.extend_from_slice(&right.to_le_bytes());
+                for (key, child) in node.iter() {
+                    buf.extend_from_slice(key);
+                    buf.extend_from_slice(&child.to_le_bytes());
+                }
+                // a node ends where the next one starts
+                match nodes.get(i + 1) {
+                    Some(next) => buf.extend_from_slice(&next[0].0),
+                    None => buf.extend_from_slice(&right_key),
+                }
+                buf.resize(node_len, 0);
+                self.write_record(&buf)?;
+                let left_key = node.first().map_or(&right_key, |(key, _)| key);
+                parents.push((left_key.clone(), address));
+            }
+            if parents.len() == 1 {
+                return Ok(parents[0].1);
+            }
+            entries = parents;
+            level += 1;
+        }
+    }
+
+    // the chunk index and object header, returns the object header address
+    fn write_dataset(&mut self, id: usize) -> std::io::Result<u64> {
+        if !self.datasets[id].buffer.is_empty() {
+            self.write_chunk(id)?;
+        }
+        let dataset = &self.datasets[id];
+        let (dtype, rows) = (dataset.dtype, dataset.rows);
+        let entries: Vec<(Vec<u8>, u64)> = dataset
+            .chunks
+            .iter()
+            .enumerate()
+            .map(|(i, &(address, nbytes))| (chunk_key(nbytes, (i * CHUNK_ROWS) as u64), address))
+            .collect();
+        let btree = if entries.is_empty() {
+            UNDEFINED
+        } else {
+            let end = chunk_key(0, (entries.len() * CHUNK_ROWS) as u64);
+            self.write_btree(1, CHUNK_K, entries, end)?
+        };
+
+        // version 2 fill value: allocated as the chunks are written, no fill value
+        let fill_value = vec![2, 3, 2, 0];
+
+        // version 3 chunked layout, the chunk dims end with the element size
+        let mut layout = vec![3, 2, 2];
+        layout.extend_from_slice(&btree.to_le_bytes());
+        layout.extend_from_slice(&(CHUNK_ROWS as u32).to_le_bytes());
+        layout.extend_from_slice(&(dtype.size() as u32).to_le_bytes());
+
+        // version 1 filter pipeline, every filter has one parameter padded to 8 bytes
+        let mut filters = vec![1, 2, 0, 0, 0, 0, 0, 0];
+        for (filter, parameter) in [
+            (FILTER_SHUFFLE, dtype.size() as u32),
+            (FILTER_DEFLATE, DEFLATE_LEVEL),
+        ] {
+            filters.extend_from_slice(&filter.to_le_bytes());
+            filters.extend_from_slice(&0u16.to_le_bytes()); // no name
+            filters.extend_from_slice(&0u16.to_le_bytes()); // not optional
+            filters.extend_from_slice(&1u16.to_le_bytes());
+            filters.extend_from_slice(&parameter.to_le_bytes());
+            filters.extend_from_slice(&[0; 4]);
+        }
+
+        let header = object_header(&[
+            (DATASPACE, 0, dataspace(&[rows])),
+            (DATATYPE, CONSTANT, dtype.message()),
+            (FILL_VALUE, CONSTANT, fill_value),
+            (LAYOUT, 0, layout),
+            (FILTER_PIPELINE, 0, filters),
+        ]);
+        self.write_record(&header)
+    }
+
+    // Local heap with the names, symbol table nodes and B-tree, and the
+    // object header. children are the names and object headers of the
+    // datasets and groups in it, sorted by name.
+    fn write_group(&mut self, id: usize, children: &[Link]) -> std::io::Result<()> {
+        // the heap starts with an empty name, for the first B-tree key
+        let mut names = vec![0; 8];
+        let mut offsets = Vec::new();
+        for link in children.iter() {
+            offsets.push(names.len() as u64);
+            names.extend_from_slice(link.name.as_bytes());
+            names.push(0);
+            pad8(&mut names);
+        }
+        let heap = self.position;
+        let mut buf = b"HEAP".to_vec();
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(&(names.len() as u64).to_le_bytes());
+        buf.extend_from_slice(&HEAP_FREE_NULL.to_le_bytes());
+        buf.extend_from_slice(&(heap + 32).to_le_bytes());
+        buf.extend(names);
+        self.write_record(&buf)?;
+
+        // symbol table nodes, each one is keyed by the last name in it
+        let node_len = 8 + 2 * GROUP_LEAF_K * 40;
+        let mut entries = Vec::new();
+        let mut left_key = 0u64;
+        let symbols: Vec<_> = children.iter().zip(offsets.iter()).collect();
+        for node in symbols.chunks(2 * GROUP_LEAF_K) {
+            let mut buf = b"SNOD".to_vec();
+            buf.extend_from_slice(&[1, 0]);
+            buf.extend_from_slice(&(node.len() as u16).to_le_bytes());
+            for (link, offset) in node.iter() {
+                buf.extend_from_slice(&offset.to_le_bytes());
+                buf.extend_from_slice(&link.header.to_le_bytes());
+                match link.stab {
+                    Some((btree, heap)) => {
+                        buf.extend_from_slice(&CACHE_STAB.to_le_bytes());
+                        buf.extend_from_slice(&[0; 4]);
+                        buf.extend_from_slice(&btree.to_le_bytes());
+                        buf.extend_from_slice(&heap.to_le_bytes());
+                    }
+                    None => buf.extend_from_slice(&[0; 24]),
+                }
+            }
+            buf.resize(node_len, 0);
+            let address = self.write_record(&buf)?;
+            entries.push((left_key.to_le_bytes().to_vec(), address));
+            left_key = *node.last().unwrap().1;
+        }
+        let btree = self.write_btree(
+            0,
+            GROUP_INTERNAL_K,
+            entries,
+            left_key.to_le_bytes().to_vec(),
+        )?;
+
+        let mut symbol_table = btree.to_le_bytes().to_vec();
+        symbol_table.extend_from_slice(&heap.to_le_bytes());
+        let mut messages = vec![(SYMBOL_TABLE, 0, symbol_table)];
+        for (name, value) in self.groups[id].attributes.iter() {
+            messages.push((ATTRIBUTE, 0, string_attribute(name, value)));
+        }
+        let header = self.write_record(&object_header(&messages))?;
+        let group = &mut self.groups[id];
+        group.btree = btree;
+        group.heap = heap;
+        group.header = header;
+        Ok(())
+    }
+
+    pub fn close(mut self) -> std::io::Result<()> {
+        let mut headers = Vec::new();
+        for id in 0..self.datasets.len() {
+            headers.push(self.write_dataset(id)?);
+        }
+
+        // parents are always made before their children, so going backwards
+        // every group is written after the groups in it
+        for id in (0..self.groups.len()).rev() {
+            let path = self.groups[id].path.clone();
+            let mut children = Vec::new();
+            for (dataset, &header) in self.datasets.iter().zip(headers.iter()) {
+                let (parent, name) = split_path(&dataset.path);
+                if parent == path {
+                    children.push(Link {
+                        name: name.to_string(),
+                        header,
+                        stab: None,
+                    });
+                }
+            }
+            for group in self.groups.iter().filter(|g| g.path != "/") {
+                let (parent, name) = split_path(&group.path);
+                if parent == path {
+                    children.push(Link {
+                        name: name.to_string(),
+                        header: group.header,
+                        stab: Some((group.btree, group.heap)),
+                    });
+                }
+            }
+            children.sort_by(|a, b| a.name.cmp(&b.name));
+            self.write_group(id, &children)?;
+        }
+
+        let root = &self.groups[0];
+        let mut superblock = SIGNATURE.to_vec();
+        // superblock, free space, root entry and shared header versions, 8 byte addresses and lengths
+        superblock.extend_from_slice(&[0, 0, 0, 0, 0, 8, 8, 0]);
+        superblock.extend_from_slice(&(GROUP_LEAF_K as u16).to_le_bytes());
+        superblock.extend_from_slice(&(GROUP_INTERNAL_K as u16).to_le_bytes());
+        superblock.extend_from_slice(&0u32.to_le_bytes()); // consistency flags
+        superblock.extend_from_slice(&0u64.to_le_bytes()); // base address
+        superblock.extend_from_slice(&UNDEFINED.to_le_bytes()); // free space info
+        superblock.extend_from_slice(&self.position.to_le_bytes()); // end of file
+        superblock.extend_from_slice(&UNDEFINED.to_le_bytes()); // driver info
+                                                                // root group symbol table entry
+        superblock.extend_from_slice(&0u64.to_le_bytes());
+        superblock.extend_from_slice(&root.header.to_le_bytes());
+        superblock.extend_from_slice(&CACHE_STAB.to_le_bytes());
+        superblock.extend_from_slice(&[0; 4]);
+        superblock.extend_from_slice(&root.btree.to_le_bytes());
+        superblock.extend_from_slice(&root.heap.to_le_bytes());
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&superblock)?;
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+    use std::io::Read;
+
+    /*
+    A reader for the files we write, to check them without libhdf5. It follows
+    the B-trees and symbol tables from the superblock down, like libhdf5 does.
+    */
+
+    pub struct H5Contents {
+        // path, type and the values as they are in memory (little endian)
+        pub datasets: Vec<(String, H5Type, Vec<u8>)>,
+        // group, name and value
+        pub attributes: Vec<(String, String, String)>,
+    }
+
+    impl H5Contents {
+        // the values of a dataset, formatted like the csv
+        pub fn values(&self, path: &str) -> Vec<String> {
+            let (_, dtype, data) = match self.datasets.iter().find(|(p, _, _)| p == path) {
+                Some(dataset) => dataset,
+                None => panic!("no dataset {}", path),
+            };
+            data.chunks(dtype.size())
+                .map(|v| match dtype {
+                    H5Type::U8 => v[0].to_string(),
+                    H5Type::U32 => u32::from_le_bytes(v.try_into().unwrap()).to_string(),
+                    H5Type::U64 => u64::from_le_bytes(v.try_into().unwrap()).to_string(),
+                    H5Type::I64 => i64::from_le_bytes(v.try_into().unwrap()).to_string(),
+                    H5Type::F64 => f64::from_le_bytes(v.try_into().unwrap()).to_string(),
+                })
+                .collect()
+        }
+
+        pub fn attribute(&self, group: &str, name: &str) -> &str {
+            match self
+                .attributes
+                .iter()
+                .find(|(g, n, _)| g == group && n == name)
+            {
+                Some((_, _, value)) => value,
+                None => panic!("no attribute {} in {}", name, group),
+            }
+        }
+    }
+
+    fn u16_at(data: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(data[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    fn u64_at(data: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+    }
+
+    fn name_at(data: &[u8], at: usize) -> String {
+        let end = at + data[at..].iter().position(|&b| b == 0).unwrap();
+        String::from_utf8(data[at..end].to_vec()).unwrap()
+    }
+
+    // the messages of a version 1 object header
+    fn messages(data: &[u8], address: u64) -> Vec<(u16, &[u8])> {
+        let at = address as usize;
+        assert_eq!(data[at], 1, "object header version");
+        let count = u16_at(data, at + 2) as usize;
+        let len = u32_at(data, at + 8) as usize;
+        let mut messages = Vec::new();
+        let mut p = at + 16;
+        while p < at + 16 + len {
+            let size = u16_at(data, p + 2) as usize;
+            assert_eq!(size % 8, 0);
+            messages.push((u16_at(data, p), &data[p + 8..p + 8 + size]));
+            p += 8 + size;
+        }
+        assert_eq!(p, at + 16 + len);
+        assert_eq!(messages.len(), count);
+        messages
+    }
+
+    // the children of a version 1 B-tree, checking the keys on the way down.
+    // key_len is 8 for groups and 24 for the chunks of our one dimensional datasets.
+    fn btree(data: &[u8], address: u64, node_type: u8, k: usize) -> Vec<(Vec<u8>, u64, Vec<u8>)> {
+        let key_len = if node_type == 0 { 8 } else { 24 };
+        let at = address as usize;
+        assert_eq!(&data[at..at + 4], b"TREE");
+        assert_eq!(data[at + 4], node_type);
+        let level = data[at + 5];
+        let n = u16_at(data, at + 6) as usize;
+        assert!(n <= 2 * k, "node with {} entries", n);
+        let mut children = Vec::new();
+        for i in 0..n {
+            let p = at + 24 + i * (key_len + 8);
+            let left = data[p..p + key_len].to_vec();
+            let child = u64_at(data, p + key_len);
+            let right = data[p + key_len + 8..p + 2 * key_len + 8].to_vec();
+            if level == 0 {
+                children.push((left, child, right));
+            } else {
+                let below = btree(data, child, node_type, k);
+                assert_eq!(below.first().unwrap().0, left);
+                assert_eq!(below.last().unwrap().2, right);
+                children.extend(below);
+            }
+        }
+        children
+    }
+
+    // the datasets and groups in a group, and its attributes
+    fn read_group(
+        data: &[u8],
+        path: &str,
+        header: u64,
+        stab: (u64, u64),
+        contents: &mut H5Contents,
+    ) {
+        let mut symbol_table = None;
+        for (msg_type, msg) in messages(data, header) {
+            match msg_type {
+                SYMBOL_TABLE => symbol_table = Some((u64_at(msg, 0), u64_at(msg, 8))),
+                ATTRIBUTE => {
+                    assert_eq!(msg[0], 1);
+                    let name_len = u16_at(msg, 2) as usize;
+                    let dtype_len = u16_at(msg, 4) as usize;
+                    let space_len = u16_at(msg, 6) as usize;
+                    let name = name_at(msg, 8);
+                    assert_eq!(name.len() + 1, name_len);
+                    let dtype = 8 + name_len.next_multiple_of(8);
+                    // string class, version 1
+                    assert_eq!(msg[dtype], 0x13);
+                    let size = u32_at(msg, dtype + 4) as usize;
+                    let value =
+                        dtype + dtype_len.next_multiple_of(8) + space_len.next_multiple_of(8);
+                    let value = &msg[value..value + size];
+                    let end = value.iter().position(|&b| b == 0).unwrap_or(size);
+                    let value = String::from_utf8(value[..end].to_vec()).unwrap();
+                    contents.attributes.push((path.to_string(), name, value));
+                }
+                _ => panic!("message {} in group {}", msg_type, path),
+            }
+        }
+        // the cache in the parent's entry has to agree with the group
+        assert_eq!(symbol_table, Some(stab), "symbol table of {}", path);
+        let (tree, heap) = stab;
+        let heap = heap as usize;
+        assert_eq!(&data[heap..heap + 4], b"HEAP");
+        let names = u64_at(data, heap + 24) as usize;
+
+        let mut previous = String::new();
+        for (left, node, right) in btree(data, tree, 0, GROUP_INTERNAL_K) {
+            let node = node as usize;
+            assert_eq!(&data[node..node + 4], b"SNOD");
+            let n = u16_at(data, node + 6) as usize;
+            assert!(n <= 2 * GROUP_LEAF_K);
+            assert_eq!(name_at(data, names + u64_at(&left, 0) as usize), previous);
+            for i in 0..n {
+                let entry = node + 8 + 40 * i;
+                let name = name_at(data, names + u64_at(data, entry) as usize);
+                assert!(name > previous, "{} is not after {}", name, previous);
+                let child = if path == "/" {
+                    format!("/{}", name)
+                } else {
+                    format!("{}/{}", path, name)
+                };
+                let header = u64_at(data, entry + 8);
+                match u32_at(data, entry + 16) {
+                    CACHE_STAB => {
+                        let stab = (u64_at(data, entry + 24), u64_at(data, entry + 32));
+                        read_group(data, &child, header, stab, contents);
+                    }
+                    _ => read_dataset(data, &child, header, contents),
+                }
+                previous = name;
+            }
+            assert_eq!(name_at(data, names + u64_at(&right, 0) as usize), previous);
+        }
+    }
+
+    fn read_dataset(data: &[u8], path: &str, header: u64, contents: &mut H5Contents) {
+        let (mut rows, mut dtype, mut tree, mut filters) = (None, None, None, Vec::new());
+        for (msg_type, msg) in messages(data, header) {
+            match msg_type {
+                DATASPACE => {
+                    assert_eq!(msg[1], 1, "rank of {}", path);
+                    assert_eq!(u64_at(msg, 16), UNDEFINED, "{} is not extendable", path);
+                    rows = Some(u64_at(msg, 8) as usize);
+                }
+                DATATYPE => {
+                    let size = u32_at(msg, 4);
+                    dtype = Some(match (msg[0], msg[1], size) {
+                        (0x10, 0, 1) => H5Type::U8,
+                        (0x10, 0, 4) => H5Type::U32,
+                        (0x10, 0, 8) => H5Type::U64,
+                        (0x10, 0x08, 8) => H5Type::I64,
+                        (0x11, 0x20, 8) => H5Type::F64,
+                        t => panic!("datatype {:?} of {}", t, path),
+                    });
+                }
+                FILL_VALUE => (),
+                LAYOUT => {
+                    assert_eq!(&msg[..3], [3, 2, 2]);
+                    assert_eq!(u32_at(msg, 11) as usize, CHUNK_ROWS);
+                    tree = Some(u64_at(msg, 3));
+                }
+                FILTER_PIPELINE => {
+                    for i in 0..msg[1] as usize {
+                        let filter = 8 + 16 * i;
+                        filters.push((u16_at(msg, filter), u32_at(msg, filter + 8)));
+                    }
+                }
+                _ => panic!("message {} in dataset {}", msg_type, path),
+            }
+        }
+        let (rows, dtype, tree) = (rows.unwrap(), dtype.unwrap(), tree.unwrap());
+        assert_eq!(
+            filters,
+            [
+                (FILTER_SHUFFLE, dtype.size() as u32),
+                (FILTER_DEFLATE, DEFLATE_LEVEL)
+            ]
+        );
+
+        let size = dtype.size();
+        let mut values = Vec::new();
+        if tree != UNDEFINED {
+            for (i, (key, chunk, _)) in btree(data, tree, 1, CHUNK_K).into_iter().enumerate() {
+                let nbytes = u32_at(&key, 0) as usize;
+                assert_eq!(u64_at(&key, 8) as usize, i * CHUNK_ROWS);
+                let mut shuffled = Vec::new();
+                ZlibDecoder::new(&data[chunk as usize..chunk as usize + nbytes])
+                    .read_to_end(&mut shuffled)
+                    .unwrap();
+                assert_eq!(shuffled.len(), CHUNK_ROWS * size);
+                for row in 0..CHUNK_ROWS {
+                    for byte in 0..size {
+                        values.push(shuffled[byte * CHUNK_ROWS + row]);
+                    }
+                }
+            }
+        }
+        // the last chunk is padded out to full size
+        assert_eq!(
+            values.len(),
+            (rows * size).next_multiple_of(CHUNK_ROWS * size)
+        );
+        values.truncate(rows * size);
+        contents.datasets.push((path.to_string(), dtype, values));
+    }
+
+    pub fn read_h5(filename: &str) -> H5Contents {
+        let data = std::fs::read(filename).unwrap();
+        assert_eq!(data[..8], SIGNATURE);
+        assert_eq!(data[8..16], [0, 0, 0, 0, 0, 8, 8, 0]);
+        assert_eq!(u16_at(&data, 16) as usize, GROUP_LEAF_K);
+        assert_eq!(u16_at(&data, 18) as usize, GROUP_INTERNAL_K);
+        assert_eq!(
+            u64_at(&data, 40) as usize,
+            data.len(),
+            "end of file address"
+        );
+        let root = 56;
+        assert_eq!(u32_at(&data, root + 16), CACHE_STAB);
+        let mut contents = H5Contents {
+            datasets: Vec::new(),
+            attributes: Vec::new(),
+        };
+        let stab = (u64_at(&data, root + 24), u64_at(&data, root + 32));
+        read_group(&data, "/", u64_at(&data, root + 8), stab, &mut contents);
+        contents
+    }
+
+    fn test_file(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!(
+                "midas_converter_{}_{}.h5",
+                name,
+                std::process::id()
+            ))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    // the datatype messages of '<f8' and '<i8' in files written by h5py
+    #[test]
+    fn datatype_bytes() {
+        assert_eq!(
+            H5Type::F64.message(),
+            [
+                0x11, 0x20, 0x3f, 0, 8, 0, 0, 0, 0, 0, 0x40, 0, 0x34, 0x0b, 0, 0x34, 0xff, 0x03, 0,
+                0
+            ]
+        );
+        assert_eq!(
+            H5Type::I64.message(),
+            [0x10, 0x08, 0, 0, 8, 0, 0, 0, 0, 0, 0x40, 0]
+        );
+    }
+
+    #[test]
+    fn empty_file() {
+        let filename = test_file("empty");
+        H5File::create(&filename).unwrap().close().unwrap();
+        let contents = read_h5(&filename);
+        assert!(contents.datasets.is_empty() && contents.attributes.is_empty());
+        std::fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn datasets_round_trip() {
+        let filename = test_file("datasets");
+        let mut h5 = H5File::create(&filename).unwrap();
+        h5.set_attribute("/", "run_number", "123");
+        h5.set_attribute("/", "empty", "");
+        h5.set_attribute("/a/b", "mod_type", "scp");
+
+        // enough chunks for a second level in the chunk B-tree, the chunk number
+        // in every value so they can't be mixed up
+        let bytes = h5.create_dataset("/a/b/bytes", H5Type::U8);
+        let nbytes = (2 * CHUNK_K + 1) * CHUNK_ROWS + 3;
+        h5.append(bytes, (0..nbytes).map(|i| (i / CHUNK_ROWS) as u8))
+            .unwrap();
+        let words = h5.create_dataset("/a/words", H5Type::U32);
+        let longs = h5.create_dataset("/a/longs", H5Type::U64);
+        let signed = h5.create_dataset("/a/signed", H5Type::I64);
+        let doubles = h5.create_dataset("/a/doubles", H5Type::F64);
+        // appended a bit at a time, like the sorters do
+        for start in (0..CHUNK_ROWS + 10).step_by(1000) {
+            let rows = start..(start + 1000).min(CHUNK_ROWS + 10);
+            h5.append(words, rows.clone().map(|i| (i as u32).wrapping_mul(65537)))
+                .unwrap();
+            h5.append(longs, rows.clone().map(|i| (i as u64) << 40))
+                .unwrap();
+            h5.append(signed, rows.clone().map(|i| i as i64 - 500))
+                .unwrap();
+            h5.append(doubles, rows.map(|i| i as f64 * 0.5)).unwrap();
+        }
+        h5.create_dataset("/a/nothing", H5Type::U32);
+        // enough names for a second level in the group B-tree
+        let nnames = 2 * GROUP_LEAF_K * 2 * GROUP_INTERNAL_K + 1;
+        for i in 0..nnames {
+            let id = h5.create_dataset(&format!("/many/x{:04}", i), H5Type::U32);
+            h5.append(id, [i as u32]).unwrap();
+        }
+        h5.close().unwrap();
+
+        let contents = read_h5(&filename);
+        assert_eq!(contents.attribute("/", "run_number"), "123");
+        assert_eq!(contents.attribute("/", "empty"), "");
+        assert_eq!(contents.attribute("/a/b", "mod_type"), "scp");
+
+        let (_, dtype, values) = contents
+            .datasets
+            .iter()
+            .find(|(p, _, _)| p == "/a/b/bytes")
+            .unwrap();
+        assert_eq!(*dtype, H5Type::U8);
+        assert_eq!(values.len(), nbytes);
+        assert!(values
+            .iter()
+            .enumerate()
+            .all(|(i, &v)| v as usize == i / CHUNK_ROWS));
+        let rows = CHUNK_ROWS + 10;
+        let expected = |f: &dyn Fn(usize) -> String| (0..rows).map(f).collect::<Vec<_>>();
+        assert_eq!(
+            contents.values("/a/words"),
+            expected(&|i| ((i as u32).wrapping_mul(65537)).to_string())
+        );
+        assert_eq!(
+            contents.values("/a/longs"),
+            expected(&|i| ((i as u64) << 40).to_string())
+        );
+        assert_eq!(
+            contents.values("/a/signed"),
+            expected(&|i| (i as i64 - 500).to_string())
+        );
+        assert_eq!(
+            contents.values("/a/doubles"),
+            expected(&|i| (i as f64 * 0.5).to_string())
+        );
+        assert!(contents.values("/a/nothing").is_empty());
+        for i in 0..nnames {
+            assert_eq!(
+                contents.values(&format!("/many/x{:04}", i)),
+                [i.to_string()]
+            );
+        }
+        std::fs::remove_file(&filename).unwrap();
+    }
+}
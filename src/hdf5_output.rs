@@ -0,0 +1,202 @@
+use crate::error::ConverterError;
+use crate::hdf5_file::{H5File, H5Type};
+use crate::mdpp_bank::MDPPEvent;
+use crate::module_config::Module;
+use crate::sis3820::ScalerBank;
+use crate::v1730_bank::v1730Hit;
+use crate::v785_bank::v785Hit;
+use std::collections::HashMap;
+
+/*
+The HDF5 output. It gets filled by the sorters every chunk_size events, same
+as the csv, and the values go to disk a chunk at a time (see hdf5_file.rs).
+
+Every module gets a group under the family of its module type, /mdpp/<name>,
+/v785/<name> or /v1730/<name>, with one dataset per decoded field. The scaler
+channels are in /scalers and the run metadata is in attributes of the root
+group.
+*/
+
+const MDPP_FIELDS: [(&str, H5Type); 9] = [
+    ("module", H5Type::U8),
+    ("channel", H5Type::U8),
+    ("adc", H5Type::U32),
+    ("long", H5Type::U32),
+    ("short", H5Type::U32),
+    ("tdc", H5Type::U32),
+    ("trigger_dt", H5Type::I64),
+    ("pileup", H5Type::U8),
+    ("evt_ts", H5Type::U64),
+];
+const V785_FIELDS: [(&str, H5Type); 3] = [
+    ("channel", H5Type::U8),
+    ("adc", H5Type::U32),
+    ("evt_ts", H5Type::U32),
+];
+const V1730_FIELDS: [(&str, H5Type); 4] = [
+    ("channel", H5Type::U8),
+    ("long", H5Type::U32),
+    ("coarse_time", H5Type::U64),
+    ("time", H5Type::F64),
+];
+
+pub struct H5Output {
+    filename: String,
+    file: H5File,
+    flag_events: bool,
+    subruns: bool,
+    // dataset ids of every module, in the order of the fields above with
+    // flags and subrun at the end
+    modules: HashMap<String, Vec<usize>>,
+    scalers: Vec<usize>,
+}
+
+impl H5Output {
+    pub fn create(
+        filename: &str,
+        modules: &[Module],
+        flag_events: bool,
+        subruns: bool,
+        metadata: &[(String, String)],
+    ) -> Result<Self, ConverterError> {
+        let mut file =
+            H5File::create(filename).map_err(|e| ConverterError::Io(filename.to_string(), e))?;
+        for (key, value) in metadata.iter() {
+            file.set_attribute("/", key, value);
+        }
+
+        let mut module_datasets = HashMap::new();
+        for m in modules.iter() {
+            let (family, fields): (&str, &[(&str, H5Type)]) = match m.mod_type.as_str() {
+                "scp" | "qdc" => ("mdpp", &MDPP_FIELDS),
+                "adc" => ("v785", &V785_FIELDS),
+                "v1730" => ("v1730", &V1730_FIELDS),
+                _ => continue,
+            };
+            let group = format!("/{}/{}", family, m.name);
+            file.set_attribute(&group, "mod_type", &m.mod_type);
+            file.set_attribute(&group, "nchannels", &m.nchannels.to_string());
+            let mut ids: Vec<usize> = fields
+                .iter()
+                .map(|(field, dtype)| file.create_dataset(&format!("{}/{}", group, field), *dtype))
+                .collect();
+            if flag_events {
+                ids.push(file.create_dataset(&format!("{}/flags", group), H5Type::U8));
+            }
+            if subruns {
+                ids.push(file.create_dataset(&format!("{}/subrun", group), H5Type::U32));
+            }
+            module_datasets.insert(m.name.clone(), ids);
+        }
+        let scalers = (0..32)
+            .map(|i| file.create_dataset(&format!("/scalers/chan_{}", i), H5Type::U32))
+            .collect();
+
+        Ok(H5Output {
+            filename: filename.to_string(),
+            file,
+            flag_events,
+            subruns,
+            modules: module_datasets,
+            scalers,
+        })
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    pub fn write_mdpp(&mut self, module: &str, events: &[MDPPEvent]) -> std::io::Result<()> {
+        let ids = match self.modules.get(module) {
+            Some(ids) => ids,
+            None => return Ok(()),
+        };
+        let hits = || {
+            events.iter().flat_map(|event| {
+                event
+                    .channels
+                    .iter()
+                    .zip(event.channel_hits.iter())
+                    .map(move |(&channel, hit)| (event, channel, hit))
+            })
+        };
+        let file = &mut self.file;
+        file.append(ids[0], hits().map(|(e, _, _)| e.module_id as u8))?;
+        file.append(ids[1], hits().map(|(_, c, _)| c as u8))?;
+        file.append(ids[2], hits().map(|(_, _, h)| h.adc_value))?;
+        file.append(ids[3], hits().map(|(_, _, h)| h.long_value))?;
+        file.append(ids[4], hits().map(|(_, _, h)| h.short_value))?;
+        file.append(ids[5], hits().map(|(_, _, h)| h.tdc_value))?;
+        file.append(ids[6], hits().map(|(_, _, h)| h.trigger_dt_value))?;
+        file.append(ids[7], hits().map(|(_, _, h)| h.pile_up as u8))?;
+        file.append(ids[8], hits().map(|(e, _, _)| e.evt_timestamp))?;
+        if self.flag_events {
+            file.append(ids[9], hits().map(|(e, _, _)| e.flags))?;
+        }
+        if self.subruns {
+            file.append(ids[ids.len() - 1], hits().map(|(e, _, _)| e.subrun))?;
+        }
+        Ok(())
+    }
+
+    // one row per channel of every hit, like the csv
+    pub fn write_v785(&mut self, module: &str, hits: &[v785Hit]) -> std::io::Result<()> {
+        let ids = match self.modules.get(module) {
+            Some(ids) => ids,
+            None => return Ok(()),
+        };
+        let rows = || {
+            hits.iter().flat_map(|hit| {
+                hit.values
+                    .iter()
+                    .enumerate()
+                    .map(move |(c, &v)| (hit, c, v))
+            })
+        };
+        let file = &mut self.file;
+        file.append(ids[0], rows().map(|(_, c, _)| c as u8))?;
+        file.append(ids[1], rows().map(|(_, _, v)| v))?;
+        file.append(ids[2], rows().map(|(h, _, _)| h.evt))?;
+        if self.flag_events {
+            file.append(ids[3], rows().map(|(h, _, _)| h.flags))?;
+        }
+        if self.subruns {
+            file.append(ids[ids.len() - 1], rows().map(|(h, _, _)| h.subrun))?;
+        }
+        Ok(())
+    }
+
+    pub fn write_v1730(&mut self, module: &str, hits: &[v1730Hit]) -> std::io::Result<()> {
+        let ids = match self.modules.get(module) {
+            Some(ids) => ids,
+            None => return Ok(()),
+        };
+        let file = &mut self.file;
+        file.append(ids[0], hits.iter().map(|h| h.channel as u8))?;
+        file.append(ids[1], hits.iter().map(|h| h.long))?;
+        file.append(ids[2], hits.iter().map(|h| h.coarse_time))?;
+        file.append(ids[3], hits.iter().map(|h| h.time))?;
+        if self.flag_events {
+            file.append(ids[4], hits.iter().map(|h| h.flags))?;
+        }
+        if self.subruns {
+            file.append(ids[ids.len() - 1], hits.iter().map(|h| h.subrun))?;
+        }
+        Ok(())
+    }
+
+    // the sorters hang on to the scalers until the end of the run
+    pub fn write_scalers(&mut self, banks: &[ScalerBank]) -> std::io::Result<()> {
+        for (i, &id) in self.scalers.iter().enumerate() {
+            self.file.append(id, banks.iter().map(|b| b.data[i]))?;
+        }
+        Ok(())
+    }
+
+    pub fn close(self) -> Result<(), ConverterError> {
+        let filename = self.filename;
+        self.file
+            .close()
+            .map_err(|e| ConverterError::Io(filename, e))
+    }
+}
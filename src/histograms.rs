@@ -0,0 +1,809 @@
+use crate::error::ConverterError;
+use crate::mdpp_bank::MDPPEvent;
+use crate::module_config::Module;
+use crate::radware;
+use crate::root_file::{self, RootAxis, RootFile};
+use crate::v1730_bank::v1730Hit;
+use crate::v785_bank::v785Hit;
+use clap::ValueEnum;
+use polars::prelude::*;
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+
+/*
+Quick look spectra.
+
+The sorters hand every chunk of decoded hits to Histograms right before they are
+written out, so the spectra are filled on the same pass as the hit table. Each
+[[histograms]] entry in the config makes one histogram per channel of a module,
+1D if only x is given and 2D (x vs y) if y is given too:
+
+[[histograms]]
+module = "SCP1"
+x = "long"
+bins = 4096
+range = [0, 65536]
+y = "short"          # optional
+y_bins = 1024        # defaults to bins
+y_range = [0, 65536] # defaults to range
+channels = [0, 1]    # defaults to all of them
+
+Underflow and overflow are dropped.
+*/
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HistogramFormat {
+    /// one .npy file per histogram
+    Npy,
+    /// all histograms in a single .npz archive
+    Npz,
+    /// one plain text file per histogram
+    Txt,
+    /// a parquet table with the non-empty bins
+    Parquet,
+    /// RadWare .spe spectra for the 1D histograms
+    Spe,
+    /// RadWare 16 bit .mat matrices for the 2D histograms
+    Mat,
+    /// RadWare 32 bit .m4b matrices for the 2D histograms
+    M4b,
+    /// a ROOT file with TH1D and TH2D objects
+    Root,
+}
+
+pub fn default_histogram_formats() -> Vec<HistogramFormat> {
+    vec![HistogramFormat::Npz]
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct HistogramConfig {
+    pub module: String,
+    pub x: String,
+    #[serde(default = "default_bins")]
+    pub bins: usize,
+    #[serde(default = "default_range")]
+    pub range: [f64; 2],
+    pub y: Option<String>,
+    pub y_bins: Option<usize>,
+    pub y_range: Option<[f64; 2]>,
+    pub channels: Option<Vec<u32>>,
+}
+
+fn default_bins() -> usize {
+    4096
+}
+
+fn default_range() -> [f64; 2] {
+    [0.0, 65536.0]
+}
+
+// the hit values that can be histogrammed
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Quantity {
+    Adc,
+    Long,
+    Short,
+    Tdc,
+    Time,
+}
+
+impl Quantity {
+    fn from_name(name: &str, mod_type: &str) -> Option<Self> {
+        let q = match name {
+            "adc" => Quantity::Adc,
+            "long" => Quantity::Long,
+            "short" => Quantity::Short,
+            "tdc" => Quantity::Tdc,
+            "time" => Quantity::Time,
+            _ => return None,
+        };
+        // check the module actually has it
+        let allowed: &[Quantity] = match mod_type {
+            "scp" => &[Quantity::Adc, Quantity::Tdc],
+            "qdc" => &[Quantity::Long, Quantity::Short, Quantity::Tdc],
+            "adc" => &[Quantity::Adc],
+            "v1730" => &[Quantity::Long, Quantity::Time],
+            _ => &[],
+        };
+        allowed.contains(&q).then_some(q)
+    }
+}
+
+struct Axis {
+    quantity: Quantity,
+    name: String,
+    bins: usize,
+    min: f64,
+    max: f64,
+}
+
+impl Axis {
+    fn bin(&self, value: f64) -> Option<usize> {
+        if value < self.min || value >= self.max {
+            return None;
+        }
+        let bin = ((value - self.min) / (self.max - self.min) * self.bins as f64) as usize;
+        Some(bin.min(self.bins - 1))
+    }
+
+    fn edges(&self) -> Vec<f64> {
+        let width = (self.max - self.min) / self.bins as f64;
+        (0..=self.bins)
+            .map(|i| self.min + i as f64 * width)
+            .collect()
+    }
+}
+
+pub struct Histogram {
+    pub name: String,
+    pub module: String,
+    pub channel: u32,
+    x: Axis,
+    y: Option<Axis>,
+    // row major, x is the slow index for 2D
+    counts: Vec<u64>,
+}
+
+impl Histogram {
+    fn fill(&mut self, value: impl Fn(Quantity) -> Option<f64>) {
+        let x_bin = match value(self.x.quantity).and_then(|v| self.x.bin(v)) {
+            Some(b) => b,
+            None => return,
+        };
+        match &self.y {
+            None => self.counts[x_bin] += 1,
+            Some(y) => {
+                if let Some(y_bin) = value(y.quantity).and_then(|v| y.bin(v)) {
+                    self.counts[x_bin * y.bins + y_bin] += 1;
+                }
+            }
+        }
+    }
+
+    fn shape(&self) -> Vec<usize> {
+        match &self.y {
+            None => vec![self.x.bins],
+            Some(y) => vec![self.x.bins, y.bins],
+        }
+    }
+}
+
+pub struct Histograms {
+    pub histograms: Vec<Histogram>,
+    // histogram indices for each module name and channel
+    by_channel: HashMap<String, HashMap<u32, Vec<usize>>>,
+}
+
+impl Histograms {
+    pub fn new(configs: &[HistogramConfig], modules: &[Module]) -> Result<Self, ConverterError> {
+        let mut histograms = Vec::new();
+        let mut by_channel: HashMap<String, HashMap<u32, Vec<usize>>> = HashMap::new();
+        for config in configs.iter() {
+            let module = modules
+                .iter()
+                .find(|m| m.name == config.module)
+                .ok_or_else(|| {
+                    ConverterError::Config(format!(
+                        "histogram for module {} which is not in the config",
+                        config.module
+                    ))
+                })?;
+            let axis = |name: &str, bins: usize, range: [f64; 2]| {
+                let quantity = Quantity::from_name(name, &module.mod_type).ok_or_else(|| {
+                    ConverterError::Config(format!(
+                        "module {} ({}) has no {} to histogram",
+                        module.name, module.mod_type, name
+                    ))
+                })?;
+                if bins == 0 || range[1] <= range[0] {
+                    return Err(ConverterError::Config(format!(
+                        "histogram of {} {} needs at least one bin and a range with max > min",
+                        module.name, name
+                    )));
+                }
+                Ok(Axis {
+                    quantity,
+                    name: name.to_string(),
+                    bins,
+                    min: range[0],
+                    max: range[1],
+                })
+            };
+            let channels = config
+                .channels
+                .clone()
+                .unwrap_or((0..module.nchannels).collect());
+            for channel in channels {
+                let x = axis(&config.x, config.bins, config.range)?;
+                let y = match &config.y {
+                    Some(y) => Some(axis(
+                        y,
+                        config.y_bins.unwrap_or(config.bins),
+                        config.y_range.unwrap_or(config.range),
+                    )?),
+                    None => None,
+                };
+                let name = match &y {
+                    Some(y) => format!("{}_{}_vs_{}_ch{}", module.name, x.name, y.name, channel),
+                    None => format!("{}_{}_ch{}", module.name, x.name, channel),
+                };
+                let size = x.bins * y.as_ref().map_or(1, |y| y.bins);
+                by_channel
+                    .entry(module.name.to_string())
+                    .or_default()
+                    .entry(channel)
+                    .or_default()
+                    .push(histograms.len());
+                histograms.push(Histogram {
+                    name,
+                    module: module.name.to_string(),
+                    channel,
+                    x,
+                    y,
+                    counts: vec![0; size],
+                });
+            }
+        }
+        Ok(Histograms {
+            histograms,
+            by_channel,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.histograms.is_empty()
+    }
+
+    pub fn fill_mdpp(&mut self, module: &str, events: &[MDPPEvent]) {
+        let channels = match self.by_channel.get(module) {
+            Some(c) => c,
+            None => return,
+        };
+        for event in events.iter() {
+            for (channel, hit) in event.channels.iter().zip(event.channel_hits.iter()) {
+                for &i in channels.get(channel).into_iter().flatten() {
+                    // only the values that were in the data, not the zeros they start as
+                    self.histograms[i].fill(|q| match q {
+                        Quantity::Adc => hit.adc_filled.then_some(hit.adc_value as f64),
+                        Quantity::Long => hit.long_filled.then_some(hit.long_value as f64),
+                        Quantity::Short => hit.short_filled.then_some(hit.short_value as f64),
+                        Quantity::Tdc => hit.tdc_filled.then_some(hit.tdc_value as f64),
+                        Quantity::Time => None,
+                    });
+                }
+            }
+        }
+    }
+
+    pub fn fill_v785(&mut self, module: &str, hits: &[v785Hit]) {
+        let channels = match self.by_channel.get(module) {
+            Some(c) => c,
+            None => return,
+        };
+        for hit in hits.iter() {
+            for (channel, &value) in hit.values.iter().enumerate() {
+                // every channel is read out, zero means it did not fire
+                if value == 0 {
+                    continue;
+                }
+                for &i in channels.get(&(channel as u32)).into_iter().flatten() {
+                    self.histograms[i].fill(|q| (q == Quantity::Adc).then_some(value as f64));
+                }
+            }
+        }
+    }
+
+    pub fn fill_v1730(&mut self, module: &str, hits: &[v1730Hit]) {
+        let channels = match self.by_channel.get(module) {
+            Some(c) => c,
+            None => return,
+        };
+        for hit in hits.iter() {
+            for &i in channels.get(&hit.channel).into_iter().flatten() {
+                self.histograms[i].fill(|q| match q {
+                    Quantity::Long => Some(hit.long as f64),
+                    Quantity::Time => Some(hit.time),
+                    _ => None,
+                });
+            }
+        }
+    }
+
+    pub fn write(
+        &self,
+        output_stem: &str,
+        formats: &[HistogramFormat],
+    ) -> Result<(), ConverterError> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let dir = format!("{}_hists", output_stem);
+        for format in formats.iter() {
+            match format {
+                HistogramFormat::Npy => {
+                    fs::create_dir_all(&dir).map_err(|e| ConverterError::Io(dir.clone(), e))?;
+                    for (name, contents) in self.npy_arrays() {
+                        let filename = format!("{}/{}.npy", dir, name);
+                        fs::write(&filename, contents)
+                            .map_err(|e| ConverterError::Io(filename.clone(), e))?;
+                    }
+                }
+                HistogramFormat::Npz => {
+                    let filename = format!("{}_hists.npz", output_stem);
+                    write_npz(&filename, &self.npy_arrays())
+                        .map_err(|e| ConverterError::Io(filename.clone(), e))?;
+                }
+                HistogramFormat::Txt => {
+                    fs::create_dir_all(&dir).map_err(|e| ConverterError::Io(dir.clone(), e))?;
+                    for h in self.histograms.iter() {
+                        let filename = format!("{}/{}.txt", dir, h.name);
+                        write_txt(&filename, h)
+                            .map_err(|e| ConverterError::Io(filename.clone(), e))?;
+                    }
+                }
+                HistogramFormat::Parquet => {
+                    let filename = format!("{}_hists.parquet", output_stem);
+                    self.write_parquet(&filename)?;
+                }
+                HistogramFormat::Spe => {
+                    fs::create_dir_all(&dir).map_err(|e| ConverterError::Io(dir.clone(), e))?;
+                    for h in self.histograms.iter().filter(|h| h.y.is_none()) {
+                        if h.x.bins > radware::SPE_MAX_CHANNELS {
+                            println!(
+                                "Skipping {}: .spe spectra have at most {} channels",
+                                h.name,
+                                radware::SPE_MAX_CHANNELS
+                            );
+                            continue;
+                        }
+                        let filename = format!("{}/{}.spe", dir, h.name);
+                        radware::write_spe(&filename, &h.name, &h.counts)
+                            .map_err(|e| ConverterError::Io(filename.clone(), e))?;
+                    }
+                }
+                HistogramFormat::Mat | HistogramFormat::M4b => {
+                    fs::create_dir_all(&dir).map_err(|e| ConverterError::Io(dir.clone(), e))?;
+                    let four_byte = *format == HistogramFormat::M4b;
+                    for h in self.histograms.iter() {
+                        let y = match &h.y {
+                            Some(y) => y,
+                            None => continue,
+                        };
+                        if h.x.bins > radware::MATRIX_SIZE || y.bins > radware::MATRIX_SIZE {
+                            println!(
+                                "Skipping {}: RadWare matrices have at most {} x {} channels",
+                                h.name,
+                                radware::MATRIX_SIZE,
+                                radware::MATRIX_SIZE
+                            );
+                            continue;
+                        }
+                        let extension = if four_byte { "m4b" } else { "mat" };
+                        let filename = format!("{}/{}.{}", dir, h.name, extension);
+                        radware::write_matrix(&filename, &h.counts, h.x.bins, y.bins, four_byte)
+                            .map_err(|e| ConverterError::Io(filename.clone(), e))?;
+                    }
+                }
+                HistogramFormat::Root => {
+                    let filename = format!("{}_hists.root", output_stem);
+                    self.write_root(&filename)
+                        .map_err(|e| ConverterError::Io(filename.clone(), e))?;
+                }
+            }
+        }
+        println!("Histograms written to {}_hists", output_stem);
+        Ok(())
+    }
+
+    // every histogram with its bin edges, as (array name, .npy contents)
+    fn npy_arrays(&self) -> Vec<(String, Vec<u8>)> {
+        let mut arrays = Vec::new();
+        for h in self.histograms.iter() {
+            arrays.push((h.name.to_string(), npy_u64(&h.counts, &h.shape())));
+            let x_edges = h.x.edges();
+            arrays.push((
+                format!("{}_x_edges", h.name),
+                npy_f64(&x_edges, &[x_edges.len()]),
+            ));
+            if let Some(y) = &h.y {
+                let y_edges = y.edges();
+                arrays.push((
+                    format!("{}_y_edges", h.name),
+                    npy_f64(&y_edges, &[y_edges.len()]),
+                ));
+            }
+        }
+        arrays
+    }
+
+    fn write_root(&self, filename: &str) -> std::io::Result<()> {
+        let mut file = RootFile::create(filename, "Histograms")?;
+        for h in self.histograms.iter() {
+            let title = format!("{} channel {}", h.module, h.channel);
+            let x = RootAxis {
+                name: "xaxis",
+                title: &h.x.name,
+                nbins: h.x.bins,
+                min: h.x.min,
+                max: h.x.max,
+            };
+            match &h.y {
+                None => {
+                    let data = root_file::th1d(&h.name, &title, &x, &h.counts);
+                    file.add_object("TH1D", &h.name, &title, &data)?;
+                }
+                Some(y) => {
+                    let y = RootAxis {
+                        name: "yaxis",
+                        title: &y.name,
+                        nbins: y.bins,
+                        min: y.min,
+                        max: y.max,
+                    };
+                    let data = root_file::th2d(&h.name, &title, &x, &y, &h.counts);
+                    file.add_object("TH2D", &h.name, &title, &data)?;
+                }
+            }
+        }
+        file.close()
+    }
+
+    // long table with one row per non-empty bin, y columns are null for 1D
+    fn write_parquet(&self, filename: &str) -> Result<(), ConverterError> {
+        let mut names = Vec::new();
+        let mut modules = Vec::new();
+        let mut channels = Vec::new();
+        let mut x_lows = Vec::new();
+        let mut y_lows = Vec::new();
+        let mut counts = Vec::new();
+        for h in self.histograms.iter() {
+            let x_edges = h.x.edges();
+            let y_edges = h.y.as_ref().map(|y| y.edges());
+            let y_bins = h.y.as_ref().map_or(1, |y| y.bins);
+            for (i, &count) in h.counts.iter().enumerate() {
+                if count == 0 {
+                    continue;
+                }
+                names.push(h.name.as_str());
+                modules.push(h.module.as_str());
+                channels.push(h.channel);
+                x_lows.push(x_edges[i / y_bins]);
+                y_lows.push(y_edges.as_ref().map(|e| e[i % y_bins]));
+                counts.push(count);
+            }
+        }
+        let mut df = df!(
+            "histogram" => names,
+            "module" => modules,
+            "channel" => channels,
+            "x_low" => x_lows,
+            "y_low" => y_lows,
+            "count" => counts
+        )
+        .map_err(|e| ConverterError::Output(e.to_string()))?;
+        let file =
+            File::create(filename).map_err(|e| ConverterError::Io(filename.to_string(), e))?;
+        ParquetWriter::new(file)
+            .finish(&mut df)
+            .map_err(|e| ConverterError::Output(e.to_string()))?;
+        Ok(())
+    }
+}
+
+fn write_txt(filename: &str, h: &Histogram) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(filename)?);
+    let x_edges = h.x.edges();
+    match &h.y {
+        None => {
+            writeln!(
+                file,
+                "# {} module {} channel {}",
+                h.name, h.module, h.channel
+            )?;
+            writeln!(file, "# {}_low count", h.x.name)?;
+            for (i, count) in h.counts.iter().enumerate() {
+                writeln!(file, "{} {}", x_edges[i], count)?;
+            }
+        }
+        Some(y) => {
+            writeln!(
+                file,
+                "# {} module {} channel {}",
+                h.name, h.module, h.channel
+            )?;
+            writeln!(
+                file,
+                "# one row per {} bin from {} to {}, one column per {} bin from {} to {}",
+                h.x.name, h.x.min, h.x.max, y.name, y.min, y.max
+            )?;
+            for row in h.counts.chunks(y.bins) {
+                let line = row
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect::<Vec<String>>()
+                    .join(" ");
+                writeln!(file, "{}", line)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/*
+---------- NumPy files ----------
+
+A .npy file is a magic string, a python dict literal describing the array and
+then the raw data. A .npz is just a zip archive of .npy files, which we write
+without compression so there is no need for a zip library.
+*/
+
+fn npy_header(descr: &str, shape: &[usize]) -> Vec<u8> {
+    let shape_str = match shape {
+        [n] => format!("({},)", n),
+        _ => format!(
+            "({})",
+            shape
+                .iter()
+                .map(|n| n.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+    };
+    let mut dict = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        descr, shape_str
+    );
+    // the header is padded with spaces so the data starts on a 64 byte boundary
+    let unpadded = 10 + dict.len() + 1;
+    dict.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    dict.push('\n');
+    let mut header = b"\x93NUMPY\x01\x00".to_vec();
+    header.extend_from_slice(&(dict.len() as u16).to_le_bytes());
+    header.extend_from_slice(dict.as_bytes());
+    header
+}
+
+fn npy_u64(data: &[u64], shape: &[usize]) -> Vec<u8> {
+    let mut contents = npy_header("<u8", shape);
+    for value in data.iter() {
+        contents.extend_from_slice(&value.to_le_bytes());
+    }
+    contents
+}
+
+fn npy_f64(data: &[f64], shape: &[usize]) -> Vec<u8> {
+    let mut contents = npy_header("<f8", shape);
+    for value in data.iter() {
+        contents.extend_from_slice(&value.to_le_bytes());
+    }
+    contents
+}
+
+// Stored (uncompressed) zip archive. A 2D histogram of every channel easily
+// goes over 4 GB, so anything that doesn't fit the 32 bit fields of the zip
+// format gets zip64 records, which numpy reads like any other zip.
+fn write_npz(filename: &str, arrays: &[(String, Vec<u8>)]) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(filename)?);
+    write_zip(&mut file, arrays, u32::MAX as u64)?;
+    file.flush()
+}
+
+// zip32_limit is where the 32 bit fields give up, u32::MAX but for the tests
+fn write_zip(
+    w: &mut impl Write,
+    arrays: &[(String, Vec<u8>)],
+    zip32_limit: u64,
+) -> std::io::Result<()> {
+    let mut central_directory = Vec::new();
+    let mut offset: u64 = 0;
+    for (name, contents) in arrays.iter() {
+        let name = format!("{}.npy", name);
+        let crc = crc32fast::hash(contents);
+        let size = contents.len() as u64;
+        // the real sizes and offset go in the zip64 extra field, the 32 bit ones
+        // are all ones
+        let zip64 = size >= zip32_limit || offset >= zip32_limit;
+        let (version, size32, offset32) = match zip64 {
+            true => (45u16, u32::MAX, u32::MAX),
+            false => (20u16, size as u32, offset as u32),
+        };
+        let mut local_extra = Vec::new();
+        let mut central_extra = Vec::new();
+        if zip64 {
+            local_extra.extend_from_slice(&1u16.to_le_bytes());
+            local_extra.extend_from_slice(&16u16.to_le_bytes());
+            local_extra.extend_from_slice(&size.to_le_bytes());
+            local_extra.extend_from_slice(&size.to_le_bytes());
+            central_extra.extend_from_slice(&1u16.to_le_bytes());
+            central_extra.extend_from_slice(&24u16.to_le_bytes());
+            central_extra.extend_from_slice(&size.to_le_bytes());
+            central_extra.extend_from_slice(&size.to_le_bytes());
+            central_extra.extend_from_slice(&offset.to_le_bytes());
+        }
+        // version, flags, method (stored), time, date (1980-01-01), crc, sizes, name length
+        let mut common = Vec::new();
+        common.extend_from_slice(&version.to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+        common.extend_from_slice(&0x21u16.to_le_bytes());
+        common.extend_from_slice(&crc.to_le_bytes());
+        common.extend_from_slice(&size32.to_le_bytes());
+        common.extend_from_slice(&size32.to_le_bytes());
+        common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+
+        w.write_all(&0x04034b50u32.to_le_bytes())?;
+        w.write_all(&common)?;
+        w.write_all(&(local_extra.len() as u16).to_le_bytes())?;
+        w.write_all(name.as_bytes())?;
+        w.write_all(&local_extra)?;
+        w.write_all(contents)?;
+
+        // version made by, then the same fields, extra length, comment length,
+        // disk, attributes, offset
+        central_directory.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        central_directory.extend_from_slice(&version.to_le_bytes());
+        central_directory.extend_from_slice(&common);
+        central_directory.extend_from_slice(&(central_extra.len() as u16).to_le_bytes());
+        central_directory.extend_from_slice(&0u16.to_le_bytes());
+        central_directory.extend_from_slice(&0u16.to_le_bytes());
+        central_directory.extend_from_slice(&0u16.to_le_bytes());
+        central_directory.extend_from_slice(&0u32.to_le_bytes());
+        central_directory.extend_from_slice(&offset32.to_le_bytes());
+        central_directory.extend_from_slice(name.as_bytes());
+        central_directory.extend_from_slice(&central_extra);
+
+        offset += 30 + (name.len() + local_extra.len()) as u64 + size;
+    }
+    w.write_all(&central_directory)?;
+
+    let entries = arrays.len() as u64;
+    let directory_size = central_directory.len() as u64;
+    let zip64 =
+        entries >= u16::MAX as u64 || directory_size >= zip32_limit || offset >= zip32_limit;
+    if zip64 {
+        // zip64 end of central directory record: its size, versions, disks,
+        // entries on this disk and in total, directory size and offset
+        let end_offset = offset + directory_size;
+        w.write_all(&0x06064b50u32.to_le_bytes())?;
+        w.write_all(&44u64.to_le_bytes())?;
+        w.write_all(&45u16.to_le_bytes())?;
+        w.write_all(&45u16.to_le_bytes())?;
+        w.write_all(&0u32.to_le_bytes())?;
+        w.write_all(&0u32.to_le_bytes())?;
+        w.write_all(&entries.to_le_bytes())?;
+        w.write_all(&entries.to_le_bytes())?;
+        w.write_all(&directory_size.to_le_bytes())?;
+        w.write_all(&offset.to_le_bytes())?;
+        // and where to find it: disk, offset, number of disks
+        w.write_all(&0x07064b50u32.to_le_bytes())?;
+        w.write_all(&0u32.to_le_bytes())?;
+        w.write_all(&end_offset.to_le_bytes())?;
+        w.write_all(&1u32.to_le_bytes())?;
+    }
+    // end of central directory record, all ones if it is in the zip64 one
+    let (entries16, directory_size32, offset32) = match zip64 {
+        true => (u16::MAX, u32::MAX, u32::MAX),
+        false => (entries as u16, directory_size as u32, offset as u32),
+    };
+    w.write_all(&0x06054b50u32.to_le_bytes())?;
+    w.write_all(&0u16.to_le_bytes())?;
+    w.write_all(&0u16.to_le_bytes())?;
+    w.write_all(&entries16.to_le_bytes())?;
+    w.write_all(&entries16.to_le_bytes())?;
+    w.write_all(&directory_size32.to_le_bytes())?;
+    w.write_all(&offset32.to_le_bytes())?;
+    w.write_all(&0u16.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(data: &[u8], offset: usize) -> u64 {
+        u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap()) as u64
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u64 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as u64
+    }
+
+    fn u64_at(data: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+    }
+
+    // The files of a zip, found through the central directory like numpy does,
+    // and whether it needed the zip64 end records.
+    fn read_zip(zip: &[u8]) -> (Vec<(String, Vec<u8>)>, bool) {
+        let end = zip.len() - 22;
+        assert_eq!(u32_at(zip, end), 0x06054b50);
+        let (mut entries, mut directory) = (u16_at(zip, end + 10), u32_at(zip, end + 16));
+        let zip64 = entries == 0xFFFF;
+        if zip64 {
+            let locator = end - 20;
+            assert_eq!(u32_at(zip, locator), 0x07064b50);
+            let end64 = u64_at(zip, locator + 8) as usize;
+            assert_eq!(u32_at(zip, end64), 0x06064b50);
+            entries = u64_at(zip, end64 + 32);
+            directory = u64_at(zip, end64 + 48);
+        }
+
+        let mut files = Vec::new();
+        let mut entry = directory as usize;
+        for _ in 0..entries {
+            assert_eq!(u32_at(zip, entry), 0x02014b50);
+            let crc = u32_at(zip, entry + 16) as u32;
+            let mut size = u32_at(zip, entry + 24);
+            let name_length = u16_at(zip, entry + 28) as usize;
+            let extra_length = u16_at(zip, entry + 30) as usize;
+            let mut offset = u32_at(zip, entry + 42);
+            let name = &zip[entry + 46..entry + 46 + name_length];
+            // the zip64 fields are there for the ones that are all ones, in order
+            let mut extra = entry + 46 + name_length + 4;
+            if size == 0xFFFF_FFFF {
+                assert_eq!(u16_at(zip, extra - 4), 1);
+                size = u64_at(zip, extra);
+                assert_eq!(u64_at(zip, extra + 8), size);
+                extra += 16;
+            }
+            if offset == 0xFFFF_FFFF {
+                offset = u64_at(zip, extra);
+            }
+
+            let local = offset as usize;
+            assert_eq!(u32_at(zip, local), 0x04034b50);
+            assert_eq!(&zip[local + 30..local + 30 + name_length], name);
+            let start = local + 30 + name_length + u16_at(zip, local + 28) as usize;
+            let contents = zip[start..start + size as usize].to_vec();
+            assert_eq!(crc32fast::hash(&contents), crc);
+            files.push((String::from_utf8(name.to_vec()).unwrap(), contents));
+            entry += 46 + name_length + extra_length;
+        }
+        (files, zip64)
+    }
+
+    fn arrays(sizes: &[usize]) -> Vec<(String, Vec<u8>)> {
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(i, &size)| {
+                (
+                    format!("h{}", i),
+                    (0..size).map(|b| (b * 7 + i) as u8).collect(),
+                )
+            })
+            .collect()
+    }
+
+    fn check_zip(arrays: &[(String, Vec<u8>)], zip32_limit: u64, zip64: bool) {
+        let mut zip = Vec::new();
+        write_zip(&mut zip, arrays, zip32_limit).unwrap();
+        let (files, has_zip64) = read_zip(&zip);
+        assert_eq!(has_zip64, zip64);
+        assert_eq!(files.len(), arrays.len());
+        for ((name, contents), (file, file_contents)) in arrays.iter().zip(&files) {
+            assert_eq!(*file, format!("{}.npy", name));
+            assert!(contents == file_contents, "{} differs", name);
+        }
+    }
+
+    #[test]
+    fn small_zip() {
+        check_zip(&arrays(&[10, 0, 300]), u32::MAX as u64, false);
+    }
+
+    // with a limit of 100 bytes the second and third array are past it and the
+    // first is too big, the same as a 2D histogram of 32 channels past 4 GB
+    #[test]
+    fn big_arrays_get_zip64() {
+        check_zip(&arrays(&[150, 20, 30]), 100, true);
+    }
+
+    #[test]
+    fn many_arrays_get_zip64() {
+        check_zip(&arrays(&vec![4; 70_000]), u32::MAX as u64, true);
+    }
+}
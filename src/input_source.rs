@@ -0,0 +1,138 @@
+use crate::error::ConverterError;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::TcpStream;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/*
+Where the midas data comes from.
+
+Files on disk are normally read in one go and opened with FileView (or the
+salvage reader). The other sources are a stream of bytes that is only there
+once, so they go through the event framing of midas_stream.rs instead:
+
+  -                     stdin, e.g. lz4cat run00123.mid.lz4 | midas-converter - ...
+  tcp://host:port       a relay that sends the raw file contents
+  a file with --follow  a file the logger is still writing
+*/
+
+// how often to look at a growing file for more data
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// pipes and sockets are read in pieces of this size
+const READ_SIZE: usize = 1 << 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InputSource {
+    File(String),
+    Stdin,
+    Tcp(String),
+}
+
+impl InputSource {
+    pub fn from_arg(arg: &str) -> Self {
+        if arg == "-" {
+            InputSource::Stdin
+        } else if let Some(address) = arg.strip_prefix("tcp://") {
+            InputSource::Tcp(address.to_string())
+        } else {
+            InputSource::File(arg.to_string())
+        }
+    }
+
+    // stdin and sockets can only be read as a stream
+    pub fn is_stream(&self) -> bool {
+        !matches!(self, InputSource::File(_))
+    }
+
+    // what the outputs are named after if there is no -o
+    pub fn stem(&self) -> String {
+        match self {
+            InputSource::File(file) => file.split('.').next().unwrap().to_string(),
+            InputSource::Stdin => "stdin".to_string(),
+            InputSource::Tcp(address) => address.replace([':', '.', '/'], "_"),
+        }
+    }
+
+    pub fn open(&self, timeout: Duration) -> Result<SourceReader, ConverterError> {
+        match self {
+            InputSource::File(path) => Ok(SourceReader::Growing {
+                path: path.clone(),
+                file: None,
+                timeout,
+                last_growth: Instant::now(),
+            }),
+            InputSource::Stdin => Ok(SourceReader::Stream(Box::new(io::stdin()))),
+            InputSource::Tcp(address) => {
+                let stream = TcpStream::connect(address)
+                    .map_err(|e| ConverterError::Io(format!("tcp://{}", address), e))?;
+                Ok(SourceReader::Stream(Box::new(stream)))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for InputSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputSource::File(path) => write!(f, "{}", path),
+            InputSource::Stdin => write!(f, "stdin"),
+            InputSource::Tcp(address) => write!(f, "tcp://{}", address),
+        }
+    }
+}
+
+pub enum SourceReader {
+    // a file that is still being written, it ends when it stops growing
+    Growing {
+        path: String,
+        // opened once it exists
+        file: Option<File>,
+        timeout: Duration,
+        last_growth: Instant,
+    },
+    // stdin or a socket, it ends when the other side closes it
+    Stream(Box<dyn Read + Send>),
+}
+
+impl SourceReader {
+    // Appends the next piece of data to buf, waiting for it if need be. Returns
+    // false once there is no more.
+    pub fn read_more(&mut self, buf: &mut Vec<u8>) -> io::Result<bool> {
+        match self {
+            SourceReader::Growing {
+                path,
+                file,
+                timeout,
+                last_growth,
+            } => loop {
+                if file.is_none() && Path::new(path).exists() {
+                    *file = Some(File::open(&*path)?);
+                }
+                if let Some(file) = file {
+                    if file.read_to_end(buf)? > 0 {
+                        *last_growth = Instant::now();
+                        return Ok(true);
+                    }
+                }
+                if last_growth.elapsed() > *timeout {
+                    return Ok(false);
+                }
+                sleep(POLL_INTERVAL);
+            },
+            SourceReader::Stream(stream) => {
+                let start = buf.len();
+                buf.resize(start + READ_SIZE, 0);
+                let read = loop {
+                    match stream.read(&mut buf[start..]) {
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        result => break result,
+                    }
+                };
+                buf.truncate(start + *read.as_ref().unwrap_or(&0));
+                Ok(read? > 0)
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use crate::arrow_stream::ArrowStream;
+use crate::dataset_output;
+use crate::error::ConverterError;
+use crate::mdpp_bank::MDPPEvent;
+use crate::output_options::OutputConfig;
+use crate::v1730_bank::v1730Hit;
+use crate::v785_bank::v785Hit;
+use polars::io::{ipc, parquet};
+use polars::prelude::*;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/*
+Parquet and feather files written during the sort, for --follow.
+
+Normally the hit table goes to the csv first and is converted once the run is
+done. A followed run can go on for hours, so instead the sorter hands over the
+hits every time it flushes and they are written straight away, the parquet file
+gets row groups and the feather file record batches. The columns are the same
+as the converted files, and so are the rows since the sorters write the csv in
+the same order.
+
+Both formats only get their footer when the run is finished, until then the
+files can't be read. The csv (-c) and the dataset (--dataset) are readable while
+the run is still going, and so is the arrow stream (--arrow-stream) which gets
+the same batches for any run, followed or not.
+*/
+
+struct Writers {
+    parquet: Option<parquet::BatchedWriter<File>>,
+    feather: Option<ipc::BatchedWriter<File>>,
+}
+
+pub struct LiveOutput {
+    parquet_path: Option<PathBuf>,
+    feather_path: Option<PathBuf>,
+    flag_events: bool,
+    subruns: bool,
+    output: OutputConfig,
+    // the hits of all modules since the last flush
+    pending: Option<DataFrame>,
+    // opened at the first flush, when the columns are known
+    writers: Option<Writers>,
+    stream: Option<ArrowStream>,
+}
+
+fn live_error(path: &Path) -> impl Fn(PolarsError) -> ConverterError + '_ {
+    move |e| ConverterError::Output(format!("{}: {}", path.display(), e))
+}
+
+impl LiveOutput {
+    pub fn create(
+        output_stem: &str,
+        parquet: bool,
+        feather: bool,
+        flag_events: bool,
+        subruns: bool,
+        output: &OutputConfig,
+        stream: Option<ArrowStream>,
+    ) -> Result<Self, ConverterError> {
+        // catch bad settings before waiting for the run
+        output.parquet_options()?;
+        Ok(LiveOutput {
+            parquet_path: parquet.then(|| PathBuf::from(format!("{}.parquet", output_stem))),
+            feather_path: feather.then(|| PathBuf::from(format!("{}.feather", output_stem))),
+            flag_events,
+            subruns,
+            output: output.clone(),
+            pending: None,
+            writers: None,
+            stream,
+        })
+    }
+
+    fn add(&mut self, columns: Vec<Series>) -> Result<(), ConverterError> {
+        let df = DataFrame::new(columns).map_err(|e| ConverterError::Output(e.to_string()))?;
+        match &mut self.pending {
+            Some(pending) => {
+                pending
+                    .vstack_mut(&df)
+                    .map_err(|e| ConverterError::Output(e.to_string()))?;
+            }
+            None => self.pending = Some(df),
+        }
+        Ok(())
+    }
+
+    pub fn add_mdpp(&mut self, events: &[MDPPEvent]) -> Result<(), ConverterError> {
+        let columns = dataset_output::mdpp_columns(events, self.flag_events, self.subruns);
+        self.add(columns)
+    }
+
+    pub fn add_v785(&mut self, hits: &[v785Hit]) -> Result<(), ConverterError> {
+        let columns = dataset_output::v785_columns(hits, self.flag_events, self.subruns);
+        self.add(columns)
+    }
+
+    pub fn add_v1730(&mut self, hits: &[v1730Hit]) -> Result<(), ConverterError> {
+        let columns = dataset_output::v1730_columns(hits, self.flag_events, self.subruns);
+        self.add(columns)
+    }
+
+    fn open_writers(&mut self, schema: &Schema) -> Result<Writers, ConverterError> {
+        if let Some(stream) = &mut self.stream {
+            stream.start(schema, self.output.ipc_options().compression);
+        }
+        let parquet = match &self.parquet_path {
+            Some(path) => {
+                let options = self.output.parquet_options()?;
+                let file = File::create(path)
+                    .map_err(|e| ConverterError::Io(path.display().to_string(), e))?;
+                Some(
+                    ParquetWriter::new(file)
+                        .with_compression(options.compression)
+                        .with_statistics(options.statistics)
+                        .batched(&self.output.parquet_schema(schema))
+                        .map_err(live_error(path))?,
+                )
+            }
+            None => None,
+        };
+        let feather = match &self.feather_path {
+            Some(path) => {
+                let file = File::create(path)
+                    .map_err(|e| ConverterError::Io(path.display().to_string(), e))?;
+                Some(
+                    IpcWriter::new(file)
+                        .with_compression(self.output.ipc_options().compression)
+                        .batched(schema)
+                        .map_err(live_error(path))?,
+                )
+            }
+            None => None,
+        };
+        Ok(Writers { parquet, feather })
+    }
+
+    // writes everything since the last flush
+    pub fn flush(&mut self) -> Result<(), ConverterError> {
+        let mut df = match self.pending.take() {
+            Some(df) => df,
+            None => return Ok(()),
+        };
+        if self.writers.is_none() {
+            self.writers = Some(self.open_writers(&df.schema())?);
+        }
+        if df.height() == 0 {
+            return Ok(());
+        }
+        // the batched writers want a single chunk per column
+        df.as_single_chunk_par();
+        if let Some(stream) = &mut self.stream {
+            stream.write_batch(&df);
+        }
+        let writers = self.writers.as_mut().unwrap();
+        if let (Some(writer), Some(path)) = (&mut writers.parquet, &self.parquet_path) {
+            let rows = self.output.row_group_size;
+            for offset in (0..df.height()).step_by(rows) {
+                let batch = self.output.parquet_columns(df.slice(offset as i64, rows))?;
+                writer.write_batch(&batch).map_err(live_error(path))?;
+            }
+        }
+        if let (Some(writer), Some(path)) = (&mut writers.feather, &self.feather_path) {
+            writer.write_batch(&df).map_err(live_error(path))?;
+        }
+        Ok(())
+    }
+
+    // The footers, once the run is over. The run metadata is added afterwards like
+    // for the converted files, the EOR is only known once the sort is done.
+    pub fn finish(mut self) -> Result<(), ConverterError> {
+        self.flush()?;
+        if let Some(stream) = self.stream.take() {
+            stream.finish();
+        }
+        let mut writers = match self.writers.take() {
+            Some(writers) => writers,
+            None => return Ok(()),
+        };
+        if let (Some(writer), Some(path)) = (&mut writers.parquet, &self.parquet_path) {
+            writer.finish().map_err(live_error(path))?;
+        }
+        if let (Some(writer), Some(path)) = (&mut writers.feather, &self.feather_path) {
+            writer.finish().map_err(live_error(path))?;
+        }
+        Ok(())
+    }
+}
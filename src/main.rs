@@ -1,19 +1,51 @@
-use midasio::read::file::FileView;
 use smartstring::SmartString;
 use std::fs;
+mod arrow_stream;
+mod bank_scan;
+mod batch;
 mod bitmasks;
+mod dataset_output;
 mod diagnostics;
+mod error;
+mod event_index;
+mod event_sequence;
+mod extract;
+mod generate;
+mod hdf5_file;
+mod hdf5_output;
+mod histograms;
+mod input_source;
+mod live_output;
 mod mdpp_bank;
+mod midas_stream;
+mod midas_structs;
 mod module_config;
+mod odb;
+mod output_diff;
+mod output_options;
+mod pipeline;
+mod quicklook;
+mod radware;
+mod root_file;
+mod root_streamers;
+mod root_tree;
+mod run_metadata;
+mod salvage;
 mod sis3820;
+mod skim;
 mod sort;
+mod sort_outputs;
+mod subruns;
+mod unknown_banks;
 mod v1730_bank;
 mod v785_bank;
 mod write_data;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use error::ConverterError;
 use indicatif::ProgressBar;
+use input_source::InputSource;
 use polars::prelude::*;
-use std::path::Path;
+use std::path::PathBuf;
 use std::process::exit;
 use std::process::Command;
 use std::time::Duration;
@@ -21,13 +53,60 @@ use std::time::Duration;
 // I ripped this straight from the clap documentation
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    input_file: String,
+    #[command(subcommand)]
+    command: Option<Commands>,
+    /// midas files or globs to convert, - for stdin or tcp://host:port
+    #[arg(required_unless_present = "run_list")]
+    input_files: Vec<String>,
+    /// file with more midas files to convert, one per line
+    #[arg(long)]
+    run_list: Option<String>,
+    /// number of runs converted at the same time
+    #[arg(long, short, default_value_t = 1)]
+    jobs: usize,
+    /// threads decoding the events of each run
+    #[arg(long, short, default_value_t = 1)]
+    threads: usize,
+    /// convert sub-run files (run00123_000.mid, run00123_001.mid, ...) one at a time
+    #[arg(long, default_value_t = false)]
+    separate_subruns: bool,
+    /// convert a run while it is being written, until its EOR shows up
+    #[arg(long, default_value_t = false)]
+    follow: bool,
+    /// write <input>_index.csv with where every event is in the midas file, for extract
+    #[arg(long, default_value_t = false)]
+    index: bool,
+    /// seconds between flushes of the outputs when following a run or reading a stream
+    #[arg(long, default_value_t = 10)]
+    flush_interval: u64,
+    /// seconds without new data before a followed run is finished without an EOR
+    #[arg(long, default_value_t = 600)]
+    follow_timeout: u64,
     #[arg(long, short)]
     output_file: Option<String>,
-    config_file: String,
+    #[arg(long = "config", short = 'C')]
+    config_file: Option<String>,
+    #[arg(long, default_value_t = false)]
+    odb_config: bool,
     #[arg(long, default_value_t = 10000000)]
     chunk_size: usize,
+    /// what to do with banks that are not in the config, overrides the config file
+    #[arg(long, value_enum)]
+    unknown_banks: Option<unknown_banks::UnknownBankPolicy>,
+    /// skip and count corrupted words and banks instead of stopping
+    #[arg(long, default_value_t = false)]
+    resilient: bool,
+    /// add a flags column marking events after a gap, repeats and out of order events
+    #[arg(long, default_value_t = false)]
+    flag_events: bool,
+    /// histogram output formats, overrides the config file
+    #[arg(long, value_enum, value_delimiter = ',')]
+    histogram_format: Option<Vec<histograms::HistogramFormat>>,
+    /// only write the histograms, not the hit table
+    #[arg(long, default_value_t = false)]
+    histograms_only: bool,
     #[arg(long, short, default_value_t = false)]
     diagnostic: bool,
     #[arg(long, short, default_value_t = false)]
@@ -36,40 +115,444 @@ struct Args {
     parquet: bool,
     #[arg(long, short, default_value_t = false)]
     feather: bool,
+    /// ROOT file with hits, scalers and metadata trees
+    #[arg(long, short, default_value_t = false)]
+    root: bool,
+    /// HDF5 file with a group per module, written during the sort
+    #[arg(long, default_value_t = false)]
+    hdf5: bool,
+    /// hive partitioned parquet dataset directory (run=N/module=NAME) to add the run to
+    #[arg(long)]
+    dataset: Option<String>,
+    /// send the hit table as an arrow ipc stream to - (stdout), unix:/path or tcp://host:port
+    #[arg(long)]
+    arrow_stream: Option<String>,
+    /// serve quick look spectra and rates on http://127.0.0.1:PORT/ during the conversion
+    #[arg(long, value_name = "PORT")]
+    quicklook: Option<u16>,
+    /// parquet compression codec, overrides the config file
+    #[arg(long, value_enum)]
+    parquet_compression: Option<output_options::ParquetCodec>,
+    /// zstd (1-22), gzip (0-10) or brotli (0-11) compression level
+    #[arg(long)]
+    compression_level: Option<i32>,
+    /// write column statistics to the parquet file
+    #[arg(long, default_value_t = false)]
+    statistics: bool,
+    /// rows per parquet row group
+    #[arg(long)]
+    row_group_size: Option<usize>,
+    /// dictionary encode the module and channel columns of the parquet file
+    #[arg(long, default_value_t = false)]
+    dictionary: bool,
+    /// feather (IPC) compression codec, overrides the config file
+    #[arg(long, value_enum)]
+    ipc_compression: Option<output_options::IpcCodec>,
 }
 
-fn standard_convert(args: &mut Args, output_file: &mut str, pb: ProgressBar) {
-    // create all of the output formats that the user wants
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Scan a midas file and write a starter config file
+    Init {
+        input_file: String,
+        /// name of the config file, defaults to <input>_config.toml
+        #[arg(long, short)]
+        output_file: Option<String>,
+    },
+    /// Data quality report for every bank in a midas file
+    Diagnostics {
+        input_file: String,
+        /// without a config (or --odb-config) the module types are guessed like init does
+        #[arg(long = "config", short = 'C')]
+        config_file: Option<String>,
+        #[arg(long, default_value_t = false)]
+        odb_config: bool,
+        /// name of the JSON report, defaults to <input>_diagnostics.json
+        #[arg(long, short)]
+        output_file: Option<String>,
+    },
+    /// Write a midas file with only some of the events of a run
+    Skim(SkimArgs),
+    /// Write a synthetic midas file for the modules in a config, with a truth table of the hits
+    Generate(GenerateArgs),
+    /// Compare two converted outputs of a run (parquet, feather or csv) hit by hit
+    Diff(DiffArgs),
+    /// Print single events of a midas file, found with the index of a conversion with --index
+    Extract(ExtractArgs),
+}
 
-    // output parquet path buffer for the parquet_sink method.
-    let output_file_parquet = Path::new(&format!(
-        "{}{}",
-        output_file.split('.').next().unwrap(),
-        ".parquet"
-    ))
-    .to_path_buf();
+#[derive(clap::Args, Debug)]
+struct SkimArgs {
+    input_file: String,
+    /// only needed for --hit, without it the module types are guessed like init does
+    #[arg(long = "config", short = 'C')]
+    config_file: Option<String>,
+    #[arg(long, default_value_t = false)]
+    odb_config: bool,
+    /// name of the skimmed file, defaults to <input>_skim.mid
+    #[arg(long, short)]
+    output_file: Option<String>,
+    /// keep these midas event ids
+    #[arg(long, value_delimiter = ',')]
+    event_id: Vec<u16>,
+    /// keep events with any of these trigger mask bits set, e.g. 0x1
+    #[arg(long)]
+    trigger_mask: Option<String>,
+    /// keep this range of serial numbers, e.g. 100-200
+    #[arg(long)]
+    serials: Option<String>,
+    /// keep this range of seconds since the BOR, e.g. 60-120
+    #[arg(long)]
+    times: Option<String>,
+    /// keep events with a hit like "SCP1 channel 3 adc > 1000", any of them if given more than once
+    #[arg(long)]
+    hit: Vec<String>,
+}
+
+// decompress the file in the most janky way possible
+fn decompress_input(input_file: &str) -> Result<String, ConverterError> {
+    if input_file.contains("lz4") {
+        let status = Command::new("lz4")
+            .arg("-d")
+            .arg("-f")
+            .arg(input_file)
+            .status()
+            .map_err(|e| ConverterError::Io(input_file.to_string(), e))?;
+        if !status.success() {
+            return Err(ConverterError::Midas(format!(
+                "The command lz4 -d -f {} failed!",
+                input_file
+            )));
+        }
+        println!("{}", input_file.replace(".lz4", ""));
+        Ok(input_file.replace(".lz4", ""))
+    } else {
+        Ok(input_file.to_string())
+    }
+}
+
+// remove file if we created it
+fn remove_decompressed(input_file: &str, filename: &str) {
+    if input_file.contains("lz4") {
+        if let Err(e) = fs::remove_file(filename) {
+            println!("Failed to delete {}: {}", filename, e);
+        }
+    }
+}
 
-    let output_file_feather = Path::new(&format!(
-        "{}{}",
-        output_file.split('.').next().unwrap(),
-        ".feather"
+fn read_midas_file(filename: &str) -> Result<Vec<u8>, ConverterError> {
+    fs::read(filename).map_err(|e| ConverterError::Io(filename.to_string(), e))
+}
+
+// the whole contents of a midas file, decompressed if it has to be
+fn read_input(input_file: &str) -> Result<Vec<u8>, ConverterError> {
+    let filename = decompress_input(input_file)?;
+    let contents = read_midas_file(&filename);
+    remove_decompressed(input_file, &filename);
+    contents
+}
+
+// The modules of -C and --odb-config like a conversion resolves them, None
+// without either.
+fn config_modules(
+    config_file: &Option<String>,
+    odb_config: bool,
+    bor_odb: &serde_json::Value,
+) -> Result<Option<Vec<module_config::Module>>, ConverterError> {
+    if config_file.is_none() && !odb_config {
+        return Ok(None);
+    }
+    let toml_config = match config_file {
+        Some(f) => Some(module_config::create_config(f)?),
+        None => None,
+    };
+    Ok(Some(
+        module_config::resolve_config(toml_config, bor_odb, odb_config)?.modules,
     ))
-    .to_path_buf();
+}
 
-    // This should stream the csv off the disk and periodically dump to the parquet file.
-    let p_w_args = ParquetWriteOptions {
-        compression: ParquetCompression::Lz4Raw,
-        statistics: false,
-        row_group_size: Some(60 * 1024 * 1024),
-        data_pagesize_limit: None,
-        maintain_order: true,
+fn init_config(input_file: &str, output_file: Option<String>) -> Result<(), ConverterError> {
+    let config_file = output_file.unwrap_or(format!(
+        "{}_config.toml",
+        input_file.split('.').next().unwrap()
+    ));
+    let filename = decompress_input(input_file)?;
+    let contents = read_midas_file(&filename);
+    remove_decompressed(input_file, &filename);
+    let contents = contents?;
+    let midas_input = salvage::MidasInput::open(&contents)?;
+    let banks = bank_scan::scan_file(midas_input.events());
+    bank_scan::print_summary(&banks);
+    bank_scan::write_starter_config(&banks, &config_file)
+        .map_err(|e| ConverterError::Io(config_file.clone(), e))?;
+    println!("Starter config written to {}", config_file);
+    Ok(())
+}
+
+fn run_diagnostics(
+    input_file: &str,
+    config_file: Option<String>,
+    odb_config: bool,
+    output_file: Option<String>,
+) -> Result<bool, ConverterError> {
+    let json_file = output_file.unwrap_or(format!(
+        "{}_diagnostics.json",
+        input_file.split('.').next().unwrap()
+    ));
+    let filename = decompress_input(input_file)?;
+    let contents = read_midas_file(&filename);
+    remove_decompressed(input_file, &filename);
+    let contents = contents?;
+    let midas_input = salvage::MidasInput::open(&contents)?;
+
+    let bor_odb = midas_input.run_metadata().bor_odb;
+    let modules = match config_modules(&config_file, odb_config, &bor_odb)? {
+        Some(modules) => modules,
+        None => bank_scan::guessed_modules(&bank_scan::scan_file(midas_input.events())),
+    };
+
+    let report = diagnostics::run_diagnostics(midas_input.events(), &modules);
+    report.print_table();
+    report
+        .write_json(&json_file)
+        .map_err(|e| ConverterError::Io(json_file.clone(), e))?;
+    println!("Report written to {}", json_file);
+    Ok(midas_input.is_complete())
+}
+
+#[derive(clap::Args, Debug)]
+struct GenerateArgs {
+    /// the modules to generate banks for, the same config the file is converted with
+    #[arg(long = "config", short = 'C')]
+    config_file: String,
+    /// the truth tables are <output>_truth.csv and <output>_truth_scaler.csv
+    #[arg(long, short, default_value = "synthetic.mid")]
+    output_file: String,
+    /// number of trigger events
+    #[arg(long, default_value_t = 10000)]
+    events: u64,
+    /// triggers per second
+    #[arg(long, default_value_t = 1000.0)]
+    rate: f64,
+    /// probability for each channel to fire in a trigger
+    #[arg(long, default_value_t = 0.25)]
+    occupancy: f64,
+    /// fraction of noise hits and MDPP dummy words
+    #[arg(long, default_value_t = 0.01)]
+    noise: f64,
+    /// fraction of MDPP events that get a cut off event in front of them
+    #[arg(long, default_value_t = 0.0)]
+    broken: f64,
+    /// seconds between scaler events, 0 for none
+    #[arg(long, default_value_t = 1.0)]
+    scaler_interval: f64,
+    #[arg(long, default_value_t = 1)]
+    seed: u64,
+    #[arg(long, default_value_t = 1)]
+    run_number: u32,
+}
+
+#[derive(clap::Args, Debug)]
+struct DiffArgs {
+    output_a: String,
+    output_b: String,
+    /// largest difference between two values that still counts as the same
+    #[arg(long, default_value_t = 0.0)]
+    tolerance: f64,
+    /// fraction of the hits of a module that may be added, removed or changed
+    #[arg(long, default_value_t = 0.0)]
+    max_changed: f64,
+    /// largest chi2/ndf between the histograms of a channel
+    #[arg(long, default_value_t = 1.0)]
+    max_chi2: f64,
+}
+
+#[derive(clap::Args, Debug)]
+struct ExtractArgs {
+    input_file: String,
+    /// without a config (or --odb-config) the banks are printed as hex
+    #[arg(long = "config", short = 'C')]
+    config_file: Option<String>,
+    #[arg(long, default_value_t = false)]
+    odb_config: bool,
+    /// index written by a conversion with --index, defaults to <input>_index.csv
+    #[arg(long)]
+    index: Option<String>,
+    /// a serial number or a range of them, e.g. 100-200
+    #[arg(long)]
+    serial: Option<String>,
+    /// only events with these midas event ids
+    #[arg(long, value_delimiter = ',')]
+    event_id: Vec<u16>,
+    /// with --timestamp, the events with this hit of the module in the converted output
+    #[arg(long, requires = "timestamp")]
+    module: Option<String>,
+    /// evt_ts (coarse_time for the v1730) of a hit of --module
+    #[arg(long, requires = "module")]
+    timestamp: Option<i64>,
+    /// print the raw words of every bank instead of decoding them
+    #[arg(long, default_value_t = false)]
+    hex: bool,
+}
+
+fn run_extract(args: &ExtractArgs) -> Result<bool, ConverterError> {
+    let index_file = args
+        .index
+        .clone()
+        .unwrap_or(event_index::index_filename(&args.input_file));
+    let (index_modules, entries) = event_index::read_index(&index_file)?;
+    let selection = extract::ExtractSelection {
+        serials: match &args.serial {
+            Some(serial) => Some(match serial.parse::<u32>() {
+                Ok(serial) => (serial, serial),
+                Err(_) => skim::parse_range(serial)?,
+            }),
+            None => None,
+        },
+        event_ids: args.event_id.clone(),
+        timestamp: args.module.clone().zip(args.timestamp),
     };
+    let selected = extract::select(&index_modules, &entries, &selection)?;
+    if selected.is_empty() {
+        println!("No events in {} match.", index_file);
+        return Ok(true);
+    }
+
+    // the index is for the uncompressed file
+    let filename = decompress_input(&args.input_file)?;
+    let result = extract::read_bor(&filename).and_then(|(start_time, bor_odb)| {
+        let modules = config_modules(
+            &args.config_file,
+            args.odb_config,
+            &odb::parse_odb(&bor_odb),
+        )?
+        .unwrap_or_default();
+        extract::extract(
+            &filename,
+            start_time,
+            &index_modules,
+            &selected,
+            &modules,
+            args.hex,
+        )
+    });
+    remove_decompressed(&args.input_file, &filename);
+    result.map(|_| true)
+}
+
+fn run_generate(args: &GenerateArgs) -> Result<bool, ConverterError> {
+    let toml_config = module_config::create_config(&args.config_file)?;
+    // checks the modules the same way a conversion would
+    let config = module_config::resolve_config(Some(toml_config), &serde_json::Value::Null, false)?;
+    let settings = generate::GeneratorSettings {
+        events: args.events,
+        rate: args.rate,
+        occupancy: args.occupancy,
+        noise: args.noise,
+        broken: args.broken,
+        scaler_interval: args.scaler_interval,
+        seed: args.seed,
+        run_number: args.run_number,
+    };
+    let summary = generate::generate(
+        &config.modules,
+        &config.odb_config,
+        &settings,
+        &args.output_file,
+    )?;
+    println!(
+        "Wrote {} trigger events ({} s) with {} hits and {} scaler events to {}",
+        summary.trigger_events,
+        summary.duration,
+        summary.hits,
+        summary.scaler_events,
+        args.output_file
+    );
+    if summary.broken_events > 0 {
+        println!("  {} broken MDPP events", summary.broken_events);
+    }
+    Ok(true)
+}
+
+fn run_skim(args: &SkimArgs) -> Result<bool, ConverterError> {
+    let output_file = args.output_file.clone().unwrap_or(format!(
+        "{}_skim.mid",
+        args.input_file.split('.').next().unwrap()
+    ));
+    let filename = decompress_input(&args.input_file)?;
+    let contents = read_midas_file(&filename);
+    remove_decompressed(&args.input_file, &filename);
+    let contents = contents?;
+    let midas_input = salvage::MidasInput::open(&contents)?;
 
-    let ipc_args = IpcWriterOptions {
-        compression: None,
-        maintain_order: true,
+    // the modules are only needed to decode the hits
+    let modules = if args.hit.is_empty() {
+        Vec::new()
+    } else {
+        let bor_odb = midas_input.run_metadata().bor_odb;
+        match config_modules(&args.config_file, args.odb_config, &bor_odb)? {
+            Some(modules) => modules,
+            None => bank_scan::guessed_modules(&bank_scan::scan_file(midas_input.events())),
+        }
+    };
+    let selection = skim::SkimSelection {
+        event_ids: args.event_id.clone(),
+        trigger_mask: match &args.trigger_mask {
+            Some(mask) => Some(skim::parse_trigger_mask(mask)?),
+            None => None,
+        },
+        serials: match &args.serials {
+            Some(range) => Some(skim::parse_range(range)?),
+            None => None,
+        },
+        times: match &args.times {
+            Some(range) => Some(skim::parse_range(range)?),
+            None => None,
+        },
+        hits: args
+            .hit
+            .iter()
+            .map(|h| skim::HitCondition::parse(h, &modules))
+            .collect::<Result<_, _>>()?,
     };
 
+    let summary = skim::skim(&midas_input, &modules, &selection, &output_file)?;
+    println!(
+        "Kept {} of {} events in {}",
+        summary.kept, summary.events, output_file
+    );
+    Ok(midas_input.is_complete())
+}
+
+fn output_error(e: PolarsError) -> ConverterError {
+    ConverterError::Output(e.to_string())
+}
+
+fn standard_convert(
+    args: &Args,
+    output_file: &str,
+    metadata: &[(String, String)],
+    output: &output_options::OutputConfig,
+    live: bool,
+    is_v785: bool,
+    pb: ProgressBar,
+) -> Result<(), ConverterError> {
+    // create all of the output formats that the user wants
+
+    // output parquet path buffer for write_parquet.
+    let output_file_parquet = PathBuf::from(format!(
+        "{}.parquet",
+        sort_outputs::output_stem(output_file)
+    ));
+
+    let output_file_feather = PathBuf::from(format!(
+        "{}.feather",
+        sort_outputs::output_stem(output_file)
+    ));
+
+    // This should stream the csv off the disk and periodically dump to the parquet file.
+    let ipc_args = output.ipc_options();
+
     let mut sch = Schema::new();
 
     let columns = [
@@ -82,18 +565,29 @@ fn standard_convert(args: &mut Args, output_file: &mut str, pb: ProgressBar) {
         "trigger_dt",
         "pileup",
         "evt_ts",
+        "flags",
+        "subrun",
     ];
 
+    // the module id is 8 bits and the values are 16 bits in the MDPP data words,
+    // the v785 adc is the whole 32 bit data word
+    let adc_type = if is_v785 {
+        DataType::UInt32
+    } else {
+        DataType::UInt16
+    };
     let col_types = [
-        DataType::Int32,   // module id
-        DataType::Int32,   // channel id
-        DataType::Int32,   // adc
-        DataType::Int32,   // long integral
-        DataType::Int32,   // short integral
-        DataType::Int32,   // tdc
+        DataType::UInt8,   // module id
+        DataType::UInt8,   // channel id
+        adc_type,          // adc
+        DataType::UInt16,  // long integral
+        DataType::UInt16,  // short integral
+        DataType::UInt16,  // tdc
         DataType::Int64,   // trigger dt
         DataType::Boolean, // Pileup flag
         DataType::Int64,   // Event or timestamp
+        DataType::UInt8,   // event_sequence flags
+        DataType::UInt16,  // sub-run file
     ];
 
     for (&col, dt) in columns.iter().zip(col_types.iter()) {
@@ -102,81 +596,83 @@ fn standard_convert(args: &mut Args, output_file: &mut str, pb: ProgressBar) {
         sch.with_column(s, dt.to_owned());
     }
 
-    if args.parquet {
+    // a streamed run already has its parquet and feather files, they only need the metadata
+    if args.parquet && !live {
         // Parquet dump
-        LazyCsvReader::new(&output_file)
-            .has_header(true)
-            .with_dtype_overwrite(Some(&sch))
-            .finish()
-            .unwrap()
-            .with_type_coercion(true)
-            .sink_parquet(output_file_parquet, p_w_args)
-            .expect("Error writing parquet file.");
+        output.write_parquet(output_file, &sch, &output_file_parquet)?;
+    }
+    if args.parquet {
+        run_metadata::add_parquet_metadata(&output_file_parquet, metadata)
+            .map_err(|e| ConverterError::Io(output_file_parquet.display().to_string(), e))?;
 
         pb.println("Parquet conversion done!");
     }
-    if args.feather {
+    if args.feather && !live {
         // feather dump
-        LazyCsvReader::new(&output_file)
+        LazyCsvReader::new(output_file)
             .has_header(true)
             .with_dtype_overwrite(Some(&sch))
             .finish()
-            .unwrap()
+            .map_err(output_error)?
             .with_type_coercion(true)
-            .sink_ipc(output_file_feather, ipc_args)
-            .expect("Error writing feather file.");
+            .sink_ipc(output_file_feather.clone(), ipc_args)
+            .map_err(output_error)?;
+    }
+    if args.feather {
+        run_metadata::add_ipc_metadata(&output_file_feather, metadata, ipc_args.compression)
+            .map_err(output_error)?;
         pb.finish_with_message("Feather conversion done!");
     }
+    if args.root {
+        // ROOT trees, written without ROOT
+        let stem = sort_outputs::output_stem(output_file);
+        let output_file_root = format!("{}.root", stem);
+        let scaler_file = format!("{}_scaler.csv", stem);
+        root_tree::write_root(&output_file_root, output_file, &scaler_file, &sch, metadata)?;
+        pb.println("ROOT conversion done!");
+    }
     // delete the csv if you want to cover your tracks
     if !args.csv {
-        Command::new("rm")
-            .arg(&output_file)
-            .status()
-            .expect("Failed to delete csv file.");
+        fs::remove_file(output_file).map_err(|e| ConverterError::Io(output_file.to_string(), e))?;
     }
+    Ok(())
 }
 
-fn v1730_convert(args: &mut Args, output_file: &mut str, pb: ProgressBar) {
+fn v1730_convert(
+    args: &Args,
+    output_file: &str,
+    metadata: &[(String, String)],
+    output: &output_options::OutputConfig,
+    live: bool,
+    pb: ProgressBar,
+) -> Result<(), ConverterError> {
     // create all of the output formats that the user wants
 
-    // output parquet path buffer for the parquet_sink method.
-    let output_file_parquet = Path::new(&format!(
-        "{}{}",
-        output_file.split('.').next().unwrap(),
-        ".parquet"
-    ))
-    .to_path_buf();
+    // output parquet path buffer for write_parquet.
+    let output_file_parquet = PathBuf::from(format!(
+        "{}.parquet",
+        sort_outputs::output_stem(output_file)
+    ));
 
-    let output_file_feather = Path::new(&format!(
-        "{}{}",
-        output_file.split('.').next().unwrap(),
-        ".feather"
-    ))
-    .to_path_buf();
+    let output_file_feather = PathBuf::from(format!(
+        "{}.feather",
+        sort_outputs::output_stem(output_file)
+    ));
 
     // This should stream the csv off the disk and periodically dump to the parquet file.
-    let p_w_args = ParquetWriteOptions {
-        compression: ParquetCompression::Lz4Raw,
-        statistics: false,
-        row_group_size: Some(60 * 1024 * 1024),
-        data_pagesize_limit: None,
-        maintain_order: true,
-    };
-
-    let ipc_args = IpcWriterOptions {
-        compression: None,
-        maintain_order: true,
-    };
+    let ipc_args = output.ipc_options();
 
     let mut sch = Schema::new();
 
-    let columns = ["channel", "long", "coarse_time", "time"];
+    let columns = ["channel", "long", "coarse_time", "time", "flags", "subrun"];
 
     let col_types = [
-        DataType::Int32,   // channel id
-        DataType::Int32,   // long integral
+        DataType::UInt8,   // channel id
+        DataType::UInt16,  // long integral
         DataType::Int64,   // coarse time
         DataType::Float64, // time
+        DataType::UInt8,   // event_sequence flags
+        DataType::UInt16,  // sub-run file
     ];
 
     for (&col, dt) in columns.iter().zip(col_types.iter()) {
@@ -185,153 +681,494 @@ fn v1730_convert(args: &mut Args, output_file: &mut str, pb: ProgressBar) {
         sch.with_column(s, dt.to_owned());
     }
 
-    if args.parquet {
+    // a streamed run already has its parquet and feather files, they only need the metadata
+    if args.parquet && !live {
         // Parquet dump
-        LazyCsvReader::new(&output_file)
-            .has_header(true)
-            .with_dtype_overwrite(Some(&sch))
-            .finish()
-            .unwrap()
-            .with_type_coercion(true)
-            .sink_parquet(output_file_parquet, p_w_args)
-            .expect("Error writing parquet file.");
+        output.write_parquet(output_file, &sch, &output_file_parquet)?;
+    }
+    if args.parquet {
+        run_metadata::add_parquet_metadata(&output_file_parquet, metadata)
+            .map_err(|e| ConverterError::Io(output_file_parquet.display().to_string(), e))?;
 
         pb.println("Parquet conversion done!");
     }
-    if args.feather {
+    if args.feather && !live {
         // feather dump
-        LazyCsvReader::new(&output_file)
+        LazyCsvReader::new(output_file)
             .has_header(true)
             .with_dtype_overwrite(Some(&sch))
             .finish()
-            .unwrap()
+            .map_err(output_error)?
             .with_type_coercion(true)
-            .sink_ipc(output_file_feather, ipc_args)
-            .expect("Error writing feather file.");
+            .sink_ipc(output_file_feather.clone(), ipc_args)
+            .map_err(output_error)?;
+    }
+    if args.feather {
+        run_metadata::add_ipc_metadata(&output_file_feather, metadata, ipc_args.compression)
+            .map_err(output_error)?;
         pb.finish_with_message("Feather conversion done!");
     }
+    if args.root {
+        // ROOT trees, written without ROOT
+        let stem = sort_outputs::output_stem(output_file);
+        let output_file_root = format!("{}.root", stem);
+        let scaler_file = format!("{}_scaler.csv", stem);
+        root_tree::write_root(&output_file_root, output_file, &scaler_file, &sch, metadata)?;
+        pb.println("ROOT conversion done!");
+    }
     // delete the csv if you want to cover your tracks
     if !args.csv {
-        Command::new("rm")
-            .arg(&output_file)
-            .status()
-            .expect("Failed to delete csv file.");
+        fs::remove_file(output_file).map_err(|e| ConverterError::Io(output_file.to_string(), e))?;
     }
+    Ok(())
 }
 
-fn main() {
-    // parse the command line args
-    let mut args = Args::parse();
-    // if an output filename was not passed, then generate it from the input
-    if args.output_file.is_none() {
-        // just to remind myself later, split the string, get the first Option<element> before . with next
-        let temp_filename = format!("{}{}", args.input_file.split('.').next().unwrap(), ".csv");
-        args.output_file = Some(temp_filename);
+// The files from the command line and the run list, with the globs expanded and
+// the sub-runs grouped.
+fn input_runs(args: &Args) -> Result<Vec<subruns::RunFiles>, ConverterError> {
+    // the config file used to be the second positional argument
+    if let Some(toml) = args.input_files.iter().find(|f| f.ends_with(".toml")) {
+        return Err(ConverterError::Config(format!(
+            "{} looks like a config file, pass it with --config",
+            toml
+        )));
+    }
+    let mut patterns = args.input_files.clone();
+    if let Some(run_list) = &args.run_list {
+        let contents =
+            fs::read_to_string(run_list).map_err(|e| ConverterError::Io(run_list.clone(), e))?;
+        patterns.extend(
+            contents
+                .lines()
+                .map(|l| l.trim())
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(|l| l.to_string()),
+        );
+    }
+
+    // stdin and sockets can't be globbed or grouped, and are read as they come
+    if let Some(stream) = patterns
+        .iter()
+        .find(|p| InputSource::from_arg(p).is_stream())
+    {
+        if patterns.len() > 1 {
+            return Err(ConverterError::Config(format!(
+                "{} has to be the only input",
+                stream
+            )));
+        }
+        if args.diagnostic {
+            return Err(ConverterError::Config(
+                "--diagnostic needs a midas file, not a stream".to_string(),
+            ));
+        }
+        return Ok(vec![subruns::RunFiles::single(stream.clone())]);
+    }
+
+    let mut files = Vec::new();
+    for pattern in patterns {
+        // plain names are passed through so that a missing file is reported by the run
+        if !pattern.contains(['*', '?', '[']) {
+            files.push(pattern);
+            continue;
+        }
+        let paths = glob::glob(&pattern)
+            .map_err(|e| ConverterError::Config(format!("bad pattern {}: {}", pattern, e)))?;
+        let before = files.len();
+        for path in paths {
+            let path =
+                path.map_err(|e| ConverterError::Io(e.path().display().to_string(), e.into()))?;
+            files.push(path.display().to_string());
+        }
+        if files.len() == before {
+            return Err(ConverterError::Config(format!(
+                "no files matching {}",
+                pattern
+            )));
+        }
+    }
+    if files.is_empty() {
+        return Err(ConverterError::Config("no input files".to_string()));
+    }
+    let runs = subruns::group_subruns(files, !args.separate_subruns)?;
+    if runs.len() > 1 && args.output_file.is_some() {
+        return Err(ConverterError::Config(
+            "--output-file only works with a single run".to_string(),
+        ));
+    }
+    if runs.len() > 1 && args.arrow_stream.is_some() {
+        return Err(ConverterError::Config(
+            "--arrow-stream only works with a single run".to_string(),
+        ));
+    }
+    if runs.len() > 1 && args.quicklook.is_some() {
+        return Err(ConverterError::Config(
+            "--quicklook only works with a single run".to_string(),
+        ));
     }
+    // the file is read as it is written, so it can't be compressed or in pieces
+    if args.follow && (runs.len() > 1 || runs[0].is_merged() || runs[0].files[0].1.contains("lz4"))
+    {
+        return Err(ConverterError::Config(
+            "--follow only works with a single uncompressed midas file".to_string(),
+        ));
+    }
+    // the offsets are into one midas file that is all there when we start
+    if args.index
+        && (args.follow
+            || runs
+                .iter()
+                .any(|r| r.is_merged() || InputSource::from_arg(&r.files[0].1).is_stream()))
+    {
+        return Err(ConverterError::Config(
+            "--index needs one midas file per run, use --separate-subruns for sub-runs and don't --follow".to_string(),
+        ));
+    }
+    Ok(runs)
+}
 
-    // Now move the value out of the struct so that we don't have to jump through hoops.
-    let mut output_file = args.output_file.clone().unwrap();
-    let scaler_output_file = format!("{}_scaler.csv", output_file.split('.').next().unwrap());
+// Returns true if the run was converted without skipping any data.
+fn convert(args: &Args, run: &subruns::RunFiles, pb: ProgressBar) -> Result<bool, ConverterError> {
+    // if an output filename was not passed, then generate it from the input
+    let mut output_file = args
+        .output_file
+        .clone()
+        .unwrap_or(format!("{}.csv", run.stem));
+    let scaler_output_file = format!("{}_scaler.csv", sort_outputs::output_stem(&output_file));
     // if it is user supplied, make sure it has a .csv
     if !output_file.contains(".csv") {
         output_file = format!("{}{}", output_file, ".csv");
     }
 
-    // decompress the file in the most janky way possible
-    let filename = if args.input_file.contains("lz4") {
-        Command::new("lz4")
-            .arg("-d")
-            .arg("-f")
-            .arg(args.input_file.clone())
-            .status()
-            .unwrap_or_else(|_| panic!("The command lz4 -d -f {} failed!", args.input_file.clone()));
-        println!("{}", args.input_file.replace(".lz4", ""));
-        args.input_file.replace(".lz4", "")
+    // if we want diagnostics, every sub-run gets its own report
+    if args.diagnostic {
+        let mut complete = true;
+        for (_, input_file) in run.files.iter() {
+            complete &=
+                run_diagnostics(input_file, args.config_file.clone(), args.odb_config, None)?;
+        }
+        return Ok(complete);
+    }
+
+    // before anything is printed, the stream may be stdout
+    let mut arrow_stream = match &args.arrow_stream {
+        Some(target) => Some(arrow_stream::ArrowStream::open(target)?),
+        None => None,
+    };
+
+    // a run that is still being written, stdin or a socket is read as it comes
+    let source = InputSource::from_arg(&run.files[0].1);
+    let streamed = if args.follow || source.is_stream() {
+        Some(midas_stream::MidasStream::open(
+            &source,
+            Duration::from_secs(args.follow_timeout),
+        )?)
     } else {
-        args.input_file.clone()
+        None
     };
 
     // see midasio package documentation for details
-    let contents = fs::read(&filename).expect("Failed to read midas file.");
-    let file_view = FileView::try_from(&contents[..]).expect("Failed to create FileView of midas file.");
-    // if we want diagnostics
-    if args.diagnostic {
-        let (one, two, three) = diagnostics::event_diagnostics(&file_view);
-        println!("Banks: {}, Headers: {}, Event Ends: {}", one, two, three);
-        // remove file if we created it
-        if args.input_file.contains("lz4") {
-            Command::new("rm")
-                .arg(filename)
-                .status()
-                .expect("Failed to delete file.");
+    // the sub-runs are read one at a time, this is the first one
+    let first_contents = match &streamed {
+        Some(_) => None,
+        None => Some(read_input(&run.files[0].1)?),
+    };
+    // falls back to the salvage reader if the file is cut off or corrupted
+    let first_input = first_contents
+        .as_deref()
+        .map(salvage::MidasInput::open)
+        .transpose()?;
+    // save the ODB dumps and pick out the keys that go into the output metadata
+    let mut run_info = match (&streamed, &first_input) {
+        (Some(streamed), _) => streamed.run_metadata(),
+        (None, Some(first)) => {
+            // the EOR is in the last sub-run, which is read again when its turn comes
+            let last = match run.files.last().filter(|_| run.is_merged()) {
+                Some((_, last_file)) => {
+                    let contents = read_input(last_file)?;
+                    Some(salvage::MidasInput::open(&contents)?.run_metadata())
+                }
+                None => None,
+            };
+            subruns::run_metadata(first.run_metadata(), last)
         }
-        exit(0);
-    }
+        (None, None) => unreachable!(),
+    };
+    let output_stem = sort_outputs::output_stem(&output_file);
+    run_info
+        .write_sidecars(&output_stem)
+        .map_err(|e| ConverterError::Io(output_stem.clone(), e))?;
 
     // get the configuration, which will choose the type of sorter to use
-    let config: module_config::Config = module_config::create_config(&args.config_file);
+    let toml_config = match &args.config_file {
+        Some(f) => Some(module_config::create_config(f)?),
+        None => None,
+    };
+    let mut config: module_config::Config =
+        module_config::resolve_config(toml_config, &run_info.bor_odb, args.odb_config)?;
+    if let Some(policy) = args.unknown_banks {
+        config.unknown_banks = policy;
+    }
+    config.resilient |= args.resilient;
+    config.flag_events |= args.flag_events;
+    config.subruns = run.is_merged();
+    config.threads = args.threads;
+    // the quick look page wants the hits as they come too
+    config.flush_interval = (streamed.is_some() || args.quicklook.is_some())
+        .then(|| Duration::from_secs(args.flush_interval));
+    if let Some(formats) = args.histogram_format.clone() {
+        config.histogram_formats = formats;
+    }
+    config.histograms_only |= args.histograms_only;
+    if let Some(codec) = args.parquet_compression {
+        config.output.parquet_compression = codec;
+    }
+    if args.compression_level.is_some() {
+        config.output.compression_level = args.compression_level;
+    }
+    config.output.statistics |= args.statistics;
+    if let Some(rows) = args.row_group_size {
+        config.output.row_group_size = rows;
+    }
+    config.output.dictionary |= args.dictionary;
+    if let Some(codec) = args.ipc_compression {
+        config.output.ipc_compression = codec;
+    }
+    // catch bad settings before the sort instead of after it
+    config.output.parquet_options()?;
+    let output_config = config.output.clone();
+    let histograms_only = config.histograms_only;
+    let metadata_config = config.metadata.clone();
+    let metadata = run_info.key_values(&config.metadata);
+    let hdf5_output = if args.hdf5 {
+        Some(hdf5_output::H5Output::create(
+            &format!("{}.h5", output_stem),
+            &config.modules,
+            config.flag_events,
+            config.subruns,
+            &metadata,
+        )?)
+    } else {
+        None
+    };
+    let dataset_output = match &args.dataset {
+        Some(dataset) => Some(dataset_output::DatasetOutput::create(
+            dataset,
+            run_info.run_number,
+            &config.modules,
+            config.flag_events,
+            config.subruns,
+            &config.output,
+            &metadata,
+        )?),
+        None => None,
+    };
+    if histograms_only && arrow_stream.take().is_some() {
+        println!("There is no hit table for the arrow stream with --histograms-only.");
+    }
+    // a streamed run writes its parquet and feather files as it goes
+    let live = streamed.is_some();
+    let live_output =
+        if (live && (args.parquet || args.feather) || arrow_stream.is_some()) && !histograms_only {
+            Some(live_output::LiveOutput::create(
+                &output_stem,
+                live && args.parquet,
+                live && args.feather,
+                config.flag_events,
+                config.subruns,
+                &config.output,
+                arrow_stream,
+            )?)
+        } else {
+            None
+        };
+    let quicklook = match args.quicklook {
+        Some(port) => Some(quicklook::QuickLook::start(
+            port,
+            &output_stem,
+            run_info.start_timestamp,
+            &config.modules,
+        )?),
+        None => None,
+    };
+    // --index is only allowed for a single midas file
+    if let (true, Some(input), Some(contents)) = (args.index, &first_input, &first_contents) {
+        let index_file = event_index::index_filename(&run.files[0].1);
+        let indexed = event_index::write_index(input, contents, &config.modules, &index_file)?;
+        println!("Indexed {} events in {}", indexed, index_file);
+    }
 
     // initialize the sorter
-    let mut is_adc = false;
-    let mut is_v1730 = false;
-    for m in config.modules.iter() {
-        if m.mod_type == "adc" {
-            is_adc = true;
-            break;
-        } else if m.mod_type == "v1730" {
-            is_v1730 = true;
-            break;
+    let is_v1730 = config.modules.iter().any(|m| m.mod_type == "v1730");
+    let is_v785 = config.modules.iter().any(|m| m.mod_type == "adc");
+    let outputs = sort_outputs::SortOutputs {
+        filename: output_file.clone(),
+        scaler_filename: scaler_output_file,
+        hdf5: hdf5_output,
+        dataset: dataset_output,
+        live: live_output,
+        quicklook,
+    };
+    let mut sorter = sort::Sorter::new(args.chunk_size, config, outputs, pb.clone())?;
+    let mut complete = true;
+    match (&streamed, first_input) {
+        (Some(streamed), _) => {
+            // every chunk is dropped once it is sorted
+            for chunk in streamed.chunks()? {
+                sorter.sort_events(chunk.events())?;
+            }
+            complete &= streamed.is_complete();
+        }
+        (None, Some(first)) => {
+            sorter.sort_events(subruns::events(&first, run.files[0].0))?;
+            complete &= first.is_complete();
+            // the sorter carries the open events over, the file contents can go
+            drop(first);
+            drop(first_contents);
+            for (subrun, input_file) in run.files.iter().skip(1) {
+                let contents = read_input(input_file)?;
+                let input = salvage::MidasInput::open(&contents)?;
+                sorter.sort_events(subruns::events(&input, *subrun))?;
+                complete &= input.is_complete();
+                run_info.salvaged |= matches!(input, salvage::MidasInput::Salvaged(_));
+            }
         }
+        (None, None) => unreachable!(),
     }
+    let errors = sorter.finish()?;
 
-    // Dont be stupid
-    if is_adc & is_v1730 {
-        panic!("Configuration has both adc and v1730 modules!!")
+    // the EOR of a streamed run has only just been read, and a sub-run in the
+    // middle may have been salvaged
+    let metadata = match &streamed {
+        Some(streamed) => {
+            streamed.print_summary();
+            let run_info = streamed.run_metadata();
+            run_info
+                .write_sidecars(&output_stem)
+                .map_err(|e| ConverterError::Io(output_stem.clone(), e))?;
+            run_info.key_values(&metadata_config)
+        }
+        None if run_info.salvaged && !metadata.iter().any(|(k, _)| k == "salvaged") => {
+            run_info.key_values(&metadata_config)
+        }
+        None => metadata,
     };
 
-    if is_adc {
-        sort::v785Sort::new(
-            output_file.clone(),
-            scaler_output_file,
-            args.chunk_size,
-            config,
-        )
-        .sort_loop(&file_view);
-    } else if is_v1730 {
-        sort::v1730Sort::new(
-            output_file.clone(),
-            scaler_output_file,
-            args.chunk_size,
-            config,
-        )
-        .sort_loop(&file_view);
-    } else {
-        sort::MDPPSort::new(
-            output_file.clone(),
-            scaler_output_file,
-            args.chunk_size,
-            config,
-        )
-        .sort_loop(&file_view);
-    }
-
-    // remove file if we created it
-    if args.input_file.contains("lz4") {
-        Command::new("rm")
-            .arg(Path::new(&filename))
-            .status()
-            .expect("Failed to delete file.");
+    // nothing to convert without a hit table
+    if histograms_only {
+        return Ok(errors.is_clean() && complete);
     }
 
     // spinner while we convert
-    let pb = ProgressBar::new_spinner();
     pb.enable_steady_tick(Duration::from_millis(200));
     pb.tick();
     pb.set_message("Converting to final formats...");
 
     if is_v1730 {
-        v1730_convert(&mut args, &mut output_file, pb)
+        v1730_convert(args, &output_file, &metadata, &output_config, live, pb)?
     } else {
-        standard_convert(&mut args, &mut output_file, pb)
+        standard_convert(
+            args,
+            &output_file,
+            &metadata,
+            &output_config,
+            live,
+            is_v785,
+            pb,
+        )?
+    }
+    Ok(errors.is_clean() && complete)
+}
+
+fn main() {
+    // parse the command line args
+    let args = Args::parse();
+    let result = match args.command {
+        Some(Commands::Init {
+            ref input_file,
+            ref output_file,
+        }) => init_config(input_file, output_file.clone()).map(|_| true),
+        Some(Commands::Diagnostics {
+            ref input_file,
+            ref config_file,
+            odb_config,
+            ref output_file,
+        }) => run_diagnostics(
+            input_file,
+            config_file.clone(),
+            odb_config,
+            output_file.clone(),
+        ),
+        Some(Commands::Skim(ref skim_args)) => run_skim(skim_args),
+        Some(Commands::Generate(ref generate_args)) => run_generate(generate_args),
+        Some(Commands::Extract(ref extract_args)) => run_extract(extract_args),
+        Some(Commands::Diff(ref diff_args)) => {
+            let settings = output_diff::DiffSettings {
+                tolerance: diff_args.tolerance,
+                max_changed: diff_args.max_changed,
+                max_chi2: diff_args.max_chi2,
+            };
+            match output_diff::diff_outputs(&diff_args.output_a, &diff_args.output_b, &settings) {
+                Ok(false) => exit(error::EXIT_OUTPUTS_DIFFER),
+                result => result,
+            }
+        }
+        None => match input_runs(&args) {
+            Ok(runs) if runs.len() == 1 => convert(&args, &runs[0], ProgressBar::new_spinner()),
+            // a failed run doesn't stop the others, they are all reported at the end
+            Ok(runs) => {
+                let labels: Vec<String> = runs.iter().map(|r| r.label()).collect();
+                let converted =
+                    batch::run_batch(&labels, args.jobs, |i, pb| convert(&args, &runs[i], pb));
+                exit(if converted {
+                    0
+                } else {
+                    error::EXIT_BATCH_FAILED
+                });
+            }
+            Err(e) => Err(e),
+        },
+    };
+    match result {
+        Ok(true) => exit(0),
+        Ok(false) => exit(error::EXIT_SKIPPED_DATA),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            exit(e.exit_code());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every command takes the config the same way
+    #[test]
+    fn config_option() {
+        let config = |argv: &[&str]| match Args::try_parse_from(argv).unwrap().command {
+            Some(Commands::Diagnostics { config_file, .. }) => config_file,
+            Some(Commands::Skim(args)) => args.config_file,
+            Some(Commands::Extract(args)) => args.config_file,
+            Some(Commands::Generate(args)) => Some(args.config_file),
+            _ => panic!("{:?} is not a command with a config", argv),
+        };
+        for command in ["diagnostics", "skim", "extract"] {
+            let with = ["midas-converter", command, "run.mid", "-C", "c.toml"];
+            assert_eq!(config(&with), Some("c.toml".to_string()));
+            let long = ["midas-converter", command, "--config", "c.toml", "run.mid"];
+            assert_eq!(config(&long), Some("c.toml".to_string()));
+            assert_eq!(config(&["midas-converter", command, "run.mid"]), None);
+            // the config used to come after the input
+            assert!(
+                Args::try_parse_from(["midas-converter", command, "run.mid", "c.toml"]).is_err()
+            );
+        }
+        let generate = ["midas-converter", "generate", "-C", "c.toml", "-o", "s.mid"];
+        assert_eq!(config(&generate), Some("c.toml".to_string()));
+        assert!(Args::try_parse_from(["midas-converter", "generate", "c.toml"]).is_err());
+
+        let args = Args::try_parse_from(["midas-converter", "run.mid", "-C", "c.toml"]).unwrap();
+        assert_eq!(args.config_file, Some("c.toml".to_string()));
     }
 }
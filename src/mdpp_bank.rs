@@ -1,4 +1,6 @@
 use crate::bitmasks;
+use crate::error::DecodeError;
+use crate::event_sequence::Sequence;
 
 /* This is going to be a refactoring of the ideas present in the
 original mdpp16_scp.rs file.
@@ -23,11 +25,12 @@ pub struct MDPPHit {
     pub tdc_value: u32,
     pub trigger_dt_value: i64,
     pub pile_up: bool,
-    adc_filled: bool,
-    long_filled: bool,
-    short_filled: bool,
-    tdc_filled: bool,
-    trigger_dt_filled: bool,
+    // which of the values were actually in the data
+    pub adc_filled: bool,
+    pub long_filled: bool,
+    pub short_filled: bool,
+    pub tdc_filled: bool,
+    pub trigger_dt_filled: bool,
 }
 
 impl MDPPHit {
@@ -107,6 +110,10 @@ pub struct MDPPEvent {
     pub evt_timestamp: u64, // depends on setup either event counter or timestamp
     pub channels: Vec<u32>,
     pub channel_hits: Vec<MDPPHit>,
+    // event_sequence flags, set once the event is complete
+    pub flags: u8,
+    // sub-run file the event was completed in
+    pub subrun: u32,
     extended_ts: u32,
     extended_ts_filled: bool,
 }
@@ -118,6 +125,8 @@ impl MDPPEvent {
             evt_timestamp: 0,
             channels: Vec::with_capacity(32),
             channel_hits: Vec::with_capacity(32),
+            flags: 0,
+            subrun: 0,
             extended_ts: 0,
             extended_ts_filled: false,
         }
@@ -248,6 +257,8 @@ impl MDPPEvent {
 pub struct MDPPBank {
     pub events: Vec<MDPPEvent>,
     current_event: usize,
+    // completed events that have been through check_sequence
+    checked_events: usize,
     pub start: bool,
     pub stop: bool,
     pub junk_events: i64,
@@ -259,45 +270,99 @@ pub struct MDPPBank {
 impl MDPPBank {
     // create a new MDPP bank object that initialize with a chunk size.
     pub fn new() -> Self {
+        Self::with_capacity(10000000)
+    }
+
+    // the decoder workers only ever hold one batch of events
+    pub fn with_capacity(capacity: usize) -> Self {
         MDPPBank {
-            events: Vec::with_capacity(10000000),
+            events: Vec::with_capacity(capacity),
             current_event: 0,
+            checked_events: 0,
             start: false,
             stop: false,
             junk_events: 0,
         }
     }
 
-    pub fn parse(&mut self, bank_type: &str, nchannels: u32, bank: &[u8]) {
+    // Returns the number of words that were skipped. Without resilient the first
+    // bad word is returned as an error instead.
+    pub fn parse(
+        &mut self,
+        bank_type: &str,
+        nchannels: u32,
+        bank: &[u8],
+        resilient: bool,
+    ) -> Result<u64, DecodeError> {
+        Self::check_bank(bank_type, nchannels, bank)?;
+        let mut bad_words = 0;
         // start looping through the data 32 bit words
         for chunk in bank.chunks(4) {
             let temp = bitmasks::to_u32_le(chunk);
-            // get the identifier
-            let data_sig = temp >> 30 & bitmasks::TWO_BIT;
-            match data_sig {
-                // match again based on the type of module it is
-                0 => match bank_type {
-                    "qdc" => Self::parse_qdc(self, nchannels, temp),
-                    "scp" => Self::parse_scp(self, nchannels, temp),
-                    _ => panic!("Unknown module type: {}", bank_type),
-                },
-                1 => Self::parse_header(self, temp),
-                3 => Self::parse_end_event(self, temp),
-                _ => panic!("Invalid Data signature in bank!"),
+            if let Err(e) = self.parse_word(bank_type, nchannels, temp) {
+                if !resilient {
+                    return Err(e);
+                }
+                bad_words += 1;
             }
         }
+        Ok(bad_words)
+    }
+
+    // the errors that reject a bank before any of its words are looked at
+    pub fn check_bank(bank_type: &str, nchannels: u32, bank: &[u8]) -> Result<(), DecodeError> {
+        if bank_type != "qdc" && bank_type != "scp" {
+            return Err(DecodeError::UnknownModuleType(bank_type.to_string()));
+        }
+        if nchannels != 16 && nchannels != 32 {
+            return Err(DecodeError::UnsupportedChannels(nchannels));
+        }
+        if !bank.len().is_multiple_of(4) {
+            return Err(DecodeError::BankLength(bank.len()));
+        }
+        Ok(())
+    }
+
+    // byte offset of the first header word in the bank
+    pub fn first_header(bank: &[u8]) -> Option<usize> {
+        bank.chunks(4)
+            .position(|chunk| bitmasks::to_u32_le(chunk) >> 30 & bitmasks::TWO_BIT == 1)
+            .map(|i| i * 4)
+    }
+
+    fn parse_word(
+        &mut self,
+        bank_type: &str,
+        nchannels: u32,
+        word: u32,
+    ) -> Result<(), DecodeError> {
+        // get the identifier
+        let data_sig = word >> 30 & bitmasks::TWO_BIT;
+        match data_sig {
+            // match again based on the type of module it is
+            0 => match bank_type {
+                "qdc" => Self::parse_qdc(self, nchannels, word),
+                _ => Self::parse_scp(self, nchannels, word),
+            },
+            1 => {
+                Self::parse_header(self, word);
+                Ok(())
+            }
+            3 => {
+                Self::parse_end_event(self, word);
+                Ok(())
+            }
+            _ => Err(DecodeError::InvalidDataSignature(word)),
+        }
     }
 
     fn parse_header(&mut self, header: u32) {
         // here we create the event
         let module_id: u32 = header >> 16 & bitmasks::EIGHT_BIT;
         let _nwords: u32 = bitmasks::TEN_BIT;
-        if self.start {
-            // this only happens if the last event is ill formed, so get rid of it.
-            self.events.pop();
-            self.junk_events += 1;
-            return;
-        }
+        // this only happens if the last event is ill formed, so get rid of it
+        // and start over with this header.
+        self.drop_open_event();
         self.start = true;
         self.events.push(MDPPEvent::new(module_id));
     }
@@ -314,26 +379,34 @@ impl MDPPBank {
         self.start = false;
     }
 
+    // the event that data words get added to, which only exists between a header and an end of event
+    fn current(&mut self, data_word: u32) -> Result<&mut MDPPEvent, DecodeError> {
+        if !self.start {
+            return Err(DecodeError::DataOutsideEvent(data_word));
+        }
+        Ok(&mut self.events[self.current_event])
+    }
+
     // see if this is real data or dummy events/extended timestamp
-    fn check_subheader(&mut self, data_word: u32) -> bool {
+    fn check_subheader(&mut self, data_word: u32) -> Result<bool, DecodeError> {
         let subheader = data_word >> 28 & bitmasks::TWO_BIT;
         match subheader {
-            0 => false, // dummy event
-            1 => true,  // actual data
+            0 => Ok(false), // dummy event
+            1 => Ok(true),  // actual data
             2 => {
                 // extended timestamp
                 let ts = data_word & bitmasks::SIXTEEN_BIT;
-                self.events[self.current_event].extended_ts(ts);
-                false
+                self.current(data_word)?.extended_ts(ts);
+                Ok(false)
             }
 
-            _ => panic!("Invalid subheader in bank word!"),
+            _ => Err(DecodeError::InvalidSubheader(data_word)),
         }
     }
 
     // handles the 16/32 qdc logic
-    fn parse_qdc(&mut self, nchannels: u32, data_word: u32) {
-        if self.check_subheader(data_word) {
+    fn parse_qdc(&mut self, nchannels: u32, data_word: u32) -> Result<(), DecodeError> {
+        if self.check_subheader(data_word)? {
             // get the channel
             let channel_mask = if nchannels == 32 {
                 bitmasks::SEVEN_BIT
@@ -348,25 +421,26 @@ impl MDPPBank {
             // now check what kind of event we have
             match evt_type {
                 0 => {
-                    self.push_long(nchannels, channel, data_word);
+                    self.push_long(nchannels, channel, data_word)?;
                 }
 
                 1 => {
-                    self.push_tdc(nchannels, channel, data_word);
+                    self.push_tdc(nchannels, channel, data_word)?;
                 }
 
-                2 => self.push_trigger_dt(nchannels, channel, data_word),
+                2 => self.push_trigger_dt(nchannels, channel, data_word)?,
                 3 => {
-                    self.push_short(nchannels, channel, data_word);
+                    self.push_short(nchannels, channel, data_word)?;
                 }
-                _ => panic!("Unknown event type!!"),
+                _ => return Err(DecodeError::UnknownEventType(data_word)),
             }
         }
+        Ok(())
     }
 
     // handles the 16/32 scp logic
-    fn parse_scp(&mut self, nchannels: u32, data_word: u32) {
-        if self.check_subheader(data_word) {
+    fn parse_scp(&mut self, nchannels: u32, data_word: u32) -> Result<(), DecodeError> {
+        if self.check_subheader(data_word)? {
             // get the channel
             let channel_mask = if nchannels == 32 {
                 bitmasks::SEVEN_BIT
@@ -381,52 +455,140 @@ impl MDPPBank {
             // now check what kind of event we have
             match evt_type {
                 0 => {
-                    self.push_adc(nchannels, channel, data_word);
+                    self.push_adc(nchannels, channel, data_word)?;
                 }
 
                 1 => {
-                    self.push_tdc(nchannels, channel, data_word);
+                    self.push_tdc(nchannels, channel, data_word)?;
                 }
 
-                2 => self.push_trigger_dt(nchannels, channel, data_word),
-                _ => panic!("Unknown event type!!"),
+                2 => self.push_trigger_dt(nchannels, channel, data_word)?,
+                _ => return Err(DecodeError::UnknownEventType(data_word)),
             }
         }
+        Ok(())
     }
 
     // These update the events
-    fn push_adc(&mut self, nchannels: u32, channel: u32, data_word: u32) {
+    fn push_adc(
+        &mut self,
+        nchannels: u32,
+        channel: u32,
+        data_word: u32,
+    ) -> Result<(), DecodeError> {
         let adc = data_word & bitmasks::SIXTEEN_BIT;
         let pile_up = match nchannels {
             16 => (data_word >> 23 & bitmasks::ONE_BIT) != 0,
             32 => (data_word >> 24 & bitmasks::ONE_BIT) != 0,
-            _ => panic!("Invalid number of channels: {}", nchannels),
+            _ => return Err(DecodeError::UnsupportedChannels(nchannels)),
         };
-        self.events[self.current_event].add_adc(channel, adc, pile_up);
+        self.current(data_word)?.add_adc(channel, adc, pile_up);
+        Ok(())
     }
 
-    fn push_tdc(&mut self, _nchannels: u32, channel: u32, data_word: u32) {
+    fn push_tdc(
+        &mut self,
+        _nchannels: u32,
+        channel: u32,
+        data_word: u32,
+    ) -> Result<(), DecodeError> {
         let tdc = data_word & bitmasks::SIXTEEN_BIT;
-        self.events[self.current_event].add_tdc(channel, tdc);
+        self.current(data_word)?.add_tdc(channel, tdc);
+        Ok(())
     }
 
-    fn push_long(&mut self, _nchannels: u32, channel: u32, data_word: u32) {
+    fn push_long(
+        &mut self,
+        _nchannels: u32,
+        channel: u32,
+        data_word: u32,
+    ) -> Result<(), DecodeError> {
         let long_value = data_word & bitmasks::SIXTEEN_BIT;
-        self.events[self.current_event].add_long(channel, long_value);
+        self.current(data_word)?.add_long(channel, long_value);
+        Ok(())
     }
 
-    fn push_short(&mut self, _nchannels: u32, channel: u32, data_word: u32) {
+    fn push_short(
+        &mut self,
+        _nchannels: u32,
+        channel: u32,
+        data_word: u32,
+    ) -> Result<(), DecodeError> {
         let short_value = data_word & bitmasks::SIXTEEN_BIT;
-        self.events[self.current_event].add_short(channel, short_value);
+        self.current(data_word)?.add_short(channel, short_value);
+        Ok(())
     }
 
-    fn push_trigger_dt(&mut self, _nchannels: u32, channel: u32, data_word: u32) {
+    fn push_trigger_dt(
+        &mut self,
+        _nchannels: u32,
+        channel: u32,
+        data_word: u32,
+    ) -> Result<(), DecodeError> {
         let trigger_dt = data_word & bitmasks::SIXTEEN_BIT;
-        self.events[self.current_event].add_trigger_dt(channel, trigger_dt);
+        self.current(data_word)?.add_trigger_dt(channel, trigger_dt);
+        Ok(())
+    }
+
+    // only the events that have seen their end of event word
+    pub fn completed_events(&self) -> &[MDPPEvent] {
+        &self.events[..self.current_event]
+    }
+
+    // Run the newly completed events through the event counter check and mark the
+    // sub-run they were completed in, returns how many there were.
+    pub fn check_sequence(&mut self, counter: &mut Sequence, subrun: u32) -> u64 {
+        let new_events = &mut self.events[self.checked_events..self.current_event];
+        for event in new_events.iter_mut() {
+            event.flags = counter.add(event.evt_timestamp);
+            event.subrun = subrun;
+        }
+        self.checked_events = self.current_event;
+        new_events.len() as u64
+    }
+
+    /*
+    Carrying events across the batches of the parallel decoding (pipeline.rs).
+    A worker starts a batch with an empty bank, so the writer has to fit what it
+    found onto the end of the events it already has.
+    */
+
+    // a header came along before the end of event word
+    pub fn drop_open_event(&mut self) {
+        if self.start {
+            self.events.pop();
+            self.junk_events += 1;
+            self.start = false;
+        }
+    }
+
+    // events that a worker completed, nothing can be open here
+    pub fn append_completed(&mut self, events: impl Iterator<Item = MDPPEvent>) {
+        for event in events {
+            self.events.push(event);
+            self.current_event += 1;
+            self.stop = true;
+        }
+    }
+
+    // hand the event still waiting for its end of event word to the next batch
+    pub fn take_open_event(&mut self) -> Option<MDPPEvent> {
+        if !self.start {
+            return None;
+        }
+        self.start = false;
+        self.events.pop()
+    }
+
+    pub fn continue_event(&mut self, event: MDPPEvent) {
+        self.events.push(event);
+        self.start = true;
     }
 
     pub fn clear_data(&mut self) {
+        // keep the event that is still waiting for its end of event word
+        self.events.drain(..self.current_event);
         self.current_event = 0;
-        self.events.clear();
+        self.checked_events = 0;
     }
 }
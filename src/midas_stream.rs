@@ -0,0 +1,346 @@
+use crate::error::ConverterError;
+use crate::input_source::{InputSource, SourceReader};
+use crate::odb;
+use crate::run_metadata::RunMetadata;
+use crate::salvage::{self, Record, EOR_ID, EVENT_HEADER_SIZE, MAGIC};
+use midasio::read::event::EventView;
+use serde_json::Value;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/*
+Converting a run from a stream of bytes: a file the logger is still writing
+(--follow), stdin or a socket. See input_source.rs for where the bytes come
+from, this is the part that cuts them into events.
+
+Every read gives a chunk with the events that are all there, the sorter sorts
+them and the chunk is dropped, the open MDPP events carry over in the sorter
+like they do from one sub-run file to the next. The sorter flushes what it has
+every --flush-interval seconds as well as every chunk. The run is done when the
+EOR shows up. If the stream ends without one (a followed file that stops
+growing for --follow-timeout seconds, or the other end closing the pipe) the
+DAQ probably died, and the run is finished like a cut off file.
+*/
+
+fn io_error(source: &InputSource) -> impl Fn(std::io::Error) -> ConverterError + '_ {
+    move |e| ConverterError::Io(source.to_string(), e)
+}
+
+// what is only known once the stream has been read
+#[derive(Default)]
+struct StreamEnd {
+    final_timestamp: Option<u32>,
+    final_odb: Option<Vec<u8>>,
+    last_event_time: Option<u32>,
+    skipped_bytes: usize,
+}
+
+pub struct MidasStream {
+    source: InputSource,
+    run_number: u32,
+    initial_timestamp: u32,
+    initial_odb: Vec<u8>,
+    timeout: Duration,
+    // the reader and whatever came after the BOR, until chunks() takes them
+    reader: Mutex<Option<(SourceReader, Vec<u8>)>>,
+    end: Mutex<StreamEnd>,
+}
+
+impl MidasStream {
+    // waits for the BOR, a followed file may not have been created yet
+    pub fn open(source: &InputSource, timeout: Duration) -> Result<Self, ConverterError> {
+        let mut reader = source.open(timeout)?;
+        let mut contents = Vec::new();
+        loop {
+            if contents.len() >= 4
+                && (salvage::u16_at(&contents, 0) != salvage::BOR_ID
+                    || salvage::u16_at(&contents, 2) != MAGIC)
+            {
+                return Err(ConverterError::Midas(format!(
+                    "no begin of run record, {} does not look like a midas file",
+                    source
+                )));
+            }
+            if contents.len() >= EVENT_HEADER_SIZE {
+                let odb_size = salvage::u32_at(&contents, 12) as usize;
+                if contents.len() >= EVENT_HEADER_SIZE + odb_size {
+                    let (initial_timestamp, initial_odb, events_start) =
+                        salvage::odb_record(&contents, 0);
+                    let stream = MidasStream {
+                        source: source.clone(),
+                        run_number: salvage::u32_at(&contents, 4),
+                        initial_timestamp,
+                        initial_odb: initial_odb.to_vec(),
+                        timeout,
+                        reader: Mutex::new(None),
+                        end: Mutex::new(StreamEnd::default()),
+                    };
+                    contents.drain(..events_start);
+                    *stream.reader.lock().unwrap() = Some((reader, contents));
+                    return Ok(stream);
+                }
+            }
+            if !reader.read_more(&mut contents).map_err(io_error(source))? {
+                return Err(ConverterError::Midas(if source.is_stream() {
+                    format!("{} ended before the begin of run record", source)
+                } else {
+                    format!("gave up waiting for the begin of run record of {}", source)
+                }));
+            }
+        }
+    }
+
+    // the events in chunks as they arrive
+    pub fn chunks(&self) -> Result<StreamChunks<'_>, ConverterError> {
+        let (reader, pending) = self.reader.lock().unwrap().take().ok_or_else(|| {
+            ConverterError::Midas(format!("the events of {} were already read", self.source))
+        })?;
+        Ok(StreamChunks {
+            stream: self,
+            reader,
+            pending,
+            // the first events may have come with the BOR
+            first: true,
+            done: false,
+        })
+    }
+
+    // false if the run ended without an EOR or something had to be skipped
+    pub fn is_complete(&self) -> bool {
+        let end = self.end.lock().unwrap();
+        end.final_odb.is_some() && end.skipped_bytes == 0
+    }
+
+    // with the EOR once the run is done
+    pub fn run_metadata(&self) -> RunMetadata {
+        let end = self.end.lock().unwrap();
+        RunMetadata {
+            run_number: self.run_number,
+            start_timestamp: self.initial_timestamp,
+            stop_timestamp: end
+                .final_timestamp
+                .or(end.last_event_time)
+                .unwrap_or(self.initial_timestamp),
+            bor_odb: odb::parse_odb(&self.initial_odb),
+            eor_odb: end.final_odb.as_deref().map_or(Value::Null, odb::parse_odb),
+            salvaged: end.skipped_bytes > 0,
+        }
+    }
+
+    pub fn print_summary(&self) {
+        let end = self.end.lock().unwrap();
+        if end.final_odb.is_none() {
+            if self.source.is_stream() {
+                println!(
+                    "{} ended without an end of run record, the run was finished without it.",
+                    self.source
+                );
+            } else {
+                println!(
+                    "No end of run record after waiting {} s, the run was finished without it.",
+                    self.timeout.as_secs()
+                );
+            }
+        }
+        if end.skipped_bytes > 0 {
+            println!("Skipped {} bytes that were not events.", end.skipped_bytes);
+        }
+    }
+}
+
+// whole events cut from the stream, they own their bytes
+pub struct StreamChunk {
+    data: Vec<u8>,
+    offsets: Vec<(usize, usize)>,
+}
+
+impl StreamChunk {
+    // the sub-run is always 0
+    pub fn events(&self) -> impl Iterator<Item = (u32, EventView<'_>)> + Send {
+        self.offsets.iter().filter_map(|&(start, length)| {
+            EventView::try_from_le_bytes(&self.data[start..start + length])
+                .ok()
+                .map(|event| (0, event))
+        })
+    }
+}
+
+pub struct StreamChunks<'f> {
+    stream: &'f MidasStream,
+    reader: SourceReader,
+    // bytes read that are not a whole event yet
+    pending: Vec<u8>,
+    first: bool,
+    done: bool,
+}
+
+impl StreamChunks<'_> {
+    // Cuts the whole events at the start of pending into a chunk, the bool is
+    // false once the EOR has been read.
+    fn take_events(&mut self) -> (StreamChunk, bool) {
+        let mut offsets = Vec::new();
+        let mut offset = 0;
+        let mut skipped = 0;
+        let mut running = true;
+        while offset + EVENT_HEADER_SIZE <= self.pending.len() {
+            if salvage::u16_at(&self.pending, offset) == EOR_ID
+                && salvage::u16_at(&self.pending, offset + 2) == MAGIC
+            {
+                let odb_size = salvage::u32_at(&self.pending, offset + 12) as usize;
+                if offset + EVENT_HEADER_SIZE + odb_size > self.pending.len() {
+                    break;
+                }
+                let (final_timestamp, final_odb, end) = salvage::odb_record(&self.pending, offset);
+                let mut stream_end = self.stream.end.lock().unwrap();
+                stream_end.final_timestamp = Some(final_timestamp);
+                stream_end.final_odb = Some(final_odb.to_vec());
+                offset = end;
+                running = false;
+                break;
+            }
+            let record = match salvage::event_length(&self.pending, offset) {
+                // wait for the rest of it
+                Some(length) if offset + length > self.pending.len() => break,
+                Some(_) => salvage::record_at(&self.pending, offset),
+                None => None,
+            };
+            match record {
+                Some((Record::Event(_), length)) => {
+                    offsets.push((offset, length));
+                    offset += length;
+                }
+                // nothing to sort in a message
+                Some((Record::Message(_), length)) => offset += length,
+                // not an event, look for the next one like the salvage reader
+                None => {
+                    // a header needs the bank size and flags as well
+                    if offset + EVENT_HEADER_SIZE + 8 > self.pending.len() {
+                        break;
+                    }
+                    skipped += 1;
+                    offset += 1;
+                }
+            }
+        }
+
+        // the rest of pending is moved down and the events keep their bytes
+        let rest = self.pending.split_off(offset);
+        let chunk = StreamChunk {
+            data: std::mem::replace(&mut self.pending, rest),
+            offsets,
+        };
+        let mut stream_end = self.stream.end.lock().unwrap();
+        if let Some((_, event)) = chunk.events().last() {
+            stream_end.last_event_time = Some(event.timestamp());
+        }
+        stream_end.skipped_bytes += skipped;
+        (chunk, running)
+    }
+}
+
+impl Iterator for StreamChunks<'_> {
+    type Item = StreamChunk;
+
+    fn next(&mut self) -> Option<StreamChunk> {
+        loop {
+            if self.done {
+                return None;
+            }
+            let read = if self.first {
+                self.first = false;
+                Ok(true)
+            } else {
+                self.reader.read_more(&mut self.pending)
+            };
+            match read {
+                Ok(true) => {
+                    let (chunk, running) = self.take_events();
+                    self.done = !running;
+                    if !chunk.offsets.is_empty() {
+                        return Some(chunk);
+                    }
+                }
+                Ok(false) => {
+                    // whatever is left of an event that was cut off
+                    self.stream.end.lock().unwrap().skipped_bytes += self.pending.len();
+                    self.done = true;
+                }
+                Err(e) => {
+                    println!("Stopped reading {}: {}", self.stream.source, e);
+                    self.done = true;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::salvage::tests::b32a_run;
+    use std::io::Write;
+    use std::thread;
+
+    // the run comes in pieces, cut in the middle of the events and the message
+    fn write_in_pieces(mut w: impl Write, run: &[u8]) {
+        for piece in run.chunks(37) {
+            w.write_all(piece).unwrap();
+            w.flush().unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    // the serials and banks of every event of the stream
+    fn read_stream(source: &InputSource) -> (MidasStream, Vec<(u32, Vec<u8>)>) {
+        let stream = MidasStream::open(source, Duration::from_secs(2)).unwrap();
+        let mut events = Vec::new();
+        for chunk in stream.chunks().unwrap() {
+            for (_, event) in chunk.events() {
+                events.push((event.serial_number(), event.all_banks_slice().to_vec()));
+            }
+        }
+        (stream, events)
+    }
+
+    fn check_stream(stream: &MidasStream, events: &[(u32, Vec<u8>)]) {
+        let (run, offsets) = b32a_run();
+        let expected: Vec<(u32, Vec<u8>)> = offsets
+            .iter()
+            .enumerate()
+            .map(|(i, &(offset, length))| (i as u32, run[offset + 24..offset + length].to_vec()))
+            .collect();
+        assert_eq!(events, expected);
+        assert!(stream.is_complete());
+        assert_eq!(stream.run_metadata().stop_timestamp, 1010);
+    }
+
+    #[test]
+    fn follow_aligned_banks_and_messages() {
+        let path = std::env::temp_dir().join(format!("follow_{}.mid", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let writer = {
+            let path = path.clone();
+            thread::spawn(move || {
+                write_in_pieces(std::fs::File::create(path).unwrap(), &b32a_run().0)
+            })
+        };
+        let (stream, events) = read_stream(&InputSource::File(path.to_str().unwrap().to_string()));
+        writer.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        check_stream(&stream, &events);
+    }
+
+    #[test]
+    fn socket_aligned_banks_and_messages() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let writer = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            // the stream ends when the socket is dropped
+            write_in_pieces(socket, &b32a_run().0)
+        });
+        let (stream, events) = read_stream(&InputSource::Tcp(address));
+        writer.join().unwrap();
+        check_stream(&stream, &events);
+    }
+}
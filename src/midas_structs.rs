@@ -1,23 +1,106 @@
-use binread::{io::Cursor, BinRead};
-use std::io::BufRead;
-use std::io::BufReader;
+use crate::salvage::{BOR_ID, EOR_ID, MAGIC};
+use midasio::read::event::EventView;
+use std::io::{self, Write};
 
-#[derive(Debug, BinRead)]
+/*
+The headers of a midas file, for writing one (skim and generate). Everything
+is little endian, the way the DAQ writes it.
+
+  BOR: id 0x8000, magic, run number, time, odb size, odb
+  events: event header, bank header, the banks
+  banks: name, type, data size, data padded to 8 bytes (32 bit banks)
+  EOR: id 0x8001, magic, run number, time, odb size, odb
+*/
+
+// TID_DWORD, everything we write is 32 bit words
+const DWORD_TYPE: u32 = 6;
+
+#[derive(Debug)]
 pub struct EventHeader {
-    event_id: u16,
-    trigger_mask: u16,
-    serial_number: u32,
-    time_stamp: u32,
-    event_size: u32,
+    pub event_id: u16,
+    pub trigger_mask: u16,
+    pub serial_number: u32,
+    pub time_stamp: u32,
+    // bank header plus banks
+    pub event_size: u32,
+}
+
+impl EventHeader {
+    pub fn write(&self, w: &mut impl Write) -> io::Result<()> {
+        w.write_all(&self.event_id.to_le_bytes())?;
+        w.write_all(&self.trigger_mask.to_le_bytes())?;
+        w.write_all(&self.serial_number.to_le_bytes())?;
+        w.write_all(&self.time_stamp.to_le_bytes())?;
+        w.write_all(&self.event_size.to_le_bytes())
+    }
 }
 
-#[derive(Debug, BinRead)]
+#[derive(Debug)]
 pub struct BankHeader {
-    all_banks_size: u32,
-    flags: u32,
+    pub all_banks_size: u32,
+    pub flags: u32,
+}
+
+impl BankHeader {
+    pub fn write(&self, w: &mut impl Write) -> io::Result<()> {
+        w.write_all(&self.all_banks_size.to_le_bytes())?;
+        w.write_all(&self.flags.to_le_bytes())
+    }
+}
+
+// the BOR and EOR look like an event header with the odb dump as the data
+fn write_odb_record(
+    w: &mut impl Write,
+    id: u16,
+    run_number: u32,
+    time: u32,
+    odb: &[u8],
+) -> io::Result<()> {
+    EventHeader {
+        event_id: id,
+        trigger_mask: MAGIC,
+        serial_number: run_number,
+        time_stamp: time,
+        event_size: odb.len() as u32,
+    }
+    .write(w)?;
+    w.write_all(odb)
+}
+
+pub fn write_bor(w: &mut impl Write, run_number: u32, time: u32, odb: &[u8]) -> io::Result<()> {
+    write_odb_record(w, BOR_ID, run_number, time, odb)
+}
+
+pub fn write_eor(w: &mut impl Write, run_number: u32, time: u32, odb: &[u8]) -> io::Result<()> {
+    write_odb_record(w, EOR_ID, run_number, time, odb)
+}
+
+pub fn write_bank32(w: &mut impl Write, name: &str, words: &[u32]) -> io::Result<()> {
+    let size = 4 * words.len();
+    w.write_all(name.as_bytes())?;
+    w.write_all(&DWORD_TYPE.to_le_bytes())?;
+    w.write_all(&(size as u32).to_le_bytes())?;
+    for word in words {
+        w.write_all(&word.to_le_bytes())?;
+    }
+    w.write_all(&[0; 8][..(8 - size % 8) % 8])
 }
 
-#[derive(Debug, BinRead)]
-pub struct Byte {
-    all_banks_size: u8,
+// an event as it was read, the banks are copied as they are
+pub fn write_event(w: &mut impl Write, event: &EventView) -> io::Result<()> {
+    let banks = event.all_banks_slice();
+    EventHeader {
+        event_id: event.id(),
+        trigger_mask: event.trigger_mask(),
+        serial_number: event.serial_number(),
+        time_stamp: event.timestamp(),
+        event_size: banks.len() as u32 + 8,
+    }
+    .write(w)?;
+    BankHeader {
+        all_banks_size: banks.len() as u32,
+        flags: event.flags(),
+    }
+    .write(w)?;
+    w.write_all(banks)
 }
@@ -1,5 +1,12 @@
+use crate::error::ConverterError;
+use crate::histograms::{self, HistogramConfig, HistogramFormat};
+use crate::odb;
+use crate::output_options::OutputConfig;
+use crate::unknown_banks::UnknownBankPolicy;
 use serde_derive::Deserialize;
+use serde_json::Value;
 use std::fs;
+use std::time::Duration;
 
 #[derive(Debug, Deserialize, Clone)]
 #[allow(dead_code)]
@@ -9,21 +16,474 @@ pub struct Module {
     pub mod_type: String,
     pub nchannels: u32,
     setup_file: String,
+    // the end of event word holds a timestamp instead of an event counter
+    #[serde(default)]
+    pub timestamp: bool,
 }
 
+impl Module {
+    // for modules that did not come from a config file, like the init guesses
+    pub fn new(name: &str, mod_type: &str, nchannels: u32) -> Self {
+        Module {
+            name: name.to_string(),
+            address: String::new(),
+            mod_type: mod_type.to_string(),
+            nchannels,
+            setup_file: String::new(),
+            timestamp: false,
+        }
+    }
+}
+
+// ODB keys that get embedded into the parquet/feather files.
+// odb_keys are looked up in the begin-of-run dump, eor_odb_keys in the end-of-run one.
+#[derive(Debug, Deserialize, Clone)]
+pub struct MetadataConfig {
+    #[serde(default = "default_odb_keys")]
+    pub odb_keys: Vec<String>,
+    #[serde(default = "default_eor_odb_keys")]
+    pub eor_odb_keys: Vec<String>,
+}
+
+fn default_odb_keys() -> Vec<String> {
+    vec![
+        "/Runinfo/Run number".to_string(),
+        "/Runinfo/Start time".to_string(),
+        "/Experiment/Run parameters/Comment".to_string(),
+    ]
+}
+
+fn default_eor_odb_keys() -> Vec<String> {
+    vec!["/Runinfo/Stop time".to_string()]
+}
+
+impl Default for MetadataConfig {
+    fn default() -> Self {
+        MetadataConfig {
+            odb_keys: default_odb_keys(),
+            eor_odb_keys: default_eor_odb_keys(),
+        }
+    }
+}
+
+// Where the module settings live in the ODB. Every subdirectory of modules_path
+// is one module, and the *_key entries are the key names inside that subdirectory.
 #[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct OdbMapping {
+    pub modules_path: String,
+    pub name_key: String,
+    pub mod_type_key: String,
+    pub nchannels_key: String,
+    pub address_key: String,
+    pub setup_file_key: String,
+}
+
+impl Default for OdbMapping {
+    fn default() -> Self {
+        OdbMapping {
+            modules_path: "/Equipment/VME/Settings/Modules".to_string(),
+            name_key: "bank".to_string(),
+            mod_type_key: "type".to_string(),
+            nchannels_key: "nchannels".to_string(),
+            address_key: "address".to_string(),
+            setup_file_key: "setup_file".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
 pub struct Config {
+    #[serde(default)]
     pub modules: Vec<Module>,
+    #[serde(default)]
+    pub metadata: MetadataConfig,
+    #[serde(default)]
+    pub odb_config: OdbMapping,
+    #[serde(default)]
+    pub unknown_banks: UnknownBankPolicy,
+    #[serde(default)]
+    pub resilient: bool,
+    #[serde(default)]
+    pub flag_events: bool,
+    #[serde(default)]
+    pub histograms: Vec<HistogramConfig>,
+    #[serde(default = "histograms::default_histogram_formats")]
+    pub histogram_formats: Vec<HistogramFormat>,
+    // skip the hit table when only the spectra are wanted
+    #[serde(default)]
+    pub histograms_only: bool,
+    // parquet and feather writer settings
+    #[serde(default)]
+    pub output: OutputConfig,
+    // set when the run is read from several sub-run files, not from the config file
+    #[serde(skip)]
+    pub subruns: bool,
+    // decoder threads, from the command line
+    #[serde(skip)]
+    pub threads: usize,
+    // also flush this often when following a run that is still being written
+    #[serde(skip)]
+    pub flush_interval: Option<Duration>,
 }
 
-pub fn create_config(config_filename: &str) -> Config {
+pub fn create_config(config_filename: &str) -> Result<Config, ConverterError> {
     let contents = match fs::read_to_string(config_filename) {
         Ok(c) => c,
-        Err(_) => {
-            panic!();
+        Err(e) => {
+            return Err(ConverterError::Io(config_filename.to_string(), e));
+        }
+    };
+
+    let data: Config = toml::from_str(&contents)
+        .map_err(|e| ConverterError::Config(format!("{}: {}", config_filename, e)))?;
+    Ok(data)
+}
+
+// catch the configs that would otherwise blow up in the middle of a run
+fn validate_config(config: &Config) -> Result<(), ConverterError> {
+    for m in config.modules.iter() {
+        match m.mod_type.as_str() {
+            "scp" | "qdc" => {
+                if m.nchannels != 16 && m.nchannels != 32 {
+                    return Err(ConverterError::Config(format!(
+                        "module {} has {} channels, only 16 and 32 are supported",
+                        m.name, m.nchannels
+                    )));
+                }
+            }
+            "adc" | "v1730" => {}
+            _ => {
+                return Err(ConverterError::Config(format!(
+                    "module {} has unknown type {}",
+                    m.name, m.mod_type
+                )))
+            }
         }
+    }
+    let is_adc = config.modules.iter().any(|m| m.mod_type == "adc");
+    let is_v1730 = config.modules.iter().any(|m| m.mod_type == "v1730");
+    // Dont be stupid
+    if is_adc && is_v1730 {
+        return Err(ConverterError::Config(
+            "configuration has both adc and v1730 modules".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Build the module list from the frontend settings in the begin-of-run ODB.
+pub fn config_from_odb(bor_odb: &Value, mapping: &OdbMapping) -> Vec<Module> {
+    let modules_dir = match odb::odb_get(bor_odb, &mapping.modules_path).and_then(|v| v.as_object())
+    {
+        Some(dir) => dir,
+        None => return Vec::new(),
     };
 
-    let data: Config = toml::from_str(&contents).unwrap();
-    data
+    let mut modules = Vec::new();
+    for (dir_name, settings) in modules_dir.iter() {
+        // skip the /key and /last_written entries of JSON dumps
+        if !settings.is_object() {
+            continue;
+        }
+        let get_string = |key: &str| odb::odb_get(settings, key).map(odb::odb_value_string);
+        let mod_type = match get_string(&mapping.mod_type_key) {
+            Some(t) => t.to_lowercase(),
+            None => {
+                println!(
+                    "ODB module {} has no {} key, skipping it.",
+                    dir_name, mapping.mod_type_key
+                );
+                continue;
+            }
+        };
+        let nchannels =
+            match odb::odb_get(settings, &mapping.nchannels_key).and_then(|v| v.as_u64()) {
+                Some(n) => n as u32,
+                None => {
+                    println!(
+                        "ODB module {} has no {} key, skipping it.",
+                        dir_name, mapping.nchannels_key
+                    );
+                    continue;
+                }
+            };
+        // addresses are usually stored as a plain integer, but we keep them as hex strings
+        let address = match odb::odb_get(settings, &mapping.address_key) {
+            Some(Value::Number(n)) => format!("{:#X}", n.as_u64().unwrap_or(0)),
+            Some(v) => odb::odb_value_string(v),
+            None => String::new(),
+        };
+        modules.push(Module {
+            name: get_string(&mapping.name_key).unwrap_or(dir_name.to_string()),
+            address,
+            mod_type,
+            nchannels,
+            setup_file: get_string(&mapping.setup_file_key).unwrap_or_default(),
+            timestamp: false,
+        });
+    }
+    modules
+}
+
+// The other way around, the module settings as the frontend would leave them in
+// the ODB, so that config_from_odb gets the same modules back (generate).
+pub fn config_to_odb(modules: &[Module], mapping: &OdbMapping) -> Value {
+    let mut modules_dir = serde_json::Map::new();
+    for m in modules {
+        let mut settings = serde_json::Map::new();
+        settings.insert(mapping.name_key.clone(), Value::from(m.name.clone()));
+        settings.insert(
+            mapping.mod_type_key.clone(),
+            Value::from(m.mod_type.clone()),
+        );
+        settings.insert(mapping.nchannels_key.clone(), Value::from(m.nchannels));
+        settings.insert(mapping.address_key.clone(), Value::from(m.address.clone()));
+        settings.insert(
+            mapping.setup_file_key.clone(),
+            Value::from(m.setup_file.clone()),
+        );
+        modules_dir.insert(m.name.to_lowercase(), Value::Object(settings));
+    }
+    // build the directories of modules_path from the inside out
+    mapping
+        .modules_path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .rev()
+        .fold(Value::Object(modules_dir), |inner, name| {
+            let mut dir = serde_json::Map::new();
+            dir.insert(name.to_string(), inner);
+            Value::Object(dir)
+        })
+}
+
+/*
+Combine the ODB and the TOML config.
+
+With use_odb the ODB is the starting point, any module that is also in the
+TOML gets overridden by it, and modules only in the TOML are added. If the ODB
+has nothing to offer we fall back to the TOML.
+
+Without use_odb the TOML is used as is, but it is still checked against the ODB
+so that a stale config file doesn't silently mis-decode a run.
+*/
+pub fn resolve_config(
+    toml_config: Option<Config>,
+    bor_odb: &Value,
+    use_odb: bool,
+) -> Result<Config, ConverterError> {
+    // without either there are no modules, and every bank would be unknown
+    if toml_config.is_none() && !use_odb {
+        return Err(ConverterError::Config(
+            "no config file was given, pass one or use --odb-config to take the modules from the ODB"
+                .to_string(),
+        ));
+    }
+    let mut config = toml_config.clone().unwrap_or_default();
+    let odb_modules = config_from_odb(bor_odb, &config.odb_config);
+
+    if odb_modules.is_empty() {
+        if use_odb {
+            println!(
+                "No modules found in the ODB under {}, falling back to the config file.",
+                config.odb_config.modules_path
+            );
+        }
+        if toml_config.is_none() {
+            return Err(ConverterError::Config(
+                "no module configuration found in the ODB and no config file was given".to_string(),
+            ));
+        }
+        validate_config(&config)?;
+        return Ok(config);
+    }
+
+    // tell the user about every module the two sources disagree on
+    for m in config.modules.iter() {
+        match odb_modules.iter().find(|o| o.name == m.name) {
+            Some(o) if o.mod_type != m.mod_type || o.nchannels != m.nchannels => println!(
+                "Config file and ODB disagree on module {}: config has {} with {} channels, ODB has {} with {} channels.",
+                m.name, m.mod_type, m.nchannels, o.mod_type, o.nchannels
+            ),
+            Some(_) => {}
+            None => println!("Module {} is in the config file but not in the ODB.", m.name),
+        }
+    }
+
+    if use_odb {
+        let mut modules = odb_modules;
+        for m in config.modules.iter() {
+            match modules.iter_mut().find(|o| o.name == m.name) {
+                Some(o) => *o = m.clone(),
+                None => modules.push(m.clone()),
+            }
+        }
+        config.modules = modules;
+    }
+    validate_config(&config)?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn toml_config(text: &str) -> Config {
+        toml::from_str(text).unwrap()
+    }
+
+    // the frontend settings as the ODB has them, under the default path
+    fn bor_odb() -> Value {
+        json!({
+            "Equipment": {"VME": {"Settings": {"Modules": {
+                "qdc1": {"bank": "QDC1", "type": "QDC", "nchannels": 32, "address": 65536},
+                "scp1": {"bank": "SCP1", "type": "scp", "nchannels": 16, "setup_file": "scp1.txt"},
+                "last_written": 1000
+            }}}}
+        })
+    }
+
+    fn summary(config: &Config) -> Vec<(String, String, u32)> {
+        config
+            .modules
+            .iter()
+            .map(|m| (m.name.clone(), m.mod_type.clone(), m.nchannels))
+            .collect()
+    }
+
+    fn module(name: &str, mod_type: &str, nchannels: u32) -> (String, String, u32) {
+        (name.to_string(), mod_type.to_string(), nchannels)
+    }
+
+    const CONFIG: &str = r#"
+[[modules]]
+name = "QDC1"
+address = "0x20000"
+mod_type = "qdc"
+nchannels = 16
+setup_file = ""
+
+[[modules]]
+name = "SCP2"
+address = "0x30000"
+mod_type = "scp"
+nchannels = 32
+setup_file = ""
+timestamp = true
+"#;
+
+    #[test]
+    fn modules_from_the_odb() {
+        let modules = config_from_odb(&bor_odb(), &OdbMapping::default());
+        assert_eq!(modules.len(), 2);
+        assert_eq!(modules[0].name, "QDC1");
+        assert_eq!(modules[0].mod_type, "qdc");
+        assert_eq!(modules[0].address, "0x10000");
+        assert_eq!(modules[1].name, "SCP1");
+        assert_eq!(modules[1].setup_file, "scp1.txt");
+
+        // whatever config_to_odb writes comes back the same
+        let mapping = OdbMapping {
+            modules_path: "/Frontend/Modules".to_string(),
+            ..OdbMapping::default()
+        };
+        let written = config_to_odb(&modules, &mapping);
+        let back = config_from_odb(&written, &mapping);
+        assert_eq!(
+            summary(&Config {
+                modules: back,
+                ..Config::default()
+            }),
+            summary(&Config {
+                modules,
+                ..Config::default()
+            })
+        );
+        // nothing under the default path of that ODB
+        assert!(config_from_odb(&written, &OdbMapping::default()).is_empty());
+    }
+
+    #[test]
+    fn needs_a_source() {
+        assert!(matches!(
+            resolve_config(None, &bor_odb(), false),
+            Err(ConverterError::Config(_))
+        ));
+        // --odb-config on a run whose ODB has no modules
+        assert!(matches!(
+            resolve_config(None, &json!({}), true),
+            Err(ConverterError::Config(_))
+        ));
+    }
+
+    #[test]
+    fn config_file_only() {
+        // the config file is used as is, with or without modules in the ODB
+        for odb in [json!({}), bor_odb()] {
+            let config = resolve_config(Some(toml_config(CONFIG)), &odb, false).unwrap();
+            assert_eq!(
+                summary(&config),
+                vec![module("QDC1", "qdc", 16), module("SCP2", "scp", 32)]
+            );
+            assert!(config.modules[1].timestamp);
+        }
+        // --odb-config falls back to the config file when the ODB has nothing
+        let config = resolve_config(Some(toml_config(CONFIG)), &json!({}), true).unwrap();
+        assert_eq!(
+            summary(&config),
+            vec![module("QDC1", "qdc", 16), module("SCP2", "scp", 32)]
+        );
+    }
+
+    #[test]
+    fn odb_only() {
+        let config = resolve_config(None, &bor_odb(), true).unwrap();
+        assert_eq!(
+            summary(&config),
+            vec![module("QDC1", "qdc", 32), module("SCP1", "scp", 16)]
+        );
+        // the ODB modules go through the same checks as the config file
+        let mut odb = bor_odb();
+        odb["Equipment"]["VME"]["Settings"]["Modules"]["qdc1"]["nchannels"] = json!(8);
+        assert!(matches!(
+            resolve_config(None, &odb, true),
+            Err(ConverterError::Config(_))
+        ));
+    }
+
+    #[test]
+    fn config_file_overrides_the_odb() {
+        // QDC1 is in both and they disagree, the config file wins, SCP1 is only
+        // in the ODB and SCP2 only in the config file
+        let config = resolve_config(Some(toml_config(CONFIG)), &bor_odb(), true).unwrap();
+        assert_eq!(
+            summary(&config),
+            vec![
+                module("QDC1", "qdc", 16),
+                module("SCP1", "scp", 16),
+                module("SCP2", "scp", 32)
+            ]
+        );
+        assert_eq!(config.modules[0].address, "0x20000");
+        assert!(config.modules[2].timestamp);
+    }
+
+    #[test]
+    fn odb_path_from_the_config_file() {
+        let odb = json!({"Frontend": {"Modules": {"adc": {"name": "ADC1", "kind": "adc", "nchannels": 32}}}});
+        let config = toml_config(
+            r#"
+[odb_config]
+modules_path = "/Frontend/Modules"
+name_key = "name"
+mod_type_key = "kind"
+"#,
+        );
+        let config = resolve_config(Some(config), &odb, true).unwrap();
+        assert_eq!(summary(&config), vec![module("ADC1", "adc", 32)]);
+        // the default path doesn't find them
+        assert!(config_from_odb(&odb, &OdbMapping::default()).is_empty());
+    }
 }
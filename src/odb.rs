@@ -0,0 +1,361 @@
+use serde_json::{Map, Number, Value};
+
+/*
+The begin-of-run and end-of-run events of a MIDAS file carry a full dump of
+the ODB. Depending on how the logger is set up that dump is either XML or
+JSON, so both get parsed into the same serde_json::Value tree. The tree can
+be written straight to disk as a sidecar file and looked up with the usual
+ODB paths, i.e. "/Runinfo/Run number".
+
+ODB key names are case insensitive, so the lookups are too.
+*/
+
+pub fn parse_odb(dump: &[u8]) -> Value {
+    // the dumps are null terminated and padded, get rid of that first
+    let text = String::from_utf8_lossy(dump);
+    let text = text.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+
+    if text.starts_with('{') {
+        if let Ok(v) = serde_json::from_str::<Value>(text) {
+            return v;
+        }
+    } else if text.starts_with('<') {
+        if let Some(v) = parse_xml_odb(text) {
+            return v;
+        }
+    }
+    // we don't know what this is, so just hang on to the text
+    Value::String(text.to_string())
+}
+
+// find the value for a path like "/Runinfo/Run number"
+pub fn odb_get<'a>(odb: &'a Value, path: &str) -> Option<&'a Value> {
+    let mut current = odb;
+    for name in path.split('/').filter(|s| !s.is_empty()) {
+        let dir = current.as_object()?;
+        current = dir
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)?;
+    }
+    Some(current)
+}
+
+// strings are written without quotes, everything else as json
+pub fn odb_value_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.to_string(),
+        _ => value.to_string(),
+    }
+}
+
+/*
+---------- XML ODB ----------
+
+The XML dumps only use a tiny part of XML:
+
+<odb root="/">
+  <dir name="Runinfo">
+    <key name="Run number" type="INT">88</key>
+    <keyarray name="Thresholds" type="WORD" num_values="2">
+      <value index="0">100</value>
+      <value index="1">120</value>
+    </keyarray>
+  </dir>
+</odb>
+
+so we get away with a small hand rolled parser instead of pulling in an XML crate.
+*/
+
+struct XmlElement {
+    tag: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<XmlElement>,
+    text: String,
+}
+
+impl XmlElement {
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+struct XmlParser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> XmlParser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    // skip over everything up to and including the pattern
+    fn skip_past(&mut self, pattern: &str) -> Option<()> {
+        let index = self.rest().find(pattern)?;
+        self.pos += index + pattern.len();
+        Some(())
+    }
+
+    // skip the <?xml ... ?> declaration, comments and doctypes
+    fn skip_misc(&mut self) -> Option<()> {
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+            if rest.starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if rest.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if rest.starts_with("<!") {
+                self.skip_past(">")?;
+            } else {
+                return Some(());
+            }
+        }
+    }
+
+    fn parse_name(&mut self) -> String {
+        let rest = self.rest();
+        let end = rest
+            .find(|c: char| c.is_whitespace() || c == '>' || c == '/' || c == '=')
+            .unwrap_or(rest.len());
+        self.pos += end;
+        rest[..end].to_string()
+    }
+
+    fn parse_element(&mut self) -> Option<XmlElement> {
+        self.skip_misc()?;
+        if !self.rest().starts_with('<') {
+            return None;
+        }
+        self.pos += 1;
+        let mut element = XmlElement {
+            tag: self.parse_name(),
+            attributes: Vec::new(),
+            children: Vec::new(),
+            text: String::new(),
+        };
+
+        // attributes
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+            if rest.starts_with("/>") {
+                self.pos += 2;
+                return Some(element);
+            } else if rest.starts_with('>') {
+                self.pos += 1;
+                break;
+            }
+            let name = self.parse_name();
+            if name.is_empty() {
+                return None;
+            }
+            self.skip_whitespace();
+            if !self.rest().starts_with('=') {
+                return None;
+            }
+            self.pos += 1;
+            self.skip_whitespace();
+            let quote = self.rest().chars().next()?;
+            if quote != '"' && quote != '\'' {
+                return None;
+            }
+            self.pos += 1;
+            let end = self.rest().find(quote)?;
+            let value = unescape_xml(&self.rest()[..end]);
+            self.pos += end + 1;
+            element.attributes.push((name, value));
+        }
+
+        // content, which is either text or more elements
+        loop {
+            let rest = self.rest();
+            if rest.starts_with("</") {
+                self.skip_past(">")?;
+                return Some(element);
+            } else if rest.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if rest.starts_with('<') {
+                let child = self.parse_element()?;
+                element.children.push(child);
+            } else {
+                let end = rest.find('<')?;
+                element.text.push_str(&unescape_xml(&rest[..end]));
+                self.pos += end;
+            }
+        }
+    }
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// turn the text of a key into a value based on its ODB type
+fn typed_value(odb_type: &str, text: &str) -> Value {
+    let text = text.trim();
+    match odb_type {
+        "BYTE" | "WORD" | "DWORD" | "UINT8" | "UINT16" | "UINT32" | "UINT64" | "SBYTE"
+        | "SHORT" | "INT" | "INT8" | "INT16" | "INT32" | "INT64" | "BITFIELD" => {
+            if let Ok(v) = text.parse::<i64>() {
+                Value::Number(v.into())
+            } else if let Ok(v) = text.parse::<u64>() {
+                Value::Number(v.into())
+            } else {
+                Value::String(text.to_string())
+            }
+        }
+        "FLOAT" | "DOUBLE" => match text.parse::<f64>().ok().and_then(Number::from_f64) {
+            Some(n) => Value::Number(n),
+            None => Value::String(text.to_string()),
+        },
+        "BOOL" => Value::Bool(text == "y" || text == "1"),
+        _ => Value::String(text.to_string()),
+    }
+}
+
+fn xml_to_value(dir: &XmlElement) -> Value {
+    let mut map = Map::new();
+    for child in dir.children.iter() {
+        let name = match child.attribute("name") {
+            Some(n) => n.to_string(),
+            None => continue,
+        };
+        let odb_type = child.attribute("type").unwrap_or("STRING");
+        let value = match child.tag.as_str() {
+            "dir" => xml_to_value(child),
+            "key" => typed_value(odb_type, &child.text),
+            "keyarray" => {
+                // values carry their own index, but they are always written in order
+                Value::Array(
+                    child
+                        .children
+                        .iter()
+                        .filter(|v| v.tag == "value")
+                        .map(|v| typed_value(odb_type, &v.text))
+                        .collect(),
+                )
+            }
+            _ => continue,
+        };
+        map.insert(name, value);
+    }
+    Value::Object(map)
+}
+
+fn parse_xml_odb(text: &str) -> Option<Value> {
+    let mut parser = XmlParser { text, pos: 0 };
+    let root = parser.parse_element()?;
+    if root.tag != "odb" {
+        return None;
+    }
+    Some(xml_to_value(&root))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const DUMP: &str = r#"<?xml version="1.0" encoding="ISO-8859-1"?>
+<!-- created by odbxx -->
+<odb root="/" filename="run00088.mid">
+  <dir name="Runinfo">
+    <key name="Run number" type="INT">88</key>
+    <key name="Start time" type="STRING" size="32">Mon Oct 19 10:14:51 2026</key>
+  </dir>
+  <dir name="Equipment">
+    <dir name="VME">
+      <dir name="Settings">
+        <key name="Enabled" type="BOOL">y</key>
+        <key name="Gain" type="DOUBLE">1.5</key>
+        <key name="Comment" type="STRING" size="64">a &lt;b&gt; &amp; &quot;c&quot;</key>
+        <keyarray name="Thresholds" type="WORD" num_values="3">
+          <value index="0">100</value>
+          <value index="1">120</value>
+          <value index="2">140</value>
+        </keyarray>
+        <keyarray name="Names" type="STRING" num_values="2" size="16">
+          <value index="0">QDC1</value>
+          <value index="1">SCP1</value>
+        </keyarray>
+        <keyarray name="Empty" type="INT" num_values="0"/>
+      </dir>
+    </dir>
+  </dir>
+</odb>
+"#;
+
+    #[test]
+    fn nested_keys() {
+        let odb = parse_odb(format!("{DUMP}\0\0\0").as_bytes());
+        assert_eq!(odb_get(&odb, "/Runinfo/Run number"), Some(&json!(88)));
+        assert_eq!(
+            odb_get(&odb, "/Runinfo/Start time").map(odb_value_string),
+            Some("Mon Oct 19 10:14:51 2026".to_string())
+        );
+        let settings = odb_get(&odb, "/Equipment/VME/Settings").unwrap();
+        assert_eq!(settings["Enabled"], json!(true));
+        assert_eq!(settings["Gain"], json!(1.5));
+        assert_eq!(settings["Comment"], json!("a <b> & \"c\""));
+        // the lookups don't care about case, missing keys are None
+        assert_eq!(
+            odb_get(&odb, "/equipment/vme/settings/gain"),
+            Some(&json!(1.5))
+        );
+        assert_eq!(odb_get(&odb, "/Equipment/VME/Missing"), None);
+        assert_eq!(odb_get(&odb, "/Runinfo/Run number/deeper"), None);
+    }
+
+    #[test]
+    fn arrays() {
+        let odb = parse_odb(DUMP.as_bytes());
+        let settings = odb_get(&odb, "/Equipment/VME/Settings").unwrap();
+        assert_eq!(settings["Thresholds"], json!([100, 120, 140]));
+        assert_eq!(settings["Names"], json!(["QDC1", "SCP1"]));
+        assert_eq!(settings["Empty"], json!([]));
+        assert_eq!(odb_value_string(&settings["Thresholds"]), "[100,120,140]");
+    }
+
+    #[test]
+    fn json_dumps() {
+        let odb = parse_odb(br#"{"Runinfo": {"Run number": 88}}"#);
+        assert_eq!(odb_get(&odb, "/Runinfo/Run number"), Some(&json!(88)));
+    }
+
+    #[test]
+    fn malformed_input() {
+        for dump in [
+            // cut off in the middle
+            &DUMP[..DUMP.len() / 2],
+            // not an odb dump
+            "<html><body/></html>",
+            // attribute without a value, and without quotes
+            r#"<odb><key name>1</key></odb>"#,
+            r#"<odb><key name=Gain type="INT">1</key></odb>"#,
+            // comment that never ends
+            "<!-- <odb/>",
+            "{\"Runinfo\": ",
+            "just some text",
+        ] {
+            // what we can't parse is kept as text
+            assert_eq!(parse_odb(dump.as_bytes()), json!(dump.trim()), "{dump}");
+        }
+        // keys without a name or with unknown tags are skipped
+        let odb = parse_odb(br#"<odb><key type="INT">1</key><link name="l">/x</link><key name="k" type="INT">x</key></odb>"#);
+        assert_eq!(odb, json!({"k": "x"}));
+    }
+}
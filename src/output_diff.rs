@@ -0,0 +1,560 @@
+use crate::error::ConverterError;
+use polars::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+/*
+Comparing two converted outputs of the same run (diff), to see if a config
+change or a new version of the converter changed the physics.
+
+The outputs can be parquet, feather or csv files of any of the sorters, in any
+mix. A hit is matched by module, channel and timestamp (evt_ts, or coarse_time
+for the v1730), and if the same key shows up more than once the n-th one in a
+is matched with the n-th one in b. Matched hits are changed if any of the other
+columns both files have differ by more than the tolerance. The module and
+channel columns can be dictionary encoded, everything is compared as numbers.
+
+On top of that every value column is histogrammed per module and channel (1024
+bins over the range of both, zeros left out since those are values that were
+not in the data) and the two histograms are compared with
+chi2 = sum (a - b)^2 / (a + b) over the bins that have anything in them. chi2/ndf
+is 0 for the same spectrum, and around 1 for two independent samples of it.
+
+The outputs differ if a module has more than max_changed (a fraction of its
+hits) added, removed or changed hits, or a histogram has a chi2/ndf above
+max_chi2.
+*/
+
+pub struct DiffSettings {
+    // largest difference between two values that counts as the same
+    pub tolerance: f64,
+    // fraction of the hits of a module that may be different
+    pub max_changed: f64,
+    pub max_chi2: f64,
+}
+
+const NBINS: usize = 1024;
+// these say where the hit is, not what was measured
+const TIMESTAMP_COLUMNS: [&str; 2] = ["evt_ts", "coarse_time"];
+// written depending on the command line, not on the data
+const SKIPPED_COLUMNS: [&str; 2] = ["flags", "subrun"];
+
+fn diff_error(path: &str) -> impl Fn(PolarsError) -> ConverterError + '_ {
+    move |e| ConverterError::Output(format!("{}: {}", path, e))
+}
+
+pub fn read_output(path: &str) -> Result<DataFrame, ConverterError> {
+    if !Path::new(path).is_file() {
+        return Err(ConverterError::Io(
+            path.to_string(),
+            std::io::Error::from(std::io::ErrorKind::NotFound),
+        ));
+    }
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("");
+    let lf = match extension {
+        "parquet" => LazyFrame::scan_parquet(path, Default::default()),
+        "feather" | "arrow" | "ipc" => LazyFrame::scan_ipc(path, Default::default()),
+        "csv" => LazyCsvReader::new(path).has_header(true).finish(),
+        _ => {
+            return Err(ConverterError::Config(format!(
+                "{} is not a .parquet, .feather or .csv file",
+                path
+            )))
+        }
+    };
+    lf.and_then(|lf| lf.collect()).map_err(diff_error(path))
+}
+
+// any column as numbers, dictionary encoded ones hold the numbers as strings
+fn numbers(df: &DataFrame, name: &str, path: &str) -> Result<Vec<f64>, ConverterError> {
+    let column = df.column(name).map_err(diff_error(path))?;
+    let column = match column.dtype() {
+        DataType::Categorical(..) => column.cast(&DataType::Utf8).map_err(diff_error(path))?,
+        _ => column.clone(),
+    };
+    let column = column.cast(&DataType::Float64).map_err(diff_error(path))?;
+    Ok(column
+        .f64()
+        .map_err(diff_error(path))?
+        .into_iter()
+        .map(|v| v.unwrap_or(f64::NAN))
+        .collect())
+}
+
+// the columns of one output, with the key of every row
+struct Hits {
+    keys: Vec<(i64, i64, i64, u32)>,
+    values: Vec<Vec<f64>>,
+}
+
+impl Hits {
+    fn new(
+        df: &DataFrame,
+        path: &str,
+        timestamp: &str,
+        value_columns: &[String],
+    ) -> Result<Self, ConverterError> {
+        let modules = if df.get_column_names().contains(&"module") {
+            numbers(df, "module", path)?
+        } else {
+            vec![0.0; df.height()]
+        };
+        let channels = numbers(df, "channel", path)?;
+        let timestamps = numbers(df, timestamp, path)?;
+        // the n-th time the same module, channel and timestamp shows up
+        let mut seen: HashMap<(i64, i64, i64), u32> = HashMap::new();
+        let keys = modules
+            .iter()
+            .zip(channels.iter())
+            .zip(timestamps.iter())
+            .map(|((&m, &c), &t)| {
+                let key = (m as i64, c as i64, t as i64);
+                let n = seen.entry(key).or_insert(0);
+                *n += 1;
+                (key.0, key.1, key.2, *n)
+            })
+            .collect();
+        let values = value_columns
+            .iter()
+            .map(|c| numbers(df, c, path))
+            .collect::<Result<_, _>>()?;
+        Ok(Hits { keys, values })
+    }
+
+    fn row(&self, i: usize) -> impl Iterator<Item = f64> + '_ {
+        self.values.iter().map(move |c| c[i])
+    }
+}
+
+#[derive(Default)]
+struct ModuleDiff {
+    hits_a: u64,
+    hits_b: u64,
+    removed: u64,
+    added: u64,
+    changed: u64,
+    // per value column, how many matched hits differ and by how much at most
+    column_changes: Vec<(u64, f64)>,
+    // the first few of each, to have something to look at
+    examples: Vec<String>,
+}
+
+impl ModuleDiff {
+    fn differences(&self) -> u64 {
+        self.removed + self.added + self.changed
+    }
+
+    fn fraction(&self) -> f64 {
+        self.differences() as f64 / self.hits_a.max(self.hits_b).max(1) as f64
+    }
+}
+
+struct HistogramDiff {
+    module: i64,
+    channel: i64,
+    column: String,
+    entries: (u64, u64),
+    means: (f64, f64),
+    chi2_ndf: f64,
+}
+
+// per module, channel and value column, the non zero values
+type Spectra = BTreeMap<(i64, i64, usize), Vec<f64>>;
+
+fn spectra(hits: &Hits, histogrammed: &[bool]) -> Spectra {
+    let mut spectra = Spectra::new();
+    for (i, &(module, channel, _, _)) in hits.keys.iter().enumerate() {
+        for (c, value) in hits.row(i).enumerate() {
+            if histogrammed[c] && value != 0.0 && value.is_finite() {
+                spectra.entry((module, channel, c)).or_default().push(value);
+            }
+        }
+    }
+    spectra
+}
+
+fn compare_spectra(a: &[f64], b: &[f64]) -> (f64, f64, f64) {
+    let mean = |v: &[f64]| {
+        if v.is_empty() {
+            0.0
+        } else {
+            v.iter().sum::<f64>() / v.len() as f64
+        }
+    };
+    let (min, max) = a
+        .iter()
+        .chain(b.iter())
+        .fold((f64::MAX, f64::MIN), |(lo, hi), &v| (lo.min(v), hi.max(v)));
+    let width = ((max - min) / NBINS as f64).max(f64::MIN_POSITIVE);
+    let fill = |v: &[f64]| {
+        let mut bins = vec![0u64; NBINS];
+        for &x in v {
+            bins[(((x - min) / width) as usize).min(NBINS - 1)] += 1;
+        }
+        bins
+    };
+    let (bins_a, bins_b) = (fill(a), fill(b));
+    let (mut chi2, mut ndf) = (0.0, 0);
+    for (&x, &y) in bins_a.iter().zip(bins_b.iter()) {
+        if x + y > 0 {
+            chi2 += (x as f64 - y as f64).powi(2) / (x + y) as f64;
+            ndf += 1;
+        }
+    }
+    (mean(a), mean(b), chi2 / ndf.max(1) as f64)
+}
+
+fn describe(key: &(i64, i64, i64, u32), timestamp: &str) -> String {
+    format!("channel {} {} {}", key.1, timestamp, key.2)
+}
+
+pub fn diff_outputs(
+    path_a: &str,
+    path_b: &str,
+    settings: &DiffSettings,
+) -> Result<bool, ConverterError> {
+    let df_a = read_output(path_a)?;
+    let df_b = read_output(path_b)?;
+    let names_a = df_a.get_column_names();
+    let names_b = df_b.get_column_names();
+    let timestamp = TIMESTAMP_COLUMNS
+        .iter()
+        .find(|c| names_a.contains(c) && names_b.contains(c))
+        .ok_or_else(|| {
+            ConverterError::Config(format!(
+                "{} and {} don't have the same timestamp column (evt_ts or coarse_time), are they from the same kind of modules?",
+                path_a, path_b
+            ))
+        })?;
+    for (path, names) in [(path_a, &names_a), (path_b, &names_b)] {
+        if !names.contains(&"channel") {
+            return Err(ConverterError::Config(format!(
+                "{} has no channel column",
+                path
+            )));
+        }
+    }
+    for (path, names, other) in [(path_a, &names_a, &names_b), (path_b, &names_b, &names_a)] {
+        for name in names.iter().filter(|n| !other.contains(n)) {
+            println!("Column {} is only in {}, it is not compared.", name, path);
+        }
+    }
+    let value_columns: Vec<String> = names_a
+        .iter()
+        .filter(|n| names_b.contains(n))
+        .filter(|n| !["module", "channel", *timestamp].contains(n))
+        .filter(|n| !SKIPPED_COLUMNS.contains(n))
+        .map(|n| n.to_string())
+        .collect();
+    // flags like pileup are compared but not histogrammed
+    let histogrammed: Vec<bool> = value_columns
+        .iter()
+        .map(|c| {
+            df_a.column(c)
+                .map(|s| s.dtype() != &DataType::Boolean)
+                .unwrap_or(false)
+        })
+        .collect();
+
+    let hits_a = Hits::new(&df_a, path_a, timestamp, &value_columns)?;
+    let hits_b = Hits::new(&df_b, path_b, timestamp, &value_columns)?;
+    let index_b: HashMap<(i64, i64, i64, u32), usize> = hits_b
+        .keys
+        .iter()
+        .enumerate()
+        .map(|(i, &key)| (key, i))
+        .collect();
+
+    let mut modules: BTreeMap<i64, ModuleDiff> = BTreeMap::new();
+    let module_diff = |modules: &mut BTreeMap<i64, ModuleDiff>, module: i64| {
+        modules.entry(module).or_insert_with(|| ModuleDiff {
+            column_changes: vec![(0, 0.0); value_columns.len()],
+            ..Default::default()
+        });
+    };
+    let mut matched = vec![false; hits_b.keys.len()];
+    for (i, key) in hits_a.keys.iter().enumerate() {
+        module_diff(&mut modules, key.0);
+        let diff = modules.get_mut(&key.0).unwrap();
+        diff.hits_a += 1;
+        let Some(&j) = index_b.get(key) else {
+            diff.removed += 1;
+            if diff.examples.len() < 10 {
+                diff.examples
+                    .push(format!("removed {}", describe(key, timestamp)));
+            }
+            continue;
+        };
+        matched[j] = true;
+        let mut changes = Vec::new();
+        for (c, (x, y)) in hits_a.row(i).zip(hits_b.row(j)).enumerate() {
+            let difference = (x - y).abs();
+            // NaN is a missing value, the same if both are missing
+            let same = difference <= settings.tolerance || (x.is_nan() && y.is_nan());
+            if !same {
+                let (count, largest) = &mut diff.column_changes[c];
+                *count += 1;
+                *largest = largest.max(difference);
+                changes.push(format!("{} {} -> {}", value_columns[c], x, y));
+            }
+        }
+        if !changes.is_empty() {
+            diff.changed += 1;
+            if diff.examples.len() < 10 {
+                diff.examples.push(format!(
+                    "changed {}: {}",
+                    describe(key, timestamp),
+                    changes.join(", ")
+                ));
+            }
+        }
+    }
+    for (j, key) in hits_b.keys.iter().enumerate() {
+        module_diff(&mut modules, key.0);
+        let diff = modules.get_mut(&key.0).unwrap();
+        diff.hits_b += 1;
+        if !matched[j] {
+            diff.added += 1;
+            if diff.examples.len() < 10 {
+                diff.examples
+                    .push(format!("added {}", describe(key, timestamp)));
+            }
+        }
+    }
+
+    let spectra_a = spectra(&hits_a, &histogrammed);
+    let spectra_b = spectra(&hits_b, &histogrammed);
+    let mut histograms: Vec<HistogramDiff> = Vec::new();
+    let no_values = Vec::new();
+    let mut spectrum_keys: Vec<&(i64, i64, usize)> =
+        spectra_a.keys().chain(spectra_b.keys()).collect();
+    spectrum_keys.sort();
+    spectrum_keys.dedup();
+    for key in spectrum_keys {
+        let a = spectra_a.get(key).unwrap_or(&no_values);
+        let b = spectra_b.get(key).unwrap_or(&no_values);
+        let (mean_a, mean_b, chi2_ndf) = compare_spectra(a, b);
+        if chi2_ndf > 0.0 {
+            histograms.push(HistogramDiff {
+                module: key.0,
+                channel: key.1,
+                column: value_columns[key.2].clone(),
+                entries: (a.len() as u64, b.len() as u64),
+                means: (mean_a, mean_b),
+                chi2_ndf,
+            });
+        }
+    }
+    histograms.sort_by(|x, y| y.chi2_ndf.total_cmp(&x.chi2_ndf));
+
+    println!("a: {}\nb: {}\n", path_a, path_b);
+    println!(
+        "{:<8} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "module", "hits a", "hits b", "removed", "added", "changed"
+    );
+    for (module, diff) in modules.iter() {
+        println!(
+            "{:<8} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            module, diff.hits_a, diff.hits_b, diff.removed, diff.added, diff.changed
+        );
+    }
+    for (module, diff) in modules.iter().filter(|(_, d)| d.differences() > 0) {
+        println!("\nmodule {}:", module);
+        for (c, (count, largest)) in diff.column_changes.iter().enumerate() {
+            if *count > 0 {
+                println!(
+                    "  {}: {} changed, largest difference {}",
+                    value_columns[c], count, largest
+                );
+            }
+        }
+        for example in diff.examples.iter() {
+            println!("  {}", example);
+        }
+    }
+    if !histograms.is_empty() {
+        println!("\nHistograms that differ ({} of them):", histograms.len());
+        println!(
+            "{:<8} {:>8} {:<12} {:>10} {:>10} {:>12} {:>12} {:>10}",
+            "module", "channel", "column", "entries a", "entries b", "mean a", "mean b", "chi2/ndf"
+        );
+        for h in histograms.iter().take(20) {
+            println!(
+                "{:<8} {:>8} {:<12} {:>10} {:>10} {:>12.2} {:>12.2} {:>10.3}",
+                h.module,
+                h.channel,
+                h.column,
+                h.entries.0,
+                h.entries.1,
+                h.means.0,
+                h.means.1,
+                h.chi2_ndf
+            );
+        }
+        if histograms.len() > 20 {
+            println!("...");
+        }
+    }
+
+    let too_many: Vec<i64> = modules
+        .iter()
+        .filter(|(_, d)| d.differences() > 0 && d.fraction() > settings.max_changed)
+        .map(|(&m, _)| m)
+        .collect();
+    let bad_histograms = histograms
+        .iter()
+        .filter(|h| h.chi2_ndf > settings.max_chi2)
+        .count();
+    println!();
+    if too_many.is_empty() && bad_histograms == 0 {
+        println!("The outputs are the same within the tolerances.");
+        return Ok(true);
+    }
+    if !too_many.is_empty() {
+        println!(
+            "Modules {:?} have more than {}% of their hits different.",
+            too_many,
+            100.0 * settings.max_changed
+        );
+    }
+    if bad_histograms > 0 {
+        println!(
+            "{} histograms have a chi2/ndf above {}.",
+            bad_histograms, settings.max_chi2
+        );
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::tests::{
+        convert_run, generate_run, settings, test_dir, MDPP_CONFIG, V1730_CONFIG,
+    };
+    use std::fs;
+
+    fn strict() -> DiffSettings {
+        DiffSettings {
+            tolerance: 0.0,
+            max_changed: 0.0,
+            max_chi2: 1.0,
+        }
+    }
+
+    // converts a generated run to <dir>/out.csv and out.parquet
+    fn converted_run(name: &str, config: &str) -> String {
+        let dir = test_dir(name);
+        let (config_file, run, _) = generate_run(&dir, config, &settings(0.0));
+        let output = dir.join("out").to_str().unwrap().to_string();
+        assert!(convert_run(
+            &run,
+            &config_file,
+            &output,
+            &["-p", "--dictionary"]
+        ));
+        output
+    }
+
+    // a copy of the csv with edit applied to every row, rows it returns false for are left out
+    fn edit_csv(input: &str, output: &str, edit: impl Fn(usize, &mut [String]) -> bool) {
+        let contents = fs::read_to_string(input).unwrap();
+        let mut lines = contents.lines();
+        let mut edited = vec![lines.next().unwrap().to_string()];
+        for (i, line) in lines.enumerate() {
+            let mut row: Vec<String> = line.split(',').map(|v| v.to_string()).collect();
+            if edit(i, &mut row) {
+                edited.push(row.join(","));
+            }
+        }
+        fs::write(output, edited.join("\n") + "\n").unwrap();
+    }
+
+    fn add(value: &mut String, x: f64) {
+        *value = (value.parse::<f64>().unwrap() + x).to_string();
+    }
+
+    fn column(csv: &str, name: &str) -> usize {
+        let contents = fs::read_to_string(csv).unwrap();
+        let header = contents.lines().next().unwrap();
+        header.split(',').position(|c| c == name).unwrap()
+    }
+
+    #[test]
+    fn identical_outputs() {
+        let output = converted_run("diff_identical", MDPP_CONFIG);
+        let csv = format!("{}.csv", output);
+        let parquet = format!("{}.parquet", output);
+        // exit code 0
+        assert!(diff_outputs(&csv, &csv, &strict()).unwrap());
+        // the module and channel columns are dictionary encoded in the parquet file
+        assert!(diff_outputs(&csv, &parquet, &strict()).unwrap());
+        assert!(diff_outputs(&parquet, &csv, &strict()).unwrap());
+    }
+
+    #[test]
+    fn differing_outputs() {
+        let output = converted_run("diff_differing", MDPP_CONFIG);
+        let csv = format!("{}.csv", output);
+        let parquet = format!("{}.parquet", output);
+        let tdc = column(&csv, "tdc");
+
+        // a tenth of the hits changed, main exits with EXIT_OUTPUTS_DIFFER (10)
+        let changed = format!("{}_changed.csv", output);
+        edit_csv(&csv, &changed, |i, row| {
+            if i % 10 == 0 {
+                add(&mut row[tdc], 1.0);
+            }
+            true
+        });
+        assert!(!diff_outputs(&parquet, &changed, &strict()).unwrap());
+        // within max_changed, and off by one doesn't change the spectra much
+        let lenient = DiffSettings {
+            max_changed: 0.2,
+            ..strict()
+        };
+        assert!(diff_outputs(&parquet, &changed, &lenient).unwrap());
+        // or the tolerance covers it
+        let tolerant = DiffSettings {
+            tolerance: 1.0,
+            ..strict()
+        };
+        assert!(diff_outputs(&parquet, &changed, &tolerant).unwrap());
+
+        // hits that are missing can't be tolerated away
+        let removed = format!("{}_removed.csv", output);
+        edit_csv(&csv, &removed, |i, _| i % 10 != 0);
+        assert!(!diff_outputs(&parquet, &removed, &tolerant).unwrap());
+        assert!(!diff_outputs(&removed, &parquet, &tolerant).unwrap());
+    }
+
+    #[test]
+    fn float_tolerance() {
+        let output = converted_run("diff_float", V1730_CONFIG);
+        let csv = format!("{}.csv", output);
+        let time = column(&csv, "time");
+        // the fine time is a fraction of the coarse time, move every hit by a bit of that
+        let shifted = format!("{}_shifted.csv", output);
+        edit_csv(&csv, &shifted, |_, row| {
+            add(&mut row[time], 0.125);
+            true
+        });
+        let parquet = format!("{}.parquet", output);
+        assert!(!diff_outputs(&parquet, &shifted, &strict()).unwrap());
+        for tolerance in [0.25, 0.5] {
+            let settings = DiffSettings {
+                tolerance,
+                ..strict()
+            };
+            assert!(diff_outputs(&parquet, &shifted, &settings).unwrap());
+        }
+        let settings = DiffSettings {
+            tolerance: 0.1,
+            ..strict()
+        };
+        assert!(!diff_outputs(&parquet, &shifted, &settings).unwrap());
+    }
+}
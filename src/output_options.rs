@@ -0,0 +1,330 @@
+use crate::error::ConverterError;
+use clap::ValueEnum;
+use polars::prelude::*;
+use serde_derive::Deserialize;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/*
+Settings for the parquet and feather writers, from the [output] table of the
+config file. Any of them can be overridden on the command line.
+
+[output]
+parquet_compression = "zstd" # none, snappy, gzip, brotli, lz4 (default), zstd
+compression_level = 10       # zstd 1-22, gzip 0-10, brotli 0-11
+statistics = true            # min/max/null count for every column chunk
+row_group_size = 1000000     # rows, default 62914560
+dictionary = true            # dictionary encode module and channel
+ipc_compression = "lz4"      # none (default), lz4, zstd
+
+The parquet file of a conversion is written from the csv of the sort, which is
+read in batches and cut into row groups of row_group_size rows, so only about
+a row group is in memory at a time. The dataset part files and the parquet
+file written when following a run get the same row groups.
+
+polars only writes dictionary encoded columns for categoricals, so with
+dictionary = true the module and channel columns are stored as categoricals
+(strings) in the parquet files, and come back as categories in pandas. The
+feather files are left as they are.
+*/
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ParquetCodec {
+    None,
+    Snappy,
+    Gzip,
+    Brotli,
+    #[default]
+    Lz4,
+    Zstd,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IpcCodec {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct OutputConfig {
+    pub parquet_compression: ParquetCodec,
+    // the codec's own default if missing, ignored by the codecs without levels
+    pub compression_level: Option<i32>,
+    pub statistics: bool,
+    pub row_group_size: usize,
+    pub dictionary: bool,
+    pub ipc_compression: IpcCodec,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            parquet_compression: ParquetCodec::Lz4,
+            compression_level: None,
+            statistics: false,
+            row_group_size: 60 * 1024 * 1024,
+            dictionary: false,
+            ipc_compression: IpcCodec::None,
+        }
+    }
+}
+
+// the columns that get stored as dictionaries, if they are in the table
+const DICTIONARY_COLUMNS: [&str; 2] = ["module", "channel"];
+
+fn output_error(e: PolarsError) -> ConverterError {
+    ConverterError::Output(e.to_string())
+}
+
+fn level_error(codec: &str, level: i32) -> ConverterError {
+    ConverterError::Config(format!("invalid {} compression level {}", codec, level))
+}
+
+impl OutputConfig {
+    // checked before the sort so that a typo doesn't cost a whole run
+    pub fn parquet_options(&self) -> Result<ParquetWriteOptions, ConverterError> {
+        let level = self.compression_level;
+        let compression = match self.parquet_compression {
+            ParquetCodec::None => ParquetCompression::Uncompressed,
+            ParquetCodec::Snappy => ParquetCompression::Snappy,
+            ParquetCodec::Lz4 => ParquetCompression::Lz4Raw,
+            ParquetCodec::Gzip => ParquetCompression::Gzip(match level {
+                Some(l) => Some(
+                    u8::try_from(l)
+                        .ok()
+                        .and_then(|l| GzipLevel::try_new(l).ok())
+                        .ok_or_else(|| level_error("gzip", l))?,
+                ),
+                None => None,
+            }),
+            ParquetCodec::Brotli => ParquetCompression::Brotli(match level {
+                Some(l) => Some(
+                    u32::try_from(l)
+                        .ok()
+                        .and_then(|l| BrotliLevel::try_new(l).ok())
+                        .ok_or_else(|| level_error("brotli", l))?,
+                ),
+                None => None,
+            }),
+            ParquetCodec::Zstd => ParquetCompression::Zstd(match level {
+                Some(l) => Some(ZstdLevel::try_new(l).map_err(|_| level_error("zstd", l))?),
+                None => None,
+            }),
+        };
+        if self.row_group_size == 0 {
+            return Err(ConverterError::Config(
+                "row_group_size has to be at least 1".to_string(),
+            ));
+        }
+        Ok(ParquetWriteOptions {
+            compression,
+            statistics: self.statistics,
+            row_group_size: Some(self.row_group_size),
+            data_pagesize_limit: None,
+            maintain_order: true,
+        })
+    }
+
+    pub fn ipc_options(&self) -> IpcWriterOptions {
+        let compression = match self.ipc_compression {
+            IpcCodec::None => None,
+            IpcCodec::Lz4 => Some(IpcCompression::LZ4),
+            IpcCodec::Zstd => Some(IpcCompression::ZSTD),
+        };
+        IpcWriterOptions {
+            compression,
+            maintain_order: true,
+        }
+    }
+
+    // the schema of a table as it goes into a parquet file
+    pub fn parquet_schema(&self, schema: &Schema) -> Schema {
+        let mut schema = schema.clone();
+        if self.dictionary {
+            for name in DICTIONARY_COLUMNS {
+                if schema.get(name).is_some() {
+                    schema.with_column(name.into(), DataType::Categorical(None));
+                }
+            }
+        }
+        schema
+    }
+
+    // a table as it goes into a parquet file
+    pub fn parquet_columns(&self, mut df: DataFrame) -> Result<DataFrame, ConverterError> {
+        if !self.dictionary {
+            return Ok(df);
+        }
+        for name in DICTIONARY_COLUMNS {
+            if let Ok(column) = df.column(name) {
+                let column = column
+                    .cast(&DataType::Utf8)
+                    .and_then(|c| c.cast(&DataType::Categorical(None)))
+                    .map_err(output_error)?;
+                df.with_column(column).map_err(output_error)?;
+            }
+        }
+        Ok(df)
+    }
+
+    // The csv of the sort as a parquet file, schema has the types of the columns.
+    pub fn write_parquet(
+        &self,
+        csv_file: &str,
+        schema: &Schema,
+        path: &Path,
+    ) -> Result<(), ConverterError> {
+        let options = self.parquet_options()?;
+        let io_error = |e| ConverterError::Io(csv_file.to_string(), e);
+        // flags and subrun are only in the csv when they were asked for
+        let mut header = String::new();
+        BufReader::new(File::open(csv_file).map_err(io_error)?)
+            .read_line(&mut header)
+            .map_err(io_error)?;
+        let schema: Schema = header
+            .trim_end()
+            .split(',')
+            .filter_map(|column| schema.get_field(column))
+            .collect();
+        let schema = Arc::new(self.parquet_schema(&schema));
+        let mut reader = CsvReader::from_path(csv_file)
+            .map_err(output_error)?
+            .has_header(true)
+            .with_dtypes(Some(schema.clone()));
+        let mut batches = reader.batched_borrowed_mmap().map_err(output_error)?;
+        let file =
+            File::create(path).map_err(|e| ConverterError::Io(path.display().to_string(), e))?;
+        let mut writer = ParquetWriter::new(file)
+            .with_compression(options.compression)
+            .with_statistics(options.statistics)
+            .batched(&schema)
+            .map_err(output_error)?;
+
+        // the rows that don't make a whole row group yet
+        let rows = self.row_group_size;
+        let mut pending: Option<DataFrame> = None;
+        while let Some(dfs) = batches.next_batches(16).map_err(output_error)? {
+            for df in dfs {
+                let mut df = match pending.take() {
+                    Some(mut rest) => {
+                        rest.vstack_mut(&df).map_err(output_error)?;
+                        rest
+                    }
+                    None => df,
+                };
+                let whole = df.height() / rows * rows;
+                if whole > 0 {
+                    // the batched writer wants a single chunk per column
+                    df.as_single_chunk_par();
+                    for offset in (0..whole).step_by(rows) {
+                        writer
+                            .write_batch(&df.slice(offset as i64, rows))
+                            .map_err(output_error)?;
+                    }
+                    df = df.slice(whole as i64, df.height() - whole);
+                }
+                pending = Some(df);
+            }
+        }
+        if let Some(mut df) = pending.filter(|df| df.height() > 0) {
+            df.as_single_chunk_par();
+            writer.write_batch(&df).map_err(output_error)?;
+        }
+        writer.finish().map_err(output_error)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // the row group sizes, the table and whether module is dictionary encoded
+    fn write_and_read(csv: &str, output: &OutputConfig) -> (Vec<usize>, DataFrame, bool) {
+        let dir = std::env::temp_dir().join(format!("output_options_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let csv_file = dir.join("hits.csv");
+        let path = dir.join(format!("hits_{}.parquet", output.dictionary));
+        fs::write(&csv_file, csv).unwrap();
+        let schema = Schema::from_iter([
+            Field::new("module", DataType::UInt8),
+            Field::new("channel", DataType::UInt8),
+            Field::new("adc", DataType::UInt16),
+            // not in the csv
+            Field::new("flags", DataType::UInt8),
+        ]);
+        output
+            .write_parquet(csv_file.to_str().unwrap(), &schema, &path)
+            .unwrap();
+
+        let mut reader = ParquetReader::new(File::open(&path).unwrap());
+        let metadata = reader.get_metadata().unwrap().clone();
+        let row_groups = metadata.row_groups.iter().map(|rg| rg.num_rows()).collect();
+        // RLE_DICTIONARY is 8, polars doesn't export the parquet types
+        let dictionary = metadata
+            .row_groups
+            .iter()
+            .all(|rg| format!("{:?}", rg.columns()[0].column_encoding()).contains("Encoding(8)"));
+        let df = reader.finish().unwrap();
+        fs::remove_file(&csv_file).unwrap();
+        fs::remove_file(&path).unwrap();
+        (row_groups, df, dictionary)
+    }
+
+    fn csv(rows: usize) -> String {
+        let mut csv = "module,channel,adc\n".to_string();
+        for i in 0..rows {
+            csv += &format!("{},{},{}\n", i % 3, i % 16, i);
+        }
+        csv
+    }
+
+    #[test]
+    fn row_groups_are_cut() {
+        let output = OutputConfig {
+            row_group_size: 1000,
+            ..OutputConfig::default()
+        };
+        let (row_groups, df, dictionary) = write_and_read(&csv(2500), &output);
+        assert_eq!(row_groups, [1000, 1000, 500]);
+        assert!(!dictionary);
+        assert_eq!(df.column("module").unwrap().dtype(), &DataType::UInt8);
+        let adc: Vec<Option<u16>> = df
+            .column("adc")
+            .unwrap()
+            .u16()
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(adc, (0..2500).map(Some).collect::<Vec<_>>());
+
+        let (row_groups, df, _) = write_and_read(&csv(0), &output);
+        assert!(row_groups.is_empty());
+        assert_eq!(df.height(), 0);
+    }
+
+    #[test]
+    fn dictionary_columns() {
+        let output = OutputConfig {
+            row_group_size: 1000,
+            dictionary: true,
+            ..OutputConfig::default()
+        };
+        let (row_groups, df, dictionary) = write_and_read(&csv(1500), &output);
+        assert_eq!(row_groups, [1000, 500]);
+        assert!(dictionary);
+        let module = df.column("module").unwrap();
+        assert!(matches!(module.dtype(), DataType::Categorical(_)));
+        let module = module.cast(&DataType::Utf8).unwrap();
+        let values: Vec<Option<&str>> = module.utf8().unwrap().into_iter().take(4).collect();
+        assert_eq!(values, [Some("0"), Some("1"), Some("2"), Some("0")]);
+        assert_eq!(df.column("adc").unwrap().dtype(), &DataType::UInt16);
+    }
+}
@@ -0,0 +1,196 @@
+use crate::error::{ConverterError, DecodeError};
+use crate::module_config::{Config, Module};
+use crate::sis3820::ScalerBank;
+use midasio::read::event::EventView;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Mutex;
+
+/*
+Decoding one run on many cores.
+
+A reader thread cuts the midas events into batches, a pool of workers decodes
+the batches, and the thread that called the sorter puts the decoded batches
+back in order and does everything that depends on the events that came before:
+the event counter checks, finishing MDPP events that were started in an earlier
+batch, the histograms and all of the writers. So the output doesn't depend on
+how many workers there are.
+
+With a single thread the batches are decoded in place, no other threads.
+*/
+
+// midas events per batch, enough that passing them around is cheap
+const BATCH_EVENTS: usize = 1000;
+
+// A followed run gets its events one at a time as they are written, waiting
+// for a whole batch would hold them back.
+pub fn batch_events(config: &Config) -> usize {
+    if config.flush_interval.is_some() {
+        1
+    } else {
+        BATCH_EVENTS
+    }
+}
+
+pub type Batch<'a> = Vec<(u32, EventView<'a>)>;
+
+// what a worker found in a midas event, the writer replays these in order
+pub struct DecodedEvent<'a, M> {
+    pub subrun: u32,
+    pub id: u16,
+    pub serial: u32,
+    pub timestamp: u32,
+    pub banks: Vec<DecodedBank<'a, M>>,
+}
+
+pub enum DecodedBank<'a, M> {
+    // a bank of one of the configured modules, what is in it depends on the sorter
+    Module(M),
+    Unknown {
+        name: &'a str,
+        data: &'a [u8],
+    },
+    Scaler {
+        name: &'a str,
+        bank: ScalerBank,
+        result: Result<u64, DecodeError>,
+    },
+}
+
+// The part of the decoding all of the sorters share. decode_bank gets the index
+// of the module and the bank data of every trigger bank that belongs to a module.
+pub fn decode_events<'a, M>(
+    batch: Batch<'a>,
+    modules: &[Module],
+    mut decode_bank: impl FnMut(usize, &Module, &'a [u8]) -> M,
+) -> Vec<DecodedEvent<'a, M>> {
+    batch
+        .into_iter()
+        .map(|(subrun, event)| {
+            let mut banks = Vec::new();
+            // select trigger events
+            if event.id() == 1 {
+                for bank in event {
+                    if bank.data_slice().len() == 1 {
+                        continue;
+                    }
+                    match modules.iter().position(|m| m.name == bank.name()) {
+                        Some(i) => {
+                            banks.push(DecodedBank::Module(decode_bank(
+                                i,
+                                &modules[i],
+                                bank.data_slice(),
+                            )));
+                        }
+                        None => banks.push(DecodedBank::Unknown {
+                            name: bank.name(),
+                            data: bank.data_slice(),
+                        }),
+                    }
+                }
+            } else if event.id() == 2 {
+                for bank in event {
+                    let mut scalers = ScalerBank::new();
+                    let result = scalers.parse(bank.data_slice());
+                    banks.push(DecodedBank::Scaler {
+                        name: bank.name(),
+                        bank: scalers,
+                        result,
+                    });
+                }
+            }
+            DecodedEvent {
+                subrun,
+                id: event.id(),
+                serial: event.serial_number(),
+                timestamp: event.timestamp(),
+                banks,
+            }
+        })
+        .collect()
+}
+
+// Hands the batches of events to decode on threads workers and the results to
+// write in the original order. The first error from write stops everything.
+pub fn run<'a, B, D, W>(
+    events: impl Iterator<Item = (u32, EventView<'a>)> + Send,
+    threads: usize,
+    batch_events: usize,
+    decode: D,
+    mut write: W,
+) -> Result<(), ConverterError>
+where
+    B: Send,
+    D: Fn(Batch<'a>) -> B + Sync,
+    W: FnMut(B) -> Result<(), ConverterError>,
+{
+    let mut events = events;
+    if threads <= 1 {
+        loop {
+            let batch: Batch = events.by_ref().take(batch_events).collect();
+            if batch.is_empty() {
+                return Ok(());
+            }
+            write(decode(batch))?;
+        }
+    }
+
+    // a few batches in flight per worker keeps them busy without holding the whole run
+    let (batch_tx, batch_rx) = mpsc::sync_channel::<(usize, Batch<'a>)>(threads * 2);
+    let (decoded_tx, decoded_rx) = mpsc::sync_channel::<(usize, B)>(threads * 2);
+    let batch_rx = Mutex::new(batch_rx);
+    // set when writing fails, the reader stops and the workers drain what is left
+    let stop = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        let stop = &stop;
+        scope.spawn(move || {
+            for index in 0.. {
+                if stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                let batch: Batch = events.by_ref().take(batch_events).collect();
+                if batch.is_empty() || batch_tx.send((index, batch)).is_err() {
+                    break;
+                }
+            }
+        });
+        for _ in 0..threads {
+            let decoded_tx = decoded_tx.clone();
+            let (batch_rx, decode) = (&batch_rx, &decode);
+            scope.spawn(move || loop {
+                let next = batch_rx.lock().unwrap().recv();
+                let Ok((index, batch)) = next else {
+                    break;
+                };
+                if stop.load(Ordering::Relaxed) {
+                    continue;
+                }
+                if decoded_tx.send((index, decode(batch))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(decoded_tx);
+
+        // the batches come back in any order, hold on to them until it is their turn
+        let mut waiting = BTreeMap::new();
+        let mut next = 0;
+        let mut result = Ok(());
+        for (index, decoded) in decoded_rx.iter() {
+            if result.is_err() {
+                continue;
+            }
+            waiting.insert(index, decoded);
+            while let Some(decoded) = waiting.remove(&next) {
+                next += 1;
+                if let Err(e) = write(decoded) {
+                    stop.store(true, Ordering::Relaxed);
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        result
+    })
+}
@@ -0,0 +1,460 @@
+use crate::error::{ConverterError, ErrorTally};
+use crate::mdpp_bank::MDPPEvent;
+use crate::module_config::Module;
+use crate::unknown_banks::UnknownBanks;
+use crate::v1730_bank::v1730Hit;
+use crate::v785_bank::v785Hit;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/*
+A web page to check the detectors while a run is converted (--quicklook PORT).
+
+The sorters hand their hits over every time they flush, same as the histograms,
+and a small http server on 127.0.0.1 serves what has been seen so far:
+
+  /              a page with the spectra, the rates and the statistics
+  /stats.json    events, events/s, junk events, unknown banks, bad banks
+  /spectra.json  a spectrum per channel, ?module=NAME for one module
+  /rates.json    events and hits per module per second of run time
+
+The spectra are of the amplitude (adc for the SCP and the v785, the long
+integral for the QDC and the v1730) in QUICKLOOK_BINS bins, nothing to set up
+in the config. The server only lives as long as the conversion, so it is most
+useful with --follow or a stream, which flush every --flush-interval seconds.
+*/
+
+const QUICKLOOK_BINS: usize = 1024;
+// a few hours of rate points at the default flush interval
+const MAX_RATE_POINTS: usize = 2000;
+
+struct ModuleLook {
+    name: String,
+    mod_type: String,
+    quantity: &'static str,
+    max: f64,
+    // one spectrum per channel
+    spectra: Vec<Vec<u64>>,
+    hits: Vec<u64>,
+}
+
+impl ModuleLook {
+    fn fill(&mut self, channel: u32, value: f64) {
+        let channel = channel as usize;
+        if channel >= self.spectra.len() {
+            return;
+        }
+        self.hits[channel] += 1;
+        if value >= 0.0 && value < self.max {
+            let bin = (value / self.max * QUICKLOOK_BINS as f64) as usize;
+            self.spectra[channel][bin.min(QUICKLOOK_BINS - 1)] += 1;
+        }
+    }
+
+    fn total_hits(&self) -> u64 {
+        self.hits.iter().sum()
+    }
+}
+
+struct RatePoint {
+    // midas time
+    time: u32,
+    events: f64,
+    modules: Vec<f64>,
+}
+
+struct State {
+    run: String,
+    started: Instant,
+    running: bool,
+    modules: Vec<ModuleLook>,
+    start_time: u32,
+    last_time: u32,
+    events: usize,
+    junk_events: i64,
+    unknown_banks: BTreeMap<String, u64>,
+    bad_banks: usize,
+    bad_words: u64,
+    rates: VecDeque<RatePoint>,
+    // (midas time, events, hits per module) of the last rate point
+    previous: (u32, usize, Vec<u64>),
+}
+
+#[derive(Clone)]
+pub struct QuickLook {
+    state: Arc<Mutex<State>>,
+}
+
+impl QuickLook {
+    // starts the server, the run starts at the BOR time
+    pub fn start(
+        port: u16,
+        run: &str,
+        start_time: u32,
+        modules: &[Module],
+    ) -> Result<Self, ConverterError> {
+        let modules: Vec<ModuleLook> = modules
+            .iter()
+            .map(|m| {
+                let (quantity, max) = match m.mod_type.as_str() {
+                    "adc" => ("adc", 4096.0),
+                    "qdc" | "v1730" => ("long", 65536.0),
+                    _ => ("adc", 65536.0),
+                };
+                let nchannels = m.nchannels as usize;
+                ModuleLook {
+                    name: m.name.clone(),
+                    mod_type: m.mod_type.clone(),
+                    quantity,
+                    max,
+                    spectra: vec![vec![0; QUICKLOOK_BINS]; nchannels],
+                    hits: vec![0; nchannels],
+                }
+            })
+            .collect();
+        let previous = (start_time, 0, vec![0; modules.len()]);
+        let quicklook = QuickLook {
+            state: Arc::new(Mutex::new(State {
+                run: run.to_string(),
+                started: Instant::now(),
+                running: true,
+                modules,
+                start_time,
+                last_time: start_time,
+                events: 0,
+                junk_events: 0,
+                unknown_banks: BTreeMap::new(),
+                bad_banks: 0,
+                bad_words: 0,
+                rates: VecDeque::new(),
+                previous,
+            })),
+        };
+
+        // localhost only, this is not meant to be on the network
+        let address = format!("127.0.0.1:{}", port);
+        let listener =
+            TcpListener::bind(&address).map_err(|e| ConverterError::Io(address.clone(), e))?;
+        println!("Quick look at http://{}/", address);
+        let server = quicklook.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // a browser that goes away doesn't matter
+                let _ = server.respond(stream);
+            }
+        });
+        Ok(quicklook)
+    }
+
+    fn module<'a>(state: &'a mut State, name: &str) -> Option<&'a mut ModuleLook> {
+        state.modules.iter_mut().find(|m| m.name == name)
+    }
+
+    pub fn add_mdpp(&self, module: &str, events: &[MDPPEvent]) {
+        let mut state = self.state.lock().unwrap();
+        let Some(look) = Self::module(&mut state, module) else {
+            return;
+        };
+        for event in events.iter() {
+            for (&channel, hit) in event.channels.iter().zip(event.channel_hits.iter()) {
+                let value = match look.quantity {
+                    "long" => hit.long_filled.then_some(hit.long_value),
+                    _ => hit.adc_filled.then_some(hit.adc_value),
+                };
+                if let Some(value) = value {
+                    look.fill(channel, value as f64);
+                }
+            }
+        }
+    }
+
+    pub fn add_v785(&self, module: &str, hits: &[v785Hit]) {
+        let mut state = self.state.lock().unwrap();
+        let Some(look) = Self::module(&mut state, module) else {
+            return;
+        };
+        for hit in hits.iter() {
+            for (channel, &value) in hit.values.iter().enumerate() {
+                // zero means the channel did not fire
+                if value != 0 {
+                    look.fill(channel as u32, value as f64);
+                }
+            }
+        }
+    }
+
+    pub fn add_v1730(&self, module: &str, hits: &[v1730Hit]) {
+        let mut state = self.state.lock().unwrap();
+        let Some(look) = Self::module(&mut state, module) else {
+            return;
+        };
+        for hit in hits.iter() {
+            look.fill(hit.channel, hit.long as f64);
+        }
+    }
+
+    // after every flush, time is the midas time of the last event
+    pub fn update(
+        &self,
+        events: usize,
+        time: u32,
+        junk_events: i64,
+        unknown_banks: &UnknownBanks,
+        errors: &ErrorTally,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        state.events = events;
+        state.last_time = state.last_time.max(time);
+        state.junk_events = junk_events;
+        state.unknown_banks = unknown_banks.counts.clone();
+        state.bad_banks = errors.bad_banks.len();
+        state.bad_words = errors.bad_words.values().sum();
+
+        // a rate point once at least a second of the run has gone by
+        let hits: Vec<u64> = state.modules.iter().map(|m| m.total_hits()).collect();
+        let (previous_time, previous_events, previous_hits) = &state.previous;
+        let seconds = state.last_time.saturating_sub(*previous_time);
+        if seconds > 0 {
+            let point = RatePoint {
+                time: state.last_time,
+                events: (events - previous_events) as f64 / seconds as f64,
+                modules: hits
+                    .iter()
+                    .zip(previous_hits.iter())
+                    .map(|(h, p)| (h - p) as f64 / seconds as f64)
+                    .collect(),
+            };
+            state.rates.push_back(point);
+            if state.rates.len() > MAX_RATE_POINTS {
+                state.rates.pop_front();
+            }
+            state.previous = (state.last_time, events, hits);
+        }
+    }
+
+    // the sort is done, the page says so
+    pub fn finish(&self) {
+        self.state.lock().unwrap().running = false;
+    }
+
+    fn run_seconds(state: &State) -> u32 {
+        state.last_time.saturating_sub(state.start_time).max(1)
+    }
+
+    fn stats(state: &State) -> Value {
+        let elapsed = state.started.elapsed().as_secs_f64();
+        json!({
+            "run": state.run,
+            "running": state.running,
+            "elapsed_s": elapsed,
+            "events": state.events,
+            "events_per_s": state.events as f64 / elapsed.max(1e-3),
+            "run_seconds": Self::run_seconds(state),
+            "junk_events": state.junk_events,
+            "bad_banks": state.bad_banks,
+            "bad_words": state.bad_words,
+            "unknown_banks": state.unknown_banks,
+            "modules": state.modules.iter().map(|m| json!({
+                "name": m.name,
+                "type": m.mod_type,
+                "hits": m.total_hits(),
+                "rate": m.total_hits() as f64 / Self::run_seconds(state) as f64,
+            })).collect::<Vec<_>>(),
+        })
+    }
+
+    fn spectra(state: &State, module: Option<&str>) -> Value {
+        let seconds = Self::run_seconds(state) as f64;
+        let modules: Vec<Value> = state
+            .modules
+            .iter()
+            .filter(|m| module.is_none_or(|name| m.name == name))
+            .map(|m| {
+                json!({
+                    "module": m.name,
+                    "quantity": m.quantity,
+                    "range": [0.0, m.max],
+                    "bins": QUICKLOOK_BINS,
+                    "channels": m.spectra.iter().zip(m.hits.iter()).enumerate().map(|(channel, (counts, &hits))| json!({
+                        "channel": channel,
+                        "hits": hits,
+                        "rate": hits as f64 / seconds,
+                        "counts": counts,
+                    })).collect::<Vec<_>>(),
+                })
+            })
+            .collect();
+        Value::from(modules)
+    }
+
+    fn rates(state: &State) -> Value {
+        json!({
+            "modules": state.modules.iter().map(|m| m.name.clone()).collect::<Vec<_>>(),
+            "points": state.rates.iter().map(|p| json!({
+                "time": p.time,
+                "events": p.events,
+                "modules": p.modules,
+            })).collect::<Vec<_>>(),
+        })
+    }
+
+    fn respond(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        // only the request line matters
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+            let read = stream.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..read]);
+        }
+        let request = String::from_utf8_lossy(&request);
+        let mut parts = request.split_whitespace();
+        let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or("/"));
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let module = query
+            .split('&')
+            .find_map(|q| q.strip_prefix("module="))
+            .filter(|m| !m.is_empty());
+
+        let (status, content_type, body) = if method != "GET" {
+            (
+                "405 Method Not Allowed",
+                "text/plain",
+                "GET only\n".to_string(),
+            )
+        } else {
+            let state = self.state.lock().unwrap();
+            match path {
+                "/" | "/index.html" => ("200 OK", "text/html", PAGE.to_string()),
+                "/stats.json" => (
+                    "200 OK",
+                    "application/json",
+                    Self::stats(&state).to_string(),
+                ),
+                "/spectra.json" => (
+                    "200 OK",
+                    "application/json",
+                    Self::spectra(&state, module).to_string(),
+                ),
+                "/rates.json" => (
+                    "200 OK",
+                    "application/json",
+                    Self::rates(&state).to_string(),
+                ),
+                _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+            }
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+            status,
+            content_type,
+            body.len()
+        )?;
+        stream.write_all(body.as_bytes())?;
+        stream.flush()
+    }
+}
+
+// everything in one page, no outside scripts so it works on a DAQ machine without internet
+const PAGE: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>midas-converter quick look</title>
+<style>
+body { font-family: sans-serif; margin: 1em; }
+table { border-collapse: collapse; margin-bottom: 1em; }
+td, th { border: 1px solid #ccc; padding: 2px 8px; text-align: right; }
+canvas { border: 1px solid #ccc; display: block; margin: 0.5em 0; }
+</style>
+</head>
+<body>
+<h2 id="title">quick look</h2>
+<table id="stats"></table>
+<table id="modules"></table>
+<div>
+module <select id="module"></select>
+channel <select id="channel"></select>
+<label><input type="checkbox" id="log" checked> log</label>
+</div>
+<canvas id="spectrum" width="900" height="300"></canvas>
+<b>rates (per second of run time)</b>
+<canvas id="rates" width="900" height="200"></canvas>
+<script>
+const $ = id => document.getElementById(id);
+const colors = ["#1f77b4", "#d62728", "#2ca02c", "#ff7f0e", "#9467bd", "#8c564b", "#e377c2", "#7f7f7f"];
+
+function rows(table, entries) {
+  table.innerHTML = entries.map(r => "<tr>" + r.map(c => "<td>" + c + "</td>").join("") + "</tr>").join("");
+}
+
+function draw(canvas, series, log) {
+  const ctx = canvas.getContext("2d");
+  ctx.clearRect(0, 0, canvas.width, canvas.height);
+  const all = series.flatMap(s => s.y);
+  if (all.length == 0) return;
+  const f = y => log ? Math.log10(1 + y) : y;
+  const max = Math.max(1e-9, ...all.map(f));
+  series.forEach((s, i) => {
+    ctx.strokeStyle = colors[i % colors.length];
+    ctx.beginPath();
+    s.y.forEach((y, j) => {
+      const px = j / Math.max(1, s.y.length - 1) * canvas.width;
+      const py = canvas.height - f(y) / max * (canvas.height - 10);
+      j == 0 ? ctx.moveTo(px, py) : ctx.lineTo(px, py);
+    });
+    ctx.stroke();
+    ctx.fillStyle = colors[i % colors.length];
+    ctx.fillText(s.name, 10 + 80 * i, 12);
+  });
+}
+
+let spectra = [];
+async function refresh() {
+  const stats = await (await fetch("stats.json")).json();
+  $("title").textContent = stats.run + (stats.running ? " (running)" : " (done)");
+  rows($("stats"), [
+    ["events", stats.events], ["events/s", stats.events_per_s.toFixed(0)],
+    ["run seconds", stats.run_seconds], ["junk events", stats.junk_events],
+    ["bad banks", stats.bad_banks], ["bad words", stats.bad_words],
+    ["unknown banks", Object.entries(stats.unknown_banks).map(([k, v]) => k + ": " + v).join(", ") || "none"],
+  ]);
+  rows($("modules"), [["module", "type", "hits", "hits/s"]].concat(
+    stats.modules.map(m => [m.name, m.type, m.hits, m.rate.toFixed(1)])));
+
+  spectra = await (await fetch("spectra.json")).json();
+  const module = $("module");
+  if (module.options.length != spectra.length) {
+    module.innerHTML = spectra.map(m => "<option>" + m.module + "</option>").join("");
+  }
+  const m = spectra.find(m => m.module == module.value) || spectra[0];
+  if (m) {
+    const channel = $("channel");
+    if (channel.options.length != m.channels.length + 1) {
+      channel.innerHTML = "<option>all</option>" + m.channels.map(c => "<option>" + c.channel + "</option>").join("");
+    }
+    const shown = m.channels.filter(c => channel.value == "all" || c.channel == channel.value);
+    draw($("spectrum"), shown.map(c => ({name: m.quantity + " ch" + c.channel, y: c.counts})), $("log").checked);
+  }
+
+  const rates = await (await fetch("rates.json")).json();
+  draw($("rates"), [{name: "events", y: rates.points.map(p => p.events)}].concat(
+    rates.modules.map((name, i) => ({name: name, y: rates.points.map(p => p.modules[i])}))), false);
+}
+$("module").onchange = () => { $("channel").innerHTML = ""; refresh(); };
+$("channel").onchange = refresh;
+$("log").onchange = refresh;
+refresh();
+setInterval(refresh, 2000);
+</script>
+</body>
+</html>
+"##;
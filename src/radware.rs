@@ -0,0 +1,67 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/*
+Spectra for the RadWare programs (gf3, escl8r, ...).
+
+A .spe file is two Fortran unformatted records, the first one with an 8
+character name and the number of channels and the second one with the counts
+as 32 bit floats. Every record is wrapped in its length in bytes.
+
+The .mat (16 bit) and .m4b (32 bit) matrices are just 4096 x 4096 channels with
+no header at all, one row after the other. Smaller histograms are padded with
+zeros, and 16 bit channels saturate instead of wrapping around.
+*/
+
+pub const SPE_MAX_CHANNELS: usize = 16384;
+pub const MATRIX_SIZE: usize = 4096;
+
+pub fn write_spe(filename: &str, name: &str, counts: &[u64]) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(filename)?);
+    // the name is exactly 8 characters, padded with spaces
+    let mut spe_name = [b' '; 8];
+    for (c, b) in spe_name.iter_mut().zip(name.bytes()) {
+        *c = b;
+    }
+    file.write_all(&24i32.to_le_bytes())?;
+    file.write_all(&spe_name)?;
+    file.write_all(&(counts.len() as i32).to_le_bytes())?;
+    for _ in 0..3 {
+        file.write_all(&1i32.to_le_bytes())?;
+    }
+    file.write_all(&24i32.to_le_bytes())?;
+
+    let record_len = (4 * counts.len()) as i32;
+    file.write_all(&record_len.to_le_bytes())?;
+    for &count in counts.iter() {
+        file.write_all(&(count as f32).to_le_bytes())?;
+    }
+    file.write_all(&record_len.to_le_bytes())?;
+    file.flush()
+}
+
+// counts are row major with nx rows of ny channels
+pub fn write_matrix(
+    filename: &str,
+    counts: &[u64],
+    nx: usize,
+    ny: usize,
+    four_byte: bool,
+) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(filename)?);
+    for x in 0..MATRIX_SIZE {
+        for y in 0..MATRIX_SIZE {
+            let count = if x < nx && y < ny {
+                counts[x * ny + y]
+            } else {
+                0
+            };
+            if four_byte {
+                file.write_all(&(count.min(u32::MAX as u64) as u32).to_le_bytes())?;
+            } else {
+                file.write_all(&(count.min(u16::MAX as u64) as u16).to_le_bytes())?;
+            }
+        }
+    }
+    file.flush()
+}
@@ -0,0 +1,1439 @@
+use crate::root_streamers;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/*
+A small ROOT file writer, so that we don't need a ROOT installation.
+
+Only what we need is here: one top level directory and uncompressed objects.
+Everything in a ROOT file is big endian. The layout is
+
+    file header (100 bytes)
+    key + TDirectory data of the top directory
+    key + object, for every object (and the baskets of the trees)
+    key + StreamerInfo list (the classes of the objects, see root_streamers.rs)
+    key + list of all keys in the top directory
+    key + free segments
+
+Records are written as they come and the header and top directory are filled
+in by close, so trees don't have to fit in memory. Anything past 2 GB gets the
+64 bit versions of the seek pointers, same as ROOT does it.
+
+The objects are streamed with the class versions of ROOT 6, see the TH1D and
+TH2D writers at the bottom and root_tree.rs.
+*/
+
+const BEGIN: usize = 100;
+const FILE_VERSION: i32 = 62800;
+const KEY_VERSION: i16 = 4;
+const DIRECTORY_VERSION: i16 = 5;
+// versions go up by 1000 when they use 64 bit seek pointers
+const BIG_VERSION: i16 = 1000;
+const BIG_FILE: usize = i32::MAX as usize;
+// marks the 32 bit word in front of every object as a byte count
+const BYTE_COUNT_MASK: u32 = 0x4000_0000;
+// object references are offsets in the key plus this, so they are never 0 (null)
+const MAP_OFFSET: usize = 2;
+const NEW_CLASS_TAG: u32 = 0xFFFF_FFFF;
+// kIsOnHeap | kNotDeleted, which is what ROOT writes for every object
+const TOBJECT_BITS: u32 = 0x0300_0000;
+
+// Big endian buffer with the ROOT streamer basics.
+#[derive(Default)]
+pub struct RootBuffer {
+    pub data: Vec<u8>,
+    // where the buffer starts within its key, object references count from there
+    offset: usize,
+}
+
+impl RootBuffer {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_offset(offset: usize) -> Self {
+        RootBuffer {
+            data: Vec::new(),
+            offset,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn position(&self) -> usize {
+        self.offset + self.data.len()
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.data.push(v);
+    }
+
+    pub fn i16(&mut self, v: i16) {
+        self.data.extend_from_slice(&v.to_be_bytes());
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.data.extend_from_slice(&v.to_be_bytes());
+    }
+
+    pub fn i32(&mut self, v: i32) {
+        self.data.extend_from_slice(&v.to_be_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.data.extend_from_slice(&v.to_be_bytes());
+    }
+
+    pub fn i64(&mut self, v: i64) {
+        self.data.extend_from_slice(&v.to_be_bytes());
+    }
+
+    pub fn f32(&mut self, v: f32) {
+        self.data.extend_from_slice(&v.to_be_bytes());
+    }
+
+    pub fn f64(&mut self, v: f64) {
+        self.data.extend_from_slice(&v.to_be_bytes());
+    }
+
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.data.extend_from_slice(v);
+    }
+
+    // TString, long strings get a 255 marker and a 32 bit length
+    pub fn string(&mut self, s: &str) {
+        if s.len() < 255 {
+            self.u8(s.len() as u8);
+        } else {
+            self.u8(255);
+            self.i32(s.len() as i32);
+        }
+        self.bytes(s.as_bytes());
+    }
+
+    // Every versioned object starts with its length in bytes and its class version.
+    // The length isn't known until the object is done, so reserve it here and
+    // fill it in with end_object.
+    pub fn start_object(&mut self, version: i16) -> usize {
+        let position = self.len();
+        self.u32(0);
+        self.i16(version);
+        position
+    }
+
+    pub fn end_object(&mut self, position: usize) {
+        let count = (self.len() - position - 4) as u32 | BYTE_COUNT_MASK;
+        self.data[position..position + 4].copy_from_slice(&count.to_be_bytes());
+    }
+
+    pub fn tobject(&mut self) {
+        self.i16(1);
+        self.u32(0);
+        self.u32(TOBJECT_BITS);
+    }
+
+    pub fn tnamed(&mut self, name: &str, title: &str) {
+        let p = self.start_object(1);
+        self.tobject();
+        self.string(name);
+        self.string(title);
+        self.end_object(p);
+    }
+
+    // an empty TList, streamed in place (for the //-> members)
+    pub fn empty_tlist(&mut self) {
+        let p = self.start_object(5);
+        self.tobject();
+        self.string("");
+        self.i32(0);
+        self.end_object(p);
+    }
+
+    // A pointer member, written out in full the first time the object shows up:
+    // byte count, a new class tag with the class name and then the object.
+    // Finish it with end_object. Also returns the tag that other pointers to the
+    // same object use, which is where it starts in the key.
+    pub fn start_object_any(&mut self, class: &str) -> (usize, u32) {
+        let tag = (self.position() + MAP_OFFSET) as u32;
+        let position = self.len();
+        self.u32(0);
+        self.u32(NEW_CLASS_TAG);
+        self.bytes(class.as_bytes());
+        self.u8(0);
+        (position, tag)
+    }
+
+    pub fn empty_tobjarray(&mut self) {
+        let p = self.start_object(3);
+        self.tobject();
+        self.string("");
+        self.i32(0);
+        self.i32(0);
+        self.end_object(p);
+    }
+
+    // TArrayD and friends have no version, just the length and the values
+    pub fn array_f64(&mut self, values: &[f64]) {
+        self.i32(values.len() as i32);
+        for &v in values.iter() {
+            self.f64(v);
+        }
+    }
+
+    // the graphics attributes every histogram and tree carries around, at the ROOT defaults
+    pub fn att_line(&mut self) {
+        let p = self.start_object(2);
+        self.i16(602);
+        self.i16(1);
+        self.i16(1);
+        self.end_object(p);
+    }
+
+    pub fn att_fill(&mut self) {
+        let p = self.start_object(2);
+        self.i16(0);
+        self.i16(1001);
+        self.end_object(p);
+    }
+
+    pub fn att_marker(&mut self) {
+        let p = self.start_object(2);
+        self.i16(1);
+        self.i16(1);
+        self.f32(1.0);
+        self.end_object(p);
+    }
+}
+
+fn string_size(s: &str) -> usize {
+    if s.len() < 255 {
+        1 + s.len()
+    } else {
+        5 + s.len()
+    }
+}
+
+// ROOT's TDatime packs the local time into 32 bits, we use UTC
+fn datime() -> u32 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+    // days since 1970 to year/month/day, from Howard Hinnant's date algorithms
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = (yoe + era * 400 + if month <= 2 { 1 } else { 0 }) as u32;
+    ((year - 1995) << 26)
+        | (month << 22)
+        | (day << 17)
+        | ((secs_of_day / 3600) as u32) << 12
+        | (((secs_of_day % 3600) / 60) as u32) << 6
+        | (secs_of_day % 60) as u32
+}
+
+// TKey header, the object (if any) follows right after it. extra_len is for
+// classes that keep more of their header in the key, like TBasket.
+#[allow(clippy::too_many_arguments)]
+pub fn key_header(
+    class: &str,
+    name: &str,
+    title: &str,
+    object_len: usize,
+    seek_key: usize,
+    seek_pdir: usize,
+    datime: u32,
+    extra_len: usize,
+) -> Vec<u8> {
+    let big = seek_key > BIG_FILE;
+    let key_len = if big { 34 } else { 26 }
+        + string_size(class)
+        + string_size(name)
+        + string_size(title)
+        + extra_len;
+    let mut buf = RootBuffer::new();
+    buf.i32((key_len + object_len) as i32);
+    buf.i16(if big {
+        KEY_VERSION + BIG_VERSION
+    } else {
+        KEY_VERSION
+    });
+    buf.i32(object_len as i32);
+    buf.u32(datime);
+    buf.i16(key_len as i16);
+    // cycle
+    buf.i16(1);
+    if big {
+        buf.i64(seek_key as i64);
+        buf.i64(seek_pdir as i64);
+    } else {
+        buf.i32(seek_key as i32);
+        buf.i32(seek_pdir as i32);
+    }
+    buf.string(class);
+    buf.string(name);
+    buf.string(title);
+    buf.data
+}
+
+// where the bookkeeping records ended up, for the header and top directory
+#[derive(Default)]
+struct Pointers {
+    end: usize,
+    seek_free: usize,
+    nbytes_free: usize,
+    seek_info: usize,
+    nbytes_info: usize,
+    seek_keys: usize,
+    nbytes_keys: usize,
+}
+
+pub struct RootFile {
+    file: BufWriter<File>,
+    // the file name without the path, which is the name of the top directory
+    name: String,
+    title: String,
+    datime: u32,
+    position: usize,
+    // headers of the keys in the top directory, for the keys list
+    key_headers: Vec<Vec<u8>>,
+    // classes that go in the StreamerInfo
+    classes: Vec<String>,
+}
+
+impl RootFile {
+    pub fn create(filename: &str, title: &str) -> std::io::Result<Self> {
+        let name = std::path::Path::new(filename)
+            .file_name()
+            .map_or(filename.to_string(), |n| n.to_string_lossy().to_string());
+        let mut root_file = RootFile {
+            file: BufWriter::new(File::create(filename)?),
+            name,
+            title: title.to_string(),
+            datime: datime(),
+            position: 0,
+            key_headers: Vec::new(),
+            classes: Vec::new(),
+        };
+        // room for the header and the top directory, filled in by close
+        let head = root_file.head(&Pointers::default());
+        root_file.write_record(&head)?;
+        Ok(root_file)
+    }
+
+    pub fn write_record(&mut self, record: &[u8]) -> std::io::Result<usize> {
+        let seek = self.position;
+        self.file.write_all(record)?;
+        self.position += record.len();
+        Ok(seek)
+    }
+
+    // for the classes of the objects that are not in the directory, like the
+    // branches and leaves of a tree
+    pub fn add_class(&mut self, class: &str) {
+        if !self.classes.iter().any(|c| c == class) {
+            self.classes.push(class.to_string());
+        }
+    }
+
+    // data is the streamed object, starting with its byte count. It can also
+    // be built at its final position in the file, see object_buffer.
+    pub fn add_object(
+        &mut self,
+        class: &str,
+        name: &str,
+        title: &str,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        let header = key_header(
+            class,
+            name,
+            title,
+            data.len(),
+            self.position,
+            BEGIN,
+            self.datime,
+            0,
+        );
+        self.write_record(&header)?;
+        self.write_record(data)?;
+        self.key_headers.push(header);
+        self.add_class(class);
+        Ok(())
+    }
+
+    // key header for a record at the current position, for records that are not
+    // in the directory (the baskets of a tree)
+    pub fn record_header(
+        &self,
+        class: &str,
+        name: &str,
+        title: &str,
+        object_len: usize,
+        extra_len: usize,
+    ) -> Vec<u8> {
+        key_header(
+            class,
+            name,
+            title,
+            object_len,
+            self.position,
+            BEGIN,
+            self.datime,
+            extra_len,
+        )
+    }
+
+    // Objects that point to other parts of themselves (trees pointing to their
+    // own leaves) need to know where they start within their key.
+    pub fn object_buffer(&self, class: &str, name: &str, title: &str) -> RootBuffer {
+        RootBuffer::with_offset(self.record_header(class, name, title, 0, 0).len())
+    }
+
+    // file header followed by the key and data of the top directory
+    fn head(&self, p: &Pointers) -> Vec<u8> {
+        let big = p.end > BIG_FILE;
+        let directory_key = key_header("TFile", &self.name, &self.title, 0, BEGIN, 0, 0, 0);
+        let nbytes_name =
+            directory_key.len() + string_size(&self.name) + string_size(&self.title);
+
+        let mut head = RootBuffer::new();
+        head.bytes(b"root");
+        if big {
+            head.i32(FILE_VERSION + 1_000_000);
+            head.i32(BEGIN as i32);
+            head.i64(p.end as i64);
+            head.i64(p.seek_free as i64);
+        } else {
+            head.i32(FILE_VERSION);
+            head.i32(BEGIN as i32);
+            head.i32(p.end as i32);
+            head.i32(p.seek_free as i32);
+        }
+        head.i32(p.nbytes_free as i32);
+        // number of free segments
+        head.i32(1);
+        head.i32(nbytes_name as i32);
+        // bytes per seek pointer
+        head.u8(if big { 8 } else { 4 });
+        // no compression
+        head.i32(0);
+        if big {
+            head.i64(p.seek_info as i64);
+        } else {
+            head.i32(p.seek_info as i32);
+        }
+        head.i32(p.nbytes_info as i32);
+        head.u16(1);
+        head.bytes(&[0; 16]);
+        head.data.resize(BEGIN, 0);
+
+        // TFile data: the TNamed part and then the TDirectory
+        let mut directory = RootBuffer::new();
+        directory.string(&self.name);
+        directory.string(&self.title);
+        let big_directory = p.seek_keys > BIG_FILE;
+        directory.i16(if big_directory {
+            DIRECTORY_VERSION + BIG_VERSION
+        } else {
+            DIRECTORY_VERSION
+        });
+        directory.u32(self.datime);
+        directory.u32(self.datime);
+        directory.i32(p.nbytes_keys as i32);
+        directory.i32(nbytes_name as i32);
+        if big_directory {
+            directory.i64(BEGIN as i64);
+            // no parent
+            directory.i64(0);
+            directory.i64(p.seek_keys as i64);
+        } else {
+            directory.i32(BEGIN as i32);
+            directory.i32(0);
+            directory.i32(p.seek_keys as i32);
+        }
+        // UUID version and an all zero UUID
+        directory.u16(1);
+        directory.bytes(&[0; 16]);
+        // ROOT leaves room for the 64 bit pointers so the directory never changes size
+        if !big_directory {
+            directory.bytes(&[0; 12]);
+        }
+
+        head.bytes(&key_header(
+            "TFile",
+            &self.name,
+            &self.title,
+            directory.len(),
+            BEGIN,
+            0,
+            self.datime,
+            0,
+        ));
+        head.bytes(&directory.data);
+        head.data
+    }
+
+    pub fn close(mut self) -> std::io::Result<()> {
+        let (name, title, datime) = (self.name.clone(), self.title.clone(), self.datime);
+
+        // StreamerInfo, a TList with the TStreamerInfo of every class in the file
+        let mut streamer_info = self.object_buffer("TList", "StreamerInfo", "Doubly linked list");
+        root_streamers::streamer_info(&mut streamer_info, &self.classes);
+        let header = key_header(
+            "TList",
+            "StreamerInfo",
+            "Doubly linked list",
+            streamer_info.len(),
+            self.position,
+            BEGIN,
+            datime,
+            0,
+        );
+        let nbytes_info = header.len() + streamer_info.len();
+        let seek_info = self.write_record(&header)?;
+        self.write_record(&streamer_info.data)?;
+
+        // keys list, the number of keys and then all of the key headers
+        let mut keys_list = RootBuffer::new();
+        keys_list.i32(self.key_headers.len() as i32);
+        for header in self.key_headers.iter() {
+            keys_list.bytes(header);
+        }
+        let header = key_header(
+            "TFile",
+            &name,
+            &title,
+            keys_list.len(),
+            self.position,
+            BEGIN,
+            datime,
+            0,
+        );
+        let nbytes_keys = header.len() + keys_list.len();
+        let seek_keys = self.write_record(&header)?;
+        self.write_record(&keys_list.data)?;
+
+        // free segments, everything from the end of the file on
+        let seek_free = self.position;
+        let big = seek_free + 100 > BIG_FILE;
+        let free_len = if big { 18 } else { 10 };
+        let header = key_header(
+            "TFile", &name, &title, free_len, seek_free, BEGIN, datime, 0,
+        );
+        let end = seek_free + header.len() + free_len;
+        let mut free_segments = RootBuffer::new();
+        if big {
+            free_segments.i16(1 + BIG_VERSION);
+            free_segments.i64(end as i64);
+            free_segments.i64(2 * end as i64);
+        } else {
+            free_segments.i16(1);
+            free_segments.i32(end as i32);
+            free_segments.i32(2_000_000_000);
+        }
+        self.write_record(&header)?;
+        self.write_record(&free_segments.data)?;
+
+        // and finally the file header and the top directory
+        let head = self.head(&Pointers {
+            end,
+            seek_free,
+            nbytes_free: header.len() + free_len,
+            seek_info,
+            nbytes_info,
+            seek_keys,
+            nbytes_keys,
+        });
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&head)?;
+        self.file.flush()
+    }
+}
+
+/*
+---------- Histograms ----------
+*/
+
+pub struct RootAxis<'a> {
+    pub name: &'a str,
+    pub title: &'a str,
+    pub nbins: usize,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl RootAxis<'_> {
+    fn center(&self, bin: usize) -> f64 {
+        self.min + (bin as f64 + 0.5) * (self.max - self.min) / self.nbins as f64
+    }
+}
+
+// TAxis version 10 with TAttAxis version 4
+fn taxis(buf: &mut RootBuffer, axis: &RootAxis) {
+    let p = buf.start_object(10);
+    buf.tnamed(axis.name, axis.title);
+    let q = buf.start_object(4);
+    buf.i32(510); // divisions
+    buf.i16(1); // axis color
+    buf.i16(1); // label color
+    buf.i16(42); // label font
+    buf.f32(0.005); // label offset
+    buf.f32(0.035); // label size
+    buf.f32(0.03); // tick length
+    buf.f32(1.0); // title offset
+    buf.f32(0.035); // title size
+    buf.i16(1); // title color
+    buf.i16(42); // title font
+    buf.end_object(q);
+    buf.i32(axis.nbins as i32);
+    buf.f64(axis.min);
+    buf.f64(axis.max);
+    // no variable bins
+    buf.array_f64(&[]);
+    // first and last bin of the zoom, bits, time display and format
+    buf.i32(0);
+    buf.i32(0);
+    buf.u16(0);
+    buf.u8(0);
+    buf.string("");
+    // no labels or modified labels (null pointers)
+    buf.u32(0);
+    buf.u32(0);
+    buf.end_object(p);
+}
+
+// the sums ROOT keeps for the statistics box
+#[derive(Default)]
+struct Stats {
+    entries: f64,
+    tsumw: f64,
+    tsumw2: f64,
+    tsumwx: f64,
+    tsumwx2: f64,
+    tsumwy: f64,
+    tsumwy2: f64,
+    tsumwxy: f64,
+}
+
+// TH1 version 8
+fn th1(
+    buf: &mut RootBuffer,
+    name: &str,
+    title: &str,
+    axes: [&RootAxis; 3],
+    ncells: usize,
+    stats: &Stats,
+) {
+    let p = buf.start_object(8);
+    buf.tnamed(name, title);
+    buf.att_line();
+    buf.att_fill();
+    buf.att_marker();
+    buf.i32(ncells as i32);
+    for axis in axes.iter() {
+        taxis(buf, axis);
+    }
+    buf.i16(0); // bar offset
+    buf.i16(1000); // bar width
+    buf.f64(stats.entries);
+    buf.f64(stats.tsumw);
+    buf.f64(stats.tsumw2);
+    buf.f64(stats.tsumwx);
+    buf.f64(stats.tsumwx2);
+    buf.f64(-1111.0); // maximum
+    buf.f64(-1111.0); // minimum
+    buf.f64(0.0); // norm factor
+    buf.array_f64(&[]); // contour
+    buf.array_f64(&[]); // sumw2, empty for unweighted fills
+    buf.string(""); // draw option
+    buf.empty_tlist(); // functions
+    buf.i32(0); // buffer size
+    buf.u8(0); // no buffer
+    buf.i32(0); // bin error option, normal
+    buf.i32(2); // stat overflows, neutral
+    buf.end_object(p);
+}
+
+fn unused_axis(name: &str) -> RootAxis<'_> {
+    RootAxis {
+        name,
+        title: "",
+        nbins: 1,
+        min: 0.0,
+        max: 1.0,
+    }
+}
+
+// TH1D version 3. counts has no under or overflow.
+pub fn th1d(name: &str, title: &str, x: &RootAxis, counts: &[u64]) -> Vec<u8> {
+    let mut stats = Stats::default();
+    let mut bins = vec![0.0; x.nbins + 2];
+    for (i, &count) in counts.iter().enumerate() {
+        let w = count as f64;
+        bins[i + 1] = w;
+        stats.entries += w;
+        stats.tsumw += w;
+        stats.tsumw2 += w;
+        stats.tsumwx += w * x.center(i);
+        stats.tsumwx2 += w * x.center(i) * x.center(i);
+    }
+    let mut buf = RootBuffer::new();
+    let p = buf.start_object(3);
+    th1(
+        &mut buf,
+        name,
+        title,
+        [x, &unused_axis("yaxis"), &unused_axis("zaxis")],
+        bins.len(),
+        &stats,
+    );
+    buf.array_f64(&bins);
+    buf.end_object(p);
+    buf.data
+}
+
+// TH2D version 4 (TH2 version 5). counts is row major with x as the slow index.
+pub fn th2d(name: &str, title: &str, x: &RootAxis, y: &RootAxis, counts: &[u64]) -> Vec<u8> {
+    let mut stats = Stats::default();
+    let row = x.nbins + 2;
+    let mut bins = vec![0.0; row * (y.nbins + 2)];
+    for (i, &count) in counts.iter().enumerate() {
+        let (ix, iy) = (i / y.nbins, i % y.nbins);
+        let w = count as f64;
+        // ROOT has x as the fast index
+        bins[(iy + 1) * row + ix + 1] = w;
+        let (cx, cy) = (x.center(ix), y.center(iy));
+        stats.entries += w;
+        stats.tsumw += w;
+        stats.tsumw2 += w;
+        stats.tsumwx += w * cx;
+        stats.tsumwx2 += w * cx * cx;
+        stats.tsumwy += w * cy;
+        stats.tsumwy2 += w * cy * cy;
+        stats.tsumwxy += w * cx * cy;
+    }
+    let mut buf = RootBuffer::new();
+    let p = buf.start_object(4);
+    let q = buf.start_object(5);
+    th1(
+        &mut buf,
+        name,
+        title,
+        [x, y, &unused_axis("zaxis")],
+        bins.len(),
+        &stats,
+    );
+    buf.f64(1.0); // scale factor
+    buf.f64(stats.tsumwy);
+    buf.f64(stats.tsumwy2);
+    buf.f64(stats.tsumwxy);
+    buf.end_object(q);
+    buf.array_f64(&bins);
+    buf.end_object(p);
+    buf.data
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::root_streamers::{checksum, class_info};
+    use std::collections::HashMap;
+
+    /*
+    A reader for the files we write, to check them without ROOT. The objects are
+    read with the StreamerInfo of the file, so the two have to agree to the byte.
+    */
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum Value {
+        Int(i64),
+        Float(f64),
+        Text(String),
+        Array(Vec<f64>),
+        Object(Object),
+        Objects(Vec<Object>),
+        // another object in the same key
+        Ref(u32),
+        Null,
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub struct Object {
+        pub class: String,
+        // the members of the bases come first
+        pub fields: Vec<(String, Value)>,
+    }
+
+    impl Object {
+        pub fn get(&self, name: &str) -> &Value {
+            match self.fields.iter().find(|(n, _)| n == name) {
+                Some((_, value)) => value,
+                None => panic!("{} has no {}", self.class, name),
+            }
+        }
+
+        pub fn int(&self, name: &str) -> i64 {
+            match self.get(name) {
+                Value::Int(v) => *v,
+                v => panic!("{} is {:?}", name, v),
+            }
+        }
+
+        pub fn float(&self, name: &str) -> f64 {
+            match self.get(name) {
+                Value::Float(v) => *v,
+                v => panic!("{} is {:?}", name, v),
+            }
+        }
+
+        pub fn text(&self, name: &str) -> &str {
+            match self.get(name) {
+                Value::Text(v) => v,
+                v => panic!("{} is {:?}", name, v),
+            }
+        }
+
+        pub fn array(&self, name: &str) -> &[f64] {
+            match self.get(name) {
+                Value::Array(v) => v,
+                v => panic!("{} is {:?}", name, v),
+            }
+        }
+
+        pub fn object(&self, name: &str) -> &Object {
+            match self.get(name) {
+                Value::Object(v) => v,
+                v => panic!("{} is {:?}", name, v),
+            }
+        }
+
+        pub fn objects(&self, name: &str) -> &[Object] {
+            match self.get(name) {
+                Value::Objects(v) => v,
+                v => panic!("{} is {:?}", name, v),
+            }
+        }
+    }
+
+    pub struct Element {
+        name: String,
+        kind: i32,
+        type_name: String,
+        count_name: String,
+    }
+
+    pub struct Info {
+        version: i32,
+        checksum: u32,
+        elements: Vec<Element>,
+    }
+
+    pub struct Key {
+        pub class: String,
+        pub name: String,
+        pub title: String,
+        pub seek: usize,
+        pub nbytes: usize,
+        pub key_len: usize,
+        pub object_len: usize,
+    }
+
+    pub struct Reader<'a> {
+        data: &'a [u8],
+        pub pos: usize,
+        // where the key starts, object references count from there
+        key: usize,
+        infos: &'a HashMap<String, Info>,
+        tags: HashMap<u32, String>,
+        objects: Vec<u32>,
+    }
+
+    impl<'a> Reader<'a> {
+        pub fn new(
+            data: &'a [u8],
+            key: usize,
+            pos: usize,
+            infos: &'a HashMap<String, Info>,
+        ) -> Self {
+            Reader {
+                data,
+                pos,
+                key,
+                infos,
+                tags: HashMap::new(),
+                objects: Vec::new(),
+            }
+        }
+
+        fn take<const N: usize>(&mut self) -> [u8; N] {
+            let bytes = self.data[self.pos..self.pos + N].try_into().unwrap();
+            self.pos += N;
+            bytes
+        }
+
+        pub fn u8(&mut self) -> u8 {
+            self.take::<1>()[0]
+        }
+
+        pub fn i16(&mut self) -> i16 {
+            i16::from_be_bytes(self.take())
+        }
+
+        pub fn u16(&mut self) -> u16 {
+            u16::from_be_bytes(self.take())
+        }
+
+        pub fn i32(&mut self) -> i32 {
+            i32::from_be_bytes(self.take())
+        }
+
+        pub fn u32(&mut self) -> u32 {
+            u32::from_be_bytes(self.take())
+        }
+
+        pub fn i64(&mut self) -> i64 {
+            i64::from_be_bytes(self.take())
+        }
+
+        pub fn f32(&mut self) -> f32 {
+            f32::from_be_bytes(self.take())
+        }
+
+        pub fn f64(&mut self) -> f64 {
+            f64::from_be_bytes(self.take())
+        }
+
+        pub fn string(&mut self) -> String {
+            let mut len = self.u8() as usize;
+            if len == 255 {
+                len = self.i32() as usize;
+            }
+            self.pos += len;
+            String::from_utf8(self.data[self.pos - len..self.pos].to_vec()).unwrap()
+        }
+
+        // byte count and version, returns where the object ends
+        fn start(&mut self) -> (usize, i16) {
+            let count = self.u32();
+            assert!(
+                count & BYTE_COUNT_MASK != 0,
+                "no byte count at {}",
+                self.pos - 4
+            );
+            let end = self.pos + (count & !BYTE_COUNT_MASK) as usize;
+            (end, self.i16())
+        }
+
+        fn end(&self, end: usize) {
+            assert_eq!(self.pos, end, "the byte count doesn't match the object");
+        }
+
+        fn tag(&self, pos: usize) -> u32 {
+            (pos - self.key + MAP_OFFSET) as u32
+        }
+
+        fn tobject(&mut self, fields: &mut Vec<(String, Value)>) {
+            assert_eq!(self.i16(), 1);
+            fields.push(("fUniqueID".to_string(), Value::Int(self.u32() as i64)));
+            fields.push(("fBits".to_string(), Value::Int(self.u32() as i64)));
+        }
+
+        fn named(&mut self, fields: &mut Vec<(String, Value)>) {
+            let (end, version) = self.start();
+            assert_eq!(version, 1);
+            self.tobject(fields);
+            fields.push(("fName".to_string(), Value::Text(self.string())));
+            fields.push(("fTitle".to_string(), Value::Text(self.string())));
+            self.end(end);
+        }
+
+        // a pointer member or an entry of a collection
+        pub fn pointer(&mut self) -> Value {
+            let start = self.pos;
+            let word = self.u32();
+            if word == 0 {
+                return Value::Null;
+            }
+            if word & BYTE_COUNT_MASK == 0 {
+                assert!(self.objects.contains(&word), "dangling reference {}", word);
+                return Value::Ref(word);
+            }
+            let end = self.pos + (word & !BYTE_COUNT_MASK) as usize;
+            let tag_pos = self.pos;
+            let tag = self.u32();
+            let class = if tag == NEW_CLASS_TAG {
+                let name_end =
+                    self.pos + self.data[self.pos..].iter().position(|&b| b == 0).unwrap();
+                let class = String::from_utf8(self.data[self.pos..name_end].to_vec()).unwrap();
+                self.pos = name_end + 1;
+                self.tags.insert(self.tag(tag_pos), class.clone());
+                class
+            } else {
+                assert!(tag & 0x8000_0000 != 0, "bad class tag {:x}", tag);
+                self.tags[&(tag & !0x8000_0000)].clone()
+            };
+            self.objects.push(self.tag(start));
+            let object = self.object(&class);
+            self.end(end);
+            Value::Object(object)
+        }
+
+        fn collection(&mut self, class: &str, fields: &mut Vec<(String, Value)>) {
+            let (end, version) = self.start();
+            let mut items = Vec::new();
+            self.tobject(fields);
+            fields.push(("fName".to_string(), Value::Text(self.string())));
+            let n = self.i32();
+            if class == "TObjArray" {
+                assert_eq!(version, 3);
+                // lower bound
+                assert_eq!(self.i32(), 0);
+            } else {
+                assert_eq!(version, 5);
+            }
+            for _ in 0..n {
+                match self.pointer() {
+                    Value::Object(object) => items.push(object),
+                    Value::Ref(tag) => items.push(Object {
+                        class: format!("ref {}", tag),
+                        fields: Vec::new(),
+                    }),
+                    v => panic!("{:?} in a {}", v, class),
+                }
+                if class == "TList" {
+                    // option string
+                    self.string();
+                }
+            }
+            self.end(end);
+            fields.push(("items".to_string(), Value::Objects(items)));
+        }
+
+        fn element(&mut self, class: &str, fields: &mut Vec<(String, Value)>) {
+            let (end, _) = self.start();
+            let (e, version) = self.start();
+            assert_eq!(version, 4);
+            self.named(fields);
+            for name in ["fType", "fSize", "fArrayLength", "fArrayDim"] {
+                fields.push((name.to_string(), Value::Int(self.i32() as i64)));
+            }
+            for _ in 0..5 {
+                assert_eq!(self.i32(), 0);
+            }
+            fields.push(("fTypeName".to_string(), Value::Text(self.string())));
+            self.end(e);
+            match class {
+                "TStreamerBase" => {
+                    fields.push(("fBaseVersion".to_string(), Value::Int(self.i32() as i64)))
+                }
+                "TStreamerBasicPointer" => {
+                    fields.push(("fCountVersion".to_string(), Value::Int(self.i32() as i64)));
+                    fields.push(("fCountName".to_string(), Value::Text(self.string())));
+                    fields.push(("fCountClass".to_string(), Value::Text(self.string())));
+                }
+                _ => (),
+            }
+            self.end(end);
+        }
+
+        fn basic(&mut self, kind: i32) -> Value {
+            match kind {
+                1 => Value::Int(self.u8() as i8 as i64),
+                2 => Value::Int(self.i16() as i64),
+                3 | 6 => Value::Int(self.i32() as i64),
+                5 => Value::Float(self.f32() as f64),
+                8 => Value::Float(self.f64()),
+                11 | 18 => Value::Int(self.u8() as i64),
+                12 => Value::Int(self.u16() as i64),
+                13 | 15 => Value::Int(self.u32() as i64),
+                16 => Value::Int(self.i64()),
+                _ => panic!("basic type {}", kind),
+            }
+        }
+
+        pub fn object(&mut self, class: &str) -> Object {
+            let mut fields = Vec::new();
+            self.members(class, &mut fields);
+            Object {
+                class: class.to_string(),
+                fields,
+            }
+        }
+
+        fn members(&mut self, class: &str, fields: &mut Vec<(String, Value)>) {
+            match class {
+                "TObject" => return self.tobject(fields),
+                "TObjArray" | "TList" => return self.collection(class, fields),
+                "TArrayD" => {
+                    let n = self.i32();
+                    let values = (0..n).map(|_| self.f64()).collect();
+                    return fields.push(("fArray".to_string(), Value::Array(values)));
+                }
+                "TArrayI" => {
+                    let n = self.i32();
+                    let values = (0..n).map(|_| self.i32() as f64).collect();
+                    return fields.push(("fArray".to_string(), Value::Array(values)));
+                }
+                "TStreamerInfo" => {
+                    let (end, version) = self.start();
+                    assert_eq!(version, 9);
+                    self.named(fields);
+                    fields.push(("fCheckSum".to_string(), Value::Int(self.u32() as i64)));
+                    fields.push(("fClassVersion".to_string(), Value::Int(self.i32() as i64)));
+                    let elements = self.pointer();
+                    fields.push(("fElements".to_string(), elements));
+                    return self.end(end);
+                }
+                c if c.starts_with("TStreamer") => return self.element(c, fields),
+                _ => (),
+            }
+
+            let info = match self.infos.get(class) {
+                Some(info) => info,
+                None => panic!("no StreamerInfo for {}", class),
+            };
+            let (end, version) = self.start();
+            if version == 0 {
+                // classes without a version have their checksum instead
+                assert_eq!(self.u32(), info.checksum, "checksum of {}", class);
+            } else {
+                assert_eq!(version as i32, info.version, "version of {}", class);
+            }
+            for element in info.elements.iter() {
+                let value = match element.kind {
+                    0 | 66 | 67 => {
+                        self.members(&element.name, fields);
+                        continue;
+                    }
+                    1..=18 => self.basic(element.kind),
+                    41..=58 => {
+                        let count = fields
+                            .iter()
+                            .rev()
+                            .find(|(n, _)| *n == element.count_name)
+                            .map(|(_, v)| v.clone());
+                        let n = match count {
+                            Some(Value::Int(n)) => n,
+                            _ => panic!("no counter {} in {}", element.count_name, class),
+                        };
+                        let values = if self.u8() == 0 {
+                            assert_eq!(n, 0, "{} is null", element.name);
+                            Vec::new()
+                        } else {
+                            (0..n)
+                                .map(|_| match self.basic(element.kind - 40) {
+                                    Value::Int(v) => v as f64,
+                                    Value::Float(v) => v,
+                                    _ => unreachable!(),
+                                })
+                                .collect()
+                        };
+                        Value::Array(values)
+                    }
+                    61 | 62 => Value::Object(self.object(&element.type_name)),
+                    63 => Value::Object(self.object(element.type_name.trim_end_matches('*'))),
+                    64 => self.pointer(),
+                    65 => Value::Text(self.string()),
+                    kind => panic!("element type {} of {}", kind, element.name),
+                };
+                fields.push((element.name.clone(), value));
+            }
+            self.end(end);
+        }
+    }
+
+    // the key header at seek, which is also in the keys list
+    pub fn key(data: &[u8], seek: usize) -> Key {
+        let infos = HashMap::new();
+        let mut r = Reader::new(data, seek, seek, &infos);
+        let nbytes = r.i32() as usize;
+        let version = r.i16();
+        let object_len = r.i32() as usize;
+        r.u32();
+        let key_len = r.i16() as usize;
+        assert_eq!(r.i16(), 1);
+        let (seek_key, _) = if version > BIG_VERSION {
+            (r.i64() as usize, r.i64())
+        } else {
+            (r.i32() as usize, r.i32() as i64)
+        };
+        Key {
+            class: r.string(),
+            name: r.string(),
+            title: r.string(),
+            seek: seek_key,
+            nbytes,
+            key_len,
+            object_len,
+        }
+    }
+
+    pub struct Contents {
+        pub data: Vec<u8>,
+        pub keys: Vec<Key>,
+        pub infos: HashMap<String, Info>,
+        // the classes in the StreamerInfo, in order
+        pub classes: Vec<String>,
+    }
+
+    impl Contents {
+        pub fn key(&self, name: &str) -> &Key {
+            self.keys.iter().find(|k| k.name == name).unwrap()
+        }
+
+        // reads the object of a key, which has to fill it exactly
+        pub fn object(&self, key: &Key) -> Object {
+            let start = key.seek + key.key_len;
+            let mut r = Reader::new(&self.data, key.seek, start, &self.infos);
+            let object = r.object(&key.class);
+            assert_eq!(
+                r.pos,
+                start + key.object_len,
+                "{} is not all read",
+                key.name
+            );
+            assert_eq!(key.nbytes, key.key_len + key.object_len);
+            object
+        }
+    }
+
+    // Reads the header, the keys list and the StreamerInfo of a file.
+    pub fn read_root(filename: &str) -> Contents {
+        let data = std::fs::read(filename).unwrap();
+        let no_infos = HashMap::new();
+        let mut r = Reader::new(&data, 0, 0, &no_infos);
+        assert_eq!(&r.take::<4>(), b"root");
+        assert_eq!(r.i32(), FILE_VERSION);
+        assert_eq!(r.i32() as usize, BEGIN);
+        assert_eq!(
+            r.i32() as usize,
+            data.len(),
+            "fEND is not the end of the file"
+        );
+        let seek_free = r.i32() as usize;
+        let nbytes_free = r.i32() as usize;
+        assert_eq!(r.i32(), 1);
+        r.i32();
+        assert_eq!(r.u8(), 4);
+        assert_eq!(r.i32(), 0);
+        let seek_info = r.i32() as usize;
+        let nbytes_info = r.i32() as usize;
+
+        let top = key(&data, BEGIN);
+        assert_eq!(top.class, "TFile");
+        r.pos = BEGIN + top.key_len;
+        r.string();
+        r.string();
+        assert_eq!(r.i16(), DIRECTORY_VERSION);
+        r.u32();
+        r.u32();
+        let nbytes_keys = r.i32() as usize;
+        r.i32();
+        assert_eq!(r.i32() as usize, BEGIN);
+        assert_eq!(r.i32(), 0);
+        let seek_keys = r.i32() as usize;
+
+        let keys_key = key(&data, seek_keys);
+        assert_eq!(keys_key.nbytes, nbytes_keys);
+        r.pos = seek_keys + keys_key.key_len;
+        let mut keys = Vec::new();
+        for _ in 0..r.i32() {
+            let k = key(&data, r.pos);
+            r.pos += k.key_len;
+            assert_eq!(key(&data, k.seek).name, k.name);
+            keys.push(k);
+        }
+        assert_eq!(r.pos, seek_keys + nbytes_keys);
+        assert_eq!(key(&data, seek_free).nbytes, nbytes_free);
+
+        let info_key = key(&data, seek_info);
+        assert_eq!(
+            (
+                info_key.class.as_str(),
+                info_key.name.as_str(),
+                info_key.nbytes
+            ),
+            ("TList", "StreamerInfo", nbytes_info)
+        );
+        let mut contents = Contents {
+            data,
+            keys,
+            infos: HashMap::new(),
+            classes: Vec::new(),
+        };
+        let list = contents.object(&info_key);
+        for info in list.objects("items") {
+            assert_eq!(info.class, "TStreamerInfo");
+            let elements = info
+                .object("fElements")
+                .objects("items")
+                .iter()
+                .map(|e| Element {
+                    name: e.text("fName").to_string(),
+                    kind: e.int("fType") as i32,
+                    type_name: e.text("fTypeName").to_string(),
+                    count_name: match e.class.as_str() {
+                        "TStreamerBasicPointer" => e.text("fCountName").to_string(),
+                        _ => String::new(),
+                    },
+                })
+                .collect();
+            let name = info.text("fName").to_string();
+            contents.infos.insert(
+                name.clone(),
+                Info {
+                    version: info.int("fClassVersion") as i32,
+                    checksum: info.int("fCheckSum") as u32,
+                    elements,
+                },
+            );
+            contents.classes.push(name);
+        }
+        contents
+    }
+
+    fn test_file(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!(
+                "midas_converter_{}_{}.root",
+                name,
+                std::process::id()
+            ))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    #[test]
+    fn tnamed_bytes() {
+        let mut buf = RootBuffer::new();
+        buf.tnamed("h", "t");
+        assert_eq!(
+            buf.data,
+            [
+                0x40, 0, 0, 16, 0, 1, // byte count, version
+                0, 1, 0, 0, 0, 0, 3, 0, 0, 0, // TObject
+                1, b'h', 1, b't',
+            ]
+        );
+    }
+
+    #[test]
+    fn long_strings_get_a_length() {
+        let mut buf = RootBuffer::new();
+        buf.string(&"x".repeat(300));
+        assert_eq!(&buf.data[..5], [255, 0, 0, 1, 44]);
+        assert_eq!(buf.len(), 305);
+    }
+
+    #[test]
+    fn empty_file() {
+        let filename = test_file("empty");
+        RootFile::create(&filename, "nothing")
+            .unwrap()
+            .close()
+            .unwrap();
+        let contents = read_root(&filename);
+        assert!(contents.keys.is_empty());
+        assert!(contents.classes.is_empty());
+        std::fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn histograms_round_trip() {
+        let filename = test_file("histograms");
+        let x = RootAxis {
+            name: "xaxis",
+            title: "adc",
+            nbins: 4,
+            min: 0.0,
+            max: 8.0,
+        };
+        let y = RootAxis {
+            name: "yaxis",
+            title: "tdc",
+            nbins: 3,
+            min: -3.0,
+            max: 3.0,
+        };
+        let counts_1d = [1, 0, 5, 2];
+        let counts_2d: Vec<u64> = (0..12).collect();
+        let mut file = RootFile::create(&filename, "test").unwrap();
+        let h1 = th1d("h1", "one", &x, &counts_1d);
+        file.add_object("TH1D", "h1", "one", &h1).unwrap();
+        let h2 = th2d("h2", "two", &x, &y, &counts_2d);
+        file.add_object("TH2D", "h2", "two", &h2).unwrap();
+        file.close().unwrap();
+
+        let contents = read_root(&filename);
+        // every class is described once, after its bases and members
+        for (i, class) in contents.classes.iter().enumerate() {
+            let info = class_info(class).unwrap();
+            assert_eq!(contents.infos[class].checksum, checksum(info));
+            assert_eq!(contents.infos[class].version, info.version);
+            for part in contents.infos[class].elements.iter() {
+                let part = part.type_name.as_str();
+                let part = if part == "BASE" { "" } else { part };
+                if let Some(j) = contents.classes.iter().position(|c| c == part) {
+                    assert!(j < i, "{} is after {}", part, class);
+                }
+            }
+        }
+        let mut classes = contents.classes.clone();
+        classes.sort();
+        assert_eq!(
+            classes,
+            [
+                "TArray",
+                "TArrayD",
+                "TAttAxis",
+                "TAttFill",
+                "TAttLine",
+                "TAttMarker",
+                "TAxis",
+                "TH1",
+                "TH1D",
+                "TH2",
+                "TH2D",
+                "TNamed",
+                "TObject",
+            ]
+        );
+
+        let h1 = contents.object(contents.key("h1"));
+        assert_eq!((h1.text("fName"), h1.text("fTitle")), ("h1", "one"));
+        assert_eq!(h1.int("fNcells"), 6);
+        assert_eq!(h1.array("fArray"), [0.0, 1.0, 0.0, 5.0, 2.0, 0.0]);
+        assert_eq!(h1.float("fEntries"), 8.0);
+        // bin centers 1, 3, 5, 7
+        assert_eq!(h1.float("fTsumwx"), 1.0 + 25.0 + 14.0);
+        let axis = h1.object("fXaxis");
+        assert_eq!(
+            (axis.int("fNbins"), axis.float("fXmin"), axis.float("fXmax")),
+            (4, 0.0, 8.0)
+        );
+        assert_eq!(axis.text("fTitle"), "adc");
+        assert_eq!(h1.int("fStatOverflows"), 2);
+
+        let h2 = contents.object(contents.key("h2"));
+        assert_eq!(h2.int("fNcells"), 30);
+        assert_eq!(h2.object("fYaxis").float("fXmin"), -3.0);
+        let bins = h2.array("fArray");
+        for (i, &count) in counts_2d.iter().enumerate() {
+            let (ix, iy) = (i / 3, i % 3);
+            assert_eq!(bins[(iy + 1) * 6 + ix + 1], count as f64);
+        }
+        assert_eq!(bins.iter().sum::<f64>(), 66.0);
+        assert_eq!(h2.float("fTsumw"), 66.0);
+        std::fs::remove_file(&filename).unwrap();
+    }
+}
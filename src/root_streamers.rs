@@ -0,0 +1,706 @@
+use crate::root_file::RootBuffer;
+
+/*
+The StreamerInfo record of the ROOT output.
+
+A ROOT file carries a TStreamerInfo for every class it has objects of, which
+lists the members the way they were streamed. ROOT and uproot know the classes
+we write, but they go by the StreamerInfo of the file once the class has moved
+on to a newer version, so every class in the file and the classes it is made of
+are described here, at the versions root_file.rs and root_tree.rs write.
+
+Each TStreamerInfo has the checksum of its class, which ROOT compares with the
+checksum of the class it has compiled in. It is a hash of the class name, the
+names and checksums of the bases and the names and types of the persistent
+members, with the typedefs resolved (int, not Int_t) except for Long64_t.
+TObject comes out as 0x901bc02d, same as in every ROOT file.
+
+The collections (TObjArray, TList) and the TArrays have their own streamers,
+they are not described.
+*/
+
+// ROOT's TVirtualStreamerInfo::EReadWrite, the types of the streamer elements
+const K_BASE: i32 = 0;
+const K_COUNTER: i32 = 6;
+const K_BITS: i32 = 15;
+const K_OFFSET_P: i32 = 40;
+const K_OBJECT: i32 = 61;
+const K_ANY: i32 = 62;
+const K_OBJECT_IN_PLACE: i32 = 63;
+const K_OBJECT_POINTER: i32 = 64;
+const K_TSTRING: i32 = 65;
+const K_TOBJECT: i32 = 66;
+const K_TNAMED: i32 = 67;
+const STREAMER_INFO_VERSION: i16 = 9;
+const ELEMENT_VERSION: i16 = 4;
+const NEW_CLASS_TAG: u32 = 0xFFFF_FFFF;
+const CLASS_MASK: u32 = 0x8000_0000;
+const MAP_OFFSET: usize = 2;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    // int, double, ... and the enums, which are ints
+    Basic,
+    Enum,
+    // TObject::fBits
+    Bits,
+    // an int that is the length of an array member
+    Counter,
+    // array of a basic type, its length is the counter member
+    Array(&'static str),
+    // an object in place, with or without TObject
+    Object,
+    Any,
+    // pointer to an object, null or written with its class
+    Pointer,
+    // pointer that is never null (//->), the object is written in place
+    PointerInPlace,
+    TString,
+}
+
+pub struct Member {
+    pub name: &'static str,
+    // the type as ROOT spells it in the checksum and the StreamerInfo
+    pub type_name: &'static str,
+    pub kind: Kind,
+    title: &'static str,
+}
+
+pub struct ClassInfo {
+    pub name: &'static str,
+    pub version: i32,
+    title: &'static str,
+    pub bases: &'static [&'static str],
+    pub members: &'static [Member],
+}
+
+const fn m(name: &'static str, type_name: &'static str, kind: Kind, title: &'static str) -> Member {
+    Member {
+        name,
+        type_name,
+        kind,
+        title,
+    }
+}
+
+use Kind::*;
+
+static CLASSES: &[ClassInfo] = &[
+    ClassInfo {
+        name: "TObject",
+        version: 1,
+        title: "Basic ROOT object",
+        bases: &[],
+        members: &[
+            m("fUniqueID", "unsigned int", Basic, "object unique identifier"),
+            m("fBits", "unsigned int", Bits, "bit field status word"),
+        ],
+    },
+    ClassInfo {
+        name: "TNamed",
+        version: 1,
+        title: "The basis for a named object (name, title)",
+        bases: &["TObject"],
+        members: &[
+            m("fName", "TString", TString, "object identifier"),
+            m("fTitle", "TString", TString, "object title"),
+        ],
+    },
+    ClassInfo {
+        name: "TAttLine",
+        version: 2,
+        title: "Line attributes",
+        bases: &[],
+        members: &[
+            m("fLineColor", "short", Basic, "Line color"),
+            m("fLineStyle", "short", Basic, "Line style"),
+            m("fLineWidth", "short", Basic, "Line width"),
+        ],
+    },
+    ClassInfo {
+        name: "TAttFill",
+        version: 2,
+        title: "Fill area attributes",
+        bases: &[],
+        members: &[
+            m("fFillColor", "short", Basic, "Fill area color"),
+            m("fFillStyle", "short", Basic, "Fill area style"),
+        ],
+    },
+    ClassInfo {
+        name: "TAttMarker",
+        version: 2,
+        title: "Marker attributes",
+        bases: &[],
+        members: &[
+            m("fMarkerColor", "short", Basic, "Marker color"),
+            m("fMarkerStyle", "short", Basic, "Marker style"),
+            m("fMarkerSize", "float", Basic, "Marker size"),
+        ],
+    },
+    ClassInfo {
+        name: "ROOT::TIOFeatures",
+        version: 1,
+        title: "",
+        bases: &[],
+        members: &[m("fIOBits", "unsigned char", Basic, "")],
+    },
+    ClassInfo {
+        name: "TTree",
+        version: 20,
+        title: "Tree descriptor (the main ROOT I/O class)",
+        bases: &["TNamed", "TAttLine", "TAttFill", "TAttMarker"],
+        members: &[
+            m("fEntries", "Long64_t", Basic, "Number of entries"),
+            m("fTotBytes", "Long64_t", Basic, "Total number of bytes in all branches before compression"),
+            m("fZipBytes", "Long64_t", Basic, "Total number of bytes in all branches after compression"),
+            m("fSavedBytes", "Long64_t", Basic, "Number of autosaved bytes"),
+            m("fFlushedBytes", "Long64_t", Basic, "Number of auto-flushed bytes"),
+            m("fWeight", "double", Basic, "Tree weight (see TTree::SetWeight)"),
+            m("fTimerInterval", "int", Basic, "Timer interval in milliseconds"),
+            m("fScanField", "int", Basic, "Number of runs before prompting in Scan"),
+            m("fUpdate", "int", Basic, "Update frequency for EntryLoop"),
+            m("fDefaultEntryOffsetLen", "int", Basic, "Initial Length of fEntryOffset table in the basket buffers"),
+            m("fNClusterRange", "int", Counter, "Number of Cluster range in addition to the one defined by 'AutoFlush'"),
+            m("fMaxEntries", "Long64_t", Basic, "Maximum number of entries in case of circular buffers"),
+            m("fMaxEntryLoop", "Long64_t", Basic, "Maximum number of entries to process"),
+            m("fMaxVirtualSize", "Long64_t", Basic, "Maximum total size of buffers kept in memory"),
+            m("fAutoSave", "Long64_t", Basic, "Autosave tree when fAutoSave entries have been written or -fAutoSave (compressed) bytes produced"),
+            m("fAutoFlush", "Long64_t", Basic, "Auto-flush tree when fAutoFlush entries have been written or -fAutoFlush (compressed) bytes produced"),
+            m("fEstimate", "Long64_t", Basic, "Number of entries to estimate histogram limits"),
+            m("fClusterRangeEnd", "Long64_t*", Array("fNClusterRange"), "[fNClusterRange] Last entry of a cluster range."),
+            m("fClusterSize", "Long64_t*", Array("fNClusterRange"), "[fNClusterRange] Number of entries in each cluster for a given range."),
+            m("fIOFeatures", "ROOT::TIOFeatures", Any, "IO features to define for newly-written baskets and branches."),
+            m("fBranches", "TObjArray", Object, "List of Branches"),
+            m("fLeaves", "TObjArray", Object, "Direct pointers to individual branch leaves"),
+            m("fAliases", "TList*", Pointer, "List of aliases for expressions based on the tree branches."),
+            m("fIndexValues", "TArrayD", Any, "Sorted index values"),
+            m("fIndex", "TArrayI", Any, "Index of sorted values"),
+            m("fTreeIndex", "TVirtualIndex*", Pointer, "Pointer to the tree Index (if any)"),
+            m("fFriends", "TList*", Pointer, "pointer to list of friend elements"),
+            m("fUserInfo", "TList*", Pointer, "pointer to a list of user objects associated to this Tree"),
+            m("fBranchRef", "TBranchRef*", Pointer, "Branch supporting the TRefTable (if any)"),
+        ],
+    },
+    ClassInfo {
+        name: "TBranch",
+        version: 13,
+        title: "Branch descriptor",
+        bases: &["TNamed", "TAttFill"],
+        members: &[
+            m("fCompress", "int", Basic, "Compression level and algorithm"),
+            m("fBasketSize", "int", Basic, "Initial Size of  Basket Buffer"),
+            m("fEntryOffsetLen", "int", Basic, "Initial Length of fEntryOffset table in the basket buffers"),
+            m("fWriteBasket", "int", Basic, "Last basket number written"),
+            m("fEntryNumber", "Long64_t", Basic, "Current entry number (last one filled in this branch)"),
+            m("fIOFeatures", "ROOT::TIOFeatures", Any, "IO features for newly-created baskets."),
+            m("fOffset", "int", Basic, "Offset of this branch"),
+            m("fMaxBaskets", "int", Counter, "Maximum number of Baskets so far"),
+            m("fSplitLevel", "int", Basic, "Branch split level"),
+            m("fEntries", "Long64_t", Basic, "Number of entries"),
+            m("fFirstEntry", "Long64_t", Basic, "Number of the first entry in this branch"),
+            m("fTotBytes", "Long64_t", Basic, "Total number of bytes in all leaves before compression"),
+            m("fZipBytes", "Long64_t", Basic, "Total number of bytes in all leaves after compression"),
+            m("fBranches", "TObjArray", Object, "-> List of Branches of this branch"),
+            m("fLeaves", "TObjArray", Object, "-> List of leaves of this branch"),
+            m("fBaskets", "TObjArray", Object, "-> List of baskets of this branch"),
+            m("fBasketBytes", "int*", Array("fMaxBaskets"), "[fMaxBaskets] Length of baskets on file"),
+            m("fBasketEntry", "Long64_t*", Array("fMaxBaskets"), "[fMaxBaskets] Table of first entry in each basket"),
+            m("fBasketSeek", "Long64_t*", Array("fMaxBaskets"), "[fMaxBaskets] Addresses of baskets on file"),
+            m("fFileName", "TString", TString, "Name of file where buffers are stored (\"\" if in same file as Tree header)"),
+        ],
+    },
+    ClassInfo {
+        name: "TLeaf",
+        version: 2,
+        title: "Leaf: description of a Branch data type",
+        bases: &["TNamed"],
+        members: &[
+            m("fLen", "int", Basic, "Number of fixed length elements in the leaf's data."),
+            m("fLenType", "int", Basic, "Number of bytes for this data type"),
+            m("fOffset", "int", Basic, "Offset in ClonesArray object (if one)"),
+            m("fIsRange", "bool", Basic, "(=kTRUE if leaf has a range, kFALSE otherwise)"),
+            m("fIsUnsigned", "bool", Basic, "(=kTRUE if unsigned, kFALSE otherwise)"),
+            m("fLeafCount", "TLeaf*", Pointer, "Pointer to Leaf count if variable length (we do not own the counter)"),
+        ],
+    },
+    ClassInfo {
+        name: "TLeafO",
+        version: 1,
+        title: "A TLeaf for a bool data type.",
+        bases: &["TLeaf"],
+        members: &[
+            m("fMinimum", "bool", Basic, "Minimum value if leaf range is specified"),
+            m("fMaximum", "bool", Basic, "Maximum value if leaf range is specified"),
+        ],
+    },
+    ClassInfo {
+        name: "TLeafB",
+        version: 1,
+        title: "A TLeaf for an 8 bit Integer data type.",
+        bases: &["TLeaf"],
+        members: &[
+            m("fMinimum", "char", Basic, "Minimum value if leaf range is specified"),
+            m("fMaximum", "char", Basic, "Maximum value if leaf range is specified"),
+        ],
+    },
+    ClassInfo {
+        name: "TLeafS",
+        version: 1,
+        title: "A TLeaf for a 16 bit Integer data type.",
+        bases: &["TLeaf"],
+        members: &[
+            m("fMinimum", "short", Basic, "Minimum value if leaf range is specified"),
+            m("fMaximum", "short", Basic, "Maximum value if leaf range is specified"),
+        ],
+    },
+    ClassInfo {
+        name: "TLeafI",
+        version: 1,
+        title: "A TLeaf for an Integer data type.",
+        bases: &["TLeaf"],
+        members: &[
+            m("fMinimum", "int", Basic, "Minimum value if leaf range is specified"),
+            m("fMaximum", "int", Basic, "Maximum value if leaf range is specified"),
+        ],
+    },
+    ClassInfo {
+        name: "TLeafL",
+        version: 1,
+        title: "A TLeaf for a 64 bit Integer data type.",
+        bases: &["TLeaf"],
+        members: &[
+            m("fMinimum", "Long64_t", Basic, "Minimum value if leaf range is specified"),
+            m("fMaximum", "Long64_t", Basic, "Maximum value if leaf range is specified"),
+        ],
+    },
+    ClassInfo {
+        name: "TLeafD",
+        version: 1,
+        title: "A TLeaf for a 64 bit floating point data type.",
+        bases: &["TLeaf"],
+        members: &[
+            m("fMinimum", "double", Basic, "Minimum value if leaf range is specified"),
+            m("fMaximum", "double", Basic, "Maximum value if leaf range is specified"),
+        ],
+    },
+    ClassInfo {
+        name: "TLeafC",
+        version: 1,
+        title: "A TLeaf for a variable length string.",
+        bases: &["TLeaf"],
+        members: &[
+            m("fMinimum", "int", Basic, "Minimum value if leaf range is specified"),
+            m("fMaximum", "int", Basic, "Maximum value if leaf range is specified"),
+        ],
+    },
+    ClassInfo {
+        name: "TArray",
+        version: 1,
+        title: "Abstract array base class",
+        bases: &[],
+        members: &[m("fN", "int", Counter, "Number of array elements")],
+    },
+    ClassInfo {
+        name: "TArrayD",
+        version: 1,
+        title: "Array of doubles",
+        bases: &["TArray"],
+        members: &[m("fArray", "double*", Array("fN"), "[fN] Array of fN doubles")],
+    },
+    ClassInfo {
+        name: "TArrayI",
+        version: 1,
+        title: "Array of ints",
+        bases: &["TArray"],
+        members: &[m("fArray", "int*", Array("fN"), "[fN] Array of fN 32 bit integers")],
+    },
+    ClassInfo {
+        name: "TAttAxis",
+        version: 4,
+        title: "Axis attributes",
+        bases: &[],
+        members: &[
+            m("fNdivisions", "int", Basic, "Number of divisions(10000*n3 + 100*n2 + n1)"),
+            m("fAxisColor", "short", Basic, "Color of the line axis"),
+            m("fLabelColor", "short", Basic, "Color of labels"),
+            m("fLabelFont", "short", Basic, "Font for labels"),
+            m("fLabelOffset", "float", Basic, "Offset of labels"),
+            m("fLabelSize", "float", Basic, "Size of labels"),
+            m("fTickLength", "float", Basic, "Length of tick marks"),
+            m("fTitleOffset", "float", Basic, "Offset of axis title"),
+            m("fTitleSize", "float", Basic, "Size of axis title"),
+            m("fTitleColor", "short", Basic, "Color of axis title"),
+            m("fTitleFont", "short", Basic, "Font for axis title"),
+        ],
+    },
+    ClassInfo {
+        name: "TAxis",
+        version: 10,
+        title: "Axis class",
+        bases: &["TNamed", "TAttAxis"],
+        members: &[
+            m("fNbins", "int", Basic, "Number of bins"),
+            m("fXmin", "double", Basic, "Low edge of first bin"),
+            m("fXmax", "double", Basic, "Upper edge of last bin"),
+            m("fXbins", "TArrayD", Any, "Bin edges array in X"),
+            m("fFirst", "int", Basic, "First bin to display"),
+            m("fLast", "int", Basic, "Last bin to display"),
+            m("fBits2", "unsigned short", Basic, "Second bit status word"),
+            m("fTimeDisplay", "bool", Basic, "On/off displaying time values instead of numerics"),
+            m("fTimeFormat", "TString", TString, "Date&time format, ex: 09/12/99 12:34:00"),
+            m("fLabels", "THashList*", Pointer, "List of labels"),
+            m("fModLabels", "TList*", Pointer, "List of modified labels"),
+        ],
+    },
+    ClassInfo {
+        name: "TH1",
+        version: 8,
+        title: "1-Dim histogram base class",
+        bases: &["TNamed", "TAttLine", "TAttFill", "TAttMarker"],
+        members: &[
+            m("fNcells", "int", Basic, "Number of bins(1D), cells (2D) +U/Overflows"),
+            m("fXaxis", "TAxis", Object, "X axis descriptor"),
+            m("fYaxis", "TAxis", Object, "Y axis descriptor"),
+            m("fZaxis", "TAxis", Object, "Z axis descriptor"),
+            m("fBarOffset", "short", Basic, "(1000*offset) for bar charts or legos"),
+            m("fBarWidth", "short", Basic, "(1000*width) for bar charts or legos"),
+            m("fEntries", "double", Basic, "Number of entries"),
+            m("fTsumw", "double", Basic, "Total Sum of weights"),
+            m("fTsumw2", "double", Basic, "Total Sum of squares of weights"),
+            m("fTsumwx", "double", Basic, "Total Sum of weight*X"),
+            m("fTsumwx2", "double", Basic, "Total Sum of weight*X*X"),
+            m("fMaximum", "double", Basic, "Maximum value for plotting"),
+            m("fMinimum", "double", Basic, "Minimum value for plotting"),
+            m("fNormFactor", "double", Basic, "Normalization factor"),
+            m("fContour", "TArrayD", Any, "Array to display contour levels"),
+            m("fSumw2", "TArrayD", Any, "Array of sum of squares of weights"),
+            m("fOption", "TString", TString, "Histogram options"),
+            m("fFunctions", "TList*", PointerInPlace, "->Pointer to list of functions (fits and user)"),
+            m("fBufferSize", "int", Counter, "fBuffer size"),
+            m("fBuffer", "double*", Array("fBufferSize"), "[fBufferSize] entry buffer"),
+            m("fBinStatErrOpt", "TH1::EBinErrorOpt", Enum, "Option for bin statistical errors"),
+            m("fStatOverflows", "TH1::EStatOverflows", Enum, "Per object flag to use under/overflows in statistics"),
+        ],
+    },
+    ClassInfo {
+        name: "TH1D",
+        version: 3,
+        title: "1-Dim histograms (one double per channel)",
+        bases: &["TH1", "TArrayD"],
+        members: &[],
+    },
+    ClassInfo {
+        name: "TH2",
+        version: 5,
+        title: "2-Dim histogram base class",
+        bases: &["TH1"],
+        members: &[
+            m("fScalefactor", "double", Basic, "Scale factor"),
+            m("fTsumwy", "double", Basic, "Total Sum of weight*Y"),
+            m("fTsumwy2", "double", Basic, "Total Sum of weight*Y*Y"),
+            m("fTsumwxy", "double", Basic, "Total Sum of weight*X*Y"),
+        ],
+    },
+    ClassInfo {
+        name: "TH2D",
+        version: 4,
+        title: "2-Dim histograms (one double per channel)",
+        bases: &["TH2", "TArrayD"],
+        members: &[],
+    },
+];
+
+pub fn class_info(name: &str) -> Option<&'static ClassInfo> {
+    CLASSES.iter().find(|c| c.name == name)
+}
+
+// the ROOT type code and size of a basic type
+pub fn basic_type(type_name: &str) -> (i32, usize) {
+    match type_name.trim_end_matches('*') {
+        "char" => (1, 1),
+        "short" => (2, 2),
+        "int" => (3, 4),
+        "float" => (5, 4),
+        "double" => (8, 8),
+        "unsigned char" => (11, 1),
+        "unsigned short" => (12, 2),
+        "unsigned int" => (13, 4),
+        "Long64_t" => (16, 8),
+        "bool" => (18, 1),
+        // the enums are streamed as ints
+        _ => (3, 4),
+    }
+}
+
+// what sizeof gives on 64 bit linux, ROOT keeps it in the StreamerInfo
+fn object_size(type_name: &str) -> usize {
+    match type_name {
+        "ROOT::TIOFeatures" => 1,
+        "TString" | "TArrayD" | "TArrayI" => 24,
+        "TObjArray" => 64,
+        "TAxis" => 224,
+        _ => 8,
+    }
+}
+
+fn hash(mut id: u32, s: &str) -> u32 {
+    for &c in s.as_bytes() {
+        id = id.wrapping_mul(3).wrapping_add(c as u32);
+    }
+    id
+}
+
+// TClass::GetCheckSum
+pub fn checksum(class: &ClassInfo) -> u32 {
+    let mut id = hash(0, class.name);
+    for &base in class.bases {
+        id = hash(id, base);
+        id = id
+            .wrapping_mul(3)
+            .wrapping_add(checksum(class_info(base).unwrap()));
+    }
+    for member in class.members {
+        if member.kind == Enum {
+            id = id.wrapping_mul(3).wrapping_add(1);
+        }
+        id = hash(id, member.name);
+        id = hash(id, member.type_name);
+        if let Array(counter) = member.kind {
+            id = hash(id, counter);
+        }
+    }
+    id
+}
+
+// the classes and everything they are made of, the parts first
+fn add_with_parts(name: &str, classes: &mut Vec<&'static ClassInfo>) {
+    let Some(class) = class_info(name) else {
+        return;
+    };
+    if classes.iter().any(|c| c.name == name) {
+        return;
+    }
+    for &base in class.bases {
+        add_with_parts(base, classes);
+    }
+    for member in class.members {
+        if matches!(member.kind, Object | Any) {
+            add_with_parts(member.type_name, classes);
+        }
+    }
+    classes.push(class);
+}
+
+// the class or base class with the counter of an array
+fn counter_class(class: &'static ClassInfo, counter: &str) -> Option<&'static ClassInfo> {
+    if class
+        .members
+        .iter()
+        .any(|m| m.name == counter && m.kind == Counter)
+    {
+        return Some(class);
+    }
+    class
+        .bases
+        .iter()
+        .find_map(|&base| counter_class(class_info(base)?, counter))
+}
+
+// An object in a collection: byte count and the class, by name the first time
+// it shows up in the key and after that by where that was.
+fn start_object_class(
+    buf: &mut RootBuffer,
+    class: &'static str,
+    tags: &mut Vec<(&str, u32)>,
+) -> usize {
+    let position = buf.len();
+    buf.u32(0);
+    match tags.iter().find(|(c, _)| *c == class) {
+        Some(&(_, tag)) => buf.u32(tag | CLASS_MASK),
+        None => {
+            tags.push((class, (buf.position() + MAP_OFFSET) as u32));
+            buf.u32(NEW_CLASS_TAG);
+            buf.bytes(class.as_bytes());
+            buf.u8(0);
+        }
+    }
+    position
+}
+
+// TStreamerElement, which the element classes start with
+fn element(buf: &mut RootBuffer, name: &str, title: &str, kind: i32, size: usize, type_name: &str) {
+    let p = buf.start_object(ELEMENT_VERSION);
+    buf.tnamed(name, title);
+    buf.i32(kind);
+    buf.i32(size as i32);
+    // array length, dimensions and the 5 maximum indices of a fixed size array
+    for _ in 0..7 {
+        buf.i32(0);
+    }
+    buf.string(type_name);
+    buf.end_object(p);
+}
+
+fn write_base(buf: &mut RootBuffer, tags: &mut Vec<(&str, u32)>, base: &ClassInfo) {
+    let a = start_object_class(buf, "TStreamerBase", tags);
+    let p = buf.start_object(3);
+    let kind = match base.name {
+        "TObject" => K_TOBJECT,
+        "TNamed" => K_TNAMED,
+        _ => K_BASE,
+    };
+    element(buf, base.name, base.title, kind, 0, "BASE");
+    buf.i32(base.version);
+    buf.end_object(p);
+    buf.end_object(a);
+}
+
+fn write_member(
+    buf: &mut RootBuffer,
+    tags: &mut Vec<(&str, u32)>,
+    class: &'static ClassInfo,
+    member: &Member,
+) {
+    let (element_class, kind, size) = match member.kind {
+        Basic | Enum => {
+            let (kind, size) = basic_type(member.type_name);
+            ("TStreamerBasicType", kind, size)
+        }
+        Bits => ("TStreamerBasicType", K_BITS, 4),
+        Counter => ("TStreamerBasicType", K_COUNTER, 4),
+        Array(_) => (
+            "TStreamerBasicPointer",
+            K_OFFSET_P + basic_type(member.type_name).0,
+            8,
+        ),
+        Object => ("TStreamerObject", K_OBJECT, object_size(member.type_name)),
+        Any => ("TStreamerObjectAny", K_ANY, object_size(member.type_name)),
+        Pointer => ("TStreamerObjectPointer", K_OBJECT_POINTER, 8),
+        PointerInPlace => ("TStreamerObjectPointer", K_OBJECT_IN_PLACE, 8),
+        TString => ("TStreamerString", K_TSTRING, object_size(member.type_name)),
+    };
+    let a = start_object_class(buf, element_class, tags);
+    let p = buf.start_object(2);
+    element(buf, member.name, member.title, kind, size, member.type_name);
+    if let Array(counter) = member.kind {
+        let count_class = counter_class(class, counter).unwrap();
+        buf.i32(count_class.version);
+        buf.string(counter);
+        buf.string(count_class.name);
+    }
+    buf.end_object(p);
+    buf.end_object(a);
+}
+
+// The TList that goes in the StreamerInfo key, with a TStreamerInfo for the
+// classes and all of their parts. buf starts where the object goes in the key.
+pub fn streamer_info(buf: &mut RootBuffer, classes: &[String]) {
+    let mut infos = Vec::new();
+    for class in classes {
+        add_with_parts(class, &mut infos);
+    }
+    let mut tags = Vec::new();
+
+    let p = buf.start_object(5);
+    buf.tobject();
+    buf.string("");
+    buf.i32(infos.len() as i32);
+    for class in infos {
+        let a = start_object_class(buf, "TStreamerInfo", &mut tags);
+        let q = buf.start_object(STREAMER_INFO_VERSION);
+        buf.tnamed(class.name, "");
+        buf.u32(checksum(class));
+        buf.i32(class.version);
+
+        // the elements, in a TObjArray
+        let b = start_object_class(buf, "TObjArray", &mut tags);
+        let r = buf.start_object(3);
+        buf.tobject();
+        buf.string("");
+        buf.i32((class.bases.len() + class.members.len()) as i32);
+        buf.i32(0);
+        for &base in class.bases {
+            write_base(buf, &mut tags, class_info(base).unwrap());
+        }
+        for member in class.members {
+            write_member(buf, &mut tags, class, member);
+        }
+        buf.end_object(r);
+        buf.end_object(b);
+
+        buf.end_object(q);
+        buf.end_object(a);
+        // no option string
+        buf.u8(0);
+    }
+    buf.end_object(p);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the checksums in the StreamerInfo of files written by ROOT 6
+    #[test]
+    fn checksums_match_root() {
+        for (class, sum) in [
+            ("TObject", 0x901bc02d),
+            ("TNamed", 0xdfb74a3c),
+            ("TAttMarker", 0x291d8bec),
+            ("ROOT::TIOFeatures", 0x1aa12f10),
+            ("TArray", 0x007021b2),
+            ("TH1", 0x1c3740c4),
+            ("TTree", 0x7264e07f),
+        ] {
+            assert_eq!(checksum(class_info(class).unwrap()), sum, "{}", class);
+        }
+    }
+
+    #[test]
+    fn parts_come_first() {
+        let mut classes = Vec::new();
+        add_with_parts("TH1D", &mut classes);
+        add_with_parts("TH2D", &mut classes);
+        let names: Vec<&str> = classes.iter().map(|c| c.name).collect();
+        assert_eq!(
+            names,
+            [
+                "TObject",
+                "TNamed",
+                "TAttLine",
+                "TAttFill",
+                "TAttMarker",
+                "TAttAxis",
+                "TArray",
+                "TArrayD",
+                "TAxis",
+                "TH1",
+                "TH1D",
+                "TH2",
+                "TH2D",
+            ]
+        );
+    }
+
+    #[test]
+    fn every_part_is_described() {
+        for class in CLASSES {
+            for base in class.bases {
+                assert!(class_info(base).is_some(), "{}", base);
+            }
+            for member in class.members {
+                if matches!(member.kind, Object | Any) && member.type_name != "TObjArray" {
+                    assert!(
+                        class_info(member.type_name).is_some(),
+                        "{}",
+                        member.type_name
+                    );
+                }
+                if let Array(counter) = member.kind {
+                    assert!(counter_class(class, counter).is_some(), "{}", counter);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,795 @@
+use crate::error::ConverterError;
+use crate::root_file::{RootBuffer, RootFile};
+use polars::prelude::*;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor};
+
+/*
+TTrees for the ROOT output.
+
+Every column becomes a branch with a single leaf, the same thing ROOT makes
+for tree->Branch("adc", &adc, "adc/I"). The data goes into uncompressed
+baskets, which are written as soon as they are full, and the TTree object
+itself goes last since it holds the list of baskets of every branch.
+
+All branches get a basket for the same rows, so the baskets line up as
+clusters of BASKET_ENTRIES entries (fAutoFlush).
+
+The hit table gets copied over from the csv the sorters write, same as the
+parquet and feather outputs, and then the scalers and run metadata get their
+own trees.
+*/
+
+const BASKET_ENTRIES: usize = 100_000;
+const TREE_VERSION: i16 = 20;
+const BRANCH_VERSION: i16 = 13;
+const BASKET_VERSION: i16 = 3;
+// TBasket keeps version, buffer size, entry size, entries, end of data and a flag in its key
+const BASKET_HEADER_LEN: usize = 19;
+// what ROOT uses for the branches of the variable length leaves
+const ENTRY_OFFSET_LEN: i32 = 1000;
+// TIOFeatures is not a ROOT class, so it has a class checksum instead of a version.
+// This is what ROOT writes for the default (no features).
+const IO_FEATURES: [u8; 11] = [0x40, 0, 0, 7, 0, 0, 0x1a, 0xa1, 0x2f, 0x10, 0];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Leaf {
+    Bool,
+    Byte { unsigned: bool },
+    Short { unsigned: bool },
+    Int { unsigned: bool },
+    Long { unsigned: bool },
+    Double,
+    String,
+}
+
+impl Leaf {
+    fn from_dtype(dtype: &DataType) -> Option<Self> {
+        let leaf = match dtype {
+            DataType::Boolean => Leaf::Bool,
+            DataType::UInt8 => Leaf::Byte { unsigned: true },
+            DataType::Int8 => Leaf::Byte { unsigned: false },
+            DataType::UInt16 => Leaf::Short { unsigned: true },
+            DataType::Int16 => Leaf::Short { unsigned: false },
+            DataType::UInt32 => Leaf::Int { unsigned: true },
+            DataType::Int32 => Leaf::Int { unsigned: false },
+            DataType::UInt64 => Leaf::Long { unsigned: true },
+            DataType::Int64 => Leaf::Long { unsigned: false },
+            DataType::Float32 | DataType::Float64 => Leaf::Double,
+            DataType::Utf8 => Leaf::String,
+            _ => return None,
+        };
+        Some(leaf)
+    }
+
+    // what the column gets cast to before it goes in a basket
+    fn dtype(&self) -> DataType {
+        match self {
+            Leaf::Bool => DataType::Boolean,
+            Leaf::Byte { unsigned: true } => DataType::UInt8,
+            Leaf::Byte { unsigned: false } => DataType::Int8,
+            Leaf::Short { unsigned: true } => DataType::UInt16,
+            Leaf::Short { unsigned: false } => DataType::Int16,
+            Leaf::Int { unsigned: true } => DataType::UInt32,
+            Leaf::Int { unsigned: false } => DataType::Int32,
+            Leaf::Long { unsigned: true } => DataType::UInt64,
+            Leaf::Long { unsigned: false } => DataType::Int64,
+            Leaf::Double => DataType::Float64,
+            Leaf::String => DataType::Utf8,
+        }
+    }
+
+    fn class(&self) -> &'static str {
+        match self {
+            Leaf::Bool => "TLeafO",
+            Leaf::Byte { .. } => "TLeafB",
+            Leaf::Short { .. } => "TLeafS",
+            Leaf::Int { .. } => "TLeafI",
+            Leaf::Long { .. } => "TLeafL",
+            Leaf::Double => "TLeafD",
+            Leaf::String => "TLeafC",
+        }
+    }
+
+    // the type code in the branch title, adc/I
+    fn code(&self) -> char {
+        match self {
+            Leaf::Bool => 'O',
+            Leaf::Byte { unsigned: true } => 'b',
+            Leaf::Byte { unsigned: false } => 'B',
+            Leaf::Short { unsigned: true } => 's',
+            Leaf::Short { unsigned: false } => 'S',
+            Leaf::Int { unsigned: true } => 'i',
+            Leaf::Int { unsigned: false } => 'I',
+            Leaf::Long { unsigned: true } => 'l',
+            Leaf::Long { unsigned: false } => 'L',
+            Leaf::Double => 'D',
+            Leaf::String => 'C',
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Leaf::Bool | Leaf::Byte { .. } | Leaf::String => 1,
+            Leaf::Short { .. } => 2,
+            Leaf::Int { .. } => 4,
+            Leaf::Long { .. } | Leaf::Double => 8,
+        }
+    }
+
+    fn unsigned(&self) -> bool {
+        matches!(
+            self,
+            Leaf::Byte { unsigned: true }
+                | Leaf::Short { unsigned: true }
+                | Leaf::Int { unsigned: true }
+                | Leaf::Long { unsigned: true }
+        )
+    }
+}
+
+fn output_error(e: PolarsError) -> ConverterError {
+    ConverterError::Output(e.to_string())
+}
+
+struct Branch {
+    name: String,
+    leaf: Leaf,
+    // size, first entry and position of every basket
+    basket_bytes: Vec<i32>,
+    basket_entry: Vec<i64>,
+    basket_seek: Vec<i64>,
+    tot_bytes: i64,
+    // range of the values (longest string for TLeafC), it goes in the leaf
+    min: f64,
+    max: f64,
+}
+
+impl Branch {
+    fn new(name: &str, leaf: Leaf) -> Self {
+        Branch {
+            name: name.to_string(),
+            leaf,
+            basket_bytes: Vec::new(),
+            basket_entry: Vec::new(),
+            basket_seek: Vec::new(),
+            tot_bytes: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    fn track(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    // The values of one basket, big endian. Strings are variable length, so
+    // their basket also needs where each entry starts (counted from the start
+    // of the key).
+    fn basket_data(
+        &mut self,
+        series: &Series,
+        key_len: usize,
+    ) -> PolarsResult<(RootBuffer, Vec<i32>)> {
+        let series = series.cast(&self.leaf.dtype())?;
+        let mut data = RootBuffer::new();
+        let mut offsets = Vec::new();
+        match self.leaf {
+            Leaf::Bool => {
+                for v in series.bool()?.into_iter() {
+                    let v = v.unwrap_or(false) as u8;
+                    self.track(v as f64);
+                    data.u8(v);
+                }
+            }
+            Leaf::Byte { unsigned: true } => {
+                for v in series.u8()?.into_iter() {
+                    let v = v.unwrap_or(0);
+                    self.track(v as f64);
+                    data.u8(v);
+                }
+            }
+            Leaf::Byte { unsigned: false } => {
+                for v in series.i8()?.into_iter() {
+                    let v = v.unwrap_or(0);
+                    self.track(v as f64);
+                    data.u8(v as u8);
+                }
+            }
+            Leaf::Short { unsigned: true } => {
+                for v in series.u16()?.into_iter() {
+                    let v = v.unwrap_or(0);
+                    self.track(v as f64);
+                    data.u16(v);
+                }
+            }
+            Leaf::Short { unsigned: false } => {
+                for v in series.i16()?.into_iter() {
+                    let v = v.unwrap_or(0);
+                    self.track(v as f64);
+                    data.i16(v);
+                }
+            }
+            Leaf::Int { unsigned: true } => {
+                for v in series.u32()?.into_iter() {
+                    let v = v.unwrap_or(0);
+                    self.track(v as f64);
+                    data.u32(v);
+                }
+            }
+            Leaf::Int { unsigned: false } => {
+                for v in series.i32()?.into_iter() {
+                    let v = v.unwrap_or(0);
+                    self.track(v as f64);
+                    data.i32(v);
+                }
+            }
+            Leaf::Long { unsigned: true } => {
+                for v in series.u64()?.into_iter() {
+                    let v = v.unwrap_or(0);
+                    self.track(v as f64);
+                    data.bytes(&v.to_be_bytes());
+                }
+            }
+            Leaf::Long { unsigned: false } => {
+                for v in series.i64()?.into_iter() {
+                    let v = v.unwrap_or(0);
+                    self.track(v as f64);
+                    data.i64(v);
+                }
+            }
+            Leaf::Double => {
+                for v in series.f64()?.into_iter() {
+                    let v = v.unwrap_or(0.0);
+                    self.track(v);
+                    data.f64(v);
+                }
+            }
+            Leaf::String => {
+                for v in series.utf8()?.into_iter() {
+                    let v = v.unwrap_or("");
+                    offsets.push((key_len + data.len()) as i32);
+                    self.track(v.len() as f64);
+                    data.string(v);
+                }
+            }
+        }
+        Ok((data, offsets))
+    }
+
+    fn write_basket(
+        &mut self,
+        file: &mut RootFile,
+        tree: &str,
+        series: &Series,
+        first_entry: i64,
+    ) -> Result<(), ConverterError> {
+        let io_error = |e| ConverterError::Io("ROOT output".to_string(), e);
+        let key_len = file
+            .record_header("TBasket", &self.name, tree, 0, BASKET_HEADER_LEN)
+            .len()
+            + BASKET_HEADER_LEN;
+        let (mut data, mut offsets) = self.basket_data(series, key_len).map_err(output_error)?;
+        let last = key_len + data.len();
+        if self.leaf == Leaf::String {
+            // one more offset for the end of the last entry
+            offsets.push(last as i32);
+            data.i32(offsets.len() as i32);
+            for &offset in offsets.iter() {
+                data.i32(offset);
+            }
+        }
+
+        let mut record = RootBuffer::new();
+        record.bytes(&file.record_header(
+            "TBasket",
+            &self.name,
+            tree,
+            data.len(),
+            BASKET_HEADER_LEN,
+        ));
+        record.i16(BASKET_VERSION);
+        record.i32(32000.max(last) as i32);
+        // bytes per entry, or the size of the offset table for strings
+        record.i32(if self.leaf == Leaf::String {
+            ENTRY_OFFSET_LEN
+        } else {
+            self.leaf.size() as i32
+        });
+        record.i32(series.len() as i32);
+        record.i32(last as i32);
+        // only the header is in the key, the data follows
+        record.u8(0);
+        let seek = file.write_record(&record.data).map_err(io_error)?;
+        file.write_record(&data.data).map_err(io_error)?;
+
+        let nbytes = key_len + data.len();
+        self.basket_bytes.push(nbytes as i32);
+        self.basket_entry.push(first_entry);
+        self.basket_seek.push(seek as i64);
+        self.tot_bytes += nbytes as i64;
+        Ok(())
+    }
+
+    // TBranch, which also writes its leaf. Returns the tag the tree uses to
+    // point to the leaf.
+    fn stream(&self, buf: &mut RootBuffer, entries: i64) -> u32 {
+        let a = buf.start_object_any("TBranch").0;
+        let p = buf.start_object(BRANCH_VERSION);
+        buf.tnamed(
+            &self.name,
+            &format!("{}/{}", self.name, self.leaf.code()),
+        );
+        buf.att_fill();
+        buf.i32(0); // compression
+        buf.i32(32000); // basket size
+        buf.i32(if self.leaf == Leaf::String {
+            ENTRY_OFFSET_LEN
+        } else {
+            0
+        });
+        buf.i32(self.basket_bytes.len() as i32); // write basket
+        buf.i64(entries); // entry number
+        buf.bytes(&IO_FEATURES);
+        buf.i32(0); // offset
+        // one more than the baskets, for the entry after the last one
+        let max_baskets = self.basket_bytes.len() + 1;
+        buf.i32(max_baskets as i32);
+        buf.i32(0); // split level
+        buf.i64(entries);
+        buf.i64(0); // first entry
+        buf.i64(self.tot_bytes);
+        buf.i64(self.tot_bytes); // zipped bytes, nothing is compressed
+        buf.empty_tobjarray(); // sub branches
+
+        // leaves
+        let q = buf.start_object(3);
+        buf.tobject();
+        buf.string("");
+        buf.i32(1);
+        buf.i32(0);
+        let (l, tag) = buf.start_object_any(self.leaf.class());
+        self.stream_leaf(buf);
+        buf.end_object(l);
+        buf.end_object(q);
+
+        // the baskets are all on disk already
+        buf.empty_tobjarray();
+        buf.u8(1);
+        for i in 0..max_baskets {
+            buf.i32(self.basket_bytes.get(i).copied().unwrap_or(0));
+        }
+        buf.u8(1);
+        for i in 0..max_baskets {
+            buf.i64(self.basket_entry.get(i).copied().unwrap_or(entries));
+        }
+        buf.u8(1);
+        for i in 0..max_baskets {
+            buf.i64(self.basket_seek.get(i).copied().unwrap_or(0));
+        }
+        buf.string(""); // baskets are in this file
+        buf.end_object(p);
+        buf.end_object(a);
+        tag
+    }
+
+    // TLeafX version 1 around TLeaf version 2
+    fn stream_leaf(&self, buf: &mut RootBuffer) {
+        let (min, max) = if self.min <= self.max {
+            (self.min, self.max)
+        } else {
+            (0.0, 0.0)
+        };
+        let p = buf.start_object(1);
+        let q = buf.start_object(2);
+        buf.tnamed(&self.name, &self.name);
+        // number of values, for strings the longest one plus the terminator
+        buf.i32(if self.leaf == Leaf::String {
+            max as i32 + 1
+        } else {
+            1
+        });
+        buf.i32(self.leaf.size() as i32);
+        buf.i32(0); // offset
+        buf.u8(0); // is range
+        buf.u8(self.leaf.unsigned() as u8);
+        buf.u32(0); // no leaf count
+        buf.end_object(q);
+        match self.leaf {
+            Leaf::Bool | Leaf::Byte { .. } => {
+                buf.u8(min as i64 as u8);
+                buf.u8(max as i64 as u8);
+            }
+            Leaf::Short { .. } => {
+                buf.i16(min as i64 as i16);
+                buf.i16(max as i64 as i16);
+            }
+            Leaf::Int { .. } => {
+                buf.i32(min as i64 as i32);
+                buf.i32(max as i64 as i32);
+            }
+            Leaf::Long { .. } => {
+                buf.i64(min as i64);
+                buf.i64(max as i64);
+            }
+            Leaf::Double => {
+                buf.f64(min);
+                buf.f64(max);
+            }
+            Leaf::String => {
+                buf.i32(0);
+                buf.i32(max as i32 + 1);
+            }
+        }
+        buf.end_object(p);
+    }
+}
+
+pub struct TreeWriter {
+    name: String,
+    title: String,
+    branches: Vec<Branch>,
+    entries: i64,
+}
+
+impl TreeWriter {
+    pub fn new(name: &str, title: &str, schema: &Schema) -> Result<Self, ConverterError> {
+        let mut branches = Vec::new();
+        for (column, dtype) in schema.iter() {
+            let leaf = Leaf::from_dtype(dtype).ok_or_else(|| {
+                ConverterError::Output(format!(
+                    "column {} has type {}, which can't go in a ROOT tree",
+                    column, dtype
+                ))
+            })?;
+            branches.push(Branch::new(column, leaf));
+        }
+        Ok(TreeWriter {
+            name: name.to_string(),
+            title: title.to_string(),
+            branches,
+            entries: 0,
+        })
+    }
+
+    // one basket per branch with all of the rows
+    pub fn fill(&mut self, file: &mut RootFile, df: &DataFrame) -> Result<(), ConverterError> {
+        if df.height() == 0 {
+            return Ok(());
+        }
+        for branch in self.branches.iter_mut() {
+            let series = df.column(&branch.name).map_err(output_error)?;
+            branch.write_basket(file, &self.name, series, self.entries)?;
+        }
+        self.entries += df.height() as i64;
+        Ok(())
+    }
+
+    // TTree version 20
+    pub fn write(self, file: &mut RootFile) -> std::io::Result<()> {
+        let mut buf = file.object_buffer("TTree", &self.name, &self.title);
+        let tot_bytes: i64 = self.branches.iter().map(|b| b.tot_bytes).sum();
+        let p = buf.start_object(TREE_VERSION);
+        buf.tnamed(&self.name, &self.title);
+        buf.att_line();
+        buf.att_fill();
+        buf.att_marker();
+        buf.i64(self.entries);
+        buf.i64(tot_bytes);
+        buf.i64(tot_bytes); // zipped
+        buf.i64(tot_bytes); // saved
+        buf.i64(tot_bytes); // flushed
+        buf.f64(1.0); // weight
+        buf.i32(0); // timer interval
+        buf.i32(25); // scan field
+        buf.i32(0); // update
+        buf.i32(ENTRY_OFFSET_LEN); // default entry offset len
+        buf.i32(0); // cluster ranges, all clusters are fAutoFlush entries
+        buf.i64(1_000_000_000_000); // max entries
+        buf.i64(1_000_000_000_000); // max entry loop
+        buf.i64(0); // max virtual size
+        buf.i64(-300_000_000); // auto save
+        buf.i64(BASKET_ENTRIES as i64); // auto flush
+        buf.i64(1_000_000); // estimate
+        buf.u8(0); // no cluster range ends
+        buf.u8(0); // no cluster sizes
+        buf.bytes(&IO_FEATURES);
+
+        // branches
+        let q = buf.start_object(3);
+        buf.tobject();
+        buf.string("");
+        buf.i32(self.branches.len() as i32);
+        buf.i32(0);
+        let leaf_tags: Vec<u32> = self
+            .branches
+            .iter()
+            .map(|b| b.stream(&mut buf, self.entries))
+            .collect();
+        buf.end_object(q);
+
+        // leaves, which were already written with their branches
+        let q = buf.start_object(3);
+        buf.tobject();
+        buf.string("");
+        buf.i32(leaf_tags.len() as i32);
+        buf.i32(0);
+        for &tag in leaf_tags.iter() {
+            buf.u32(tag);
+        }
+        buf.end_object(q);
+
+        // no aliases, index, friends, user info or branch ref
+        buf.u32(0);
+        buf.array_f64(&[]);
+        buf.i32(0);
+        buf.u32(0);
+        buf.u32(0);
+        buf.u32(0);
+        buf.u32(0);
+        buf.end_object(p);
+        file.add_class("TBranch");
+        for branch in self.branches.iter() {
+            file.add_class(branch.leaf.class());
+        }
+        file.add_object("TTree", &self.name, &self.title, &buf.data)
+    }
+}
+
+// Copy a csv written by the sorters into a tree, the columns in schema get
+// that type and the rest are 64 bit integers. The csv is read a basket worth
+// of lines at a time so the whole table never has to be in memory.
+fn csv_to_tree(
+    file: &mut RootFile,
+    csv_file: &str,
+    name: &str,
+    title: &str,
+    schema: &Schema,
+) -> Result<(), ConverterError> {
+    let io_error = |e| ConverterError::Io(csv_file.to_string(), e);
+    let mut lines = BufReader::new(File::open(csv_file).map_err(io_error)?).lines();
+    let header = match lines.next() {
+        Some(line) => line.map_err(io_error)?,
+        None => String::new(),
+    };
+    let mut dtypes = Schema::new();
+    for column in header.trim().split(',').filter(|c| !c.is_empty()) {
+        let dtype = schema.get(column).cloned().unwrap_or(DataType::Int64);
+        dtypes.with_column(column.into(), dtype);
+    }
+    let mut tree = TreeWriter::new(name, title, &dtypes)?;
+    let dtypes = Arc::new(dtypes);
+
+    loop {
+        let mut chunk = format!("{}\n", header);
+        let mut rows = 0;
+        for line in lines.by_ref().take(BASKET_ENTRIES) {
+            let line = line.map_err(io_error)?;
+            if line.is_empty() {
+                continue;
+            }
+            chunk.push_str(&line);
+            chunk.push('\n');
+            rows += 1;
+        }
+        if rows == 0 {
+            break;
+        }
+        let df = CsvReader::new(Cursor::new(chunk.into_bytes()))
+            .has_header(true)
+            .with_dtypes(Some(dtypes.clone()))
+            .finish()
+            .map_err(output_error)?;
+        tree.fill(file, &df)?;
+    }
+    tree.write(file)
+        .map_err(|e| ConverterError::Io(name.to_string(), e))
+}
+
+// The ROOT output: the hit table, the scalers and the run metadata, each in its own tree.
+pub fn write_root(
+    filename: &str,
+    csv_file: &str,
+    scaler_file: &str,
+    schema: &Schema,
+    metadata: &[(String, String)],
+) -> Result<(), ConverterError> {
+    let io_error = |e| ConverterError::Io(filename.to_string(), e);
+    let mut file = RootFile::create(filename, "midas-converter output").map_err(io_error)?;
+    csv_to_tree(&mut file, csv_file, "hits", "Decoded hits", schema)?;
+
+    let mut scaler_schema = Schema::new();
+    for i in 0..32 {
+        scaler_schema.with_column(format!("chan_{}", i).into(), DataType::UInt32);
+    }
+    csv_to_tree(
+        &mut file,
+        scaler_file,
+        "scalers",
+        "Scaler readouts",
+        &scaler_schema,
+    )?;
+
+    let df = df!(
+        "key" => metadata.iter().map(|(k, _)| k.as_str()).collect::<Vec<&str>>(),
+        "value" => metadata.iter().map(|(_, v)| v.as_str()).collect::<Vec<&str>>()
+    )
+    .map_err(output_error)?;
+    let mut tree = TreeWriter::new("metadata", "Run metadata", &df.schema())?;
+    tree.fill(&mut file, &df)?;
+    tree.write(&mut file).map_err(io_error)?;
+    file.close().map_err(io_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::root_file::tests::{key, read_root, Contents, Object};
+    use crate::root_streamers::{checksum, class_info};
+    use std::fs;
+
+    // the values of a branch, read back from its baskets
+    fn branch_values(contents: &Contents, tree: &str, branch: &Object, leaf: &str) -> Vec<String> {
+        let name = branch.text("fName");
+        let seeks = branch.array("fBasketSeek");
+        let entries = branch.array("fBasketEntry");
+        let nbaskets = branch.int("fWriteBasket") as usize;
+        assert_eq!(entries[nbaskets], branch.int("fEntries") as f64);
+        let mut values = Vec::new();
+        for i in 0..nbaskets {
+            let basket = key(&contents.data, seeks[i] as usize);
+            assert_eq!(
+                (basket.class.as_str(), basket.name.as_str()),
+                ("TBasket", name)
+            );
+            assert_eq!(basket.title, tree);
+            assert_eq!(basket.nbytes as f64, branch.array("fBasketBytes")[i]);
+            // the rest of the basket header, after the generic part of the key
+            let mut head = &contents.data[basket.seek + basket.key_len - BASKET_HEADER_LEN..];
+            let mut take = |n: usize| {
+                let (a, b) = head.split_at(n);
+                head = b;
+                a
+            };
+            assert_eq!(
+                i16::from_be_bytes(take(2).try_into().unwrap()),
+                BASKET_VERSION
+            );
+            take(8);
+            let n = i32::from_be_bytes(take(4).try_into().unwrap()) as usize;
+            assert_eq!(n as f64, entries[i + 1] - entries[i]);
+            let last = i32::from_be_bytes(take(4).try_into().unwrap()) as usize;
+
+            let mut data = &contents.data[basket.seek + basket.key_len..basket.seek + last];
+            let mut take = |n: usize| {
+                let (a, b) = data.split_at(n);
+                data = b;
+                a
+            };
+            for _ in 0..n {
+                let value = match leaf {
+                    "TLeafO" => (take(1)[0] != 0).to_string(),
+                    "TLeafS" => u16::from_be_bytes(take(2).try_into().unwrap()).to_string(),
+                    "TLeafI" => i32::from_be_bytes(take(4).try_into().unwrap()).to_string(),
+                    "TLeafL" => i64::from_be_bytes(take(8).try_into().unwrap()).to_string(),
+                    "TLeafD" => f64::from_be_bytes(take(8).try_into().unwrap()).to_string(),
+                    "TLeafC" => {
+                        let len = take(1)[0] as usize;
+                        String::from_utf8(take(len).to_vec()).unwrap()
+                    }
+                    _ => panic!("{} leaf", leaf),
+                };
+                values.push(value);
+            }
+            assert!(data.is_empty());
+        }
+        values
+    }
+
+    #[test]
+    fn io_features_checksum() {
+        let sum = checksum(class_info("ROOT::TIOFeatures").unwrap());
+        assert_eq!(IO_FEATURES[6..10], sum.to_be_bytes());
+    }
+
+    #[test]
+    fn trees_round_trip() {
+        let dir = std::env::temp_dir().join(format!("midas_converter_tree_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+
+        // more than a basket of rows, so the branches get three
+        let rows = 2 * BASKET_ENTRIES + 7;
+        let mut csv = String::from("adc,energy,chan,pileup,module,timestamp\n");
+        for i in 0..rows {
+            csv.push_str(&format!(
+                "{},{},{},{},{},{}\n",
+                i as i32 - 5,
+                i as f64 * 0.25,
+                i % 32,
+                i % 3 == 0,
+                ["QDC1", "SCP1"][i % 2],
+                i as i64 * 1_000_000_007,
+            ));
+        }
+        fs::write(path("hits.csv"), &csv).unwrap();
+        fs::write(path("scalers.csv"), "chan_0,chan_1\n0,0\n10,20\n").unwrap();
+        let mut schema = Schema::new();
+        schema.with_column("adc".into(), DataType::Int32);
+        schema.with_column("energy".into(), DataType::Float64);
+        schema.with_column("chan".into(), DataType::UInt16);
+        schema.with_column("pileup".into(), DataType::Boolean);
+        schema.with_column("module".into(), DataType::Utf8);
+        let metadata = vec![
+            ("run_number".to_string(), "123".to_string()),
+            ("title".to_string(), "a test run".to_string()),
+        ];
+        write_root(
+            &path("out.root"),
+            &path("hits.csv"),
+            &path("scalers.csv"),
+            &schema,
+            &metadata,
+        )
+        .unwrap();
+
+        let contents = read_root(&path("out.root"));
+        let names: Vec<&str> = contents.keys.iter().map(|k| k.name.as_str()).collect();
+        assert_eq!(names, ["hits", "scalers", "metadata"]);
+        for class in [
+            "TTree", "TBranch", "TLeafI", "TLeafD", "TLeafS", "TLeafO", "TLeafC", "TLeafL",
+        ] {
+            assert!(contents.classes.iter().any(|c| c == class), "{}", class);
+        }
+
+        let hits = contents.object(contents.key("hits"));
+        assert_eq!(hits.int("fEntries"), rows as i64);
+        assert_eq!(hits.int("fAutoFlush"), BASKET_ENTRIES as i64);
+        let branches = hits.object("fBranches").objects("items");
+        let leaves = hits.object("fLeaves").objects("items");
+        assert_eq!(leaves.len(), 6);
+        let lines: Vec<Vec<&str>> = csv
+            .lines()
+            .skip(1)
+            .map(|l| l.split(',').collect())
+            .collect();
+        for (column, branch) in branches.iter().enumerate() {
+            assert_eq!(branch.int("fWriteBasket"), 3);
+            let leaf = &branch.object("fLeaves").objects("items")[0];
+            // the tree's leaves point to the ones in the branches
+            assert!(leaves[column].class.starts_with("ref "));
+            let values = branch_values(&contents, "hits", branch, &leaf.class);
+            assert_eq!(values.len(), rows);
+            for (line, value) in lines.iter().zip(values.iter()) {
+                assert_eq!(line[column], value, "{}", branch.text("fName"));
+            }
+        }
+        let adc = &branches[0].object("fLeaves").objects("items")[0];
+        assert_eq!(
+            (adc.int("fMinimum"), adc.int("fMaximum")),
+            (-5, rows as i64 - 6)
+        );
+        assert_eq!(
+            branches[2].object("fLeaves").objects("items")[0].int("fIsUnsigned"),
+            1
+        );
+
+        let scalers = contents.object(contents.key("scalers"));
+        let branch = &scalers.object("fBranches").objects("items")[1];
+        assert_eq!(branch.text("fTitle"), "chan_1/i");
+        assert_eq!(
+            branch_values(&contents, "scalers", branch, "TLeafI"),
+            ["0", "20"]
+        );
+
+        let tree = contents.object(contents.key("metadata"));
+        let branches = tree.object("fBranches").objects("items");
+        assert_eq!(
+            branch_values(&contents, "metadata", &branches[1], "TLeafC"),
+            ["123", "a test run"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+use crate::module_config::MetadataConfig;
+use crate::odb;
+use crate::salvage::SalvagedFile;
+use midasio::read::file::FileView;
+use parquet_format_safe::thrift::protocol::{TCompactInputProtocol, TCompactOutputProtocol};
+use parquet_format_safe::{FileMetaData, KeyValue};
+use polars::export::arrow::io::ipc::{read, write};
+use polars::prelude::*;
+use serde_json::Value;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+/*
+Run level information that the converter would otherwise throw away.
+
+The ODB dumps from the BOR and EOR events are kept around so that they can be
+written as JSON sidecar files, and a handful of user selected ODB keys are
+embedded as key-value metadata in the parquet and feather outputs.
+*/
+
+pub struct RunMetadata {
+    pub run_number: u32,
+    pub start_timestamp: u32,
+    pub stop_timestamp: u32,
+    pub bor_odb: Value,
+    pub eor_odb: Value,
+    // set when the file had to go through the salvage reader
+    pub salvaged: bool,
+}
+
+impl RunMetadata {
+    pub fn from_file_view(file_view: &FileView) -> Self {
+        RunMetadata {
+            run_number: file_view.run_number(),
+            start_timestamp: file_view.initial_timestamp(),
+            stop_timestamp: file_view.final_timestamp(),
+            bor_odb: odb::parse_odb(file_view.initial_odb()),
+            eor_odb: odb::parse_odb(file_view.final_odb()),
+            salvaged: false,
+        }
+    }
+
+    // A cut off file has no EOR, so the stop time is taken from the last event
+    // that made it and there is no EOR ODB.
+    pub fn from_salvaged(salvaged: &SalvagedFile) -> Self {
+        let last_event_time = salvaged
+            .events
+            .last()
+            .map_or(salvaged.initial_timestamp, |e| e.timestamp());
+        RunMetadata {
+            run_number: salvaged.run_number,
+            start_timestamp: salvaged.initial_timestamp,
+            stop_timestamp: salvaged.final_timestamp.unwrap_or(last_event_time),
+            bor_odb: odb::parse_odb(salvaged.initial_odb),
+            eor_odb: salvaged.final_odb.map_or(Value::Null, odb::parse_odb),
+            salvaged: true,
+        }
+    }
+
+    // dump both of the ODBs next to the output file
+    pub fn write_sidecars(&self, output_stem: &str) -> std::io::Result<()> {
+        for (odb_dump, tag) in [(&self.bor_odb, "bor"), (&self.eor_odb, "eor")] {
+            if odb_dump.is_null() {
+                continue;
+            }
+            let filename = format!("{}_{}_odb.json", output_stem, tag);
+            let file = BufWriter::new(File::create(&filename)?);
+            serde_json::to_writer_pretty(file, odb_dump)?;
+        }
+        Ok(())
+    }
+
+    // The key-value pairs that get attached to the output files. The header values
+    // are always there, the ODB keys only if they can be found.
+    pub fn key_values(&self, config: &MetadataConfig) -> Vec<(String, String)> {
+        let mut kv = vec![
+            ("run_number".to_string(), self.run_number.to_string()),
+            (
+                "start_timestamp".to_string(),
+                self.start_timestamp.to_string(),
+            ),
+            (
+                "stop_timestamp".to_string(),
+                self.stop_timestamp.to_string(),
+            ),
+        ];
+        if self.salvaged {
+            kv.push(("salvaged".to_string(), "true".to_string()));
+        }
+        let lookups = config
+            .odb_keys
+            .iter()
+            .map(|k| (k, &self.bor_odb))
+            .chain(config.eor_odb_keys.iter().map(|k| (k, &self.eor_odb)));
+        for (key, odb_dump) in lookups {
+            match odb::odb_get(odb_dump, key) {
+                Some(v) => kv.push((key.to_string(), odb::odb_value_string(v))),
+                None => println!("ODB key {} not found, it will not be in the metadata.", key),
+            }
+        }
+        kv
+    }
+}
+
+// Parquet keeps its key-value metadata in the thrift footer at the end of the file,
+// so we can just swap the footer instead of rewriting all of the row groups.
+pub fn add_parquet_metadata(path: &Path, kv: &[(String, String)]) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    // the file ends with the footer length and "PAR1"
+    let file_len = file.seek(SeekFrom::End(0))?;
+    let mut tail = [0u8; 8];
+    file.seek(SeekFrom::End(-8))?;
+    file.read_exact(&mut tail)?;
+    let footer_len = u32::from_le_bytes(tail[..4].try_into().unwrap()) as u64;
+    let footer_start = file_len - 8 - footer_len;
+
+    file.seek(SeekFrom::Start(footer_start))?;
+    let mut footer = vec![0u8; footer_len as usize];
+    file.read_exact(&mut footer)?;
+    let mut in_protocol = TCompactInputProtocol::new(&footer[..], footer.len() * 2);
+    let mut metadata = FileMetaData::read_from_in_protocol(&mut in_protocol)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+
+    let key_value_metadata = metadata.key_value_metadata.get_or_insert_with(Vec::new);
+    for (k, v) in kv.iter() {
+        key_value_metadata.push(KeyValue::new(k.to_string(), v.to_string()));
+    }
+
+    let mut new_footer = Vec::new();
+    let mut out_protocol = TCompactOutputProtocol::new(&mut new_footer);
+    metadata
+        .write_to_out_protocol(&mut out_protocol)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+
+    file.set_len(footer_start)?;
+    file.seek(SeekFrom::Start(footer_start))?;
+    file.write_all(&new_footer)?;
+    file.write_all(&(new_footer.len() as u32).to_le_bytes())?;
+    file.write_all(b"PAR1")?;
+    Ok(())
+}
+
+// The arrow schema is written at the start and the end of a feather file, so here
+// we copy the record batches over to a new file with the extended schema.
+pub fn add_ipc_metadata(
+    path: &Path,
+    kv: &[(String, String)],
+    compression: Option<IpcCompression>,
+) -> PolarsResult<()> {
+    let temp_path = path.with_extension("feather.tmp");
+    {
+        let mut reader = File::open(path)?;
+        let metadata = read::read_file_metadata(&mut reader)?;
+        let mut schema = (*metadata.schema).clone();
+        schema.metadata.extend(kv.iter().cloned());
+        let ipc_fields = metadata.ipc_schema.fields.clone();
+        let reader = read::FileReader::new(reader, metadata, None, None);
+
+        let mut writer = write::FileWriter::try_new(
+            BufWriter::new(File::create(&temp_path)?),
+            Arc::new(schema),
+            Some(ipc_fields),
+            write::WriteOptions {
+                compression: compression.map(|c| c.into()),
+            },
+        )?;
+        for chunk in reader {
+            writer.write(&chunk?, None)?;
+        }
+        writer.finish()?;
+    }
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
@@ -0,0 +1,430 @@
+use crate::bitmasks;
+use crate::error::ConverterError;
+use crate::run_metadata::RunMetadata;
+use midasio::read::event::EventView;
+use midasio::read::file::FileView;
+
+/*
+Reader for midas files that FileView refuses to open.
+
+When the DAQ dies the last file of a run is cut off part way through an event
+and there is no EOR, and every now and then a file has garbage in the middle
+of it. FileView wants the whole file to be valid, so here we walk the event
+headers ourselves. Every event that midasio is happy with is kept, and when we
+run into something that is not an event we step forward one byte at a time
+until the next valid event header shows up. The byte ranges that were stepped
+over are kept so that they can be reported at the end.
+*/
+
+pub const BOR_ID: u16 = 0x8000;
+pub const EOR_ID: u16 = 0x8001;
+// the cm_msg lines the logger puts in the file, an event with text instead of banks
+pub const MESSAGE_ID: u16 = 0x8002;
+pub const MAGIC: u16 = 0x494d;
+// id, trigger mask, serial number, timestamp, event size
+pub const EVENT_HEADER_SIZE: usize = 16;
+// event header plus all banks size and flags
+const MIN_EVENT_SIZE: usize = EVENT_HEADER_SIZE + 8;
+// the flags midas uses for 16 bit, 32 bit and 64 bit aligned 32 bit banks
+const BANK16_FLAG: u32 = 1;
+pub const BANK32_FLAG: u32 = 17;
+pub const BANK32A_FLAG: u32 = 49;
+
+// time and odb dump of a BOR or EOR
+pub type OdbRecord<'a> = (u32, &'a [u8]);
+
+pub struct SalvagedFile<'a> {
+    pub run_number: u32,
+    pub initial_timestamp: u32,
+    pub initial_odb: &'a [u8],
+    // only there if the EOR made it into the file
+    pub final_timestamp: Option<u32>,
+    pub final_odb: Option<&'a [u8]>,
+    pub events: Vec<EventView<'a>>,
+    // the text of the messages in between the events
+    pub messages: Vec<&'a [u8]>,
+    // [start, end) byte offsets of everything that was not an event
+    pub skipped: Vec<(usize, usize)>,
+}
+
+pub fn u16_at(contents: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([contents[offset], contents[offset + 1]])
+}
+
+pub fn u32_at(contents: &[u8], offset: usize) -> u32 {
+    bitmasks::to_u32_le(&contents[offset..offset + 4])
+}
+
+// BOR and EOR share the same layout: id, magic, run number, time, odb size, odb
+pub fn odb_record(contents: &[u8], offset: usize) -> (u32, &[u8], usize) {
+    let time = u32_at(contents, offset + 8);
+    let odb_size = u32_at(contents, offset + 12) as usize;
+    let start = offset + EVENT_HEADER_SIZE;
+    let end = start.saturating_add(odb_size).min(contents.len());
+    (time, &contents[start..end], end)
+}
+
+// The total length of the event starting at offset if its header looks like one,
+// the rest of the event doesn't have to be there yet. Messages count as events.
+pub fn event_length(contents: &[u8], offset: usize) -> Option<usize> {
+    if offset + EVENT_HEADER_SIZE > contents.len() {
+        return None;
+    }
+    let id = u16_at(contents, offset);
+    let event_size = u32_at(contents, offset + 12) as usize;
+    if id == MESSAGE_ID {
+        // at least the null at the end of the text
+        return (event_size > 0).then_some(EVENT_HEADER_SIZE + event_size);
+    }
+    if offset + MIN_EVENT_SIZE > contents.len() || id == BOR_ID || id == EOR_ID {
+        return None;
+    }
+    // cheap checks first, most offsets in garbage fail here
+    let all_banks_size = u32_at(contents, offset + 16) as usize;
+    let flags = u32_at(contents, offset + 20);
+    if event_size < 8 || event_size - 8 != all_banks_size {
+        return None;
+    }
+    if flags != BANK16_FLAG && flags != BANK32_FLAG && flags != BANK32A_FLAG {
+        return None;
+    }
+    Some(EVENT_HEADER_SIZE + event_size)
+}
+
+// what can be in between the BOR and the EOR
+pub enum Record<'a> {
+    Event(EventView<'a>),
+    // the text, with its null
+    Message(&'a [u8]),
+}
+
+// Try to read an event or message starting at offset, returns it with its total length.
+pub fn record_at(contents: &[u8], offset: usize) -> Option<(Record<'_>, usize)> {
+    let total = event_length(contents, offset)?;
+    if offset + total > contents.len() {
+        return None;
+    }
+    let data = &contents[offset..offset + total];
+    if u16_at(data, 0) == MESSAGE_ID {
+        // garbage can start with the id too, but it won't end the text with a null
+        return (data[total - 1] == 0)
+            .then_some((Record::Message(&data[EVENT_HEADER_SIZE..]), total));
+    }
+    EventView::try_from_le_bytes(data)
+        .ok()
+        .map(|event| (Record::Event(event), total))
+}
+
+// Try to read an event starting at offset, returns it with its total length.
+pub fn event_at(contents: &[u8], offset: usize) -> Option<(EventView<'_>, usize)> {
+    match record_at(contents, offset)? {
+        (Record::Event(event), total) => Some((event, total)),
+        (Record::Message(_), _) => None,
+    }
+}
+
+pub fn salvage(contents: &[u8]) -> Result<SalvagedFile<'_>, ConverterError> {
+    // without a BOR we do not even know what we are looking at
+    if contents.len() < EVENT_HEADER_SIZE
+        || u16_at(contents, 0) != BOR_ID
+        || u16_at(contents, 2) != MAGIC
+    {
+        return Err(ConverterError::Midas(
+            "no begin of run record, this does not look like a midas file".to_string(),
+        ));
+    }
+    let run_number = u32_at(contents, 4);
+    let (initial_timestamp, initial_odb, mut offset) = odb_record(contents, 0);
+
+    let mut salvaged = SalvagedFile {
+        run_number,
+        initial_timestamp,
+        initial_odb,
+        final_timestamp: None,
+        final_odb: None,
+        events: Vec::new(),
+        messages: Vec::new(),
+        skipped: Vec::new(),
+    };
+
+    let mut skip_start: Option<usize> = None;
+    while offset < contents.len() {
+        if let Some((record, length)) = record_at(contents, offset) {
+            if let Some(start) = skip_start.take() {
+                salvaged.skipped.push((start, offset));
+            }
+            match record {
+                Record::Event(event) => salvaged.events.push(event),
+                Record::Message(text) => salvaged.messages.push(text),
+            }
+            offset += length;
+            continue;
+        }
+        if offset + EVENT_HEADER_SIZE <= contents.len()
+            && u16_at(contents, offset) == EOR_ID
+            && u16_at(contents, offset + 2) == MAGIC
+        {
+            if let Some(start) = skip_start.take() {
+                salvaged.skipped.push((start, offset));
+            }
+            let (final_timestamp, final_odb, end) = odb_record(contents, offset);
+            salvaged.final_timestamp = Some(final_timestamp);
+            salvaged.final_odb = Some(final_odb);
+            offset = end;
+            break;
+        }
+        // not an event, look for the next one
+        skip_start.get_or_insert(offset);
+        offset += 1;
+    }
+    if let Some(start) = skip_start {
+        salvaged.skipped.push((start, offset));
+    }
+    // anything after the EOR is junk too
+    if offset < contents.len() {
+        salvaged.skipped.push((offset, contents.len()));
+    }
+    Ok(salvaged)
+}
+
+impl SalvagedFile<'_> {
+    pub fn skipped_bytes(&self) -> usize {
+        self.skipped.iter().map(|(start, end)| end - start).sum()
+    }
+
+    pub fn print_summary(&self) {
+        println!(
+            "Salvaged {} events from run {}.",
+            self.events.len(),
+            self.run_number
+        );
+        if !self.messages.is_empty() {
+            println!("  {} messages in between.", self.messages.len());
+        }
+        if self.final_timestamp.is_none() {
+            println!("  The end of run record is missing, the file was cut off.");
+        }
+        if self.skipped.is_empty() {
+            return;
+        }
+        println!(
+            "  Skipped {} bytes in {} ranges:",
+            self.skipped_bytes(),
+            self.skipped.len()
+        );
+        for (start, end) in self.skipped.iter().take(20) {
+            println!("    bytes {}..{} ({} bytes)", start, end, end - start);
+        }
+        if self.skipped.len() > 20 {
+            println!("    ...");
+        }
+    }
+}
+
+// Either a file that midasio could open as is, or whatever we could salvage.
+pub enum MidasInput<'a> {
+    Whole(FileView<'a>),
+    Salvaged(SalvagedFile<'a>),
+}
+
+impl<'a> MidasInput<'a> {
+    pub fn open(contents: &'a [u8]) -> Result<Self, ConverterError> {
+        match FileView::try_from(contents) {
+            Ok(file_view) => Ok(MidasInput::Whole(file_view)),
+            Err(e) => {
+                println!(
+                    "Could not read the whole midas file ({}), salvaging what we can.",
+                    e
+                );
+                let salvaged = salvage(contents)?;
+                salvaged.print_summary();
+                Ok(MidasInput::Salvaged(salvaged))
+            }
+        }
+    }
+
+    pub fn events(&self) -> Box<dyn Iterator<Item = EventView<'a>> + Send + '_> {
+        match self {
+            MidasInput::Whole(file_view) => Box::new(file_view.into_iter()),
+            MidasInput::Salvaged(salvaged) => Box::new(salvaged.events.iter().copied()),
+        }
+    }
+
+    pub fn run_metadata(&self) -> RunMetadata {
+        match self {
+            MidasInput::Whole(file_view) => RunMetadata::from_file_view(file_view),
+            MidasInput::Salvaged(salvaged) => RunMetadata::from_salvaged(salvaged),
+        }
+    }
+
+    // the raw BOR and EOR, for writing the run back out
+    pub fn odb_records(&self) -> (u32, OdbRecord<'a>, Option<OdbRecord<'a>>) {
+        match self {
+            MidasInput::Whole(file_view) => (
+                file_view.run_number(),
+                (file_view.initial_timestamp(), file_view.initial_odb()),
+                Some((file_view.final_timestamp(), file_view.final_odb())),
+            ),
+            MidasInput::Salvaged(salvaged) => (
+                salvaged.run_number,
+                (salvaged.initial_timestamp, salvaged.initial_odb),
+                salvaged.final_timestamp.zip(salvaged.final_odb),
+            ),
+        }
+    }
+
+    // false if anything had to be thrown away to read the file
+    pub fn is_complete(&self) -> bool {
+        match self {
+            MidasInput::Whole(_) => true,
+            MidasInput::Salvaged(salvaged) => {
+                salvaged.skipped.is_empty() && salvaged.final_timestamp.is_some()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::midas_structs::{self, BankHeader, EventHeader};
+    use std::io::Write;
+
+    // 64 bit aligned 32 bit banks have a reserved word after the size
+    fn bank32a(name: &str, words: &[u32]) -> Vec<u8> {
+        let mut bank = name.as_bytes().to_vec();
+        bank.extend_from_slice(&6u32.to_le_bytes());
+        bank.extend_from_slice(&(4 * words.len() as u32).to_le_bytes());
+        bank.extend_from_slice(&0u32.to_le_bytes());
+        for word in words {
+            bank.extend_from_slice(&word.to_le_bytes());
+        }
+        bank.resize(bank.len().next_multiple_of(8), 0);
+        bank
+    }
+
+    fn event(serial: u32, flags: u32, banks: &[u8]) -> Vec<u8> {
+        let mut event = Vec::new();
+        EventHeader {
+            event_id: 1,
+            trigger_mask: 0,
+            serial_number: serial,
+            time_stamp: 1000 + serial,
+            event_size: banks.len() as u32 + 8,
+        }
+        .write(&mut event)
+        .unwrap();
+        BankHeader {
+            all_banks_size: banks.len() as u32,
+            flags,
+        }
+        .write(&mut event)
+        .unwrap();
+        event.extend_from_slice(banks);
+        event
+    }
+
+    pub fn message(text: &str) -> Vec<u8> {
+        let mut message = Vec::new();
+        EventHeader {
+            event_id: MESSAGE_ID,
+            // MT_INFO
+            trigger_mask: 2,
+            serial_number: 0,
+            time_stamp: 1001,
+            event_size: text.len() as u32 + 1,
+        }
+        .write(&mut message)
+        .unwrap();
+        message.extend_from_slice(text.as_bytes());
+        message.push(0);
+        message
+    }
+
+    // A run with two B32A events, a message in between and a B32 event, and
+    // the offsets of the events in it.
+    pub fn b32a_run() -> (Vec<u8>, Vec<(usize, usize)>) {
+        let mut run = Vec::new();
+        midas_structs::write_bor(&mut run, 5, 1000, b"<odb/>").unwrap();
+        let mut events = vec![
+            event(0, BANK32A_FLAG, &bank32a("SCP1", &[1, 2, 3])),
+            message("[mhttpd,INFO] Run #5 started"),
+            event(
+                1,
+                BANK32A_FLAG,
+                &[bank32a("SCP1", &[4]), bank32a("SCP2", &[5, 6])].concat(),
+            ),
+        ];
+        let mut b32 = Vec::new();
+        midas_structs::write_bank32(&mut b32, "SCP1", &[7, 8]).unwrap();
+        events.push(event(2, BANK32_FLAG, &b32));
+        let mut offsets = Vec::new();
+        for event in events {
+            if u16_at(&event, 0) != MESSAGE_ID {
+                offsets.push((run.len(), event.len()));
+            }
+            run.write_all(&event).unwrap();
+        }
+        midas_structs::write_eor(&mut run, 5, 1010, b"<odb/>").unwrap();
+        (run, offsets)
+    }
+
+    #[test]
+    fn aligned_banks_and_messages() {
+        let (run, offsets) = b32a_run();
+        for &(offset, length) in &offsets {
+            assert_eq!(event_length(&run, offset), Some(length));
+        }
+        let input = MidasInput::open(&run).unwrap();
+        assert!(input.is_complete());
+        let MidasInput::Salvaged(salvaged) = &input else {
+            panic!("midasio does not read messages");
+        };
+        assert!(salvaged.skipped.is_empty());
+        assert_eq!(
+            salvaged.messages,
+            [b"[mhttpd,INFO] Run #5 started\0".as_slice()]
+        );
+        assert_eq!(salvaged.final_timestamp, Some(1010));
+
+        let banks: Vec<Vec<(String, Vec<u8>)>> = input
+            .events()
+            .map(|event| {
+                event
+                    .into_iter()
+                    .map(|bank| (bank.name().to_string(), bank.data_slice().to_vec()))
+                    .collect()
+            })
+            .collect();
+        let words = |words: &[u32]| {
+            words
+                .iter()
+                .flat_map(|w| w.to_le_bytes())
+                .collect::<Vec<u8>>()
+        };
+        assert_eq!(
+            banks,
+            [
+                vec![("SCP1".to_string(), words(&[1, 2, 3]))],
+                vec![
+                    ("SCP1".to_string(), words(&[4])),
+                    ("SCP2".to_string(), words(&[5, 6]))
+                ],
+                vec![("SCP1".to_string(), words(&[7, 8]))],
+            ]
+        );
+    }
+
+    #[test]
+    fn garbage_is_skipped() {
+        let (mut run, offsets) = b32a_run();
+        // a message without its null in front of an event with bad flags
+        let (start, length) = offsets[0];
+        run.splice(start..start, message("cut off")[..23].iter().copied());
+        run[start + 23 + 20] = 3;
+        let salvaged = salvage(&run).unwrap();
+        assert_eq!(salvaged.events.len(), 2);
+        assert_eq!(salvaged.messages.len(), 1);
+        assert_eq!(salvaged.skipped, [(start, start + 23 + length)]);
+    }
+}
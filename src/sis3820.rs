@@ -1,4 +1,5 @@
 use crate::bitmasks;
+use crate::error::DecodeError;
 
 pub struct ScalerBank {
     pub data: Vec<u32>,
@@ -9,10 +10,20 @@ impl ScalerBank {
         ScalerBank { data: vec![0; 32] }
     }
 
-    pub fn parse(&mut self, bank: &[u8]) {
+    pub fn parse(&mut self, bank: &[u8]) -> Result<u64, DecodeError> {
+        if !bank.len().is_multiple_of(4) {
+            return Err(DecodeError::BankLength(bank.len()));
+        }
+        if bank.len() > 4 * self.data.len() {
+            return Err(DecodeError::BankSize {
+                expected: self.data.len(),
+                found: bank.len() / 4,
+            });
+        }
         for (i, chunk) in bank.chunks(4).enumerate() {
             let temp = bitmasks::to_u32_le(chunk);
             self.data[i] = temp;
         }
+        Ok(0)
     }
 }
@@ -0,0 +1,512 @@
+use crate::error::ConverterError;
+use crate::mdpp_bank::MDPPBank;
+use crate::midas_structs;
+use crate::module_config::Module;
+use crate::salvage::MidasInput;
+use crate::v1730_bank::v1730Bank;
+use crate::v785_bank::v785Bank;
+use midasio::read::event::EventView;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/*
+Writing a smaller midas file with some of the events of a run (skim), to hand
+raw data to someone else or to run another decoder over part of a run.
+
+The output is a proper midas file: the BOR and EOR with their ODB dumps are
+copied, and every event that is kept is copied as it is. An event is kept if
+it passes all of the selections that are given:
+
+  --event-id 1,2         the midas event id is one of these
+  --trigger-mask 0x1     the trigger mask has one of these bits set
+  --serials 100-200      the serial number is in the range (both ends included)
+  --times 60-120         seconds since the BOR, same
+  --hit "SCP1 channel 3 adc > 1000"
+
+--hit can be given more than once and the event is kept if any of them is
+true for any hit in its banks. The channel is optional, and the quantities are
+the same as for the histograms (adc, long, short, tdc and time). The MDPP
+decoders keep going across midas events like in the sorter, so an MDPP event
+that was split over two midas events counts for the one it ended in, and only
+that one is kept.
+
+A file without an EOR gets one with the BOR ODB and the time of the last
+event, so that midasio can read the skim.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Equal,
+    NotEqual,
+}
+
+impl Comparison {
+    fn from_str(op: &str) -> Option<Self> {
+        Some(match op {
+            ">" => Comparison::Greater,
+            ">=" => Comparison::GreaterEqual,
+            "<" => Comparison::Less,
+            "<=" => Comparison::LessEqual,
+            "==" | "=" => Comparison::Equal,
+            "!=" => Comparison::NotEqual,
+            _ => return None,
+        })
+    }
+
+    fn test(&self, value: f64, threshold: f64) -> bool {
+        match self {
+            Comparison::Greater => value > threshold,
+            Comparison::GreaterEqual => value >= threshold,
+            Comparison::Less => value < threshold,
+            Comparison::LessEqual => value <= threshold,
+            Comparison::Equal => value == threshold,
+            Comparison::NotEqual => value != threshold,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HitCondition {
+    module: String,
+    channel: Option<u32>,
+    quantity: String,
+    comparison: Comparison,
+    threshold: f64,
+}
+
+impl HitCondition {
+    // "SCP1 channel 3 adc > 1000", the module name is matched against the config
+    pub fn parse(condition: &str, modules: &[Module]) -> Result<Self, ConverterError> {
+        let bad = |why: &str| {
+            ConverterError::Config(format!("bad hit condition \"{}\": {}", condition, why))
+        };
+        let words: Vec<&str> = condition.split_whitespace().collect();
+        let (name, channel, rest) = match words.as_slice() {
+            [name, "channel" | "ch", channel, rest @ ..] => (
+                name,
+                Some(
+                    channel
+                        .parse()
+                        .map_err(|_| bad("the channel is not a number"))?,
+                ),
+                rest,
+            ),
+            [name, rest @ ..] => (name, None, rest),
+            [] => return Err(bad("it is empty")),
+        };
+        let [quantity, op, threshold] = rest else {
+            return Err(bad(
+                "expected <module> [channel <n>] <quantity> <op> <value>",
+            ));
+        };
+        let module = modules
+            .iter()
+            .find(|m| m.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| bad(&format!("there is no module {} in the config", name)))?;
+        let allowed: &[&str] = match module.mod_type.as_str() {
+            "scp" => &["adc", "tdc"],
+            "qdc" => &["long", "short", "tdc"],
+            "adc" => &["adc"],
+            "v1730" => &["long", "time"],
+            _ => &[],
+        };
+        if !allowed.contains(quantity) {
+            return Err(bad(&format!(
+                "module {} ({}) has no {}",
+                module.name, module.mod_type, quantity
+            )));
+        }
+        Ok(HitCondition {
+            module: module.name.clone(),
+            channel,
+            quantity: quantity.to_string(),
+            comparison: Comparison::from_str(op)
+                .ok_or_else(|| bad("the comparison has to be one of > >= < <= == !="))?,
+            threshold: threshold
+                .parse()
+                .map_err(|_| bad("the value is not a number"))?,
+        })
+    }
+
+    fn test(&self, channel: u32, quantity: &str, value: f64) -> bool {
+        self.channel.is_none_or(|c| c == channel)
+            && self.quantity == quantity
+            && self.comparison.test(value, self.threshold)
+    }
+}
+
+// "100-200", "100-" or "100", both ends included
+pub fn parse_range(range: &str) -> Result<(u32, u32), ConverterError> {
+    let bad = || ConverterError::Config(format!("bad range {}, use A-B, A- or A", range));
+    let (start, end) = match range.split_once('-') {
+        Some((start, "")) => (start, None),
+        Some((start, end)) => (start, Some(end)),
+        None => (range, Some(range)),
+    };
+    let start = start.trim().parse().map_err(|_| bad())?;
+    let end = match end {
+        Some(end) => end.trim().parse().map_err(|_| bad())?,
+        None => u32::MAX,
+    };
+    Ok((start, end))
+}
+
+pub fn parse_trigger_mask(mask: &str) -> Result<u16, ConverterError> {
+    match mask.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => mask.parse(),
+    }
+    .map_err(|_| ConverterError::Config(format!("bad trigger mask {}", mask)))
+}
+
+#[derive(Debug, Default)]
+pub struct SkimSelection {
+    pub event_ids: Vec<u16>,
+    pub trigger_mask: Option<u16>,
+    pub serials: Option<(u32, u32)>,
+    pub times: Option<(u32, u32)>,
+    pub hits: Vec<HitCondition>,
+}
+
+// the decoder of every module with a hit condition, kept between midas events
+enum Decoder {
+    Mdpp(MDPPBank),
+    Adc(v785Bank),
+    V1730(v1730Bank),
+}
+
+struct HitFilter<'m> {
+    conditions: &'m [HitCondition],
+    modules: Vec<(&'m Module, Decoder)>,
+}
+
+impl<'m> HitFilter<'m> {
+    fn new(conditions: &'m [HitCondition], modules: &'m [Module]) -> Self {
+        let modules = modules
+            .iter()
+            .filter(|m| conditions.iter().any(|c| c.module == m.name))
+            .filter_map(|m| {
+                let decoder = match m.mod_type.as_str() {
+                    "scp" | "qdc" => Decoder::Mdpp(MDPPBank::with_capacity(64)),
+                    "adc" => Decoder::Adc(v785Bank::new()),
+                    "v1730" => Decoder::V1730(v1730Bank::new()),
+                    _ => return None,
+                };
+                Some((m, decoder))
+            })
+            .collect();
+        HitFilter {
+            conditions,
+            modules,
+        }
+    }
+
+    // true if any hit in the trigger banks of the event passes any condition
+    fn test(&mut self, event: &EventView) -> bool {
+        if event.id() != 1 {
+            return false;
+        }
+        let mut found = false;
+        for bank in *event {
+            // same as the sorters, one byte banks are empty
+            if bank.data_slice().len() == 1 {
+                continue;
+            }
+            let Some((module, decoder)) =
+                self.modules.iter_mut().find(|(m, _)| m.name == bank.name())
+            else {
+                continue;
+            };
+            let conditions = self.conditions.iter().filter(|c| c.module == module.name);
+            let mut hits: Vec<(u32, &str, f64)> = Vec::new();
+            // bad data just doesn't match anything
+            match decoder {
+                Decoder::Mdpp(mdpp) => {
+                    let _ = mdpp.parse(&module.mod_type, module.nchannels, bank.data_slice(), true);
+                    for e in mdpp.completed_events() {
+                        for (&channel, hit) in e.channels.iter().zip(e.channel_hits.iter()) {
+                            for (filled, quantity, value) in [
+                                (hit.adc_filled, "adc", hit.adc_value),
+                                (hit.long_filled, "long", hit.long_value),
+                                (hit.short_filled, "short", hit.short_value),
+                                (hit.tdc_filled, "tdc", hit.tdc_value),
+                            ] {
+                                if filled {
+                                    hits.push((channel, quantity, value as f64));
+                                }
+                            }
+                        }
+                    }
+                    mdpp.clear_data();
+                }
+                Decoder::Adc(v785) => {
+                    let _ = v785.parse(bank.data_slice());
+                    for hit in v785.hits.drain(..) {
+                        for (channel, &value) in hit.values.iter().enumerate() {
+                            // zero means the channel did not fire
+                            if value != 0 {
+                                hits.push((channel as u32, "adc", value as f64));
+                            }
+                        }
+                    }
+                }
+                Decoder::V1730(v1730) => {
+                    let _ = v1730.parse(bank.data_slice(), true);
+                    for hit in v1730.hits.drain(..) {
+                        hits.push((hit.channel, "long", hit.long as f64));
+                        hits.push((hit.channel, "time", hit.time));
+                    }
+                }
+            }
+            // keep decoding the other banks, the MDPP decoders need every bank
+            found |= conditions
+                .into_iter()
+                .any(|c| hits.iter().any(|&(ch, q, v)| c.test(ch, q, v)));
+        }
+        found
+    }
+}
+
+pub struct SkimSummary {
+    pub events: u64,
+    pub kept: u64,
+}
+
+pub fn skim(
+    input: &MidasInput,
+    modules: &[Module],
+    selection: &SkimSelection,
+    output_file: &str,
+) -> Result<SkimSummary, ConverterError> {
+    let io_error = |e| ConverterError::Io(output_file.to_string(), e);
+    let (run_number, (initial_timestamp, initial_odb), final_odb) = input.odb_records();
+    let mut writer = BufWriter::new(File::create(output_file).map_err(io_error)?);
+    midas_structs::write_bor(&mut writer, run_number, initial_timestamp, initial_odb)
+        .map_err(io_error)?;
+
+    let mut hit_filter = HitFilter::new(&selection.hits, modules);
+    let mut summary = SkimSummary { events: 0, kept: 0 };
+    let mut last_timestamp = initial_timestamp;
+    for event in input.events() {
+        summary.events += 1;
+        last_timestamp = last_timestamp.max(event.timestamp());
+        let seconds = event.timestamp().saturating_sub(initial_timestamp);
+        let keep = (selection.event_ids.is_empty() || selection.event_ids.contains(&event.id()))
+            && selection
+                .trigger_mask
+                .is_none_or(|mask| event.trigger_mask() & mask != 0)
+            && selection
+                .serials
+                .is_none_or(|(a, b)| (a..=b).contains(&event.serial_number()))
+            && selection
+                .times
+                .is_none_or(|(a, b)| (a..=b).contains(&seconds));
+        // the decoders see every event, so that split MDPP events come out right
+        let hit = selection.hits.is_empty() || hit_filter.test(&event);
+        if keep && hit {
+            midas_structs::write_event(&mut writer, &event).map_err(io_error)?;
+            summary.kept += 1;
+        }
+    }
+
+    match final_odb {
+        Some((final_timestamp, final_odb)) => {
+            midas_structs::write_eor(&mut writer, run_number, final_timestamp, final_odb)
+        }
+        None => {
+            println!("The input has no end of run record, the skim gets one with the BOR ODB.");
+            midas_structs::write_eor(&mut writer, run_number, last_timestamp, initial_odb)
+        }
+    }
+    .map_err(io_error)?;
+    writer.flush().map_err(io_error)?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::tests::{generate_run, settings, test_dir};
+    use crate::module_config;
+    use std::collections::BTreeSet;
+    use std::fs;
+
+    // without timestamps the event counters of the truth table are the trigger serials
+    const CONFIG: &str = r#"
+[[modules]]
+name = "SCP1"
+address = "0xE1FA0000"
+mod_type = "scp"
+nchannels = 16
+setup_file = "x"
+
+[[modules]]
+name = "QDC1"
+address = "0xE1FB0000"
+mod_type = "qdc"
+nchannels = 32
+setup_file = "x"
+"#;
+
+    struct Run {
+        dir: std::path::PathBuf,
+        contents: Vec<u8>,
+        modules: Vec<Module>,
+        // module,channel,adc,long,short,tdc,trigger_dt,pileup,evt_ts with pileup as 0 or 1
+        truth: Vec<Vec<f64>>,
+    }
+
+    fn generated_run(name: &str) -> Run {
+        let dir = test_dir(name);
+        let (config_file, run, _) = generate_run(&dir, CONFIG, &settings(0.0));
+        let truth = fs::read_to_string(format!("{}_truth.csv", run.split('.').next().unwrap()))
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|l| {
+                l.split(',')
+                    .map(|v| v.parse().unwrap_or((v == "true") as u8 as f64))
+                    .collect()
+            })
+            .collect();
+        Run {
+            contents: fs::read(&run).unwrap(),
+            modules: module_config::create_config(&config_file).unwrap().modules,
+            dir,
+            truth,
+        }
+    }
+
+    // the (id, serial) of every event in the skim, which has to be a readable run
+    fn skimmed(run: &Run, selection: &SkimSelection, name: &str) -> Vec<(u16, u32)> {
+        let output = run.dir.join(name).to_str().unwrap().to_string();
+        let input = MidasInput::open(&run.contents).unwrap();
+        let summary = skim(&input, &run.modules, selection, &output).unwrap();
+        let contents = fs::read(&output).unwrap();
+        let skim = MidasInput::open(&contents).unwrap();
+        assert!(skim.is_complete());
+        assert_eq!(skim.odb_records().0, 12);
+        let events: Vec<(u16, u32)> = skim.events().map(|e| (e.id(), e.serial_number())).collect();
+        assert_eq!(summary.kept, events.len() as u64);
+        assert_eq!(summary.events, input.events().count() as u64);
+        events
+    }
+
+    // every trigger, also the ones without any hits in the truth table
+    fn triggers(run: &Run) -> Vec<(u16, u32, u32)> {
+        MidasInput::open(&run.contents)
+            .unwrap()
+            .events()
+            .map(|e| (e.id(), e.serial_number(), e.timestamp()))
+            .collect()
+    }
+
+    #[test]
+    fn event_selection() {
+        let run = generated_run("skim_events");
+        let events = triggers(&run);
+        let count = |id| events.iter().filter(|e| e.0 == id).count();
+        for (selection, id) in [
+            (
+                SkimSelection {
+                    event_ids: vec![1],
+                    ..Default::default()
+                },
+                1,
+            ),
+            (
+                SkimSelection {
+                    trigger_mask: Some(parse_trigger_mask("0x2").unwrap()),
+                    ..Default::default()
+                },
+                2,
+            ),
+        ] {
+            let kept = skimmed(&run, &selection, &format!("id{}.mid", id));
+            assert!(kept.iter().all(|e| e.0 == id));
+            assert_eq!(kept.len(), count(id));
+        }
+        assert_eq!(count(1), 3000);
+        assert!(count(2) > 0);
+    }
+
+    #[test]
+    fn serial_selection() {
+        let run = generated_run("skim_serials");
+        let selection = SkimSelection {
+            event_ids: vec![1],
+            serials: Some(parse_range("100-199").unwrap()),
+            ..Default::default()
+        };
+        let kept: Vec<u32> = skimmed(&run, &selection, "serials.mid")
+            .into_iter()
+            .map(|e| e.1)
+            .collect();
+        assert_eq!(kept, (100..=199).collect::<Vec<_>>());
+        // the truth table hits of those triggers are the ones in the skim
+        let in_truth: BTreeSet<u32> = run
+            .truth
+            .iter()
+            .map(|r| r[8] as u32)
+            .filter(|s| (100..=199).contains(s))
+            .collect();
+        assert!(in_truth.iter().all(|s| kept.contains(s)));
+        assert!(in_truth.len() > 90);
+    }
+
+    #[test]
+    fn time_selection() {
+        let run = generated_run("skim_times");
+        let events = triggers(&run);
+        let start_time = MidasInput::open(&run.contents).unwrap().odb_records().1 .0;
+        let selection = SkimSelection {
+            times: Some(parse_range("1-1").unwrap()),
+            ..Default::default()
+        };
+        let kept = skimmed(&run, &selection, "times.mid");
+        let expected: Vec<(u16, u32)> = events
+            .iter()
+            .filter(|e| e.2 - start_time == 1)
+            .map(|e| (e.0, e.1))
+            .collect();
+        assert_eq!(kept, expected);
+        // about a second of triggers at 1 kHz, in one piece
+        let serials: Vec<u32> = kept.iter().filter(|e| e.0 == 1).map(|e| e.1).collect();
+        assert!(serials.len() > 800 && serials.len() < 1200);
+        assert!(serials.windows(2).all(|w| w[1] == w[0] + 1));
+    }
+
+    #[test]
+    fn hit_selection() {
+        let run = generated_run("skim_hits");
+        // either condition keeps the event, only trigger events have hits
+        let selection = SkimSelection {
+            hits: vec![
+                HitCondition::parse("QDC1 channel 3 long > 30000", &run.modules).unwrap(),
+                HitCondition::parse("scp1 tdc <= 500", &run.modules).unwrap(),
+            ],
+            ..Default::default()
+        };
+        let kept: BTreeSet<u32> = skimmed(&run, &selection, "hits.mid")
+            .into_iter()
+            .map(|(id, serial)| {
+                assert_eq!(id, 1);
+                serial
+            })
+            .collect();
+        let expected: BTreeSet<u32> = run
+            .truth
+            .iter()
+            .filter(|r| {
+                (r[0] == 1.0 && r[1] == 3.0 && r[3] > 30000.0) || (r[0] == 0.0 && r[5] <= 500.0)
+            })
+            .map(|r| r[8] as u32)
+            .collect();
+        assert!(!expected.is_empty() && expected.len() < 3000);
+        assert_eq!(kept, expected);
+    }
+}
@@ -1,344 +1,529 @@
+use crate::error::{ConverterError, DecodeError, ErrorTally};
+use crate::event_sequence::SequenceChecks;
 use crate::mdpp_bank::MDPPBank;
 use crate::module_config;
-use crate::sis3820::ScalerBank;
+use crate::pipeline::{self, Batch, DecodedBank};
+use crate::sort_outputs::{ModuleBank, SortOutputs, SortProgress, SortSink};
+use crate::unknown_banks::UnknownBanks;
 use crate::v1730_bank::v1730Bank;
 use crate::v785_bank::v785Bank;
-use crate::write_data::{CSVFile, CSVScaler, CSVv1730, CSVv785};
 use indicatif::ProgressBar;
-use midasio::read::file::FileView;
-use std::collections::HashMap;
-use std::io::{stdin, stdout, Write};
-use std::time::Duration;
+use midasio::read::event::EventView;
+use std::time::{Duration, Instant};
 
-fn strip_trailing_newline(input: &str) -> &str {
-    input
-        .strip_suffix("\r\n")
-        .or(input.strip_suffix('\n'))
-        .unwrap_or(input)
+/*
+The sorters are made once per run, get the events with sort_events and write
+what is left with finish. The sub-run files of a run are read one at a time and
+each goes through sort_events, everything that carries over from one file to
+the next (open MDPP events, event counters, the outputs) lives in the sorter.
+*/
+
+// What a decoder worker makes of an MDPP bank. The words before the first header
+// of the module in a batch belong to an event started in an earlier batch, the
+// worker can't do anything with them so the writer parses them.
+struct MDPPPiece<'a> {
+    module: usize,
+    prefix: &'a [u8],
+    // the first header of the module in the batch is in this bank
+    first_header: bool,
+    result: Result<u64, DecodeError>,
+    // events the worker completed in this bank
+    completed: usize,
+}
+
+// the worker's events and the banks of the batch, one per module
+struct MDPPBatch<'a> {
+    events: Vec<pipeline::DecodedEvent<'a, MDPPPiece<'a>>>,
+    banks: Vec<MDPPBank>,
 }
 
-pub struct MDPPSort {
-    filename: String,
-    scaler_filename: String,
+// the v785 and v1730 banks stand on their own
+struct HitPiece {
+    module: usize,
+    result: Result<u64, DecodeError>,
+    hits: usize,
+}
+
+// the bookkeeping every sorter does for every event
+struct SortState {
     chunk_size: usize,
-    config: module_config::Config,
+    flush_interval: Option<Duration>,
+    unknown_banks: UnknownBanks,
+    errors: ErrorTally,
+    sequences: SequenceChecks,
+    events_towards_chunks: usize,
+    event_num: usize,
+    last_flush: Instant,
+    last_time: u32,
+    pb: ProgressBar,
 }
 
-impl MDPPSort {
-    pub fn new(
-        filename: String,
-        scaler_filename: String,
+impl SortState {
+    fn new(
         chunk_size: usize,
-        config: module_config::Config,
-    ) -> Self {
-        MDPPSort {
-            filename,
-            scaler_filename,
+        config: &module_config::Config,
+        filename: &str,
+        pb: ProgressBar,
+    ) -> Result<Self, ConverterError> {
+        // setup the progress bar, it is one of many in a batch
+        pb.enable_steady_tick(Duration::from_millis(200));
+        Ok(SortState {
             chunk_size,
-            config,
+            flush_interval: config.flush_interval,
+            unknown_banks: UnknownBanks::new(config.unknown_banks, filename)
+                .map_err(|e| ConverterError::Io(filename.to_string(), e))?,
+            errors: ErrorTally::new(config.resilient),
+            sequences: SequenceChecks::new(),
+            events_towards_chunks: 0,
+            event_num: 0,
+            last_flush: Instant::now(),
+            last_time: 0,
+            pb,
+        })
+    }
+
+    fn next_event(&mut self, timestamp: u32) {
+        self.pb.tick();
+        self.event_num += 1;
+        self.events_towards_chunks += 1;
+        self.last_time = timestamp;
+    }
+
+    // write data to disk if we surpass the chunk size, and every flush interval
+    // while following a run. Some(true) when it's the flush interval.
+    fn flush_due(&mut self) -> Option<bool> {
+        let interval_due = self
+            .flush_interval
+            .is_some_and(|interval| self.last_flush.elapsed() >= interval);
+        if self.events_towards_chunks > self.chunk_size || interval_due {
+            self.last_flush = Instant::now();
+            self.pb
+                .set_message(format!("Events Processed: {}", self.event_num));
+            self.events_towards_chunks = 0;
+            Some(interval_due)
+        } else {
+            None
         }
     }
 
-    pub fn sort_loop<'a>(self, file_view: &'a FileView) {
-        // set up the file dumper
-        let mut mdpp_file_dumper = CSVFile::new(&self.filename);
-        let mut scaler_file_dumper = CSVScaler::new(&self.scaler_filename);
+    fn progress(&self) -> SortProgress<'_> {
+        SortProgress {
+            events: self.event_num,
+            last_time: self.last_time,
+            unknown_banks: &self.unknown_banks,
+            errors: &self.errors,
+        }
+    }
 
-        // we keep a hash map of banks, this allows us to track incomplete
-        // mdpp events across Midas events and hopefully complete them.
-        let mut bank_hash: HashMap<_, _> = self
-            .config
-            .modules
-            .iter()
-            .map(|m| (m.name.to_string(), MDPPBank::new()))
-            .collect();
-        // the scaler banks are simple, and do not require much abstraction
-        let mut scaler_banks: Vec<ScalerBank> = vec![ScalerBank::new()];
-        // setup the progress bar
-        let pb = ProgressBar::new_spinner();
-        pb.enable_steady_tick(Duration::from_millis(200));
-        let mut events_towards_chunks: usize = 0;
-        for (event_num, event) in (*file_view).into_iter().enumerate() {
-            pb.tick();
-            events_towards_chunks += 1;
-            // junk should now be in their own banks
-            // select trigger events
-            if event.id() == 1 {
-                for bank in event {
-                    if bank.data_slice().len() == 1 {
-                        continue;
-                    }
-                    // find the information associated with the bank name
-                    let m: Option<&module_config::Module> =
-                        self.config.modules.iter().find(|&m| m.name == bank.name());
-                    // call the MDPPBank structure associated with midas bank, if the bank name is invalid let the user know.
-                    match m {
-                        Some(m) => bank_hash.get_mut(&m.name).unwrap().parse(
-                            &m.mod_type.to_string(),
-                            m.nchannels,
-                            bank.data_slice(),
-                        ),
-                        None => {
-                            println!("No bank matching name {}", bank.name());
-                            println!("Press enter to continue, enter any other string to quit.");
-                            let mut user_choice = String::new();
-                            let _ = stdout().flush();
-                            stdin()
-                                .read_line(&mut user_choice)
-                                .expect("A valid string was not entered");
-                            if strip_trailing_newline(&user_choice).is_empty() {
-                                continue;
-                            } else {
-                                panic! {"User has aborted reading of file after invalid bank name."};
-                            }
-                        }
-                    }
-                }
-            } else if event.id() == 2 {
-                for bank in event {
-                    let mut temp = ScalerBank::new();
-                    temp.parse(bank.data_slice());
-                    scaler_banks.push(temp);
-                }
-            }
+    fn finish(self) -> ErrorTally {
+        self.unknown_banks.print_summary();
+        self.errors.print_summary();
+        self.sequences.print_summary();
+        self.errors
+    }
+}
 
-            // write data to disk if we surpass the chunk size
-            if events_towards_chunks > self.chunk_size {
-                // only write the banks that are complete
-                // we iterate over the config again because we don't want the
-                // loop to own the hashmap.
-                pb.set_message(format!("Events Processed: {}", event_num));
-                events_towards_chunks = 0;
+// what the sorters need to know about the midas event a module's bank was in
+struct EventInfo {
+    serial: u32,
+    subrun: u32,
+    serial_flag: u8,
+}
 
-                for m in self.config.modules.iter() {
-                    // start signals that a header has been read, but not an end
-                    // of event bank
-                    let temp = bank_hash.get_mut(&m.name.to_string()).unwrap();
-                    mdpp_file_dumper.write_data(temp);
+// Goes through the decoded events of a batch in order. This is the same for
+// every module, add_piece puts what a worker made of a module's bank into the
+// banks of the sorter.
+fn write_events<'a, B: ModuleBank, P>(
+    events: Vec<pipeline::DecodedEvent<'a, P>>,
+    sink: &mut SortSink<B>,
+    state: &mut SortState,
+    banks: &mut [B],
+    mut add_piece: impl FnMut(&mut [B], &mut SortState, P, &EventInfo) -> Result<(), ConverterError>,
+) -> Result<(), ConverterError> {
+    for event in events {
+        state.next_event(event.timestamp);
+        let info = EventInfo {
+            serial: event.serial,
+            subrun: event.subrun,
+            serial_flag: state.sequences.add_serial(event.id, event.serial),
+        };
+        for bank in event.banks {
+            match bank {
+                DecodedBank::Module(piece) => add_piece(banks, state, piece, &info)?,
+                DecodedBank::Unknown { name, data } => {
+                    state
+                        .unknown_banks
+                        .handle(event.serial, event.id, name, data)?
+                }
+                DecodedBank::Scaler { name, bank, result } => {
+                    if result.is_ok() {
+                        sink.add_scaler(bank);
+                    }
+                    state.errors.check(event.serial, name, result)?;
                 }
             }
-
-            // check if we are on the last iteration
         }
-        // show progress
 
-        // These are the banks that are left over if we have already dumped the data.
-        for m in self.config.modules.iter() {
-            // start signals that a header has been read, but not an end
-            // of event bank
-            let temp = bank_hash.get_mut(&m.name.to_string()).unwrap();
-            mdpp_file_dumper.write_data(temp);
-        }
-        // dump the scalers to their own csv file.
-        for i in 0..scaler_banks.len() {
-            scaler_file_dumper.write_data(&mut scaler_banks[i]);
+        // only write the banks that are complete
+        if let Some(interval_due) = state.flush_due() {
+            sink.flush(banks, interval_due, &state.progress())?;
         }
     }
+    Ok(())
 }
 
-// This is just copy and paste for now. Too lazy to try and abstract away the similarities
-
-pub struct v785Sort {
-    filename: String,
-    scaler_filename: String,
-    chunk_size: usize,
-    config: module_config::Config,
+// the sorter that goes with the modules of the config
+pub enum Sorter {
+    Mdpp(MDPPSort),
+    V785(v785Sort),
+    V1730(v1730Sort),
 }
 
-impl v785Sort {
+impl Sorter {
     pub fn new(
-        filename: String,
-        scaler_filename: String,
         chunk_size: usize,
         config: module_config::Config,
-    ) -> v785Sort {
-        v785Sort {
-            filename,
-            scaler_filename,
-            chunk_size,
-            config,
-        }
+        outputs: SortOutputs,
+        pb: ProgressBar,
+    ) -> Result<Self, ConverterError> {
+        let is_adc = config.modules.iter().any(|m| m.mod_type == "adc");
+        let is_v1730 = config.modules.iter().any(|m| m.mod_type == "v1730");
+        Ok(if is_adc {
+            Sorter::V785(Sort::new(chunk_size, config, outputs, pb, v785Bank::new)?)
+        } else if is_v1730 {
+            Sorter::V1730(Sort::new(chunk_size, config, outputs, pb, v1730Bank::new)?)
+        } else {
+            Sorter::Mdpp(Sort::new(chunk_size, config, outputs, pb, MDPPBank::new)?)
+        })
     }
 
-    pub fn sort_loop<'a>(self, file_view: &'a FileView) {
-        // set up the file dumper
-        let mut v785_file_dumper = CSVv785::new(&self.filename);
-        let mut scaler_file_dumper = CSVScaler::new(&self.scaler_filename);
-
-        // we keep a hash map of banks, this allows us to track incomplete
-        // mdpp events across Midas events and hopefully complete them.
-        let mut bank_hash: HashMap<_, _> = self
-            .config
-            .modules
-            .iter()
-            .map(|m| (m.name.to_string(), v785Bank::new()))
-            .collect();
-        // the scaler banks are simple, and do not require much abstraction
-        let mut scaler_banks: Vec<ScalerBank> = vec![ScalerBank::new()];
-        // setup the progress bar
-        let pb = ProgressBar::new_spinner();
-        pb.enable_steady_tick(Duration::from_millis(200));
-        let mut events_towards_chunks: usize = 0;
-        for (event_num, event) in (*file_view).into_iter().enumerate() {
-            pb.tick();
-            events_towards_chunks += 1;
-            // junk should now be in their own banks
-            // select trigger events
-            if event.id() == 1 {
-                for bank in event {
-                    if bank.data_slice().len() == 1 {
-                        continue;
-                    }
-                    // find the information associated with the bank name
-                    let m: Option<&module_config::Module> =
-                        self.config.modules.iter().find(|&m| m.name == bank.name());
-                    match m {
-                        Some(m) => bank_hash.get_mut(&m.name).unwrap().parse(bank.data_slice()),
-                        None => continue,
-                    }
-                    // Most of this is pointless right now, the data should be simple
-                }
-            } else if event.id() == 2 {
-                for bank in event {
-                    let mut temp = ScalerBank::new();
-                    temp.parse(bank.data_slice());
-                    scaler_banks.push(temp);
-                }
-            }
-
-            // write data to disk if we surpass the chunk size
-            if events_towards_chunks > self.chunk_size {
-                // only write the banks that are complete
-                // we iterate over the config again because we don't want the
-                // loop to own the hashmap.
-                pb.set_message(format!("Events Processed: {}", event_num));
-                events_towards_chunks = 0;
-
-                for m in self.config.modules.iter() {
-                    // start signals that a header has been read, but not an end
-                    // of event bank
-                    let temp = bank_hash.get_mut(&m.name.to_string()).unwrap();
-                    v785_file_dumper.write_data(temp);
-                }
-            }
-
-            // check if we are on the last iteration
+    pub fn sort_events<'a>(
+        &mut self,
+        // the sub-run number goes along with every event
+        events: impl Iterator<Item = (u32, EventView<'a>)> + Send,
+    ) -> Result<(), ConverterError> {
+        match self {
+            Sorter::Mdpp(sort) => sort.sort_events(events),
+            Sorter::V785(sort) => sort.sort_events(events),
+            Sorter::V1730(sort) => sort.sort_events(events),
         }
-        // show progress
+    }
 
-        // These are the banks that are left over if we have already dumped the data.
-        for m in self.config.modules.iter() {
-            // start signals that a header has been read, but not an end
-            // of event bank
-            let temp = bank_hash.get_mut(&m.name.to_string()).unwrap();
-            v785_file_dumper.write_data(temp);
-        }
-        // dump the scalers to their own csv file.
-        for i in 0..scaler_banks.len() {
-            scaler_file_dumper.write_data(&mut scaler_banks[i]);
+    pub fn finish(self) -> Result<ErrorTally, ConverterError> {
+        match self {
+            Sorter::Mdpp(sort) => sort.finish(),
+            Sorter::V785(sort) => sort.finish(),
+            Sorter::V1730(sort) => sort.finish(),
         }
     }
 }
 
-// More copy and paste for the v1730
-
-pub struct v1730Sort {
-    filename: String,
-    scaler_filename: String,
-    chunk_size: usize,
+pub struct Sort<B: ModuleBank> {
     config: module_config::Config,
+    sink: SortSink<B>,
+    state: SortState,
+    // we keep a bank per module, for the MDPP this allows us to track
+    // incomplete events across Midas events and hopefully complete them.
+    banks: Vec<B>,
 }
 
-impl v1730Sort {
+pub type MDPPSort = Sort<MDPPBank>;
+#[allow(non_camel_case_types)]
+pub type v785Sort = Sort<v785Bank>;
+#[allow(non_camel_case_types)]
+pub type v1730Sort = Sort<v1730Bank>;
+
+impl<B: ModuleBank> Sort<B> {
     pub fn new(
-        filename: String,
-        scaler_filename: String,
         chunk_size: usize,
         config: module_config::Config,
-    ) -> Self {
-        v1730Sort {
-            filename,
-            scaler_filename,
-            chunk_size,
+        outputs: SortOutputs,
+        pb: ProgressBar,
+        new_bank: fn() -> B,
+    ) -> Result<Self, ConverterError> {
+        let state = SortState::new(chunk_size, &config, &outputs.filename, pb)?;
+        // set up the file dumpers and the other outputs
+        let sink = SortSink::open(outputs, &config)?;
+        let banks = config.modules.iter().map(|_| new_bank()).collect();
+        Ok(Sort {
             config,
-        }
+            sink,
+            state,
+            banks,
+        })
     }
-    pub fn sort_loop<'a>(self, file_view: &'a FileView) {
-        // set up the file dumper
-        let mut v1730_file_dumper = CSVv1730::new(&self.filename);
-        let mut scaler_file_dumper = CSVScaler::new(&self.scaler_filename);
 
-        // we keep a hash map of banks, this allows us to track incomplete
-        // mdpp events across Midas events and hopefully complete them.
-        let mut bank_hash: HashMap<_, _> = self
-            .config
-            .modules
-            .iter()
-            .map(|m| (m.name.to_string(), v1730Bank::new()))
-            .collect();
-        // the scaler banks are simple, and do not require much abstraction
-        let mut scaler_banks: Vec<ScalerBank> = vec![ScalerBank::new()];
-        // setup the progress bar
-        let pb = ProgressBar::new_spinner();
-        pb.enable_steady_tick(Duration::from_millis(200));
-        let mut events_towards_chunks: usize = 0;
-        for (event_num, event) in (*file_view).into_iter().enumerate() {
-            pb.tick();
-            events_towards_chunks += 1;
-            // junk should now be in their own banks
-            // select trigger events
-            if event.id() == 1 {
-                for bank in event {
-                    if bank.data_slice().len() == 1 {
-                        continue;
-                    }
-                    // find the information associated with the bank name
-                    let m: Option<&module_config::Module> =
-                        self.config.modules.iter().find(|&m| m.name == bank.name());
-                    match m {
-                        Some(m) => bank_hash.get_mut(&m.name).unwrap().parse(bank.data_slice()),
-                        None => continue,
+    pub fn finish(mut self) -> Result<ErrorTally, ConverterError> {
+        self.sink.finish(&mut self.banks, &self.state.progress())?;
+        Ok(self.state.finish())
+    }
+}
+
+impl Sort<MDPPBank> {
+    pub fn sort_events<'a>(
+        &mut self,
+        events: impl Iterator<Item = (u32, EventView<'a>)> + Send,
+    ) -> Result<(), ConverterError> {
+        let Sort {
+            config,
+            sink,
+            state,
+            banks,
+        } = self;
+        let config = &*config;
+        let modules = &config.modules;
+        let resilient = config.resilient;
+
+        // the workers start every batch without any open events
+        let decode = |batch: Batch<'a>| {
+            let mut worker_banks: Vec<MDPPBank> = modules
+                .iter()
+                .map(|_| MDPPBank::with_capacity(64))
+                .collect();
+            let mut started = vec![false; modules.len()];
+            let events = pipeline::decode_events(batch, modules, |i, m, data| {
+                let mod_type = m.mod_type.to_string();
+                // a bank that is rejected as a whole doesn't get split
+                if let Err(e) = MDPPBank::check_bank(&mod_type, m.nchannels, data) {
+                    return MDPPPiece {
+                        module: i,
+                        prefix: &data[..0],
+                        first_header: false,
+                        result: Err(e),
+                        completed: 0,
+                    };
+                }
+                let (prefix, rest, first_header) = if started[i] {
+                    (&data[..0], data, false)
+                } else {
+                    match MDPPBank::first_header(data) {
+                        Some(pos) => {
+                            started[i] = true;
+                            (&data[..pos], &data[pos..], true)
+                        }
+                        None => (data, &data[data.len()..], false),
                     }
-                    // Most of this is pointless right now, the data should be simple
+                };
+                let bank = &mut worker_banks[i];
+                let before = bank.completed_events().len();
+                let result = if rest.is_empty() {
+                    Ok(0)
+                } else {
+                    bank.parse(&mod_type, m.nchannels, rest, resilient)
+                };
+                MDPPPiece {
+                    module: i,
+                    prefix,
+                    first_header,
+                    result,
+                    completed: bank.completed_events().len() - before,
                 }
-            } else if event.id() == 2 {
-                for bank in event {
-                    let mut temp = ScalerBank::new();
-                    temp.parse(bank.data_slice());
-                    scaler_banks.push(temp);
+            });
+            MDPPBatch {
+                events,
+                banks: worker_banks,
+            }
+        };
+
+        // put the batches together in order, everything that needs the events
+        // before it happens here
+        let write = |batch: MDPPBatch<'a>| -> Result<(), ConverterError> {
+            let mut worker_banks: Vec<_> = batch
+                .banks
+                .into_iter()
+                .map(|mut b| {
+                    let open = b.take_open_event();
+                    (b.junk_events, open, b.events.into_iter())
+                })
+                .collect();
+            // Mesytec events have their own counter, the serial flag is only for the summary
+            write_events(
+                batch.events,
+                sink,
+                state,
+                banks,
+                |banks, state, piece, event| {
+                    let m = &modules[piece.module];
+                    let mdpp_bank = &mut banks[piece.module];
+                    // finish the event we already have open
+                    let mut result = Ok(0);
+                    if !piece.prefix.is_empty() {
+                        result = mdpp_bank.parse(
+                            &m.mod_type.to_string(),
+                            m.nchannels,
+                            piece.prefix,
+                            resilient,
+                        );
+                    }
+                    // same as a header in parse, an event still open here is junk
+                    if piece.first_header {
+                        mdpp_bank.drop_open_event();
+                    }
+                    mdpp_bank.append_completed(
+                        worker_banks[piece.module].2.by_ref().take(piece.completed),
+                    );
+                    let result = result.and_then(|skipped| {
+                        piece.result.map(|more_skipped| skipped + more_skipped)
+                    });
+                    // check the event counters of the events this bank finished
+                    let new_events = mdpp_bank.check_sequence(
+                        state.sequences.counter(&m.name, m.timestamp),
+                        event.subrun,
+                    );
+                    state.sequences.add_events(&m.name, new_events);
+                    state.errors.check(event.serial, &m.name, result)
+                },
+            )?;
+            // the events the worker left open carry on in the next batch
+            for (bank, (junk_events, open, _)) in banks.iter_mut().zip(worker_banks) {
+                bank.junk_events += junk_events;
+                if let Some(open) = open {
+                    bank.continue_event(open);
                 }
             }
+            Ok(())
+        };
+        pipeline::run(
+            events,
+            config.threads,
+            pipeline::batch_events(config),
+            decode,
+            write,
+        )
+    }
+}
 
-            // write data to disk if we surpass the chunk size
-            if events_towards_chunks > self.chunk_size {
-                // only write the banks that are complete
-                // we iterate over the config again because we don't want the
-                // loop to own the hashmap.
-                pb.set_message(format!("Events Processed: {}", event_num));
-                events_towards_chunks = 0;
+// the v785 and v1730 hits don't span midas events, a worker's hits go to the
+// sorter's bank as they are
+impl Sort<v785Bank> {
+    pub fn sort_events<'a>(
+        &mut self,
+        events: impl Iterator<Item = (u32, EventView<'a>)> + Send,
+    ) -> Result<(), ConverterError> {
+        let Sort {
+            config,
+            sink,
+            state,
+            banks,
+        } = self;
+        let config = &*config;
+        let modules = &config.modules;
 
-                for m in self.config.modules.iter() {
-                    // start signals that a header has been read, but not an end
-                    // of event bank
-                    let temp = bank_hash.get_mut(&m.name.to_string()).unwrap();
-                    v1730_file_dumper.write_data(temp);
+        let decode = |batch: Batch<'a>| {
+            let mut worker_banks: Vec<v785Bank> = modules.iter().map(|_| v785Bank::new()).collect();
+            let events = pipeline::decode_events(batch, modules, |i, _, data| {
+                let bank = &mut worker_banks[i];
+                let before = bank.hits.len();
+                let result = bank.parse(data);
+                HitPiece {
+                    module: i,
+                    result,
+                    hits: bank.hits.len() - before,
                 }
-            }
+            });
+            (events, worker_banks)
+        };
 
-            // check if we are on the last iteration
-        }
-        // show progress
+        let write = |(events, worker_banks): (
+            Vec<pipeline::DecodedEvent<'a, HitPiece>>,
+            Vec<v785Bank>,
+        )|
+         -> Result<(), ConverterError> {
+            let mut worker_hits: Vec<_> = worker_banks
+                .into_iter()
+                .map(|b| b.hits.into_iter())
+                .collect();
+            write_events(events, sink, state, banks, |banks, state, piece, event| {
+                let m = &modules[piece.module];
+                let v785_bank = &mut banks[piece.module];
+                let before = v785_bank.hits.len();
+                v785_bank.append_hits(worker_hits[piece.module].by_ref().take(piece.hits));
+                // our own event numbers can't have gaps, so go by the midas serial
+                for hit in v785_bank.hits[before..].iter_mut() {
+                    hit.flags = event.serial_flag;
+                    hit.subrun = event.subrun;
+                }
+                state
+                    .sequences
+                    .add_events(&m.name, (v785_bank.hits.len() - before) as u64);
+                state.errors.check(event.serial, &m.name, piece.result)
+            })
+        };
+        pipeline::run(
+            events,
+            config.threads,
+            pipeline::batch_events(config),
+            decode,
+            write,
+        )
+    }
+}
 
-        // These are the banks that are left over if we have already dumped the data.
-        for m in self.config.modules.iter() {
-            // start signals that a header has been read, but not an end
-            // of event bank
-            let temp = bank_hash.get_mut(&m.name.to_string()).unwrap();
-            v1730_file_dumper.write_data(temp);
-        }
-        // dump the scalers to their own csv file.
-        for i in 0..scaler_banks.len() {
-            scaler_file_dumper.write_data(&mut scaler_banks[i]);
-        }
+impl Sort<v1730Bank> {
+    pub fn sort_events<'a>(
+        &mut self,
+        events: impl Iterator<Item = (u32, EventView<'a>)> + Send,
+    ) -> Result<(), ConverterError> {
+        let Sort {
+            config,
+            sink,
+            state,
+            banks,
+        } = self;
+        let config = &*config;
+        let modules = &config.modules;
+        let resilient = config.resilient;
+
+        let decode = |batch: Batch<'a>| {
+            let mut worker_banks: Vec<v1730Bank> =
+                modules.iter().map(|_| v1730Bank::new()).collect();
+            let events = pipeline::decode_events(batch, modules, |i, _, data| {
+                let bank = &mut worker_banks[i];
+                let before = bank.hits.len();
+                let result = bank.parse(data, resilient);
+                HitPiece {
+                    module: i,
+                    result,
+                    hits: bank.hits.len() - before,
+                }
+            });
+            (events, worker_banks)
+        };
+
+        let write = |(events, worker_banks): (
+            Vec<pipeline::DecodedEvent<'a, HitPiece>>,
+            Vec<v1730Bank>,
+        )|
+         -> Result<(), ConverterError> {
+            let mut worker_hits: Vec<_> = worker_banks
+                .into_iter()
+                .map(|b| b.hits.into_iter())
+                .collect();
+            write_events(events, sink, state, banks, |banks, state, piece, event| {
+                let m = &modules[piece.module];
+                let v1730_bank = &mut banks[piece.module];
+                let before = v1730_bank.hits.len();
+                v1730_bank
+                    .hits
+                    .extend(worker_hits[piece.module].by_ref().take(piece.hits));
+                // no event counter in the hits, so go by the midas serial
+                for hit in v1730_bank.hits[before..].iter_mut() {
+                    hit.flags = event.serial_flag;
+                    hit.subrun = event.subrun;
+                }
+                // one bank per trigger
+                if piece.result.is_ok() {
+                    state.sequences.add_events(&m.name, 1);
+                }
+                state.errors.check(event.serial, &m.name, piece.result)
+            })
+        };
+        pipeline::run(
+            events,
+            config.threads,
+            pipeline::batch_events(config),
+            decode,
+            write,
+        )
     }
 }
@@ -0,0 +1,328 @@
+use crate::dataset_output::DatasetOutput;
+use crate::error::{ConverterError, ErrorTally};
+use crate::hdf5_output::H5Output;
+use crate::histograms::{HistogramFormat, Histograms};
+use crate::live_output::LiveOutput;
+use crate::mdpp_bank::MDPPBank;
+use crate::module_config;
+use crate::quicklook::QuickLook;
+use crate::sis3820::ScalerBank;
+use crate::unknown_banks::UnknownBanks;
+use crate::v1730_bank::v1730Bank;
+use crate::v785_bank::v785Bank;
+use crate::write_data::{CSVFile, CSVScaler, CSVv1730, CSVv785};
+use std::path::Path;
+
+/*
+Everything the sorters write the decoded hits to.
+
+convert sets up the optional outputs in SortOutputs, and the sorter turns them
+into a SortSink for its kind of bank, which also holds the csv dumpers and the
+histograms. Every chunk (and every flush interval when following a run) the
+sorter hands its banks to SortSink::flush, and at the end of the run to
+SortSink::finish, which write the banks to every output and empty them.
+
+What an output does with each kind of bank is in the ModuleBank impls at the
+bottom, a new output only has to be added here.
+*/
+
+// out.d/run5.csv -> out.d/run5, the other outputs are named after the csv
+pub fn output_stem(output_file: &str) -> String {
+    Path::new(output_file)
+        .with_extension("")
+        .to_string_lossy()
+        .into_owned()
+}
+
+// attach the filename to I/O errors from the file dumpers
+fn io_error(filename: &str) -> impl Fn(std::io::Error) -> ConverterError + '_ {
+    move |e| ConverterError::Io(filename.to_string(), e)
+}
+
+pub struct SortOutputs {
+    pub filename: String,
+    pub scaler_filename: String,
+    pub hdf5: Option<H5Output>,
+    pub dataset: Option<DatasetOutput>,
+    pub live: Option<LiveOutput>,
+    pub quicklook: Option<QuickLook>,
+}
+
+// where the sort is at, for the quick look page
+pub struct SortProgress<'a> {
+    pub events: usize,
+    pub last_time: u32,
+    pub unknown_banks: &'a UnknownBanks,
+    pub errors: &'a ErrorTally,
+}
+
+// a sorter's bank of decoded hits for one module
+pub trait ModuleBank {
+    type Csv;
+    fn open_csv(filename: &str, flag_events: bool, subruns: bool) -> std::io::Result<Self::Csv>;
+    // writes the hits to the csv and empties the bank
+    fn write_csv(&mut self, csv: &mut Self::Csv) -> std::io::Result<()>;
+    fn flush_csv(csv: &mut Self::Csv) -> std::io::Result<()>;
+    fn clear(&mut self);
+    fn fill_histograms(&self, module: &str, histograms: &mut Histograms);
+    fn add_quicklook(&self, module: &str, quicklook: &QuickLook);
+    fn write_hdf5(&self, module: &str, h5: &mut H5Output) -> std::io::Result<()>;
+    fn write_dataset(
+        &self,
+        module: &str,
+        dataset: &mut DatasetOutput,
+    ) -> Result<(), ConverterError>;
+    fn add_live(&self, live: &mut LiveOutput) -> Result<(), ConverterError>;
+    // events cut off by the next header, only the MDPP has those
+    fn junk_events(&self) -> i64 {
+        0
+    }
+}
+
+pub struct SortSink<B: ModuleBank> {
+    filename: String,
+    scaler_filename: String,
+    modules: Vec<String>,
+    // no hit table if we only want the spectra
+    csv: Option<B::Csv>,
+    scaler_csv: CSVScaler,
+    scaler_banks: Vec<ScalerBank>,
+    histograms: Histograms,
+    histogram_formats: Vec<HistogramFormat>,
+    hdf5: Option<H5Output>,
+    dataset: Option<DatasetOutput>,
+    live: Option<LiveOutput>,
+    quicklook: Option<QuickLook>,
+}
+
+impl<B: ModuleBank> SortSink<B> {
+    pub fn open(
+        outputs: SortOutputs,
+        config: &module_config::Config,
+    ) -> Result<Self, ConverterError> {
+        let csv = if config.histograms_only {
+            None
+        } else {
+            Some(
+                B::open_csv(&outputs.filename, config.flag_events, config.subruns)
+                    .map_err(io_error(&outputs.filename))?,
+            )
+        };
+        let scaler_csv =
+            CSVScaler::new(&outputs.scaler_filename).map_err(io_error(&outputs.scaler_filename))?;
+        Ok(SortSink {
+            csv,
+            scaler_csv,
+            // the scaler banks are simple, and do not require much abstraction
+            scaler_banks: vec![ScalerBank::new()],
+            histograms: Histograms::new(&config.histograms, &config.modules)?,
+            histogram_formats: config.histogram_formats.clone(),
+            modules: config.modules.iter().map(|m| m.name.clone()).collect(),
+            filename: outputs.filename,
+            scaler_filename: outputs.scaler_filename,
+            hdf5: outputs.hdf5,
+            dataset: outputs.dataset,
+            live: outputs.live,
+            quicklook: outputs.quicklook,
+        })
+    }
+
+    pub fn add_scaler(&mut self, bank: ScalerBank) {
+        self.scaler_banks.push(bank);
+    }
+
+    // every output gets the hits of every module, the banks are empty afterwards
+    fn write_banks(&mut self, banks: &mut [B], flush_csv: bool) -> Result<(), ConverterError> {
+        for (name, bank) in self.modules.iter().zip(banks.iter_mut()) {
+            bank.fill_histograms(name, &mut self.histograms);
+            if let Some(quicklook) = &self.quicklook {
+                bank.add_quicklook(name, quicklook);
+            }
+            if let Some(h5) = &mut self.hdf5 {
+                bank.write_hdf5(name, h5).map_err(io_error(h5.filename()))?;
+            }
+            if let Some(dataset) = &mut self.dataset {
+                bank.write_dataset(name, dataset)?;
+            }
+            if let Some(live) = &mut self.live {
+                bank.add_live(live)?;
+            }
+            match &mut self.csv {
+                Some(csv) => {
+                    bank.write_csv(csv).map_err(io_error(&self.filename))?;
+                    if flush_csv {
+                        B::flush_csv(csv).map_err(io_error(&self.filename))?;
+                    }
+                }
+                None => bank.clear(),
+            }
+        }
+        Ok(())
+    }
+
+    fn update_quicklook(&self, banks: &[B], progress: &SortProgress) {
+        if let Some(quicklook) = &self.quicklook {
+            quicklook.update(
+                progress.events,
+                progress.last_time,
+                banks.iter().map(|b| b.junk_events()).sum(),
+                progress.unknown_banks,
+                progress.errors,
+            );
+        }
+    }
+
+    // a chunk is done, or the flush interval of a followed run is up
+    pub fn flush(
+        &mut self,
+        banks: &mut [B],
+        flush_csv: bool,
+        progress: &SortProgress,
+    ) -> Result<(), ConverterError> {
+        self.write_banks(banks, flush_csv)?;
+        if let Some(live) = &mut self.live {
+            live.flush()?;
+        }
+        self.update_quicklook(banks, progress);
+        Ok(())
+    }
+
+    // These are the banks that are left over if we have already dumped the data.
+    pub fn finish(
+        mut self,
+        banks: &mut [B],
+        progress: &SortProgress,
+    ) -> Result<(), ConverterError> {
+        self.write_banks(banks, false)?;
+        // dump the scalers to their own csv file.
+        for scaler_bank in self.scaler_banks.iter_mut() {
+            self.scaler_csv
+                .write_data(scaler_bank)
+                .map_err(io_error(&self.scaler_filename))?;
+        }
+        self.histograms
+            .write(&output_stem(&self.filename), &self.histogram_formats)?;
+        if let Some(live) = self.live.take() {
+            live.finish()?;
+        }
+        self.update_quicklook(banks, progress);
+        if let Some(quicklook) = &self.quicklook {
+            quicklook.finish();
+        }
+        if let Some(mut h5) = self.hdf5.take() {
+            h5.write_scalers(&self.scaler_banks)
+                .map_err(io_error(h5.filename()))?;
+            h5.close()?;
+        }
+        Ok(())
+    }
+}
+
+impl ModuleBank for MDPPBank {
+    type Csv = CSVFile;
+    fn open_csv(filename: &str, flag_events: bool, subruns: bool) -> std::io::Result<CSVFile> {
+        CSVFile::new(filename, flag_events, subruns)
+    }
+    fn write_csv(&mut self, csv: &mut CSVFile) -> std::io::Result<()> {
+        csv.write_data(self)
+    }
+    fn flush_csv(csv: &mut CSVFile) -> std::io::Result<()> {
+        csv.flush()
+    }
+    fn clear(&mut self) {
+        self.clear_data();
+    }
+    // only the events that are complete
+    fn fill_histograms(&self, module: &str, histograms: &mut Histograms) {
+        histograms.fill_mdpp(module, self.completed_events());
+    }
+    fn add_quicklook(&self, module: &str, quicklook: &QuickLook) {
+        quicklook.add_mdpp(module, self.completed_events());
+    }
+    fn write_hdf5(&self, module: &str, h5: &mut H5Output) -> std::io::Result<()> {
+        h5.write_mdpp(module, self.completed_events())
+    }
+    fn write_dataset(
+        &self,
+        module: &str,
+        dataset: &mut DatasetOutput,
+    ) -> Result<(), ConverterError> {
+        dataset.write_mdpp(module, self.completed_events())
+    }
+    fn add_live(&self, live: &mut LiveOutput) -> Result<(), ConverterError> {
+        live.add_mdpp(self.completed_events())
+    }
+    fn junk_events(&self) -> i64 {
+        self.junk_events
+    }
+}
+
+impl ModuleBank for v785Bank {
+    type Csv = CSVv785;
+    fn open_csv(filename: &str, flag_events: bool, subruns: bool) -> std::io::Result<CSVv785> {
+        CSVv785::new(filename, flag_events, subruns)
+    }
+    fn write_csv(&mut self, csv: &mut CSVv785) -> std::io::Result<()> {
+        csv.write_data(self)
+    }
+    fn flush_csv(csv: &mut CSVv785) -> std::io::Result<()> {
+        csv.flush()
+    }
+    fn clear(&mut self) {
+        self.hits.clear();
+    }
+    fn fill_histograms(&self, module: &str, histograms: &mut Histograms) {
+        histograms.fill_v785(module, &self.hits);
+    }
+    fn add_quicklook(&self, module: &str, quicklook: &QuickLook) {
+        quicklook.add_v785(module, &self.hits);
+    }
+    fn write_hdf5(&self, module: &str, h5: &mut H5Output) -> std::io::Result<()> {
+        h5.write_v785(module, &self.hits)
+    }
+    fn write_dataset(
+        &self,
+        module: &str,
+        dataset: &mut DatasetOutput,
+    ) -> Result<(), ConverterError> {
+        dataset.write_v785(module, &self.hits)
+    }
+    fn add_live(&self, live: &mut LiveOutput) -> Result<(), ConverterError> {
+        live.add_v785(&self.hits)
+    }
+}
+
+impl ModuleBank for v1730Bank {
+    type Csv = CSVv1730;
+    fn open_csv(filename: &str, flag_events: bool, subruns: bool) -> std::io::Result<CSVv1730> {
+        CSVv1730::new(filename, flag_events, subruns)
+    }
+    fn write_csv(&mut self, csv: &mut CSVv1730) -> std::io::Result<()> {
+        csv.write_data(self)
+    }
+    fn flush_csv(csv: &mut CSVv1730) -> std::io::Result<()> {
+        csv.flush()
+    }
+    fn clear(&mut self) {
+        self.hits.clear();
+    }
+    fn fill_histograms(&self, module: &str, histograms: &mut Histograms) {
+        histograms.fill_v1730(module, &self.hits);
+    }
+    fn add_quicklook(&self, module: &str, quicklook: &QuickLook) {
+        quicklook.add_v1730(module, &self.hits);
+    }
+    fn write_hdf5(&self, module: &str, h5: &mut H5Output) -> std::io::Result<()> {
+        h5.write_v1730(module, &self.hits)
+    }
+    fn write_dataset(
+        &self,
+        module: &str,
+        dataset: &mut DatasetOutput,
+    ) -> Result<(), ConverterError> {
+        dataset.write_v1730(module, &self.hits)
+    }
+    fn add_live(&self, live: &mut LiveOutput) -> Result<(), ConverterError> {
+        live.add_v1730(&self.hits)
+    }
+}
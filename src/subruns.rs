@@ -0,0 +1,146 @@
+use crate::error::ConverterError;
+use crate::input_source::InputSource;
+use crate::run_metadata::RunMetadata;
+use crate::salvage::MidasInput;
+use midasio::read::event::EventView;
+use std::path::Path;
+
+/*
+The logger splits long runs into sub-run files, run00123_000.mid.lz4,
+run00123_001.mid.lz4, ... Every one of them is a whole midas file with its own
+ODB dumps, but an MDPP event can start at the end of one file and finish in the
+next, so the files of a run are sorted as one stream of events and end up in a
+single output named after the run (run00123.parquet) with a subrun column. The
+files are read one after the other, only the sorter's state carries over.
+
+Only the files that are given are merged, and a run with a single sub-run file
+is converted on its own like any other file.
+*/
+
+pub struct RunFiles {
+    // the output name without the extension
+    pub stem: String,
+    // (sub-run number, file name) in sub-run order, a plain file is sub-run 0
+    pub files: Vec<(u32, String)>,
+}
+
+impl RunFiles {
+    pub fn single(file: String) -> Self {
+        RunFiles {
+            stem: InputSource::from_arg(&file).stem(),
+            files: vec![(0, file)],
+        }
+    }
+
+    // only merged runs get the subrun column
+    pub fn is_merged(&self) -> bool {
+        self.files.len() > 1
+    }
+
+    // what the batch summary calls the run
+    pub fn label(&self) -> String {
+        match (self.files.first(), self.files.last()) {
+            (Some((first, _)), Some((last, _))) if self.is_merged() => {
+                format!("{} (sub-runs {:03}-{:03})", self.stem, first, last)
+            }
+            _ => self.files[0].1.clone(),
+        }
+    }
+}
+
+// "data/run00123_004.mid.lz4" -> ("data/run00123", 4)
+// only the logger's runNNNNN_NNN names, anything else is a run of its own
+fn split_subrun(file: &str) -> Option<(String, u32)> {
+    let name = Path::new(file).file_name()?.to_str()?;
+    let (prefix, number) = name.split('.').next()?.rsplit_once('_')?;
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if !prefix.strip_prefix("run").is_some_and(digits) || number.len() != 3 || !digits(number) {
+        return None;
+    }
+    let dir = &file[..file.len() - name.len()];
+    Some((format!("{}{}", dir, prefix), number.parse().ok()?))
+}
+
+// Groups the sub-run files of every run, keeping the order the runs were given in.
+pub fn group_subruns(files: Vec<String>, merge: bool) -> Result<Vec<RunFiles>, ConverterError> {
+    // the bool marks the groups of sub-runs
+    let mut runs: Vec<(RunFiles, bool)> = Vec::new();
+    for file in files {
+        match split_subrun(&file).filter(|_| merge) {
+            Some((prefix, number)) => {
+                match runs
+                    .iter_mut()
+                    .find(|(r, split)| *split && r.stem == prefix)
+                {
+                    Some((run, _)) => run.files.push((number, file)),
+                    None => runs.push((
+                        RunFiles {
+                            stem: prefix,
+                            files: vec![(number, file)],
+                        },
+                        true,
+                    )),
+                }
+            }
+            None => runs.push((RunFiles::single(file), false)),
+        }
+    }
+
+    let mut grouped = Vec::new();
+    for (mut run, split) in runs {
+        if !split {
+            grouped.push(run);
+            continue;
+        }
+        if run.files.len() == 1 {
+            grouped.push(RunFiles::single(run.files.remove(0).1));
+            continue;
+        }
+        run.files.sort_by_key(|(number, _)| *number);
+        for pair in run.files.windows(2) {
+            let (a, b) = (pair[0].0, pair[1].0);
+            if a == b {
+                return Err(ConverterError::Config(format!(
+                    "sub-run {:03} of {} is given twice ({} and {})",
+                    a, run.stem, pair[0].1, pair[1].1
+                )));
+            }
+            if b != a + 1 {
+                println!(
+                    "Sub-runs {:03} to {:03} of {} are missing, events across the gap will be lost.",
+                    a + 1,
+                    b - 1,
+                    run.stem
+                );
+            }
+        }
+        grouped.push(run);
+    }
+    Ok(grouped)
+}
+
+// The BOR of the first sub-run and the EOR of the last one. The sub-runs in
+// between are only read when they are sorted, the salvaged flag gets theirs then.
+pub fn run_metadata(first: RunMetadata, last: Option<RunMetadata>) -> RunMetadata {
+    let mut metadata = first;
+    if let Some(last) = last {
+        if last.run_number != metadata.run_number {
+            println!(
+                "The sub-runs are from different runs ({} and {}), using run {}.",
+                metadata.run_number, last.run_number, metadata.run_number
+            );
+        }
+        metadata.stop_timestamp = last.stop_timestamp;
+        metadata.eor_odb = last.eor_odb;
+        metadata.salvaged |= last.salvaged;
+    }
+    metadata
+}
+
+// the events of one sub-run file, with its sub-run number
+pub fn events<'a, 'b>(
+    input: &'b MidasInput<'a>,
+    subrun: u32,
+) -> impl Iterator<Item = (u32, EventView<'a>)> + Send + 'b {
+    input.events().map(move |event| (subrun, event))
+}
@@ -0,0 +1,219 @@
+use crate::bitmasks;
+use crate::error::ConverterError;
+use crate::sort_outputs::output_stem;
+use clap::ValueEnum;
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/*
+What to do with a bank whose name is not in the config.
+
+Every sorter hands these banks to UnknownBanks, which counts them by name so
+that they show up in the summary at the end of the run. With dump-raw the
+contents are also written to <output>_unknown_banks.csv, one row per bank with
+the 32 bit words in hex.
+*/
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnknownBankPolicy {
+    /// count the bank and move on
+    Skip,
+    /// print a warning the first time a bank name shows up
+    #[default]
+    Warn,
+    /// stop the conversion
+    Error,
+    /// write the bank contents to a raw table
+    DumpRaw,
+}
+
+pub struct UnknownBanks {
+    policy: UnknownBankPolicy,
+    pub counts: BTreeMap<String, u64>,
+    raw_file: Option<BufWriter<File>>,
+    raw_filename: String,
+}
+
+impl UnknownBanks {
+    pub fn new(policy: UnknownBankPolicy, output_filename: &str) -> std::io::Result<Self> {
+        let raw_filename = format!("{}_unknown_banks.csv", output_stem(output_filename));
+        let raw_file = if policy == UnknownBankPolicy::DumpRaw {
+            let mut file = BufWriter::new(File::create(&raw_filename)?);
+            writeln!(file, "serial,event_id,bank,nbytes,data")?;
+            Some(file)
+        } else {
+            None
+        };
+        Ok(UnknownBanks {
+            policy,
+            counts: BTreeMap::new(),
+            raw_file,
+            raw_filename,
+        })
+    }
+
+    pub fn handle(
+        &mut self,
+        serial: u32,
+        event_id: u16,
+        name: &str,
+        data: &[u8],
+    ) -> Result<(), ConverterError> {
+        let count = self.counts.entry(name.to_string()).or_insert(0);
+        *count += 1;
+        match self.policy {
+            UnknownBankPolicy::Skip => {}
+            UnknownBankPolicy::Warn => {
+                if *count == 1 {
+                    println!(
+                        "No bank matching name {} (first seen in event serial {}), skipping it.",
+                        name, serial
+                    );
+                }
+            }
+            UnknownBankPolicy::Error => {
+                return Err(ConverterError::UnknownBank {
+                    serial,
+                    bank: name.to_string(),
+                })
+            }
+            UnknownBankPolicy::DumpRaw => {
+                let words = data
+                    .chunks(4)
+                    .map(|chunk| {
+                        if chunk.len() == 4 {
+                            format!("{:08x}", bitmasks::to_u32_le(chunk))
+                        } else {
+                            // leftover bytes at the end of odd sized banks
+                            chunk.iter().map(|b| format!("{:02x}", b)).collect()
+                        }
+                    })
+                    .collect::<Vec<String>>()
+                    .join(" ");
+                let file = self.raw_file.as_mut().unwrap();
+                writeln!(
+                    file,
+                    "{},{},{},{},{}",
+                    serial,
+                    event_id,
+                    name,
+                    data.len(),
+                    words
+                )
+                .map_err(|e| ConverterError::Io(self.raw_filename.clone(), e))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn print_summary(&self) {
+        if self.counts.is_empty() {
+            return;
+        }
+        println!("Banks not in the config:");
+        for (name, count) in self.counts.iter() {
+            println!("  {}: {}", name, count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::tests::{
+        generate_run, settings, sorted_rows, test_dir, try_convert_run, MDPP_CONFIG,
+    };
+    use crate::salvage::MidasInput;
+    use std::fs;
+    use std::path::Path;
+
+    // the run has SCP1 and QDC1 banks, the config only knows SCP1
+    fn run_with_unknown_bank(name: &str) -> (String, String, String, u64) {
+        let dir = test_dir(name);
+        let (_, run, summary) = generate_run(&dir, MDPP_CONFIG, &settings(0.0));
+        let config_file = dir.join("scp1.toml").to_str().unwrap().to_string();
+        let scp1 = MDPP_CONFIG.split("\n\n").next().unwrap();
+        fs::write(&config_file, scp1).unwrap();
+        let output = dir.join("out").to_str().unwrap().to_string();
+        (run, config_file, output, summary.trigger_events)
+    }
+
+    // the SCP1 hits are sorted no matter what happens to the QDC1 banks
+    fn check_scp1_rows(run: &str, output: &str) {
+        let (_, rows) = sorted_rows(&format!("{}.csv", output));
+        let (_, truth_rows) = sorted_rows(&format!("{}_truth.csv", output_stem(run)));
+        let truth_rows: Vec<String> = truth_rows
+            .into_iter()
+            .filter(|r| r.starts_with("0,"))
+            .collect();
+        assert!(!rows.is_empty());
+        assert!(rows == truth_rows, "SCP1 hits differ from the truth table");
+    }
+
+    #[test]
+    fn skip() {
+        let (run, config_file, output, _) = run_with_unknown_bank("unknown_skip");
+        let complete = try_convert_run(&run, &config_file, &output, &["--unknown-banks", "skip"]);
+        // a complete run, exit code 0
+        assert!(matches!(complete, Ok(true)));
+        check_scp1_rows(&run, &output);
+        assert!(!Path::new(&format!("{}_unknown_banks.csv", output)).exists());
+    }
+
+    #[test]
+    fn error() {
+        let (run, config_file, output, _) = run_with_unknown_bank("unknown_error");
+        match try_convert_run(&run, &config_file, &output, &["--unknown-banks", "error"]) {
+            Err(e @ ConverterError::UnknownBank { serial: 0, .. }) => {
+                assert_eq!(e.exit_code(), 6);
+                assert_eq!(
+                    e.to_string(),
+                    "no bank matching name QDC1 in event serial 0"
+                );
+            }
+            Err(e) => panic!("wrong error {}", e),
+            Ok(_) => panic!("the unknown bank didn't stop the conversion"),
+        }
+    }
+
+    #[test]
+    fn dump_raw() {
+        let (run, config_file, output, trigger_events) = run_with_unknown_bank("unknown_dump");
+        let complete = try_convert_run(
+            &run,
+            &config_file,
+            &output,
+            &["--unknown-banks", "dump-raw"],
+        );
+        // a complete run, exit code 0
+        assert!(matches!(complete, Ok(true)));
+        check_scp1_rows(&run, &output);
+
+        // one row per QDC1 bank, with the words of the bank
+        let contents = fs::read(&run).unwrap();
+        let input = MidasInput::open(&contents).unwrap();
+        let mut expected = vec!["serial,event_id,bank,nbytes,data".to_string()];
+        for event in input.events().filter(|e| e.id() == 1) {
+            let serial = event.serial_number();
+            for bank in event.into_iter().filter(|b| b.name() == "QDC1") {
+                let words: Vec<String> = bank
+                    .data_slice()
+                    .chunks_exact(4)
+                    .map(|b| format!("{:08x}", u32::from_le_bytes(b.try_into().unwrap())))
+                    .collect();
+                expected.push(format!(
+                    "{},1,QDC1,{},{}",
+                    serial,
+                    bank.data_slice().len(),
+                    words.join(" ")
+                ));
+            }
+        }
+        assert_eq!(expected.len() as u64, trigger_events + 1);
+        let raw = fs::read_to_string(format!("{}_unknown_banks.csv", output)).unwrap();
+        assert_eq!(raw.lines().collect::<Vec<_>>(), expected);
+    }
+}
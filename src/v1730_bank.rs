@@ -1,8 +1,5 @@
-use std::default;
-
-use itertools::Itertools;
-
 use crate::bitmasks;
+use crate::error::DecodeError;
 
 /*
 For right now I am injesting data from the front end written
@@ -10,11 +7,16 @@ by W. Fox which strips out all of the useless header info.
 
  */
 #[derive(Default)]
+#[allow(non_camel_case_types)]
 pub struct v1730Hit {
     pub channel: u32,
     pub long: u32,
     pub coarse_time: u64,
     pub time: f64,
+    // event_sequence flags of the midas event the bank came from
+    pub flags: u8,
+    // sub-run file of the midas event
+    pub subrun: u32,
 }
 
 impl v1730Hit {
@@ -34,10 +36,13 @@ impl v1730Hit {
             long,
             coarse_time,
             time,
+            flags: 0,
+            subrun: 0,
         }
     }
 }
 
+#[allow(non_camel_case_types)]
 pub struct v1730Bank {
     pub hits: Vec<v1730Hit>,
 }
@@ -49,13 +54,26 @@ impl v1730Bank {
         }
     }
 
-    pub fn parse(&mut self, bank: &[u8]) {
+    // Returns the number of words left over after the last complete hit. Without
+    // resilient a partial hit is an error.
+    pub fn parse(&mut self, bank: &[u8], resilient: bool) -> Result<u64, DecodeError> {
+        if !bank.len().is_multiple_of(4) {
+            return Err(DecodeError::BankLength(bank.len()));
+        }
         // Each bank consists of 32 bit words
         // in groups of 3 for the channel data.
         // We need to combine the u8 to u32 then group again into .
         let all_words: Vec<u32> = bank.chunks(4).map(bitmasks::to_u32_le).collect();
+        let leftover = all_words.len() % 3;
+        if leftover != 0 && !resilient {
+            return Err(DecodeError::BankSize {
+                expected: all_words.len() - leftover + 3,
+                found: all_words.len(),
+            });
+        }
         all_words
-            .chunks(3)
+            .chunks_exact(3)
             .for_each(|hit| self.hits.push(v1730Hit::new(hit)));
+        Ok(leftover as u64)
     }
 }
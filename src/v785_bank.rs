@@ -1,4 +1,5 @@
 use crate::bitmasks;
+use crate::error::DecodeError;
 
 /*
 This the for the v785 ADC bank information.
@@ -11,18 +12,29 @@ The only wrinkle is that we have to keep track of the event numbers ourselves.
  */
 
 #[derive(Default)]
+#[allow(non_camel_case_types)]
 pub struct v785Hit {
     pub values: [u32; 32],
     pub evt: u32,
+    // event_sequence flags of the midas event the bank came from
+    pub flags: u8,
+    // sub-run file of the midas event
+    pub subrun: u32,
 }
 
 impl v785Hit {
     pub fn new(evt: u32) -> v785Hit {
         let values: [u32; 32] = [0; 32];
-        v785Hit { values, evt }
+        v785Hit {
+            values,
+            evt,
+            flags: 0,
+            subrun: 0,
+        }
     }
 }
 
+#[allow(non_camel_case_types)]
 pub struct v785Bank {
     pub hits: Vec<v785Hit>,
     global_evt_num: u32,
@@ -36,21 +48,41 @@ impl v785Bank {
         }
     }
 
-    fn add_hit(&mut self, data: &[u8]) {
+    fn add_hit(&mut self, data: &[u8]) -> Result<(), DecodeError> {
+        if !data.len().is_multiple_of(4) {
+            return Err(DecodeError::BankLength(data.len()));
+        }
         let mut current_hit = v785Hit::new(self.global_evt_num);
         // convert the 4 u8 numbers to a single u32 number
         let all_data: Vec<u32> = data.chunks(4).map(bitmasks::to_u32_le).collect();
         // there are 34 u32 numbers in the bank, we only care about the first 32
-        for i in 0..32 {
-            current_hit.values[i as usize] = all_data[i as usize];
+        if all_data.len() < 32 {
+            return Err(DecodeError::BankSize {
+                expected: 32,
+                found: all_data.len(),
+            });
         }
+        current_hit.values.copy_from_slice(&all_data[..32]);
         current_hit.evt = self.global_evt_num;
         // store and increment
         self.global_evt_num += 1;
         self.hits.push(current_hit);
+        Ok(())
+    }
+
+    // hits decoded by a worker of the parallel decoding, which numbered them
+    // from zero, so they get their place in the run here
+    pub fn append_hits(&mut self, hits: impl Iterator<Item = v785Hit>) {
+        for mut hit in hits {
+            hit.evt = self.global_evt_num;
+            self.global_evt_num += 1;
+            self.hits.push(hit);
+        }
     }
 
-    pub fn parse(&mut self, bank: &[u8]) {
-        self.add_hit(bank);
+    // the whole bank is one hit, so there are no single words to skip
+    pub fn parse(&mut self, bank: &[u8]) -> Result<u64, DecodeError> {
+        self.add_hit(bank)?;
+        Ok(0)
     }
 }
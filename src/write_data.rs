@@ -6,36 +6,79 @@ use std::fs::File;
 use std::io::BufWriter;
 use std::io::Write;
 
+// finish a header line, with the flags and subrun columns if they were asked for
+fn write_flags_header(
+    file: &mut BufWriter<File>,
+    flag_events: bool,
+    subruns: bool,
+) -> std::io::Result<()> {
+    if flag_events {
+        write!(file, ",flags")?;
+    }
+    if subruns {
+        write!(file, ",subrun")?;
+    }
+    writeln!(file)
+}
+
+// finish a row
+fn write_flags(
+    file: &mut BufWriter<File>,
+    flag_events: bool,
+    flags: u8,
+    subruns: bool,
+    subrun: u32,
+) -> std::io::Result<()> {
+    if flag_events {
+        write!(file, ",{}", flags)?;
+    }
+    if subruns {
+        write!(file, ",{}", subrun)?;
+    }
+    writeln!(file)
+}
+
 pub struct CSVFile {
     first_call: bool,
+    // adds the event_sequence flags as the last column
+    flag_events: bool,
+    // and the sub-run after that
+    subruns: bool,
     file: BufWriter<File>,
 }
 
 impl CSVFile {
-    pub fn new(filename: &str) -> Self {
-        CSVFile {
+    pub fn new(filename: &str, flag_events: bool, subruns: bool) -> std::io::Result<Self> {
+        Ok(CSVFile {
             first_call: true,
-            file: BufWriter::new(File::create(filename).unwrap()),
-        }
+            flag_events,
+            subruns,
+            file: BufWriter::new(File::create(filename)?),
+        })
+    }
+
+    // so the rows so far can be read while a followed run goes on
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
     }
 
     // here is the write function for a csv.
-    pub fn write_data(&mut self, bank_data: &mut MDPPBank) {
+    pub fn write_data(&mut self, bank_data: &mut MDPPBank) -> std::io::Result<()> {
         // write the csv header if we haven't already
         if self.first_call {
-            writeln!(
+            write!(
                 self.file,
                 "module,channel,adc,long,short,tdc,trigger_dt,pileup,evt_ts"
-            )
-            .unwrap();
+            )?;
+            write_flags_header(&mut self.file, self.flag_events, self.subruns)?;
             self.first_call = false;
         }
 
         // loop through events
-        for event in &bank_data.events {
+        for event in bank_data.completed_events() {
             // loop through hits
             for (&chan, chan_hit) in event.channels.iter().zip(&event.channel_hits) {
-                writeln!(
+                write!(
                     self.file,
                     "{},{},{},{},{},{},{},{},{}",
                     event.module_id,
@@ -47,12 +90,19 @@ impl CSVFile {
                     chan_hit.trigger_dt_value,
                     chan_hit.pile_up,
                     event.evt_timestamp
-                )
-                .unwrap();
+                )?;
+                write_flags(
+                    &mut self.file,
+                    self.flag_events,
+                    event.flags,
+                    self.subruns,
+                    event.subrun,
+                )?;
             }
         }
         // free the memory for the old events
         bank_data.clear_data();
+        Ok(())
     }
 }
 
@@ -62,26 +112,27 @@ pub struct CSVScaler {
 }
 
 impl CSVScaler {
-    pub fn new(filename: &str) -> Self {
-        CSVScaler {
+    pub fn new(filename: &str) -> std::io::Result<Self> {
+        Ok(CSVScaler {
             first_call: true,
-            file: BufWriter::new(File::create(filename).unwrap()),
-        }
+            file: BufWriter::new(File::create(filename)?),
+        })
     }
 
-    fn write_header(&mut self) {
+    fn write_header(&mut self) -> std::io::Result<()> {
         // write the csv header if we haven't already
         let chan_str = (0..32)
             .map(|i| format!("chan_{}", i))
             .collect::<Vec<String>>()
             .join(",");
-        writeln!(self.file, "{}", chan_str).unwrap();
+        writeln!(self.file, "{}", chan_str)?;
         self.first_call = false;
+        Ok(())
     }
 
-    pub fn write_data(&mut self, bank_data: &mut ScalerBank) {
+    pub fn write_data(&mut self, bank_data: &mut ScalerBank) -> std::io::Result<()> {
         if self.first_call {
-            self.write_header();
+            self.write_header()?;
         }
 
         // loop through scaler data
@@ -94,36 +145,48 @@ impl CSVScaler {
                 .map(|x| x.to_string())
                 .collect::<Vec<String>>()
                 .join(",")
-        )
-        .unwrap();
+        )?;
+        Ok(())
     }
 }
 
-/// Structs for the v785
+// Structs for the v785
 
 pub struct CSVv785 {
     first_call: bool,
+    // adds the event_sequence flags as the last column
+    flag_events: bool,
+    // and the sub-run after that
+    subruns: bool,
     file: BufWriter<File>,
 }
 
 impl CSVv785 {
-    pub fn new(filename: &str) -> CSVv785 {
-        CSVv785 {
+    pub fn new(filename: &str, flag_events: bool, subruns: bool) -> std::io::Result<CSVv785> {
+        Ok(CSVv785 {
             first_call: true,
-            file: BufWriter::new(File::create(filename).unwrap()),
-        }
+            flag_events,
+            subruns,
+            file: BufWriter::new(File::create(filename)?),
+        })
     }
 
-    fn write_header(&mut self) {
+    fn write_header(&mut self) -> std::io::Result<()> {
         // write the csv header
-        writeln!(self.file, "module,channel,adc,evt_ts").unwrap();
+        write!(self.file, "module,channel,adc,evt_ts")?;
+        write_flags_header(&mut self.file, self.flag_events, self.subruns)?;
         self.first_call = false;
+        Ok(())
     }
 
-    pub fn write_data(&mut self, bank_data: &mut v785Bank) {
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+
+    pub fn write_data(&mut self, bank_data: &mut v785Bank) -> std::io::Result<()> {
         // check if we need to write the header
         if self.first_call {
-            self.write_header();
+            self.write_header()?;
         }
 
         // the v785 data is a bit different and is already stored as a vector
@@ -131,50 +194,80 @@ impl CSVv785 {
         for hit in bank_data.hits.iter() {
             for i in 0..32 {
                 //                let value_str = hit.values[i as usize].to_string();
-                writeln!(
+                write!(
                     self.file,
                     "{},{},{},{}",
                     0, i, hit.values[i as usize], hit.evt
-                )
-                .unwrap();
+                )?;
+                write_flags(
+                    &mut self.file,
+                    self.flag_events,
+                    hit.flags,
+                    self.subruns,
+                    hit.subrun,
+                )?;
             }
         }
+        // free the memory for the old hits
+        bank_data.hits.clear();
+        Ok(())
     }
 }
 
-/// v1730
+// v1730
 pub struct CSVv1730 {
     first_call: bool,
+    // adds the event_sequence flags as the last column
+    flag_events: bool,
+    // and the sub-run after that
+    subruns: bool,
     file: BufWriter<File>,
 }
 
 impl CSVv1730 {
-    pub fn new(filename: &str) -> Self {
-        CSVv1730 {
+    pub fn new(filename: &str, flag_events: bool, subruns: bool) -> std::io::Result<Self> {
+        Ok(CSVv1730 {
             first_call: true,
-            file: BufWriter::new(File::create(filename).unwrap()),
-        }
+            flag_events,
+            subruns,
+            file: BufWriter::new(File::create(filename)?),
+        })
     }
 
-    fn write_header(&mut self) {
+    fn write_header(&mut self) -> std::io::Result<()> {
         // write the csv header
-        writeln!(self.file, "channel,long,coarse_time,time").unwrap();
+        write!(self.file, "channel,long,coarse_time,time")?;
+        write_flags_header(&mut self.file, self.flag_events, self.subruns)?;
         self.first_call = false;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
     }
 
-    pub fn write_data(&mut self, bank_data: &mut v1730Bank) {
+    pub fn write_data(&mut self, bank_data: &mut v1730Bank) -> std::io::Result<()> {
         // check if we need to write the header
         if self.first_call {
-            self.write_header();
+            self.write_header()?;
         }
 
         for hit in bank_data.hits.iter() {
-            writeln!(
+            write!(
                 self.file,
                 "{},{},{},{}",
                 hit.channel, hit.long, hit.coarse_time, hit.time
-            )
-            .unwrap();
+            )?;
+            write_flags(
+                &mut self.file,
+                self.flag_events,
+                hit.flags,
+                self.subruns,
+                hit.subrun,
+            )?;
         }
+        // free the memory for the old hits
+        bank_data.hits.clear();
+        Ok(())
     }
 }